@@ -0,0 +1,77 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Benchmark parsing and traversing generated graphs, with `cargo bench -p btd`.
+
+use btd::buck::cells::CellInfo;
+use btd::buck::targets::Targets;
+use btd::changes::Changes;
+use btd::diff;
+use btd::sapling::status::Status;
+use btd::testing::graph_gen;
+use btd::testing::graph_gen::GraphSpec;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use td_util::json;
+
+const SIZES: [usize; 2] = [10_000, 100_000];
+
+fn spec(targets: usize) -> GraphSpec {
+    GraphSpec {
+        targets,
+        packages: targets / 10,
+        ..GraphSpec::default()
+    }
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for size in SIZES {
+        let mut data = Vec::new();
+        json::write_json_lines(&mut data, graph_gen::generate(&spec(size)).entries()).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| Targets::from_reader(&data[..]).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_traverse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("traverse");
+    for size in SIZES {
+        let spec = spec(size);
+        let targets = graph_gen::generate(&spec);
+        let files = graph_gen::changed_files(&spec, 10, 0)
+            .into_iter()
+            .map(Status::Modified)
+            .collect();
+        let changes = Changes::new(&CellInfo::testing(), files).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("immediate", size),
+            &targets,
+            |b, targets| {
+                b.iter(|| diff::immediate_target_changes(targets, targets, &changes, false))
+            },
+        );
+        let immediate = diff::immediate_target_changes(&targets, &targets, &changes, false);
+        group.bench_with_input(
+            BenchmarkId::new("recursive", size),
+            &targets,
+            |b, targets| {
+                b.iter(|| diff::recursive_target_changes(targets, &immediate, None, |_| true))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_traverse);
+criterion_main!(benches);
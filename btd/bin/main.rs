@@ -13,6 +13,6 @@ use td_util::cli::parse_args;
 
 #[fbinit::main]
 pub fn main(fb: fbinit::FacebookInit) -> anyhow::Result<()> {
-    let _guard = td_util::init(fb);
+    let _guard = td_util::init(fb)?;
     btd::main(parse_args()?)
 }
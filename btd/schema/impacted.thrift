@@ -0,0 +1,89 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// The `--output-format v2` output of BTD, written as a `Document` in the compact
+// protocol by `--output-encoding thrift-compact`. Each field is documented in the
+// JSON output, see `btd/README.md`.
+
+namespace py btd.impacted
+
+struct Reason {
+  1: string category;
+  2: string kind;
+  3: string confidence;
+  4: string changed_target;
+  5: optional string via;
+}
+
+struct Target {
+  1: string target;
+  2: string rule_type;
+  3: optional string oncall;
+  4: i64 depth;
+  5: list<string> labels;
+  6: Reason reason;
+  7: optional list<string> owners;
+  // `class` in the JSON, which is reserved in Thrift.
+  8: optional string target_class;
+  9: optional string test_type;
+  // The JSON text of each attribute named by `--output-attribute`.
+  10: optional map<string, string> attributes;
+  11: optional double score;
+}
+
+struct RemovedTarget {
+  1: string target;
+  2: string rule_type;
+  3: string package;
+}
+
+struct Skipped {
+  1: string target;
+  2: double score;
+  3: optional double duration;
+  4: string reason;
+}
+
+struct SnapshotHeader {
+  1: optional string revision;
+  2: optional i64 created;
+  3: optional list<string> universe;
+  4: optional string buck_version;
+}
+
+struct Alias {
+  1: string alias;
+  2: string target;
+}
+
+struct PackageError {
+  1: string package;
+  2: string category;
+  3: string error;
+}
+
+struct Metadata {
+  1: optional SnapshotHeader base;
+  2: optional SnapshotHeader diff;
+}
+
+struct Document {
+  1: i64 version;
+  2: list<Target> targets;
+  3: bool truncated;
+  4: optional list<RemovedTarget> removed;
+  5: optional list<Skipped> skipped;
+  6: optional bool partial;
+  7: optional Metadata metadata;
+  8: optional list<Target> quarantined;
+  9: optional list<Alias> aliases;
+  10: optional list<Target> immediate_changes;
+  11: optional list<Target> transitive_changes;
+  12: optional list<PackageError> errors;
+}
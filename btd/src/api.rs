@@ -0,0 +1,423 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Run the change detection in-process, for services that would rather not spawn the `btd` binary.
+//!
+//! Build a [`Config`] from the targets before/after and the changed files,
+//! optionally refine it with the builder methods, then call [`run_btd`].
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::buck::cells::CellInfo;
+use crate::buck::labels::Labels;
+use crate::buck::targets::Targets;
+use crate::buck::types::Oncall;
+use crate::buck::types::ParsedTargetPattern;
+use crate::buck::types::RuleType;
+use crate::buck::types::TargetLabel;
+use crate::change_policy::PolicyImpact;
+use crate::changes::Attribution;
+use crate::changes::Changes;
+use crate::changes::PackageChangePolicy;
+use crate::check;
+use crate::check_empty;
+use crate::diff;
+use crate::diff::AttributeDiff;
+use crate::diff::BrokenPackagePolicy;
+use crate::diff::ImmediateOptions;
+use crate::diff::ImpactReason;
+use crate::impact_rule::ImpactRule;
+use crate::impact_rule::ImpactRules;
+use crate::output::Confidence;
+use crate::package_error::ErrorCategory;
+use crate::rdeps::Edges;
+use crate::rdeps::Rdeps;
+use crate::sapling::status::read_status;
+
+/// The inputs and settings for a single change detection run.
+pub struct Config {
+    base: Targets,
+    diff: Targets,
+    changes: Changes,
+    depth: Option<usize>,
+    track_prelude_rule_changes: bool,
+    follow_loads: bool,
+    attribution: Attribution,
+    package_change_policy: PackageChangePolicy,
+    attribute_diff: AttributeDiff,
+    broken_package_policy: BrokenPackagePolicy,
+    check_errors: bool,
+    tolerated_package_errors: Vec<ErrorCategory>,
+    include_labels: Vec<String>,
+    exclude_labels: Vec<String>,
+    exclude_patterns: Vec<ParsedTargetPattern>,
+    changed_targets: Vec<ParsedTargetPattern>,
+    terminal_rules: Vec<String>,
+    impact_rules: ImpactRules,
+}
+
+impl Config {
+    pub fn new(base: Targets, diff: Targets, changes: Changes) -> Self {
+        Self {
+            base,
+            diff,
+            changes,
+            depth: None,
+            track_prelude_rule_changes: false,
+            follow_loads: false,
+            attribution: Attribution::default(),
+            package_change_policy: PackageChangePolicy::default(),
+            attribute_diff: AttributeDiff::default(),
+            broken_package_policy: BrokenPackagePolicy::default(),
+            check_errors: true,
+            tolerated_package_errors: Vec::new(),
+            include_labels: Vec::new(),
+            exclude_labels: Vec::new(),
+            exclude_patterns: Vec::new(),
+            changed_targets: Vec::new(),
+            terminal_rules: Vec::new(),
+            impact_rules: ImpactRules::default(),
+        }
+    }
+
+    /// Read the inputs from the same files the `btd` binary takes as
+    /// `--cells`, `--base`, `--diff` and `--changes`.
+    pub fn from_files(
+        cells: &Path,
+        base: &Path,
+        diff: &Path,
+        changes: &Path,
+    ) -> anyhow::Result<Self> {
+        let cells = CellInfo::new(cells)?;
+        let changes = Changes::new(&cells, read_status(changes)?)?;
+        Ok(Self::new(
+            Targets::from_file(base)?,
+            Targets::from_file(diff)?,
+            changes,
+        ))
+    }
+
+    /// Number of levels of dependency to explore, where `0` means only the
+    /// directly changed targets. Defaults to no limit.
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Look for prelude rule changes and dirty inputs in response.
+    pub fn track_prelude_rule_changes(mut self, track: bool) -> Self {
+        self.track_prelude_rule_changes = track;
+        self
+    }
+
+    /// A changed `.bzl` file impacts every target whose build file loads it,
+    /// directly or transitively.
+    pub fn follow_loads(mut self, follow: bool) -> Self {
+        self.follow_loads = follow;
+        self
+    }
+
+    /// How changed files impact targets, defaults to [`Attribution::Strict`].
+    pub fn attribution(mut self, attribution: Attribution) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// How `PACKAGE` and buckconfig file changes impact targets,
+    /// defaults to [`PackageChangePolicy::Ignore`].
+    pub fn package_change_policy(mut self, policy: PackageChangePolicy) -> Self {
+        self.package_change_policy = policy;
+        self
+    }
+
+    /// How to decide whether a target changed, see [`AttributeDiff`], which defaults to
+    /// comparing hashes. Comparing attributes needs targets read with
+    /// [`ReadOptions::attributes`](crate::buck::targets::ReadOptions::attributes).
+    pub fn attribute_diff(mut self, attribute_diff: AttributeDiff) -> Self {
+        self.attribute_diff = attribute_diff;
+        self
+    }
+
+    /// What to do with packages which only fail to evaluate after the change,
+    /// defaults to [`BrokenPackagePolicy::Fail`].
+    pub fn broken_package_policy(mut self, policy: BrokenPackagePolicy) -> Self {
+        self.broken_package_policy = policy;
+        self
+    }
+
+    /// Fail if the diff introduces new Buck2 errors (the default).
+    pub fn check_errors(mut self, check: bool) -> Self {
+        self.check_errors = check;
+        self
+    }
+
+    /// Only warn about packages failing with errors of this category, rather than failing.
+    pub fn tolerate_package_error(mut self, category: ErrorCategory) -> Self {
+        self.tolerated_package_errors.push(category);
+        self
+    }
+
+    /// Only report targets with at least one of the included labels.
+    /// Only applies to what is reported, the traversal still goes through every target.
+    pub fn include_label(mut self, label: &str) -> Self {
+        self.include_labels.push(label.to_owned());
+        self
+    }
+
+    /// Never report targets with this label.
+    pub fn exclude_label(mut self, label: &str) -> Self {
+        self.exclude_labels.push(label.to_owned());
+        self
+    }
+
+    /// Never report targets matching this pattern, e.g. `foo//experimental/...`.
+    /// Targets depending on them are still reported.
+    pub fn exclude_pattern(mut self, pattern: ParsedTargetPattern) -> Self {
+        self.exclude_patterns.push(pattern);
+        self
+    }
+
+    /// Treat targets matching this pattern as changed, e.g. `foo//lib:core`,
+    /// even if none of their files did.
+    pub fn changed_target(mut self, pattern: ParsedTargetPattern) -> Self {
+        self.changed_targets.push(pattern);
+        self
+    }
+
+    /// Don't follow the impact past targets of this rule type, e.g. `config_setting`.
+    /// The targets themselves are still reported.
+    pub fn terminal_rule(mut self, rule_type: &str) -> Self {
+        self.terminal_rules.push(rule_type.to_owned());
+        self
+    }
+
+    /// Apply a custom rule for what a change impacts, after any added before.
+    pub fn impact_rule(mut self, rule: Box<dyn ImpactRule>) -> Self {
+        self.impact_rules.push(rule);
+        self
+    }
+
+    fn wanted(&self, target: &TargetLabel, labels: &Labels) -> bool {
+        (self.include_labels.is_empty() || self.include_labels.iter().any(|x| labels.contains(x)))
+            && !self.exclude_labels.iter().any(|x| labels.contains(x))
+            && !self.exclude_patterns.iter().any(|x| x.matches(target))
+    }
+}
+
+/// A target impacted by the changes. Unlike [`Output`](crate::output::Output) it
+/// owns all its data, so it can outlive the targets it was computed from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImpactedTarget {
+    pub target: TargetLabel,
+    pub rule_type: RuleType,
+    pub oncall: Option<Oncall>,
+    /// How many levels of dependencies away from a changed target, `0` if it changed itself.
+    pub depth: u64,
+    /// The package values labels followed by the target labels.
+    pub labels: Labels,
+    pub reason: ImpactReason,
+    pub confidence: Confidence,
+}
+
+/// The targets impacted by the changes, ordered by depth, then by label.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImpactedTargets {
+    pub targets: Vec<ImpactedTarget>,
+}
+
+impl ImpactedTargets {
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ImpactedTarget> {
+        self.targets.iter()
+    }
+}
+
+/// Compute the targets impacted by the changes described in `config`.
+pub fn run_btd(config: Config) -> anyhow::Result<ImpactedTargets> {
+    let mut immediate = diff::immediate_target_changes_with(
+        &config.base,
+        &config.diff,
+        &config.changes,
+        &ImmediateOptions {
+            track_prelude_changes: config.track_prelude_rule_changes,
+            attribution: config.attribution,
+            package_change_policy: config.package_change_policy,
+            attribute_diff: config.attribute_diff.clone(),
+            changed_targets: config.changed_targets.clone(),
+            change_policy: PolicyImpact::default(),
+            broken_package_policy: config.broken_package_policy,
+            follow_loads: config.follow_loads,
+        },
+    );
+    let rules = &config.impact_rules;
+    if !rules.is_empty() {
+        immediate.add_recursive(rules.seed(&config.diff, &config.changes));
+    }
+    if config.check_errors {
+        check_empty(&check::check_errors_with(
+            &config.base,
+            &config.diff,
+            &config.changes,
+            config.broken_package_policy,
+            &config.tolerated_package_errors,
+        ))?;
+    }
+
+    let rdeps =
+        (!rules.is_empty()).then(|| Rdeps::with_rules(&config.diff, Edges::default(), rules));
+    let mut levels = Vec::new();
+    diff::recursive_target_changes_with_rdeps(
+        &config.diff,
+        rdeps.as_ref(),
+        &immediate,
+        config.depth,
+        true,
+        |x| !diff::is_terminal_rule(x, &config.terminal_rules),
+        |level| levels.push(level),
+    );
+    rules.output(&mut levels);
+
+    let mut targets = Vec::new();
+    for (depth, level) in levels.into_iter().enumerate() {
+        for (x, reason) in level {
+            let labels = x.package_values.labels.merge(&x.labels);
+            let target = x.label();
+            if config.wanted(&target, &labels) {
+                targets.push(ImpactedTarget {
+                    target,
+                    rule_type: x.rule_type.clone(),
+                    oncall: x.oncall.clone(),
+                    depth: depth as u64,
+                    labels,
+                    confidence: Confidence::new(&reason),
+                    reason,
+                });
+            }
+        }
+    }
+    Ok(ImpactedTargets { targets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::arena::Deps;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::Package;
+    use crate::buck::types::TargetHash;
+    use crate::buck::types::TargetName;
+    use crate::buck::types::TargetPattern;
+
+    fn targets(hash: &str) -> Targets {
+        let pkg = Package::new("foo//bar");
+        Targets::new(vec![
+            TargetsEntry::Target(BuckTarget {
+                hash: TargetHash::new(hash),
+                ..BuckTarget::testing("lib", pkg.as_str(), "prelude//rules.bzl:cxx_library")
+            }),
+            TargetsEntry::Target(BuckTarget {
+                deps: Deps::from([pkg.join(&TargetName::new("lib"))]),
+                labels: Labels::new(&["ci"]),
+                ..BuckTarget::testing("test", pkg.as_str(), "prelude//rules.bzl:cxx_test")
+            }),
+        ])
+    }
+
+    fn run(config: Config) -> Vec<(String, u64)> {
+        run_btd(config)
+            .unwrap()
+            .iter()
+            .map(|x| (x.target.to_string(), x.depth))
+            .collect()
+    }
+
+    fn config() -> Config {
+        Config::new(targets("1"), targets("2"), Changes::default())
+    }
+
+    #[test]
+    fn test_run_btd() {
+        assert_eq!(
+            run(config()),
+            vec![
+                ("foo//bar:lib".to_owned(), 0),
+                ("foo//bar:test".to_owned(), 1)
+            ]
+        );
+        assert_eq!(run(config().depth(0)), vec![("foo//bar:lib".to_owned(), 0)]);
+        assert!(
+            run_btd(Config::new(targets("1"), targets("1"), Changes::default()))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_run_btd_labels() {
+        assert_eq!(
+            run(config().include_label("ci")),
+            vec![("foo//bar:test".to_owned(), 1)]
+        );
+        assert_eq!(
+            run(config().exclude_label("ci")),
+            vec![("foo//bar:lib".to_owned(), 0)]
+        );
+        assert_eq!(
+            run(config().include_label("ci").exclude_label("ci")),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn test_run_btd_impact_rule() {
+        /// Every test is impacted by any change.
+        struct AllTests;
+
+        impl ImpactRule for AllTests {
+            fn name(&self) -> &str {
+                "all_tests"
+            }
+
+            fn seed<'a>(&self, diff: &'a Targets, _changes: &Changes) -> Vec<&'a BuckTarget> {
+                diff.targets()
+                    .filter(|x| x.rule_type.short() == "cxx_test")
+                    .collect()
+            }
+        }
+
+        let config = Config::new(targets("1"), targets("1"), Changes::default());
+        assert_eq!(
+            run(config.impact_rule(Box::new(AllTests))),
+            vec![("foo//bar:test".to_owned(), 0)]
+        );
+    }
+
+    #[test]
+    fn test_run_btd_exclude_pattern() {
+        let pattern = |x: &str| TargetPattern::new(x).parse().unwrap();
+        assert_eq!(
+            run(config().exclude_pattern(pattern("foo//bar:lib"))),
+            vec![("foo//bar:test".to_owned(), 1)]
+        );
+        assert_eq!(
+            run(config().exclude_pattern(pattern("foo//..."))),
+            Vec::new()
+        );
+    }
+}
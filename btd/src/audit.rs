@@ -0,0 +1,207 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Find dead or untested code in a graph: targets nothing depends on, and libraries
+//! no test covers, even transitively.
+
+use std::collections::HashSet;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use serde::Serialize;
+use td_util::json;
+
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetPattern;
+use crate::classify::Classifier;
+use crate::rdeps::Rdeps;
+
+/// Audit a targets file for orphans, which no target depends on and aren't tests,
+/// and untested libraries, which no test depends on, printing each as a line of JSON.
+#[derive(clap::Args, Debug)]
+pub struct AuditArgs {
+    /// Targets file to audit, either the JSON output of `buck2 targets` or a snapshot.
+    #[arg(long, value_name = "FILE")]
+    targets: PathBuf,
+
+    /// Only report one kind of finding.
+    #[arg(long, value_enum)]
+    only: Option<FindingKind>,
+
+    /// Only audit targets matching these patterns, as with `btd --universe`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    universe: Vec<String>,
+
+    /// Don't report targets matching these patterns, as with `btd --exclude`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    exclude: Vec<String>,
+
+    /// A JSON file as for `btd --classify-config`, whose `test_rule_types` also count
+    /// as tests, besides the rule types ending in `test`.
+    #[arg(long, value_name = "FILE")]
+    classify_config: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum FindingKind {
+    Orphan,
+    Untested,
+}
+
+/// A target which is probably dead or untested.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Finding {
+    /// Nothing depends on it, including through `ci_deps` or `tests`, and it isn't a test.
+    Orphan {
+        target: TargetLabel,
+        rule_type: String,
+    },
+    /// A library which no test depends on, directly or transitively, or lists in `tests`.
+    Untested {
+        target: TargetLabel,
+        rule_type: String,
+    },
+}
+
+/// The targets a test depends on, transitively, or which list a test in `tests`, along
+/// with everything they depend on.
+fn tested(targets: &Targets, classifier: &Classifier) -> HashSet<TargetLabel> {
+    let by_label = targets.targets_by_label();
+    let mut todo = Vec::new();
+    for x in targets.targets() {
+        if classifier.is_test(x) {
+            todo.extend(x.deps.iter().cloned());
+        } else if x.tests.iter().any(|t| by_label.contains_key(t)) {
+            todo.push(x.label());
+        }
+    }
+    let mut res = HashSet::new();
+    while let Some(label) = todo.pop() {
+        if !res.contains(&label) {
+            if let Some(x) = by_label.get(&label) {
+                todo.extend(x.deps.iter().cloned());
+            }
+            res.insert(label);
+        }
+    }
+    res
+}
+
+/// Every orphan and untested library in `targets`, in the order of the targets.
+pub fn audit(targets: &Targets, classifier: &Classifier) -> Vec<Finding> {
+    let rdeps = Rdeps::with_tests(targets);
+    let tested = tested(targets, classifier);
+    let mut res = Vec::new();
+    for x in targets.targets() {
+        if classifier.is_test(x) {
+            continue;
+        }
+        let label = x.label();
+        let rule_type = x.rule_type.short().to_owned();
+        if rdeps.get(&label).next().is_none() {
+            res.push(Finding::Orphan {
+                target: label,
+                rule_type,
+            });
+        } else if rule_type.ends_with("library") && !tested.contains(&label) {
+            res.push(Finding::Untested {
+                target: label,
+                rule_type,
+            });
+        }
+    }
+    res
+}
+
+pub fn main(args: AuditArgs) -> anyhow::Result<()> {
+    let parse = |xs: &[String]| {
+        xs.iter()
+            .map(|x| TargetPattern::new(x).parse())
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let universe = parse(&args.universe)?;
+    let exclude = parse(&args.exclude)?;
+    let classifier = match &args.classify_config {
+        Some(file) => Classifier::read_file(file)?,
+        None => Classifier::default(),
+    };
+
+    let targets = Targets::from_file(&args.targets)?.restrict(&universe);
+    let mut res = audit(&targets, &classifier);
+    res.retain(|x| {
+        let (kind, target) = match x {
+            Finding::Orphan { target, .. } => (FindingKind::Orphan, target),
+            Finding::Untested { target, .. } => (FindingKind::Untested, target),
+        };
+        args.only.map_or(true, |only| only == kind) && !exclude.iter().any(|p| p.matches(target))
+    });
+    json::write_json_lines(BufWriter::new(stdout().lock()), &res)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::TargetsEntry;
+    use crate::classify::ClassifyConfig;
+
+    #[test]
+    fn test_audit() {
+        let target = |name: &str, rule_type: &str, deps: &[&str], tests: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                tests: tests.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", &format!("prelude//rules.bzl:{rule_type}"))
+            })
+        };
+        let targets = Targets::new(vec![
+            target("util", "rust_library", &[], &[]),
+            target("lib", "rust_library", &["foo//bar:util"], &[]),
+            target("bin", "rust_binary", &["foo//bar:lib"], &[]),
+            target("covered", "rust_library", &[], &[]),
+            target("listed", "rust_library", &[], &["foo//bar:listed_test"]),
+            target("listed_test", "python_test", &[], &[]),
+            target("unit_test", "rust_test", &["foo//bar:covered"], &[]),
+            target("dead", "rust_library", &[], &[]),
+            target("check", "custom_check", &["foo//bar:bin"], &[]),
+        ]);
+        let finding = |kind: FindingKind, name: &str, rule_type: &str| {
+            let target = TargetLabel::new(&format!("foo//bar:{name}"));
+            let rule_type = rule_type.to_owned();
+            match kind {
+                FindingKind::Orphan => Finding::Orphan { target, rule_type },
+                FindingKind::Untested => Finding::Untested { target, rule_type },
+            }
+        };
+        assert_eq!(
+            audit(&targets, &Classifier::default()),
+            vec![
+                finding(FindingKind::Untested, "util", "rust_library"),
+                finding(FindingKind::Untested, "lib", "rust_library"),
+                finding(FindingKind::Orphan, "dead", "rust_library"),
+                finding(FindingKind::Orphan, "check", "custom_check"),
+            ]
+        );
+
+        // Once `custom_check` counts as a test, it covers everything below it
+        let classifier = Classifier::new(ClassifyConfig {
+            test_rule_types: vec!["custom_check".to_owned()],
+            ..ClassifyConfig::default()
+        });
+        assert_eq!(
+            audit(&targets, &classifier),
+            vec![finding(FindingKind::Orphan, "dead", "rust_library")]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Find the choke points of a graph, the targets which the impact of many others
+//! must flow through.
+//!
+//! Impact flows from a target to those depending on it, starting from the targets
+//! without dependencies. In that graph a target dominates another when every path
+//! to the other goes through it, so any change below the other impacts it only by
+//! impacting the target first. We compute the dominators with the algorithm from
+//! "A Simple, Fast Dominance Algorithm" by Cooper, Harvey and Kennedy.
+
+use std::collections::HashMap;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use td_util::json;
+
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetPattern;
+use crate::rdeps::Rdeps;
+
+/// Rank the targets by how many others they dominate, i.e. how many targets are only
+/// impacted through them, biggest first, one per line with the count.
+#[derive(clap::Args, Debug)]
+pub struct BlastRadiusArgs {
+    /// Targets file to analyse, either the JSON output of `buck2 targets` or a snapshot.
+    #[arg(long, value_name = "FILE")]
+    targets: PathBuf,
+
+    /// Only print the first `N` targets.
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Only analyse targets matching these patterns, as with `btd --universe`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    universe: Vec<String>,
+
+    /// Print each target as a JSON object with its `dominated` count.
+    #[arg(long)]
+    json_lines: bool,
+}
+
+/// A target, and how many targets it dominates, not including itself.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ChokePoint {
+    pub target: TargetLabel,
+    pub dominated: usize,
+}
+
+/// The immediate dominator of each node, given the edges, with the root last, along with
+/// the nodes in post order, so after every node they dominate. Every node must be
+/// reachable from the root.
+fn immediate_dominators(preds: &[Vec<usize>], succs: &[Vec<usize>]) -> (Vec<usize>, Vec<usize>) {
+    let root = preds.len() - 1;
+
+    // Number the nodes in post order, with an explicit stack as graphs can be deep
+    let mut order = vec![None; preds.len()];
+    let mut post = Vec::with_capacity(preds.len());
+    let mut stack = vec![(root, 0)];
+    order[root] = Some(0);
+    while let Some((node, i)) = stack.pop() {
+        match succs[node].get(i) {
+            Some(&next) => {
+                stack.push((node, i + 1));
+                if order[next].is_none() {
+                    order[next] = Some(0);
+                    stack.push((next, 0));
+                }
+            }
+            None => {
+                order[node] = Some(post.len());
+                post.push(node);
+            }
+        }
+    }
+
+    let mut idom = vec![None; preds.len()];
+    idom[root] = Some(root);
+    let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+        while a != b {
+            while order[a] < order[b] {
+                a = idom[a].unwrap();
+            }
+            while order[b] < order[a] {
+                b = idom[b].unwrap();
+            }
+        }
+        a
+    };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        // Reverse post order, skipping the root
+        for &node in post.iter().rev().skip(1) {
+            let mut new = None;
+            for &pred in &preds[node] {
+                if idom[pred].is_some() {
+                    new = Some(match new {
+                        None => pred,
+                        Some(x) => intersect(&idom, pred, x),
+                    });
+                }
+            }
+            if new != idom[node] {
+                idom[node] = new;
+                changed = true;
+            }
+        }
+    }
+    (idom.into_iter().map(Option::unwrap).collect(), post)
+}
+
+/// Mark `seen` the nodes reachable from `start`.
+fn reach(succs: &[Vec<usize>], start: usize, seen: &mut [bool]) {
+    let mut todo = vec![start];
+    while let Some(x) = todo.pop() {
+        if !seen[x] {
+            seen[x] = true;
+            todo.extend(&succs[x]);
+        }
+    }
+}
+
+/// Every target in `targets` with how many targets it dominates, biggest first.
+pub fn choke_points(targets: &Targets) -> Vec<ChokePoint> {
+    let nodes = targets.targets().collect::<Vec<_>>();
+    let index = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.label(), i))
+        .collect::<HashMap<_, _>>();
+    let rdeps = Rdeps::new(targets);
+    let root = nodes.len();
+    let mut succs = vec![Vec::new(); nodes.len() + 1];
+    let mut preds = vec![Vec::new(); nodes.len() + 1];
+    for (i, x) in nodes.iter().enumerate() {
+        for rdep in rdeps.get(&x.label()) {
+            let j = index[&rdep.label()];
+            if i != j {
+                succs[i].push(j);
+                preds[j].push(i);
+            }
+        }
+    }
+    // Impact starts at the targets without dependencies, then any left unreachable,
+    // which must be in cycles, so every target has a dominator
+    let mut seen = vec![false; root + 1];
+    let starts = (0..root)
+        .filter(|x| preds[*x].is_empty())
+        .collect::<Vec<_>>();
+    for x in starts.into_iter().chain(0..root) {
+        if !seen[x] {
+            reach(&succs, x, &mut seen);
+            succs[root].push(x);
+            preds[x].push(root);
+        }
+    }
+    let (idom, post) = immediate_dominators(&preds, &succs);
+
+    // Each node counts towards all its dominators, so add its count to its parent's
+    // in the dominator tree, from the leaves up
+    let mut dominated = vec![0; root + 1];
+    for x in post {
+        if idom[x] != root {
+            dominated[idom[x]] += dominated[x] + 1;
+        }
+    }
+
+    let mut res = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, x)| ChokePoint {
+            target: x.label(),
+            dominated: dominated[i],
+        })
+        .collect::<Vec<_>>();
+    res.sort_by(|a, b| b.dominated.cmp(&a.dominated).then(a.target.cmp(&b.target)));
+    res
+}
+
+pub fn main(args: BlastRadiusArgs) -> anyhow::Result<()> {
+    let universe = args
+        .universe
+        .iter()
+        .map(|x| TargetPattern::new(x).parse())
+        .collect::<Result<Vec<_>, _>>()?;
+    let targets = Targets::from_file(&args.targets)?.restrict(&universe);
+    let mut res = choke_points(&targets);
+    if let Some(top) = args.top {
+        res.truncate(top);
+    }
+
+    let mut out = BufWriter::new(stdout().lock());
+    if args.json_lines {
+        json::write_json_lines(out, &res)?;
+    } else {
+        for x in &res {
+            writeln!(out, "{} {}", x.target, x.dominated)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::TargetsEntry;
+
+    #[test]
+    fn test_choke_points() {
+        let target = |name: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        // `util` and `base` both feed `core`, which everything else goes through,
+        // except `side`, which `tool` reaches directly too
+        let targets = Targets::new(vec![
+            target("base", &[]),
+            target("util", &[]),
+            target("core", &["foo//bar:base", "foo//bar:util"]),
+            target("lib", &["foo//bar:core"]),
+            target("bin", &["foo//bar:lib"]),
+            target("test", &["foo//bar:lib"]),
+            target("side", &[]),
+            target("tool", &["foo//bar:core", "foo//bar:side"]),
+            // A cycle nothing outside it feeds
+            target("x", &["foo//bar:y"]),
+            target("y", &["foo//bar:x"]),
+        ]);
+        let res = choke_points(&targets)
+            .into_iter()
+            .map(|x| (x.target.target_name().as_str().to_owned(), x.dominated))
+            .collect::<Vec<_>>();
+        let expect = |xs: &[(&str, usize)]| {
+            xs.iter()
+                .map(|(x, n)| (x.to_string(), *n))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            res,
+            expect(&[
+                ("core", 3),
+                ("lib", 2),
+                ("x", 1),
+                ("base", 0),
+                ("bin", 0),
+                ("side", 0),
+                ("test", 0),
+                ("tool", 0),
+                ("util", 0),
+                ("y", 0),
+            ])
+        );
+    }
+}
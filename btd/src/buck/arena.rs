@@ -0,0 +1,229 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Contiguous storage for the dependencies of every target in a graph.
+//!
+//! Parsing gives each target its own small allocation for its deps, which across
+//! millions of targets fragments the heap. Once a graph is read, [`compact`] moves
+//! them all into one shared arena, which each target indexes by offset.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::mem;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::TargetLabel;
+
+/// The dependencies of a target. Once [`compact`]ed, a slice of an arena which is
+/// shared with the other targets of the graph.
+#[derive(Clone)]
+pub struct Deps(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    /// As parsed, before being compacted.
+    Owned(Box<[TargetLabel]>),
+    /// A `Vec` rather than a slice, so making an arena moves the deps rather than
+    /// copying them.
+    Shared {
+        arena: Arc<Vec<TargetLabel>>,
+        start: u32,
+        len: u32,
+    },
+}
+
+impl Deref for Deps {
+    type Target = [TargetLabel];
+
+    fn deref(&self) -> &[TargetLabel] {
+        match &self.0 {
+            Repr::Owned(x) => x,
+            Repr::Shared { arena, start, len } => {
+                let start = *start as usize;
+                &arena[start..start + *len as usize]
+            }
+        }
+    }
+}
+
+impl Default for Deps {
+    fn default() -> Self {
+        Self(Repr::Owned(Box::new([])))
+    }
+}
+
+impl From<Box<[TargetLabel]>> for Deps {
+    fn from(deps: Box<[TargetLabel]>) -> Self {
+        Self(Repr::Owned(deps))
+    }
+}
+
+impl From<Vec<TargetLabel>> for Deps {
+    fn from(deps: Vec<TargetLabel>) -> Self {
+        Self::from(deps.into_boxed_slice())
+    }
+}
+
+impl<const N: usize> From<[TargetLabel; N]> for Deps {
+    fn from(deps: [TargetLabel; N]) -> Self {
+        Self::from(Vec::from(deps))
+    }
+}
+
+impl FromIterator<TargetLabel> for Deps {
+    fn from_iter<I: IntoIterator<Item = TargetLabel>>(iter: I) -> Self {
+        Self::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl PartialEq for Deps {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Deps {}
+
+impl Hash for Deps {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for Deps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl Serialize for Deps {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Deps {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self::from(Box::<[TargetLabel]>::deserialize(deserializer)?))
+    }
+}
+
+/// How the deps of a graph are stored, for `--stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ArenaStats {
+    /// Number of deps, across all targets.
+    pub deps: usize,
+    /// Bytes of deps in the arenas.
+    pub bytes: usize,
+    /// Number of distinct arenas, usually `1` once compacted.
+    pub arenas: usize,
+    /// Allocations avoided compared to each target with deps owning them.
+    pub allocations_saved: usize,
+}
+
+impl ArenaStats {
+    pub fn new<'a>(targets: impl Iterator<Item = &'a BuckTarget>) -> Self {
+        let mut res = Self::default();
+        let mut arenas = HashSet::new();
+        let mut shared = 0;
+        for x in targets {
+            res.deps += x.deps.len();
+            match &x.deps.0 {
+                Repr::Shared { arena, len, .. } if *len > 0 => {
+                    shared += 1;
+                    arenas.insert(Arc::as_ptr(arena));
+                }
+                _ => {}
+            }
+        }
+        res.bytes = res.deps * mem::size_of::<TargetLabel>();
+        res.arenas = arenas.len();
+        res.allocations_saved = shared - res.arenas;
+        res
+    }
+}
+
+/// Move the deps of `targets` into a single arena.
+pub fn compact<'a>(targets: impl Iterator<Item = &'a mut BuckTarget>) {
+    let mut targets = targets.collect::<Vec<_>>();
+    let mut arena = Vec::with_capacity(targets.iter().map(|x| x.deps.len()).sum());
+    let mut lens = Vec::with_capacity(targets.len());
+    for x in &mut targets {
+        // Free each target's own allocation once copied, so the peak stays low
+        let deps = mem::take(&mut x.deps);
+        lens.push(deps.len());
+        arena.extend(deps.iter().cloned());
+    }
+    let arena = Arc::new(arena);
+    let mut start = 0;
+    for (x, len) in targets.into_iter().zip(lens) {
+        if len > 0 {
+            x.deps = Deps(Repr::Shared {
+                arena: arena.clone(),
+                start: start.try_into().expect("fewer than 2^32 deps in a graph"),
+                len: len.try_into().expect("fewer than 2^32 deps in a target"),
+            });
+            start += len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact() {
+        let target = |name: &str, deps: &[&str]| BuckTarget {
+            deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+            ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+        };
+        let mut targets = vec![
+            target("a", &[]),
+            target("b", &["foo//bar:a"]),
+            target("c", &["foo//bar:a", "foo//bar:b"]),
+        ];
+        let before = targets.clone();
+        assert_eq!(
+            ArenaStats::new(targets.iter()),
+            ArenaStats {
+                deps: 3,
+                bytes: 3 * mem::size_of::<TargetLabel>(),
+                arenas: 0,
+                allocations_saved: 0,
+            }
+        );
+
+        compact(targets.iter_mut());
+        assert_eq!(targets, before);
+        assert_eq!(
+            ArenaStats::new(targets.iter()),
+            ArenaStats {
+                deps: 3,
+                bytes: 3 * mem::size_of::<TargetLabel>(),
+                arenas: 1,
+                allocations_saved: 1,
+            }
+        );
+        assert_eq!(&*targets[2].deps, &before[2].deps[..]);
+        assert_eq!(
+            serde_json::to_string(&targets[2].deps).unwrap(),
+            r#"["foo//bar:a","foo//bar:b"]"#
+        );
+    }
+}
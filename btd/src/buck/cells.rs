@@ -8,7 +8,9 @@
  */
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs;
+use std::io;
 use std::path::Path;
 use std::sync::LazyLock;
 
@@ -20,7 +22,7 @@ use crate::buck::types::CellPath;
 use crate::buck::types::CellRelativePath;
 use crate::buck::types::ProjectRelativePath;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct CellData {
     path: ProjectRelativePath,
     build_files: Vec<String>,
@@ -47,6 +49,12 @@ enum CellError {
         prefix: String,
         value: String,
     },
+    #[error("No cells defined in the `[cells]` section of the root `.buckconfig`")]
+    NoBuckconfigCells,
+    #[error("Cell `{cell}` at `{path}` is outside the repo")]
+    OutsideRepo { cell: String, path: String },
+    #[error("Cell alias `{alias}` refers to unknown cell `{cell}`")]
+    UnknownAlias { alias: String, cell: String },
 }
 
 impl CellInfo {
@@ -76,12 +84,106 @@ impl CellInfo {
         res
     }
 
+    /// Read the output of `buck2 audit cell`, or if the file is a `.buckconfig`,
+    /// read the cells it defines with [`CellInfo::from_buckconfig`].
     pub fn new(file: &Path) -> anyhow::Result<Self> {
+        if file.file_name().is_some_and(|x| x == ".buckconfig") {
+            return Self::from_buckconfig(file);
+        }
         let data = fs::read_to_string(file)
             .with_context(|| format!("When reading `{}`", file.display()))?;
         Self::parse(&data)
     }
 
+    /// Read the cells from the `.buckconfig` in the root of a repo, without needing Buck.
+    /// The `.buckconfig` of each cell is read too, to find cells nested inside it,
+    /// along with the `[buildfile]` names for that cell.
+    pub fn from_buckconfig(file: &Path) -> anyhow::Result<Self> {
+        let root = file.parent().unwrap_or(Path::new(""));
+        Self::parse_buckconfig(|dir| {
+            let file = root.join(dir).join(".buckconfig");
+            match fs::read_to_string(&file) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !dir.is_empty() => Ok(None),
+                Err(e) => Err(e).with_context(|| format!("When reading `{}`", file.display())),
+            }
+        })
+    }
+
+    /// Like [`CellInfo::from_buckconfig`], but given a function to read the `.buckconfig`
+    /// in a directory relative to the root of the repo, returning `None` if it is absent.
+    pub fn parse_buckconfig(
+        read: impl Fn(&str) -> anyhow::Result<Option<String>>,
+    ) -> anyhow::Result<Self> {
+        let mut cells: HashMap<CellName, CellData> = HashMap::new();
+        let mut aliases = Vec::new();
+        let mut config = HashMap::new();
+        let mut todo = vec![String::new()];
+        let mut done = HashSet::new();
+        while let Some(dir) = todo.pop() {
+            if !done.insert(dir.clone()) {
+                continue;
+            }
+            let Some(data) = read(&dir)? else {
+                continue;
+            };
+            let entries = parse_buckconfig_entries(&data);
+            let dir = ProjectRelativePath::new(&dir);
+            for (section, key, value) in &entries {
+                match *section {
+                    // `repositories` is the older name for `cells`
+                    "cells" | "repositories" => {
+                        let path =
+                            normalize_path(&dir, value).ok_or_else(|| CellError::OutsideRepo {
+                                cell: (*key).to_owned(),
+                                path: (*value).to_owned(),
+                            })?;
+                        // The root `.buckconfig` is read first, so wins over nested ones
+                        cells.entry(CellName::new(key)).or_insert_with(|| {
+                            todo.push(path.as_str().to_owned());
+                            CellData {
+                                path,
+                                build_files: Self::default_build_files(key)
+                                    .iter()
+                                    .map(|x| (*x).to_owned())
+                                    .collect(),
+                            }
+                        });
+                    }
+                    "cell_aliases" if dir.as_str().is_empty() => {
+                        aliases.push(((*key).to_owned(), (*value).to_owned()))
+                    }
+                    _ => {}
+                }
+            }
+            if cells.is_empty() {
+                return Err(CellError::NoBuckconfigCells.into());
+            }
+            // The `[buildfile]` names apply to the cell rooted at this directory
+            if let Some((cell, _)) = cells.iter().find(|x| x.1.path == dir) {
+                for (section, key, value) in &entries {
+                    if *section == "buildfile" && (*key == "name" || *key == "name_v2") {
+                        config.insert(format!("{cell}//buildfile.{key}"), (*value).to_owned());
+                    }
+                }
+            }
+        }
+
+        let paths = Self::create_paths(&cells);
+        for (alias, cell) in aliases {
+            match cells.get(&CellName::new(&cell)) {
+                Some(data) => {
+                    let data = data.clone();
+                    cells.entry(CellName::new(&alias)).or_insert(data);
+                }
+                None => return Err(CellError::UnknownAlias { alias, cell }.into()),
+            }
+        }
+        let mut res = Self { cells, paths };
+        res.apply_config(&config);
+        Ok(res)
+    }
+
     fn parse_cells_data(data: &str) -> anyhow::Result<HashMap<CellName, CellData>> {
         let json: HashMap<String, String> = serde_json::from_str(data)?;
 
@@ -142,6 +244,11 @@ impl CellInfo {
 
     pub fn parse_config_data(&mut self, data: &str) -> anyhow::Result<()> {
         let json: HashMap<String, String> = serde_json::from_str(data)?;
+        self.apply_config(&json);
+        Ok(())
+    }
+
+    fn apply_config(&mut self, json: &HashMap<String, String>) {
         // name_v2 needs to take precedence, so evaluate it second
         for v2 in [false, true] {
             let want_key = if v2 {
@@ -171,7 +278,6 @@ impl CellInfo {
                 }
             }
         }
-        Ok(())
     }
 
     pub fn resolve(&self, path: &CellPath) -> anyhow::Result<ProjectRelativePath> {
@@ -185,13 +291,46 @@ impl CellInfo {
         // because we know self.paths has the longest match first, we just find the first match
         for (cell, prefix) in &self.paths {
             if let Some(x) = path.as_str().strip_prefix(prefix.as_str()) {
-                let x = x.strip_prefix('/').unwrap_or(x);
+                // Make sure cell `foo` doesn't match the path `foobar/baz`
+                let x = match x.strip_prefix('/') {
+                    Some(x) => x,
+                    None if x.is_empty() || prefix.as_str().is_empty() => x,
+                    None => continue,
+                };
                 return Ok(cell.join(&CellRelativePath::new(x)));
             }
         }
         Err(CellError::UnknownPath(path.clone()).into())
     }
 
+    /// Each cell, with the directory it is rooted at.
+    pub fn cells(&self) -> impl Iterator<Item = (&CellName, &ProjectRelativePath)> {
+        self.paths.iter().map(|(cell, path)| (cell, path))
+    }
+
+    /// The cells in the format of `buck2 audit cell --json`, as if the repo were at `root`,
+    /// and the config giving their build files, from which [`CellInfo::parse`] and
+    /// [`CellInfo::parse_config_data`] recreate them. Cell aliases are left out.
+    pub fn to_json(&self, root: &str) -> (serde_json::Value, serde_json::Value) {
+        let mut cells = serde_json::Map::new();
+        let mut config = serde_json::Map::new();
+        for (cell, path) in &self.paths {
+            let path = if path.as_str().is_empty() {
+                root.to_owned()
+            } else {
+                format!("{root}/{}", path.as_str())
+            };
+            cells.insert(cell.as_str().to_owned(), path.into());
+            if let Some(data) = self.cells.get(cell) {
+                config.insert(
+                    format!("{cell}//buildfile.name_v2"),
+                    data.build_files.join(",").into(),
+                );
+            }
+        }
+        (cells.into(), config.into())
+    }
+
     /// The default build files that we hardcode for now.
     fn default_build_files(cell: &str) -> &'static [String] {
         // TODO: We eventually want to remove the hardcoding
@@ -214,6 +353,45 @@ impl CellInfo {
     }
 }
 
+/// The `(section, key, value)` entries of a `.buckconfig`, ignoring comments, and
+/// `<file:...>` includes, which only ever hold settings rather than cells.
+fn parse_buckconfig_entries(data: &str) -> Vec<(&str, &str, &str)> {
+    let mut res = Vec::new();
+    let mut section = "";
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(x) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            section = x.trim();
+        } else if let Some((key, value)) = line.split_once('=') {
+            res.push((section, key.trim(), value.trim()));
+        }
+    }
+    res
+}
+
+/// Resolve `path`, relative to `dir`, to a path relative to the root of the repo.
+/// Returns `None` if it goes above the root.
+fn normalize_path(dir: &ProjectRelativePath, path: &str) -> Option<ProjectRelativePath> {
+    let mut res = dir
+        .as_str()
+        .split('/')
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    for x in path.split('/') {
+        match x {
+            "" | "." => {}
+            ".." => {
+                res.pop()?;
+            }
+            x => res.push(x),
+        }
+    }
+    Some(ProjectRelativePath::new(&res.join("/")))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -281,5 +459,82 @@ mod tests {
             cells.build_files(&CellName::new("cell3")).unwrap(),
             &["BUCK.v2", "BUCK"]
         );
+
+        let (value, config) = cells.to_json("/repo");
+        assert_eq!(value["cell1"], "/repo/cell1");
+        assert_eq!(value["root"], "/repo");
+        let mut copy = CellInfo::parse(&value.to_string()).unwrap();
+        copy.parse_config_data(&config.to_string()).unwrap();
+        for cell in ["root", "cell1", "cell2", "cell3"] {
+            let cell = CellName::new(cell);
+            assert_eq!(
+                copy.build_files(&cell).unwrap(),
+                cells.build_files(&cell).unwrap()
+            );
+            let path = cell.join(&CellRelativePath::new("a/b.txt"));
+            assert_eq!(copy.resolve(&path).unwrap(), cells.resolve(&path).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_cell_buckconfig() {
+        let configs = HashMap::from([
+            (
+                "",
+                "[repositories]\n\
+                 root = .\n\
+                 prelude = prelude\n\
+                 fbcode = ./fbcode/\n\
+                 \n\
+                 [cell_aliases]\n\
+                 config = prelude\n\
+                 \n\
+                 [buildfile]\n\
+                 name = BUCK\n",
+            ),
+            (
+                "fbcode",
+                "# Cells nested in fbcode, relative to it\n\
+                 [cells]\n\
+                 fbcode = .\n\
+                 inner = inner\n\
+                 prelude = ../prelude\n\
+                 [buildfile]\n\
+                 name_v2 = TARGETS\n",
+            ),
+        ]);
+        let cells = CellInfo::parse_buckconfig(|dir| Ok(configs.get(dir).map(|x| (*x).to_owned())))
+            .unwrap();
+
+        fn testcase(cells: &CellInfo, cell_path: &str, project_relative_path: &str) {
+            let cell_path = CellPath::new(cell_path);
+            let project_relative_path = ProjectRelativePath::new(project_relative_path);
+            assert_eq!(cells.resolve(&cell_path).unwrap(), project_relative_path);
+            assert_eq!(cells.unresolve(&project_relative_path).unwrap(), cell_path);
+        }
+
+        testcase(&cells, "root//file.txt", "file.txt");
+        testcase(&cells, "root//preludes/file.txt", "preludes/file.txt");
+        testcase(&cells, "prelude//file.txt", "prelude/file.txt");
+        testcase(&cells, "fbcode//foo/BUCK", "fbcode/foo/BUCK");
+        testcase(&cells, "inner//foo/BUCK", "fbcode/inner/foo/BUCK");
+        assert_eq!(
+            cells.resolve(&CellPath::new("config//rules.bzl")).unwrap(),
+            ProjectRelativePath::new("prelude/rules.bzl")
+        );
+
+        assert_eq!(
+            cells.build_files(&CellName::new("root")).unwrap(),
+            &["BUCK.v2", "BUCK"]
+        );
+        assert_eq!(
+            cells.build_files(&CellName::new("fbcode")).unwrap(),
+            &["TARGETS"]
+        );
+
+        assert!(
+            CellInfo::parse_buckconfig(|_| Ok(Some("[cells]\nroot = ..\n".to_owned()))).is_err()
+        );
+        assert!(CellInfo::parse_buckconfig(|_| Ok(Some("[cells]\n".to_owned()))).is_err());
     }
 }
@@ -7,6 +7,7 @@
  * of this source tree.
  */
 
+pub mod arena;
 pub mod cells;
 pub mod config;
 pub mod glob;
@@ -12,13 +12,17 @@ use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::process::Output;
+use std::process::Stdio;
 
 use anyhow::Context as _;
 use audit::audit_cell_arguments;
 use audit::audit_config_arguments;
 use itertools::Itertools;
 use targets::targets_arguments;
+use td_util::command::output_with_retries;
 use td_util::command::with_command;
+use td_util::command::RetryPolicy;
 use tempfile::NamedTempFile;
 use thiserror::Error;
 
@@ -35,6 +39,12 @@ pub struct Buck2 {
     root: Option<PathBuf>,
     /// The isolation directory to always use when invoking buck
     isolation_dir: Option<String>,
+    /// When to rerun a failed command.
+    retry: RetryPolicy,
+    /// How many times commands have been rerun so far.
+    retries: usize,
+    /// The directory to run commands in, if not the current one.
+    dir: Option<PathBuf>,
 }
 
 #[derive(Error, Debug)]
@@ -49,11 +59,40 @@ impl Buck2 {
             program,
             root: None,
             isolation_dir,
+            retry: RetryPolicy::default(),
+            retries: 0,
+            dir: None,
         }
     }
 
+    /// Run the same commands in `dir`, e.g. another checkout, which has its own root
+    /// and its own daemon.
+    pub fn in_dir(&self, dir: &Path) -> Self {
+        Self {
+            program: self.program.clone(),
+            root: None,
+            isolation_dir: self.isolation_dir.clone(),
+            retry: self.retry.clone(),
+            retries: 0,
+            dir: Some(dir.to_owned()),
+        }
+    }
+
+    /// Rerun commands which fail in a way `retry` allows, e.g. when the daemon dies.
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// How many times a command has been rerun, for telemetry.
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
     pub fn command(&self) -> Command {
         let mut command = Command::new(&self.program);
+        if let Some(dir) = &self.dir {
+            command.current_dir(dir);
+        }
         match &self.isolation_dir {
             None => {}
             Some(isolation_dir) => {
@@ -74,16 +113,23 @@ impl Buck2 {
         }
     }
 
-    fn root_uncached(&mut self) -> anyhow::Result<PathBuf> {
-        let mut command = self.command();
-        command.args(["root", "--kind=project"]);
-        let res = with_command(command, |mut command| {
-            let res = command.output()?;
+    /// Run `command`, retrying as the policy allows, and fail with its stderr if it never succeeds.
+    fn output(&mut self, command: Command) -> anyhow::Result<Output> {
+        let (res, retries) = with_command(command, |command| {
+            let (res, retries) = output_with_retries(command, &self.retry)?;
             res.status.exit_ok().with_context(|| {
                 format!("Buck2 stderr: {}", String::from_utf8_lossy(&res.stderr))
             })?;
-            Ok(res)
+            Ok((res, retries))
         })?;
+        self.retries += retries;
+        Ok(res)
+    }
+
+    fn root_uncached(&mut self) -> anyhow::Result<PathBuf> {
+        let mut command = self.command();
+        command.args(["root", "--kind=project"]);
+        let res = self.output(command)?;
         let path = PathBuf::from(String::from_utf8(res.stdout)?.trim());
         // Sanity check the output
         if !path.exists() {
@@ -97,13 +143,7 @@ impl Buck2 {
         let mut command = self.command();
         command.args(audit_cell_arguments());
         command.current_dir(self.root()?);
-        let res = with_command(command, |mut command| {
-            let res = command.output()?;
-            res.status.exit_ok().with_context(|| {
-                format!("Buck2 stderr: {}", String::from_utf8_lossy(&res.stderr))
-            })?;
-            Ok(res)
-        })?;
+        let res = self.output(command)?;
         Ok(String::from_utf8(res.stdout)?)
     }
 
@@ -111,13 +151,7 @@ impl Buck2 {
         let mut command = self.command();
         command.args(audit_config_arguments());
         command.current_dir(self.root()?);
-        let res = with_command(command, |mut command| {
-            let res = command.output()?;
-            res.status.exit_ok().with_context(|| {
-                format!("Buck2 stderr: {}", String::from_utf8_lossy(&res.stderr))
-            })?;
-            Ok(res)
-        })?;
+        let res = self.output(command)?;
         Ok(String::from_utf8(res.stdout)?)
     }
 
@@ -157,8 +191,19 @@ impl Buck2 {
             .arg("--output")
             .arg(output)
             .arg(at_file)
-            .args(extra_args);
+            .args(extra_args)
+            // The targets go to `--output`, stderr is captured to decide whether to retry
+            .stdout(Stdio::inherit());
 
-        with_command(command, |mut command| Ok(command.status()?.exit_ok()?))
+        self.output(command)?;
+        Ok(())
+    }
+
+    /// Stop the daemon, e.g. one started for a checkout which is about to be deleted.
+    pub fn kill(&mut self) -> anyhow::Result<()> {
+        let mut command = self.command();
+        command.arg("kill");
+        self.output(command)?;
+        Ok(())
     }
 }
@@ -66,6 +66,11 @@ impl<T> TargetMap<T> {
         }
     }
 
+    /// The labels inserted with `insert`, or `insert_pattern` with a single target.
+    pub fn literals(&self) -> impl Iterator<Item = &TargetLabel> {
+        self.literal.keys()
+    }
+
     pub fn get<'a, 'b>(&'a self, key: &'b TargetLabel) -> impl Iterator<Item = &'a T> + 'b
     where
         'a: 'b,
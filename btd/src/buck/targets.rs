@@ -7,38 +7,171 @@
  * of this source tree.
  */
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::stdin;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::ops::Deref;
 use std::path::Path;
 
+use anyhow::Context as _;
+use serde::de::IgnoredAny;
+use serde::de::MapAccess;
+use serde::de::Visitor;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 use td_util::json;
+use td_util::string::InternString;
+use thiserror::Error;
 
+use crate::buck::arena;
+use crate::buck::arena::Deps;
 use crate::buck::labels::Labels;
 use crate::buck::types::CellPath;
 use crate::buck::types::Glob;
 use crate::buck::types::Oncall;
 use crate::buck::types::Package;
 use crate::buck::types::PackageValues;
+use crate::buck::types::ParsedTargetPattern;
 use crate::buck::types::RuleType;
 use crate::buck::types::TargetHash;
 use crate::buck::types::TargetLabel;
 use crate::buck::types::TargetLabelKeyRef;
 use crate::buck::types::TargetName;
 use crate::buck::types::TargetPattern;
+use crate::snapshot;
 
 /// The output of running `buck2 targets`.
 #[derive(Clone)]
 pub struct Targets(Vec<TargetsEntry>);
 
+#[derive(Error, Debug)]
+enum TargetsError {
+    #[error("No targets were read from stdin, did the command producing them fail?")]
+    EmptyStdin,
+    #[error("The aliases starting from `{0}` form a cycle")]
+    AliasCycle(TargetLabel),
+}
+
+/// How [`Targets::from_file_with`] reads targets.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReadOptions<'a> {
+    /// Check a snapshot was written for this revision, see [`snapshot::read_from`].
+    /// JSON lines record no revision, so are read unchecked.
+    pub revision: Option<&'a str>,
+    /// Keep all the [`Attributes`] of the targets read as JSON, rather than only `actual`
+    /// (see [`BuckTarget::alias_of`]). Off by default, as all the attributes of a big
+    /// graph take a lot of memory, but few runs look at them. A snapshot keeps whichever
+    /// attributes it was written with.
+    pub attributes: bool,
+}
+
+/// A [`TargetsEntry`] read from JSON, keeping all the attributes of a target if
+/// `ATTRIBUTES`, see [`ReadOptions::attributes`]. Pruned as each line is parsed, so the
+/// attributes of the whole graph are never held at once.
+struct JsonEntry<const ATTRIBUTES: bool>(TargetsEntry);
+
+impl<const ATTRIBUTES: bool> JsonEntry<ATTRIBUTES> {
+    fn unwrap(entries: anyhow::Result<Vec<Self>>) -> anyhow::Result<Vec<TargetsEntry>> {
+        Ok(entries?.into_iter().map(|x| x.0).collect())
+    }
+}
+
+impl<'de, const ATTRIBUTES: bool> Deserialize<'de> for JsonEntry<ATTRIBUTES> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut res = TargetsEntry::deserialize(deserializer)?;
+        if let TargetsEntry::Target(x) = &mut res {
+            if !ATTRIBUTES {
+                x.attributes.retain_actual();
+            }
+        }
+        Ok(Self(res))
+    }
+}
+
 impl Targets {
+    /// Read either the JSON lines output of `buck2 targets`, or a [`snapshot`] of it.
+    /// The file `-` reads the JSON lines from stdin, with [`Targets::from_reader`].
     pub fn from_file(file: &Path) -> anyhow::Result<Targets> {
-        Ok(Self(json::read_file_lines_unordered(file)?))
+        Ok(Self::from_file_with(file, ReadOptions::default())?.0)
+    }
+
+    /// Like [`Targets::from_file`], but also returns the header if the file is a snapshot.
+    pub fn from_file_with(
+        file: &Path,
+        options: ReadOptions,
+    ) -> anyhow::Result<(Targets, Option<snapshot::Header>)> {
+        if file == Path::new("-") {
+            let res = Self::from_reader_with(BufReader::new(stdin()), options)
+                .context("When reading targets from stdin")?;
+            if res.0.is_empty() {
+                return Err(TargetsError::EmptyStdin.into());
+            }
+            Ok((res, None))
+        } else {
+            let handle =
+                File::open(file).with_context(|| format!("When opening `{}`", file.display()))?;
+            // Sniff the format from the same handle, as a pipe can only be read once
+            let (magic, reader) = json::peek(handle, snapshot::MAGIC_LEN)?;
+            if snapshot::is_snapshot_data(&magic) {
+                let (res, header) = snapshot::read_from(reader, options.revision)
+                    .with_context(|| format!("When reading snapshot `{}`", file.display()))?;
+                Ok((res, Some(header)))
+            } else {
+                let entries = if options.attributes {
+                    JsonEntry::<true>::unwrap(json::read_lines_mmap(reader))
+                } else {
+                    JsonEntry::<false>::unwrap(json::read_lines_mmap(reader))
+                };
+                let entries = entries.with_context(|| {
+                    format!("When reading JSON-lines file `{}`", file.display())
+                })?;
+                Ok((Self::new(entries), None))
+            }
+        }
+    }
+
+    /// Read the JSON lines output of `buck2 targets`, parsing it while the command is still
+    /// running, e.g. `buck2 targets --streaming ... | btd --base -`. Entries for packages
+    /// which failed to load may be interleaved with the targets, and are kept as errors.
+    pub fn from_reader(reader: impl BufRead + Send) -> anyhow::Result<Targets> {
+        Self::from_reader_with(reader, ReadOptions::default())
     }
 
+    /// Like [`Targets::from_reader`], but reading as `options` say.
+    pub fn from_reader_with(
+        reader: impl BufRead + Send,
+        options: ReadOptions,
+    ) -> anyhow::Result<Targets> {
+        let entries = if options.attributes {
+            JsonEntry::<true>::unwrap(json::read_lines_unordered(reader))
+        } else {
+            JsonEntry::<false>::unwrap(json::read_lines_unordered(reader))
+        };
+        Ok(Self::new(entries?))
+    }
+
+    /// Like [`Targets::from_file`], but given the contents of the file, for platforms
+    /// without a file system, e.g. WebAssembly in a browser.
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Targets> {
+        if snapshot::is_snapshot_data(data) {
+            snapshot::decode(data).context("When reading snapshot")
+        } else {
+            Self::from_reader(data)
+        }
+    }
+
+    /// The deps of the targets are moved into a single [`arena`].
     pub fn new(entries: Vec<TargetsEntry>) -> Self {
-        Self(entries)
+        let mut res = Self(entries);
+        arena::compact(res.targets_mut());
+        res
     }
 
     /// Return the upperbound of `self.targets().count()`.
@@ -102,6 +235,133 @@ impl Targets {
         new
     }
 
+    /// Keep only the targets matching one of `universe`, and the errors from packages
+    /// that might contain them. If `universe` is empty, keep everything.
+    pub fn restrict(self, universe: &[ParsedTargetPattern]) -> Self {
+        if universe.is_empty() {
+            return self;
+        }
+        let mut entries = self.0;
+        entries.retain(|x| match x {
+            TargetsEntry::Target(x) => universe.iter().any(|p| p.matches(&x.label())),
+            TargetsEntry::Error(x) => universe.iter().any(|p| p.matches_package(&x.package)),
+            TargetsEntry::Import(_) => true,
+        });
+        Self(entries)
+    }
+
+    /// Drop the targets whose rule type is one of `rule_types`, by short name or in full.
+    /// Dependencies on them are removed, or with `stitch`, replaced by their own deps,
+    /// so the impact still flows through them.
+    pub fn ignore_rule_types(self, rule_types: &[String], stitch: bool) -> Self {
+        if rule_types.is_empty() {
+            return self;
+        }
+        let ignored = |x: &TargetsEntry| match x {
+            TargetsEntry::Target(x) => rule_types.iter().any(|r| x.rule_type.matches(r)),
+            _ => false,
+        };
+        let (dropped, mut entries): (Vec<_>, Vec<_>) = self.0.into_iter().partition(ignored);
+        let dropped = dropped
+            .into_iter()
+            .filter_map(|x| match x {
+                TargetsEntry::Target(x) => Some((x.label(), x.deps)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        for x in &mut entries {
+            let TargetsEntry::Target(x) = x else {
+                continue;
+            };
+            if !x.deps.iter().any(|d| dropped.contains_key(d)) {
+                continue;
+            }
+            let mut deps = Vec::new();
+            let mut seen = HashSet::new();
+            let mut todo = x.deps.iter().rev().collect::<Vec<_>>();
+            while let Some(d) = todo.pop() {
+                if !seen.insert(d) {
+                    continue;
+                }
+                match dropped.get(d) {
+                    Some(next) if stitch => todo.extend(next.iter().rev()),
+                    Some(_) => {}
+                    None => deps.push(d.clone()),
+                }
+            }
+            x.deps = deps.into();
+        }
+        Self::new(entries)
+    }
+
+    /// Drop the aliases (see [`BuckTarget::alias_of`]), making their dependents depend on
+    /// the targets they stand for, following chains of aliases, so impact reaches them
+    /// directly. The hashes of the aliases are added to those of their dependents, so
+    /// pointing an alias elsewhere still impacts them. Returns the target each alias
+    /// stands for.
+    pub fn collapse_aliases(self) -> anyhow::Result<(Self, BTreeMap<TargetLabel, TargetLabel>)> {
+        let is_alias = |x: &TargetsEntry| match x {
+            TargetsEntry::Target(x) => x.alias_of().is_some(),
+            _ => false,
+        };
+        let (aliases, mut entries): (Vec<_>, Vec<_>) = self.0.into_iter().partition(is_alias);
+        let aliases = aliases
+            .into_iter()
+            .filter_map(|x| match x {
+                TargetsEntry::Target(x) => Some((x.label(), (x.alias_of()?, x.hash))),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        // What each alias finally stands for, with the hashes of the aliases on the way
+        let mut resolved = HashMap::with_capacity(aliases.len());
+        for alias in aliases.keys() {
+            let mut seen = HashSet::new();
+            let mut hashes = Vec::new();
+            let mut label = alias;
+            while let Some((actual, hash)) = aliases.get(label) {
+                if !seen.insert(label) {
+                    return Err(TargetsError::AliasCycle(alias.clone()).into());
+                }
+                hashes.push(hash.as_str());
+                label = actual;
+            }
+            resolved.insert(alias, (label, hashes.join(" ")));
+        }
+
+        for x in &mut entries {
+            let TargetsEntry::Target(x) = x else {
+                continue;
+            };
+            if !x.deps.iter().any(|d| resolved.contains_key(d)) {
+                continue;
+            }
+            let mut hash = x.hash.as_str().to_owned();
+            let mut deps = Vec::with_capacity(x.deps.len());
+            let mut seen = HashSet::new();
+            for d in x.deps.iter() {
+                let d = match resolved.get(d) {
+                    Some((actual, hashes)) => {
+                        hash.push(' ');
+                        hash.push_str(hashes);
+                        *actual
+                    }
+                    None => d,
+                };
+                if seen.insert(d) {
+                    deps.push(d.clone());
+                }
+            }
+            x.deps = deps.into();
+            x.hash = TargetHash::new(&hash);
+        }
+        let resolved = resolved
+            .into_iter()
+            .map(|(alias, (actual, _))| (alias.clone(), actual.clone()))
+            .collect();
+        Ok((Self::new(entries), resolved))
+    }
+
     pub fn entries(&self) -> impl Iterator<Item = &TargetsEntry> {
         self.0.iter()
     }
@@ -113,6 +373,13 @@ impl Targets {
         })
     }
 
+    pub fn targets_mut(&mut self) -> impl Iterator<Item = &mut BuckTarget> {
+        self.0.iter_mut().filter_map(|x| match x {
+            TargetsEntry::Target(x) => Some(x),
+            _ => None,
+        })
+    }
+
     pub fn imports(&self) -> impl Iterator<Item = &BuckImport> {
         self.0.iter().filter_map(|x| match x {
             TargetsEntry::Import(x) => Some(x),
@@ -161,7 +428,7 @@ pub struct BuckTarget {
     pub oncall: Option<Oncall>,
     /// Its dependencies (buck.deps attribute)
     #[serde(rename = "buck.deps")]
-    pub deps: Box<[TargetLabel]>,
+    pub deps: Deps,
     /// Source files used by this targets fbcode//a/c.cpp
     #[serde(rename = "buck.inputs")]
     pub inputs: Box<[CellPath]>,
@@ -171,12 +438,34 @@ pub struct BuckTarget {
     /// A target can have multiple labels
     #[serde(default, skip_serializing_if = "Labels::is_empty")]
     pub labels: Labels,
-    /// Used as additional triggers
+    /// Used as additional triggers: files which impact this target when changed,
+    /// although they aren't among its inputs
     #[serde(default, skip_serializing_if = "is_empty_slice")]
     pub ci_srcs: Box<[Glob]>,
-    /// Used as additional triggers
+    /// Used as additional triggers: targets which impact this target when impacted,
+    /// although it doesn't depend on them, e.g. a server run by a test. Either labels,
+    /// possibly relative to the package as `:name`, or patterns like `cell//dir/...`
     #[serde(default, skip_serializing_if = "is_empty_slice")]
     pub ci_deps: Box<[TargetPattern]>,
+    /// The tests of this target (`tests` attribute), which needn't depend on it
+    #[serde(default, skip_serializing_if = "is_empty_slice")]
+    pub tests: Box<[TargetLabel]>,
+    /// The toolchains this target builds with (`toolchain_deps` attribute), which aren't
+    /// among its `deps`. Only followed with `--follow-toolchain-deps`.
+    #[serde(default, skip_serializing_if = "is_empty_slice")]
+    pub toolchain_deps: Box<[TargetLabel]>,
+    /// The targets this target runs while building (`exec_deps` attribute), e.g. a code
+    /// generator, built for the execution platform. Followed like `toolchain_deps`.
+    #[serde(default, skip_serializing_if = "is_empty_slice")]
+    pub exec_deps: Box<[TargetLabel]>,
+    /// The patterns of targets allowed to depend on this one, or `PUBLIC` for any.
+    /// Empty if `buck2 targets` wasn't asked to output it.
+    #[serde(default, skip_serializing_if = "is_empty_slice")]
+    pub visibility: Box<[TargetPattern]>,
+    /// Any other attributes `buck2 targets` was asked to output, if they were read with
+    /// [`ReadOptions::attributes`].
+    #[serde(flatten)]
+    pub attributes: Attributes,
 }
 
 fn is_empty_slice<T>(x: &[T]) -> bool {
@@ -192,13 +481,43 @@ impl BuckTarget {
         TargetLabelKeyRef::new(&self.package, &self.name)
     }
 
+    /// The order of targets in the output: by cell, then package, then name. Unlike
+    /// [`label_key`](Self::label_key), `foo//` sorts before `foo-bar//`.
+    pub fn order_key(&self) -> (&str, &str, &str) {
+        let package = self.package.as_str();
+        let (cell, path) = package.split_once("//").unwrap_or(("", package));
+        (cell, path, self.name.as_str())
+    }
+
+    /// The target an `alias` or `configured_alias` stands for: its `actual` attribute,
+    /// if `buck2 targets` output it, otherwise its only dependency.
+    pub fn alias_of(&self) -> Option<TargetLabel> {
+        if !matches!(self.rule_type.short(), "alias" | "configured_alias") {
+            return None;
+        }
+        match self.attributes.get("actual") {
+            Some(serde_json::Value::String(x)) => {
+                let label = TargetLabel::new(x);
+                Some(if label.is_package_relative() {
+                    self.package.join(&label.target_name())
+                } else {
+                    label
+                })
+            }
+            _ => match &*self.deps {
+                [x] => Some(x.clone()),
+                _ => None,
+            },
+        }
+    }
+
     #[cfg(test)]
     pub fn testing(name: &str, package: &str, rule_type: &str) -> BuckTarget {
         Self {
             name: TargetName::new(name),
             package: Package::new(package),
             package_values: PackageValues::default(),
-            deps: Box::new([]),
+            deps: Deps::default(),
             inputs: Box::new([]),
             rule_type: RuleType::new(rule_type),
             hash: TargetHash::new("123abc"),
@@ -206,10 +525,107 @@ impl BuckTarget {
             oncall: None,
             ci_srcs: Box::new([]),
             ci_deps: Box::new([]),
+            tests: Box::new([]),
+            toolchain_deps: Box::new([]),
+            exec_deps: Box::new([]),
+            visibility: Box::new([]),
+            attributes: Attributes::default(),
         }
     }
 }
 
+/// The attributes of a target BTD doesn't otherwise interpret, sorted by name.
+/// Only present if `buck2 targets` was asked to output them (e.g. by
+/// `supertd targets --all-attributes`) and they were read with
+/// [`ReadOptions::attributes`], and never includes the `buck.` attributes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes(Box<[(InternString, serde_json::Value)]>);
+
+impl Attributes {
+    pub fn new(mut attributes: Vec<(InternString, serde_json::Value)>) -> Self {
+        attributes.sort_by(|a, b| a.0.cmp(&b.0));
+        Self(attributes.into_boxed_slice())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Drop every attribute but `actual`, which [`BuckTarget::alias_of`] always needs.
+    fn retain_actual(&mut self) {
+        if self.0.iter().any(|x| x.0.as_str() != "actual") {
+            self.0 = self
+                .0
+                .iter()
+                .filter(|x| x.0.as_str() == "actual")
+                .cloned()
+                .collect();
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&serde_json::Value> {
+        self.0
+            .binary_search_by(|x| x.0.as_str().cmp(name))
+            .ok()
+            .map(|i| &self.0[i].1)
+    }
+
+    /// Whether the attribute `name` is `value`, or a list containing it. Values which
+    /// aren't strings are compared by their JSON, e.g. `true` or `3`.
+    pub fn matches(&self, name: &str, value: &str) -> bool {
+        let is = |x: &serde_json::Value| match x {
+            serde_json::Value::String(x) => x == value,
+            x => serde_json::from_str::<serde_json::Value>(value).is_ok_and(|v| v == *x),
+        };
+        match self.get(name) {
+            Some(serde_json::Value::Array(xs)) => xs.iter().any(is),
+            Some(x) => is(x),
+            None => false,
+        }
+    }
+}
+
+impl Deref for Attributes {
+    type Target = [(InternString, serde_json::Value)];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Serialize for Attributes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Attributes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AttributesVisitor;
+
+        impl<'de> Visitor<'de> for AttributesVisitor {
+            type Value = Attributes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of attributes")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut res = Vec::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    if key.starts_with("buck.") {
+                        map.next_value::<IgnoredAny>()?;
+                    } else {
+                        res.push((InternString::new(&key), map.next_value()?));
+                    }
+                }
+                Ok(Attributes::new(res))
+            }
+        }
+
+        deserializer.deserialize_map(AttributesVisitor)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct BuckError {
     // Error in starlark + package file
@@ -304,7 +720,7 @@ mod tests {
                 package: Some(Package::new("fbcode//pkg")),
             }),
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([
+                deps: Deps::from([
                     TargetLabel::new("toolchains//:python"),
                     TargetLabel::new("fbcode//python:library"),
                 ]),
@@ -338,6 +754,40 @@ mod tests {
         assert_eq!(output, required);
     }
 
+    #[test]
+    fn test_read_targets_reader() {
+        // Enough lines to be parsed in several chunks, with errors interleaved
+        let mut data = String::new();
+        for i in 0..3000 {
+            let entry = if i % 100 == 0 {
+                serde_json::json!({"buck.package": format!("foo//bad{i}"), "buck.error": "Oops"})
+            } else {
+                serde_json::json!({
+                    "buck.type": "prelude//rules.bzl:cxx_library",
+                    "buck.deps": [],
+                    "buck.inputs": [],
+                    "buck.target_hash": "1",
+                    "buck.package": "foo//bar",
+                    "name": format!("t{i}"),
+                })
+            };
+            data.push_str(&format!("{entry}\n"));
+            if i % 1000 == 0 {
+                data.push('\n');
+            }
+        }
+        let res = Targets::from_reader(data.as_bytes()).unwrap();
+        assert_eq!(res.targets().count(), 2970);
+        assert_eq!(res.errors().count(), 30);
+        assert!(res.targets().any(|x| x.name.as_str() == "t2999"));
+        // Not a snapshot, so the same as reading the lines
+        let res = Targets::from_bytes(data.as_bytes()).unwrap();
+        assert_eq!(res.targets().count(), 2970);
+
+        let broken = format!("{}\n{{\"buck.package\": ", data);
+        assert!(Targets::from_reader(broken.as_bytes()).is_err());
+    }
+
     #[test]
     fn test_read_targets_extra() {
         // Check we don't choke if Buck2 suddenly starts emitting extra fields in targets, or
@@ -361,4 +811,207 @@ mod tests {
         let res = Targets::from_file(file.path()).unwrap();
         assert_eq!(res.0.len(), 1);
     }
+
+    #[test]
+    fn test_read_targets_attributes() {
+        let value = serde_json::json!(
+            [
+                {
+                    "buck.type": "prelude//rules.bzl:python_library",
+                    "buck.deps": [],
+                    "buck.inputs": [],
+                    "buck.target_hash": "43ce1a7a56f10225413a2991febb853a",
+                    "buck.package": "fbcode//me",
+                    "buck.random": "ignored",
+                    "name": "test",
+                    "srcs": ["b.py", "a.py"],
+                    "metadata": {"owner": "me"},
+                },
+            ]
+        );
+        let file = write_buck_input(value);
+
+        // Only read if asked for
+        let res = Targets::from_file(file.path()).unwrap();
+        assert!(res.targets().next().unwrap().attributes.is_empty());
+
+        let options = ReadOptions {
+            attributes: true,
+            ..ReadOptions::default()
+        };
+        let (res, _) = Targets::from_file_with(file.path(), options).unwrap();
+        let target = res.targets().next().unwrap();
+        assert_eq!(
+            target.attributes,
+            Attributes::new(vec![
+                (
+                    InternString::new("srcs"),
+                    serde_json::json!(["b.py", "a.py"])
+                ),
+                (
+                    InternString::new("metadata"),
+                    serde_json::json!({"owner": "me"})
+                ),
+            ])
+        );
+        assert_eq!(target.attributes[0].0.as_str(), "metadata");
+        assert_eq!(
+            target.attributes.get("srcs"),
+            Some(&serde_json::json!(["b.py", "a.py"]))
+        );
+        assert_eq!(target.attributes.get("missing"), None);
+        assert!(target.attributes.matches("srcs", "a.py"));
+        assert!(!target.attributes.matches("srcs", "c.py"));
+        assert!(!target.attributes.matches("metadata", "me"));
+        assert!(!target.attributes.matches("missing", "a.py"));
+
+        let json = serde_json::to_value(target).unwrap();
+        assert_eq!(json["metadata"], serde_json::json!({"owner": "me"}));
+        assert_eq!(serde_json::from_value::<BuckTarget>(json).unwrap(), *target);
+    }
+
+    #[test]
+    fn test_restrict() {
+        let target = |name: &str, pkg: &str| {
+            TargetsEntry::Target(BuckTarget::testing(
+                name,
+                pkg,
+                "prelude//rules.bzl:cxx_library",
+            ))
+        };
+        let targets = Targets::new(vec![
+            target("lib", "foo//bar"),
+            target("lib_test", "foo//bar"),
+            target("lib", "foo//baz"),
+            target("lib", "other//bar"),
+            TargetsEntry::Error(BuckError {
+                package: Package::new("foo//baz"),
+                error: "bad".to_owned(),
+            }),
+        ]);
+        let universe = [
+            TargetPattern::new("foo//bar:*_test").parse().unwrap(),
+            TargetPattern::new("other//...").parse().unwrap(),
+        ];
+        let res = targets.restrict(&universe);
+        assert_eq!(
+            res.targets()
+                .map(|x| x.label().to_string())
+                .collect::<Vec<_>>(),
+            vec!["foo//bar:lib_test", "other//bar:lib"]
+        );
+        assert_eq!(res.errors().count(), 0);
+    }
+
+    #[test]
+    fn test_ignore_rule_types() {
+        let target = |name: &str, rule_type: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", rule_type)
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", "prelude//rules.bzl:cxx_library", &[]),
+            target("files", "prelude//rules.bzl:filegroup", &["foo//bar:lib"]),
+            target("more", "prelude//rules.bzl:filegroup", &["foo//bar:files"]),
+            target(
+                "bin",
+                "prelude//rules.bzl:cxx_binary",
+                &["foo//bar:more", "other//:dep"],
+            ),
+        ]);
+        let deps = |targets: Targets| {
+            targets
+                .targets()
+                .map(|x| {
+                    format!(
+                        "{} -> {}",
+                        x.label(),
+                        x.deps.map(|x| x.to_string()).join(" ")
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            deps(targets.clone().ignore_rule_types(&[], true)),
+            deps(targets.clone())
+        );
+        let filegroup = ["filegroup".to_owned()];
+        assert_eq!(
+            deps(targets.clone().ignore_rule_types(&filegroup, false)),
+            vec!["foo//bar:lib -> ", "foo//bar:bin -> other//:dep"]
+        );
+        let filegroup = ["prelude//rules.bzl:filegroup".to_owned()];
+        assert_eq!(
+            deps(targets.ignore_rule_types(&filegroup, true)),
+            vec![
+                "foo//bar:lib -> ",
+                "foo//bar:bin -> foo//bar:lib other//:dep"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collapse_aliases() {
+        let target = |name: &str, rule_type: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                hash: TargetHash::new(name),
+                ..BuckTarget::testing(name, "foo//bar", rule_type)
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", "prelude//rules.bzl:cxx_library", &[]),
+            target("lib_alias", "alias", &["foo//bar:lib"]),
+            target("alias_alias", "alias", &["foo//bar:lib_alias"]),
+            target(
+                "bin",
+                "prelude//rules.bzl:cxx_binary",
+                &["foo//bar:alias_alias", "foo//bar:lib", "other//:dep"],
+            ),
+            TargetsEntry::Target(BuckTarget {
+                attributes: Attributes::new(vec![(
+                    InternString::new("actual"),
+                    serde_json::json!(":lib"),
+                )]),
+                ..BuckTarget::testing("actual_alias", "foo//bar", "configured_alias")
+            }),
+        ]);
+        let (targets, aliases) = targets.collapse_aliases().unwrap();
+        let targets = targets
+            .targets()
+            .map(|x| {
+                format!(
+                    "{} {} -> {}",
+                    x.label(),
+                    x.hash.as_str(),
+                    x.deps.map(|x| x.to_string()).join(" ")
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            vec![
+                "foo//bar:lib lib -> ",
+                "foo//bar:bin bin alias_alias lib_alias -> foo//bar:lib other//:dep",
+            ]
+        );
+        let lib = TargetLabel::new("foo//bar:lib");
+        assert_eq!(
+            aliases,
+            BTreeMap::from([
+                (TargetLabel::new("foo//bar:actual_alias"), lib.clone()),
+                (TargetLabel::new("foo//bar:alias_alias"), lib.clone()),
+                (TargetLabel::new("foo//bar:lib_alias"), lib),
+            ])
+        );
+
+        let cycle = Targets::new(vec![
+            target("a", "alias", &["foo//bar:b"]),
+            target("b", "alias", &["foo//bar:a"]),
+        ]);
+        assert!(cycle.collapse_aliases().is_err());
+    }
 }
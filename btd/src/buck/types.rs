@@ -9,12 +9,19 @@
 
 //! All these types mirror their equivalent in the Buck2 codebase
 
+use std::borrow::Cow;
 use std::str::FromStr;
 
 use parse_display::Display;
 use serde::Deserialize;
 use serde::Serialize;
+use td_util::intern::Interned;
+use td_util::intern::Oncalls;
+use td_util::intern::Packages;
+use td_util::intern::RuleTypes;
+use td_util::string::InternLabel;
 use td_util::string::InternString;
+use thiserror::Error;
 
 use crate::buck::cells::CellInfo;
 use crate::buck::labels::Labels;
@@ -32,15 +39,18 @@ use crate::buck::labels::Labels;
     PartialOrd,
     Ord
 )]
-pub struct TargetLabel(InternString);
+pub struct TargetLabel(InternLabel);
 
 impl TargetLabel {
     pub fn new(target: &str) -> Self {
-        Self(InternString::new(target))
+        Self(InternLabel::new(target))
     }
 
+    /// The package and the name, without any configuration.
     fn split(&self) -> (&str, &str) {
-        self.0.as_str().rsplit_once(':').unwrap()
+        let package = self.0.package().as_str();
+        let name = self.0.unconfigured().get(package.len() + 1..).unwrap_or("");
+        (package, name)
     }
 
     /// ```
@@ -52,7 +62,7 @@ impl TargetLabel {
     /// );
     /// ```
     pub fn package(&self) -> Package {
-        Package::new(self.split().0)
+        Package(self.0.package().clone())
     }
 
     /// ```
@@ -64,12 +74,11 @@ impl TargetLabel {
     /// );
     /// ```
     pub fn target_name(&self) -> TargetName {
-        TargetName::new(self.split().1)
+        TargetName(self.0.name().clone())
     }
 
     pub fn key(&self) -> TargetLabelKey {
-        let (pkg, name) = self.split();
-        TargetLabelKey(Package::new(pkg), TargetName::new(name))
+        TargetLabelKey(self.package(), self.target_name())
     }
 
     /// ```
@@ -84,10 +93,58 @@ impl TargetLabel {
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+
+    /// The configuration of a configured label, e.g. from `buck2 cquery`, which is kept
+    /// as part of its name.
+    ///
+    /// ```
+    /// use btd::buck::types::TargetLabel;
+    /// use btd::buck::types::TargetName;
+    /// let label = TargetLabel::new("foo//bar:baz (cfg//platform:linux#0123abcd)");
+    /// assert_eq!(label.configuration(), Some("cfg//platform:linux#0123abcd"));
+    /// assert_eq!(
+    ///     label.target_name(),
+    ///     TargetName::new("baz (cfg//platform:linux#0123abcd)")
+    /// );
+    /// assert_eq!(TargetLabel::new("foo//bar:baz").configuration(), None);
+    /// ```
+    pub fn configuration(&self) -> Option<&str> {
+        self.0.configuration()
+    }
+
+    /// The label without any configuration.
+    ///
+    /// ```
+    /// use btd::buck::types::TargetLabel;
+    /// assert_eq!(
+    ///     TargetLabel::new("foo//bar:baz (cfg//:linux)").unconfigured(),
+    ///     TargetLabel::new("foo//bar:baz")
+    /// );
+    /// ```
+    pub fn unconfigured(&self) -> TargetLabel {
+        match self.configuration() {
+            None => self.clone(),
+            Some(_) => Self::new(self.0.unconfigured()),
+        }
+    }
+
+    /// Whether both labels are the same target, ignoring their configurations.
+    ///
+    /// ```
+    /// use btd::buck::types::TargetLabel;
+    /// let linux = TargetLabel::new("foo//bar:baz (cfg//:linux)");
+    /// assert!(linux.same_target(&TargetLabel::new("foo//bar:baz (cfg//:macos)")));
+    /// assert!(linux.same_target(&TargetLabel::new("foo//bar:baz")));
+    /// assert!(!linux.same_target(&TargetLabel::new("foo//bar:qux (cfg//:linux)")));
+    /// ```
+    pub fn same_target(&self, other: &TargetLabel) -> bool {
+        self.0.unconfigured() == other.0.unconfigured()
+    }
 }
 
 /// Equivalent to a `TargetLabel`, used to identify a label efficiently,
 /// including when produced by the `buck2 targets` JSON output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct TargetLabelKey(Package, TargetName);
 
 impl TargetLabelKey {
@@ -104,6 +161,10 @@ impl<'a> TargetLabelKeyRef<'a> {
     pub fn new(package: &'a Package, target_name: &'a TargetName) -> Self {
         Self(package, target_name)
     }
+
+    pub fn to_key(&self) -> TargetLabelKey {
+        TargetLabelKey(self.0.clone(), self.1.clone())
+    }
 }
 
 /// Example: `fbcode//buck2:` or `fbcode//buck2/...`
@@ -223,9 +284,13 @@ impl TargetPattern {
     /// assert!(
     ///     !TargetPattern::new("foo//bar/a:literal").matches(&TargetLabel::new("foo//bar/a:nother")),
     /// );
+    /// assert!(
+    ///     TargetPattern::new("foo//bar/a:literal")
+    ///         .matches(&TargetLabel::new("foo//bar/a:literal (cfg//:linux)")),
+    /// );
     /// ```
     pub fn matches(&self, target: &TargetLabel) -> bool {
-        self.matches_str(target.as_str())
+        self.matches_str(target.0.unconfigured())
     }
 
     /// Like `matches` but takes a string instead of a `TargetLabel`.
@@ -273,6 +338,60 @@ impl TargetPattern {
             false
         }
     }
+
+    /// Split the pattern into its parts, failing if it is malformed.
+    ///
+    /// ```
+    /// use btd::buck::types::TargetLabel;
+    /// use btd::buck::types::TargetPattern;
+    /// let p = TargetPattern::new("foo//bar:test_*").parse().unwrap();
+    /// assert!(p.matches(&TargetLabel::new("foo//bar:test_baz")));
+    /// assert!(!p.matches(&TargetLabel::new("foo//bar:baz")));
+    /// assert!(!p.matches(&TargetLabel::new("foo//bar/baz:test_baz")));
+    /// assert!(p.matches(&TargetLabel::new("foo//bar:test_baz (cfg//:linux)")));
+    /// let p = TargetPattern::new("foo//bar").parse().unwrap();
+    /// assert!(p.matches(&TargetLabel::new("foo//bar:bar")));
+    /// assert!(!p.matches(&TargetLabel::new("foo//bar:baz")));
+    /// assert!(TargetPattern::new("//bar:").parse().is_err());
+    /// assert!(TargetPattern::new("foo//bar...").parse().is_err());
+    /// assert!(TargetPattern::new("foo//bar/...:baz").parse().is_err());
+    /// ```
+    pub fn parse(&self) -> Result<ParsedTargetPattern, TargetPatternError> {
+        let err = |f: fn(String) -> TargetPatternError| f(self.0.clone());
+        let (cell, rest) = match self.0.split_once("//") {
+            Some((cell, rest)) if !cell.is_empty() => (cell, rest),
+            _ => return Err(err(TargetPatternError::MissingCell)),
+        };
+        let (path, name) = match rest.split_once(':') {
+            None => (rest, None),
+            Some((path, "")) => (path, Some(None)),
+            Some((path, name)) => (path, Some(Some(name))),
+        };
+        let (path, recursive) = match path.strip_suffix("...") {
+            Some(x) if x.is_empty() || x.ends_with('/') => (x.trim_end_matches('/'), true),
+            _ => (path, false),
+        };
+        if path.contains("...") || (recursive && name.is_some()) {
+            return Err(err(TargetPatternError::InvalidRecursive));
+        }
+        let name = match name {
+            Some(name) => name,
+            None if recursive => None,
+            None => match path.rsplit('/').next() {
+                Some(x) if !x.is_empty() => Some(x),
+                _ => return Err(err(TargetPatternError::InvalidName)),
+            },
+        };
+        if name.is_some_and(|x| x.contains(['/', ':'])) {
+            return Err(err(TargetPatternError::InvalidName));
+        }
+        Ok(ParsedTargetPattern {
+            cell: cell.to_owned(),
+            path: path.to_owned(),
+            recursive,
+            name: name.map(str::to_owned),
+        })
+    }
 }
 
 impl FromStr for TargetPattern {
@@ -282,6 +401,109 @@ impl FromStr for TargetPattern {
     }
 }
 
+/// A [`TargetPattern`] split into its parts, for matching many targets.
+/// Supports the patterns `cell//path:name`, `cell//path:` (every target in the package),
+/// `cell//path/...` (every target beneath the package) and `cell//path` (short for
+/// `cell//path:path`), where `name` may contain `*` to match any sequence of characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedTargetPattern {
+    cell: String,
+    /// The path within the cell, without leading or trailing slashes.
+    path: String,
+    /// Whether the pattern ends in `...`, so also matches packages beneath `path`.
+    recursive: bool,
+    /// The target name, or `None` to match every target.
+    name: Option<String>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TargetPatternError {
+    #[error("Target pattern must have a cell qualifier like `foo//...`. Got `{0}`")]
+    MissingCell(String),
+    #[error("Target pattern may only use `...` as the last path component. Got `{0}`")]
+    InvalidRecursive(String),
+    #[error("Target pattern has an invalid target name. Got `{0}`")]
+    InvalidName(String),
+}
+
+impl ParsedTargetPattern {
+    pub fn matches(&self, target: &TargetLabel) -> bool {
+        let (package, name) = target.split();
+        self.matches_package_str(package)
+            && self
+                .name
+                .as_ref()
+                .map_or(true, |x| wildcard_matches(x, name))
+    }
+
+    /// Could a target in `package` match this pattern.
+    pub fn matches_package(&self, package: &Package) -> bool {
+        self.matches_package_str(package.as_str())
+    }
+
+    fn matches_package_str(&self, package: &str) -> bool {
+        let Some((cell, path)) = package.split_once("//") else {
+            return false;
+        };
+        if cell != self.cell {
+            false
+        } else if self.recursive && !self.path.is_empty() {
+            match path.strip_prefix(self.path.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            }
+        } else {
+            self.recursive || path == self.path
+        }
+    }
+
+    /// Does this pattern match a single specific target.
+    pub fn is_specific_target(&self) -> bool {
+        self.name.as_ref().is_some_and(|x| !x.contains('*'))
+    }
+
+    /// A pattern that `buck2` understands which matches at least the same targets,
+    /// replacing a name containing `*` with the whole package.
+    ///
+    /// ```
+    /// use btd::buck::types::TargetPattern;
+    /// let buck = |x: &str| TargetPattern::new(x).parse().unwrap().to_buck_pattern();
+    /// assert_eq!(buck("foo//bar:test_*"), TargetPattern::new("foo//bar:"));
+    /// assert_eq!(buck("foo//bar/..."), TargetPattern::new("foo//bar/..."));
+    /// assert_eq!(buck("foo//..."), TargetPattern::new("foo//..."));
+    /// assert_eq!(buck("foo//bar"), TargetPattern::new("foo//bar:bar"));
+    /// ```
+    pub fn to_buck_pattern(&self) -> TargetPattern {
+        let sep = if self.path.is_empty() { "" } else { "/" };
+        TargetPattern::new(&match &self.name {
+            _ if self.recursive => format!("{}//{}{sep}...", self.cell, self.path),
+            Some(name) if !name.contains('*') => format!("{}//{}:{name}", self.cell, self.path),
+            _ => format!("{}//{}:", self.cell, self.path),
+        })
+    }
+}
+
+/// Does `x` match `pattern`, where `*` in the pattern matches any sequence of characters.
+fn wildcard_matches(pattern: &str, x: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = x.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // No `*`, so must match exactly
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
 /// Example: `buck2` bit in `fbcode//build:buck2`
 #[derive(
     Debug,
@@ -334,7 +556,7 @@ impl CellPath {
             path.contains("//"),
             "Invalid CellPath, missing `//` from `{path}`"
         );
-        Self(InternString::new(path))
+        Self(InternString::new(&normalize_separators(path)))
     }
 
     pub fn cell(&self) -> CellName {
@@ -381,7 +603,7 @@ impl CellPath {
     /// Only makes sense for directories that you know must be on package boundaries,
     /// e.g. `BUCK` or `PACKAGE` files.
     pub fn as_package(&self) -> Package {
-        Package(self.0.clone())
+        Package(Interned::new(self.0.as_str()))
     }
 
     /// ```
@@ -478,6 +700,16 @@ impl CellPath {
         s.ends_with("/PACKAGE") || s.ends_with("/BUCK_TREE")
     }
 
+    /// ```
+    /// use btd::buck::types::CellPath;
+    /// assert!(CellPath::new("foo//.buckconfig").is_buckconfig_file());
+    /// assert!(CellPath::new("foo//mode/opt.bcfg").is_buckconfig_file());
+    /// assert!(!CellPath::new("foo//bar/buckconfig.txt").is_buckconfig_file());
+    /// ```
+    pub fn is_buckconfig_file(&self) -> bool {
+        matches!(self.extension(), Some("buckconfig" | "bcfg"))
+    }
+
     /// ```
     /// use btd::buck::types::CellPath;
     /// assert!(!CellPath::new("foo//bar/rule.bzl").is_prelude_bzl_file());
@@ -503,19 +735,23 @@ impl CellPath {
     Deserialize,
     Serialize
 )]
-pub struct Package(InternString);
+pub struct Package(Interned<Packages>);
 
 impl Package {
     pub fn new(package: &str) -> Self {
-        Self(InternString::new(package))
+        Self(Interned::new(&normalize_separators(package)))
     }
 
     pub fn join(&self, name: &TargetName) -> TargetLabel {
-        TargetLabel(InternString::new3(self.0.as_str(), ":", name.0.as_str()))
+        TargetLabel(InternLabel::join(&self.0, &name.0))
     }
 
     pub fn join_path(&self, path: &str) -> CellPath {
-        CellPath(InternString::new3(self.0.as_str(), "/", path))
+        CellPath(InternString::new3(
+            self.0.as_str(),
+            "/",
+            &normalize_separators(path),
+        ))
     }
 
     pub fn cell(&self) -> CellName {
@@ -532,7 +768,26 @@ impl Package {
 
     /// Represents the directory in which this package lives
     pub fn as_cell_path(&self) -> CellPath {
-        CellPath(self.0.clone())
+        CellPath(InternString::new(self.0.as_str()))
+    }
+
+    /// Is `package` this package or one in a subdirectory of it.
+    ///
+    /// ```
+    /// use btd::buck::types::Package;
+    /// let pkg = Package::new("foo//bar");
+    /// assert!(pkg.contains(&Package::new("foo//bar")));
+    /// assert!(pkg.contains(&Package::new("foo//bar/baz")));
+    /// assert!(!pkg.contains(&Package::new("foo//barbaz")));
+    /// assert!(!pkg.contains(&Package::new("foo//")));
+    /// assert!(Package::new("foo//").contains(&pkg));
+    /// assert!(!Package::new("foo//").contains(&Package::new("bar//baz")));
+    /// ```
+    pub fn contains(&self, package: &Package) -> bool {
+        match package.as_str().strip_prefix(self.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.as_str().ends_with('/'),
+            None => false,
+        }
     }
 }
 
@@ -561,11 +816,11 @@ impl PackageValues {
 
 /// Example: `prelude//rules.bzl:genrule`
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
-pub struct RuleType(TargetLabel);
+pub struct RuleType(Interned<RuleTypes>);
 
 impl RuleType {
     pub fn new(rule: &str) -> Self {
-        Self(TargetLabel::new(rule))
+        Self(Interned::new(rule))
     }
 
     /// ```
@@ -576,7 +831,7 @@ impl RuleType {
     /// );
     /// ```
     pub fn short(&self) -> &str {
-        let contents = self.0.0.as_str();
+        let contents = self.0.as_str();
         match contents.rsplit_once(':') {
             None => contents,
             Some((_, x)) => x,
@@ -592,21 +847,38 @@ impl RuleType {
     /// );
     /// ```
     pub fn file(&self) -> CellPath {
-        let contents = self.0.0.as_str();
+        let contents = self.0.as_str();
         match contents.rsplit_once(':') {
             None => CellPath::new(contents),
             Some((x, _)) => CellPath::new(x),
         }
     }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Whether `name` is this rule type, either in full or by its short name.
+    ///
+    /// ```
+    /// use btd::buck::types::RuleType;
+    /// let rule_type = RuleType::new("prelude//rules.bzl:filegroup");
+    /// assert!(rule_type.matches("filegroup"));
+    /// assert!(rule_type.matches("prelude//rules.bzl:filegroup"));
+    /// assert!(!rule_type.matches("genrule"));
+    /// ```
+    pub fn matches(&self, name: &str) -> bool {
+        name == self.short() || name == self.as_str()
+    }
 }
 
 /// Example: `ci_efficiency`
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
-pub struct Oncall(InternString);
+pub struct Oncall(Interned<Oncalls>);
 
 impl Oncall {
     pub fn new(oncall: &str) -> Self {
-        Self(InternString::new(oncall))
+        Self(Interned::new(oncall))
     }
 
     pub fn as_str(&self) -> &str {
@@ -621,6 +893,10 @@ impl TargetHash {
     pub fn new(hash: &str) -> Self {
         Self(hash.to_owned())
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Display)]
@@ -628,12 +904,13 @@ pub struct ProjectRelativePath(String);
 
 impl ProjectRelativePath {
     pub fn new(path: &str) -> Self {
-        Self(path.to_owned())
+        Self(normalize_separators(path).into_owned())
     }
 
     pub fn join(&self, suffix: &str) -> Self {
+        let suffix = normalize_separators(suffix);
         if self.0.is_empty() {
-            Self(suffix.to_owned())
+            Self(suffix.into_owned())
         } else {
             Self(format!("{}/{}", self.0, suffix))
         }
@@ -671,7 +948,7 @@ pub struct CellRelativePath(String);
 
 impl CellRelativePath {
     pub fn new(path: &str) -> Self {
-        Self(path.to_owned())
+        Self(normalize_separators(path).into_owned())
     }
 
     pub fn as_str(&self) -> &str {
@@ -691,6 +968,16 @@ impl CellRelativePath {
     }
 }
 
+/// Paths from Windows tools, e.g. the version control system, may separate directories
+/// with `\`, but Buck always uses `/`, so convert them before prefix matching sees them.
+fn normalize_separators(path: &str) -> Cow<'_, str> {
+    if path.contains('\\') {
+        Cow::Owned(path.replace('\\', "/"))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
 /// Is a [`Glob`] one to include or exclude.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum GlobInclusion {
@@ -708,6 +995,10 @@ impl Glob {
         Self(pattern.to_owned())
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     pub fn unpack(&self) -> (GlobInclusion, &str) {
         let s = self.0.as_str();
         match s.strip_prefix('!') {
@@ -728,4 +1019,70 @@ mod tests {
         assert_eq!(t.as_str(), s);
         assert_eq!(t.to_string(), s);
     }
+
+    #[test]
+    fn test_wildcard_matches() {
+        assert!(wildcard_matches("foo", "foo"));
+        assert!(!wildcard_matches("foo", "foobar"));
+        assert!(wildcard_matches("*", ""));
+        assert!(wildcard_matches("foo*", "foobar"));
+        assert!(wildcard_matches("*bar", "foobar"));
+        assert!(wildcard_matches("f*o*r", "foobar"));
+        assert!(!wildcard_matches("*a*a", "a"));
+        assert!(!wildcard_matches("bar*", "foobar"));
+    }
+
+    #[test]
+    fn test_parsed_pattern_matches() {
+        let matches = |pattern: &str, target: &str| {
+            TargetPattern::new(pattern)
+                .parse()
+                .unwrap()
+                .matches(&TargetLabel::new(target))
+        };
+        assert!(matches("foo//...", "foo//:baz"));
+        assert!(matches("foo//...", "foo//bar/baz:qux"));
+        assert!(!matches("foo//...", "bar//baz:qux"));
+        assert!(matches("foo//bar/...", "foo//bar:qux"));
+        assert!(matches("foo//bar/...", "foo//bar/baz:qux"));
+        assert!(!matches("foo//bar/...", "foo//bard:qux"));
+        assert!(matches("foo//bar:", "foo//bar:qux"));
+        assert!(!matches("foo//bar:", "foo//bar/baz:qux"));
+        assert!(matches("foo//:", "foo//:qux"));
+        assert!(matches("foo//bar:*_test", "foo//bar:lib_test"));
+        assert!(!matches("foo//bar:*_test", "foo//bar:lib"));
+    }
+
+    #[test]
+    fn test_windows_separators() {
+        let path = ProjectRelativePath::new("fbcode\\buck2\\src\\lib.rs");
+        assert_eq!(path.as_str(), "fbcode/buck2/src/lib.rs");
+        assert_eq!(ProjectRelativePath::new(path.as_str()), path);
+        assert_eq!(
+            ProjectRelativePath::new("fbcode")
+                .join("buck2\\BUCK")
+                .as_str(),
+            "fbcode/buck2/BUCK"
+        );
+        assert_eq!(CellRelativePath::new("buck2\\src").as_str(), "buck2/src");
+
+        let cell_path = CellPath::new("fbcode//buck2\\src\\lib.rs");
+        assert_eq!(cell_path, CellPath::new("fbcode//buck2/src/lib.rs"));
+        assert_eq!(cell_path.path().as_str(), "buck2/src/lib.rs");
+        assert_eq!(cell_path.parent(), CellPath::new("fbcode//buck2/src"));
+        assert_eq!(
+            cell_path.cell().join(&cell_path.path()),
+            CellPath::new("fbcode//buck2/src/lib.rs")
+        );
+
+        let package = Package::new("fbcode//buck2\\src");
+        assert_eq!(package, Package::new("fbcode//buck2/src"));
+        assert!(Package::new("fbcode//buck2").contains(&package));
+        assert_eq!(package.as_cell_path(), CellPath::new("fbcode//buck2/src"));
+        assert_eq!(
+            package.join_path("sub\\lib.rs"),
+            CellPath::new("fbcode//buck2/src/sub/lib.rs")
+        );
+        assert!(TargetPattern::new("fbcode//buck2/...").matches_package(&package));
+    }
 }
@@ -0,0 +1,534 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Record everything a BTD run read into a directory, a bundle, which can be replayed
+//! without the repo or Buck, so bug reports can come with a reproducer.
+//!
+//! The targets are recorded once BTD has read them, after `--universe`,
+//! `--ignore-rule-types` and `--dependency-hints` are applied, and the changes once
+//! cosmetic changes are dropped, so the arguments for those inputs are left out
+//! of the recorded arguments.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Context as _;
+use td_util::json;
+
+use crate::buck::cells::CellInfo;
+use crate::buck::targets::Attributes;
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::targets::TargetsEntry;
+use crate::buck::types::CellPath;
+use crate::buck::types::Glob;
+use crate::buck::types::Oncall;
+use crate::buck::types::Package;
+use crate::buck::types::ProjectRelativePath;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetName;
+use crate::buck::types::TargetPattern;
+use crate::sapling::status::Status;
+
+const ARGS: &str = "args.json";
+const CELLS: &str = "cells.json";
+const CONFIG: &str = "config.json";
+pub const CHANGES: &str = "changes.txt";
+pub const BASE: &str = "base.jsonl";
+pub const DIFF: &str = "diff.jsonl";
+
+/// Arguments for where the inputs come from, which the bundle replaces.
+const INPUT_ARGS: &[&str] = &[
+    "cells",
+    "config",
+    "changes",
+    "changes-from-scm",
+    "eden",
+    "simulate-changes",
+    "ignore-cosmetic-changes",
+    "file-contents-command",
+    "base",
+    "base-from-scm",
+    "diff",
+    "configured",
+    "ignore-rule-types",
+    "stitch-ignored-deps",
+    "dependency-hints",
+    "save-index",
+    "load-index",
+    "cache-dir",
+    "cache-ttl",
+    "cache-max-size",
+    "buck",
+    "buck-arg",
+    "isolation-dir",
+    "flagfile",
+    "print-rerun",
+    "record-bundle",
+    "bundle-subset",
+    "bundle-anonymize",
+    "replay-bundle",
+];
+
+/// Arguments naming files, which are copied into the bundle.
+const FILE_ARGS: &[&str] = &[
+    "change-policy",
+    "ignore-file",
+    "owners-file",
+    "classify-config",
+    "score-config",
+    "failure-rates",
+    "durations",
+];
+
+/// Arguments naming targets, anonymized along with the targets. Positional arguments
+/// are target patterns too.
+const TARGET_ARGS: &[&str] = &["universe", "exclude", "changed-targets", "why"];
+
+/// An argument on the command line, with its value if it takes one.
+#[derive(Debug, PartialEq, Eq)]
+enum Arg {
+    Flag { name: String, value: Option<String> },
+    Positional(String),
+}
+
+impl Arg {
+    fn to_arg(&self) -> String {
+        match self {
+            // Always joined with `=`, so values starting with `-` aren't taken as flags
+            Self::Flag {
+                name,
+                value: Some(value),
+            } => format!("--{name}={value}"),
+            Self::Flag { name, value: None } => format!("--{name}"),
+            Self::Positional(x) => x.clone(),
+        }
+    }
+}
+
+/// Split `argv` into arguments, using `command` to know which flags take a value.
+/// Flags are given by their long name, even if an alias was used.
+fn parse_args(command: &clap::Command, argv: &[String]) -> Vec<Arg> {
+    let mut res = Vec::new();
+    let mut args = argv.iter();
+    while let Some(x) = args.next() {
+        let Some(flag) = x.strip_prefix("--").filter(|x| !x.is_empty()) else {
+            res.push(Arg::Positional(x.clone()));
+            continue;
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (flag, None),
+        };
+        let arg = command.get_arguments().find(|x| {
+            x.get_long_and_visible_aliases()
+                .is_some_and(|xs| xs.contains(&name))
+        });
+        let value = match value {
+            None if arg.is_some_and(|x| x.get_action().takes_values()) => args.next().cloned(),
+            value => value,
+        };
+        res.push(Arg::Flag {
+            name: arg.and_then(|x| x.get_long()).unwrap_or(name).to_owned(),
+            value,
+        });
+    }
+    res
+}
+
+/// The arguments BTD was run with, after expanding arg files, without the binary or
+/// the `btd` subcommand of `supertd`. A `btd` link to `supertd` has no subcommand to drop.
+pub fn cli_args() -> anyhow::Result<Vec<String>> {
+    let mut res = td_util::cli::get_args()?
+        .into_iter()
+        .skip(1)
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    if res.first().is_some_and(|x| x == "btd") {
+        res.remove(0);
+    }
+    Ok(res)
+}
+
+/// A command running this binary to replay the bundle in `dir`.
+pub fn replay_command(dir: &Path) -> anyhow::Result<Command> {
+    let exe = env::current_exe()?;
+    // Run as `supertd btd` unless this is `btd` itself. The path has links resolved, so
+    // a `btd` link to `supertd` is `supertd` here too.
+    let is_btd = exe.file_stem().is_some_and(|x| x == "btd");
+    let mut command = Command::new(exe);
+    if !is_btd {
+        command.arg("btd");
+    }
+    command.arg("--replay-bundle").arg(dir);
+    Ok(command)
+}
+
+/// Consistently replaces the names in paths, packages and targets with hashes, keeping
+/// file extensions and the names of build files, which change how BTD treats a file.
+/// The hashes are salted afresh for each bundle, so names can't be recovered by hashing
+/// guesses, and the same name differs between bundles.
+struct Anonymizer {
+    keep: HashSet<String>,
+    /// Randomly keyed, see [`RandomState::new`].
+    salt: RandomState,
+}
+
+impl Anonymizer {
+    fn new(cells: &CellInfo) -> anyhow::Result<Self> {
+        let mut keep = HashSet::from(["PACKAGE".to_owned()]);
+        for (cell, _) in cells.cells() {
+            keep.extend(cells.build_files(cell)?.iter().cloned());
+        }
+        Ok(Self {
+            keep,
+            salt: RandomState::new(),
+        })
+    }
+
+    fn word(&self, x: &str) -> String {
+        if self.keep.contains(x) || x.contains('*') || x.chars().all(|c| c == '.') {
+            return x.to_owned();
+        }
+        let (stem, extension) = match x.rfind('.') {
+            Some(i) if i > 0 => x.split_at(i),
+            _ => (x, ""),
+        };
+        let mut hasher = self.salt.build_hasher();
+        stem.hash(&mut hasher);
+        format!("x{:016x}{extension}", hasher.finish())
+    }
+
+    fn path(&self, x: &str) -> String {
+        x.split('/')
+            .map(|x| self.word(x))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// A target name, keeping the configuration of configured targets, e.g. `baz (abc)`.
+    fn name(&self, x: &str) -> String {
+        match x.split_once(' ') {
+            Some((name, configuration)) => format!("{} {configuration}", self.word(name)),
+            None => self.word(x),
+        }
+    }
+
+    /// Anything of the form `cell//path:name`, where `:name` is optional, or a path.
+    fn pattern(&self, x: &str) -> String {
+        let Some((cell, rest)) = x.split_once("//") else {
+            return self.path(x);
+        };
+        match rest.split_once(':') {
+            Some((path, "")) => format!("{cell}//{}:", self.path(path)),
+            Some((path, name)) => format!("{cell}//{}:{}", self.path(path), self.name(name)),
+            None => format!("{cell}//{}", self.path(rest)),
+        }
+    }
+
+    fn label(&self, x: &TargetLabel) -> TargetLabel {
+        TargetLabel::new(&self.pattern(x.as_str()))
+    }
+
+    fn cell_path(&self, x: &CellPath) -> CellPath {
+        CellPath::new(&self.pattern(x.as_str()))
+    }
+
+    fn package(&self, x: &Package) -> Package {
+        Package::new(&self.pattern(x.as_str()))
+    }
+
+    fn target(&self, x: &BuckTarget) -> BuckTarget {
+        BuckTarget {
+            name: TargetName::new(&self.name(x.name.as_str())),
+            package: self.package(&x.package),
+            oncall: x
+                .oncall
+                .as_ref()
+                .map(|x| Oncall::new(&self.word(x.as_str()))),
+            deps: x.deps.iter().map(|x| self.label(x)).collect(),
+            inputs: x.inputs.iter().map(|x| self.cell_path(x)).collect(),
+            ci_srcs: x
+                .ci_srcs
+                .iter()
+                .map(|x| Glob::new(&self.pattern(x.as_str())))
+                .collect(),
+            ci_deps: x
+                .ci_deps
+                .iter()
+                .map(|x| TargetPattern::new(&self.pattern(x.as_str())))
+                .collect(),
+            tests: x.tests.iter().map(|x| self.label(x)).collect(),
+            toolchain_deps: x.toolchain_deps.iter().map(|x| self.label(x)).collect(),
+            exec_deps: x.exec_deps.iter().map(|x| self.label(x)).collect(),
+            visibility: x
+                .visibility
+                .iter()
+                .map(|x| match x.as_str() {
+                    "PUBLIC" => x.clone(),
+                    _ => TargetPattern::new(&self.pattern(x.as_str())),
+                })
+                .collect(),
+            attributes: Attributes::default(),
+            ..x.clone()
+        }
+    }
+
+    fn entry(&self, x: &TargetsEntry) -> TargetsEntry {
+        match x {
+            TargetsEntry::Target(x) => TargetsEntry::Target(self.target(x)),
+            TargetsEntry::Import(x) => {
+                let mut x = x.clone();
+                x.file = self.cell_path(&x.file);
+                x.imports = x.imports.iter().map(|x| self.cell_path(x)).collect();
+                x.package = x.package.as_ref().map(|x| self.package(x));
+                TargetsEntry::Import(x)
+            }
+            TargetsEntry::Error(x) => {
+                let mut x = x.clone();
+                x.package = self.package(&x.package);
+                x.error = "<anonymized>".to_owned();
+                TargetsEntry::Error(x)
+            }
+        }
+    }
+}
+
+/// How to record a bundle.
+pub struct RecordOptions<'a> {
+    /// Only record these targets, e.g. those impacted, rather than the whole graph.
+    pub subset: Option<&'a HashSet<TargetLabel>>,
+    /// Replace the names of paths, packages and targets with hashes.
+    pub anonymize: bool,
+    /// The arguments BTD was run with, from [`cli_args`].
+    pub args: &'a [String],
+}
+
+/// Record the inputs of a BTD run into `dir`, creating it if needed. `diff` is `None`
+/// when simulating changes on `base` alone. `command` gives the arguments BTD accepts.
+pub fn record(
+    dir: &Path,
+    cells: &CellInfo,
+    changes: &[Status<(CellPath, ProjectRelativePath)>],
+    (base, diff): (&Targets, Option<&Targets>),
+    command: &clap::Command,
+    options: &RecordOptions,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("When creating `{}`", dir.display()))?;
+    let anonymizer = options
+        .anonymize
+        .then(|| Anonymizer::new(cells))
+        .transpose()?;
+
+    let (cells_json, config_json) = cells.to_json("/repo");
+    fs::write(dir.join(CELLS), cells_json.to_string())?;
+    fs::write(dir.join(CONFIG), config_json.to_string())?;
+
+    let mut out = BufWriter::new(File::create(dir.join(CHANGES))?);
+    for x in changes {
+        let x = match &anonymizer {
+            None => x.map(|(_, path)| path.clone()),
+            Some(anonymizer) => {
+                x.try_map(|(path, _)| cells.resolve(&anonymizer.cell_path(path)))?
+            }
+        };
+        let status = match x {
+            Status::Modified(_) => 'M',
+            Status::Added(_) => 'A',
+            Status::Removed(_) => 'R',
+        };
+        writeln!(out, "{status} {}", x.get().as_str())?;
+    }
+    out.flush()?;
+
+    let write_targets = |targets: &Targets, file: &str| {
+        let entries = targets.entries().filter(|x| match (x, options.subset) {
+            (TargetsEntry::Target(x), Some(subset)) => subset.contains(&x.label()),
+            _ => true,
+        });
+        let out = BufWriter::new(File::create(dir.join(file))?);
+        match &anonymizer {
+            None => json::write_json_lines(out, entries),
+            Some(anonymizer) => json::write_json_lines(out, entries.map(|x| anonymizer.entry(x))),
+        }
+    };
+    write_targets(base, BASE)?;
+    if let Some(diff) = diff {
+        write_targets(diff, DIFF)?;
+    }
+
+    let mut args = Vec::new();
+    for x in parse_args(command, options.args) {
+        match &x {
+            Arg::Flag { name, .. } if INPUT_ARGS.contains(&name.as_str()) => continue,
+            Arg::Flag {
+                name,
+                value: Some(value),
+            } if FILE_ARGS.contains(&name.as_str()) => {
+                fs::copy(value, dir.join(name))
+                    .with_context(|| format!("When copying `{value}` into the bundle"))?;
+                args.push(Arg::Flag {
+                    name: name.clone(),
+                    value: Some(name.clone()),
+                });
+            }
+            Arg::Flag {
+                name,
+                value: Some(value),
+            } if TARGET_ARGS.contains(&name.as_str()) => args.push(Arg::Flag {
+                name: name.clone(),
+                value: Some(
+                    anonymizer
+                        .as_ref()
+                        .map_or(value.clone(), |x| x.pattern(value)),
+                ),
+            }),
+            Arg::Positional(value) => args.push(Arg::Positional(
+                anonymizer
+                    .as_ref()
+                    .map_or(value.clone(), |x| x.pattern(value)),
+            )),
+            _ => args.push(x),
+        }
+    }
+    let args = args.iter().map(Arg::to_arg).collect::<Vec<_>>();
+    fs::write(dir.join(ARGS), serde_json::to_string_pretty(&args)?)?;
+    Ok(())
+}
+
+/// The command line to replay the bundle in `dir`, with `extra` arguments after the
+/// recorded ones, e.g. to change the output format, leaving out `--replay-bundle`.
+pub fn replay_args(
+    dir: &Path,
+    command: &clap::Command,
+    extra: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let file = dir.join(ARGS);
+    let recorded: Vec<String> = serde_json::from_str(
+        &fs::read_to_string(&file).with_context(|| format!("When reading `{}`", file.display()))?,
+    )?;
+    let path = |x: &str| dir.join(x).to_string_lossy().into_owned();
+
+    let mut res = vec!["btd".to_owned()];
+    for x in parse_args(command, &recorded) {
+        res.push(match x {
+            Arg::Flag {
+                name,
+                value: Some(value),
+            } if FILE_ARGS.contains(&name.as_str()) => format!("--{name}={}", path(&value)),
+            x => x.to_arg(),
+        });
+    }
+    let diff = if dir.join(DIFF).exists() { DIFF } else { BASE };
+    for (name, file) in [
+        ("cells", CELLS),
+        ("config", CONFIG),
+        ("changes", CHANGES),
+        ("base", BASE),
+        ("diff", diff),
+    ] {
+        res.push(format!("--{name}={}", path(file)));
+    }
+    res.extend(
+        parse_args(command, extra)
+            .iter()
+            .filter(|x| !matches!(x, Arg::Flag { name, .. } if name == "replay-bundle"))
+            .map(Arg::to_arg),
+    );
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Arg as ClapArg;
+    use clap::ArgAction;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let command = clap::Command::new("btd")
+            .arg(
+                ClapArg::new("depth")
+                    .long("depth")
+                    .visible_alias("max-depth"),
+            )
+            .arg(ClapArg::new("json").long("json").action(ArgAction::SetTrue))
+            .arg(ClapArg::new("universe").action(ArgAction::Append));
+        let argv = [
+            "--max-depth",
+            "2",
+            "--json",
+            "foo//...",
+            "--depth=-1",
+            "--unknown",
+        ];
+        let args = parse_args(&command, &argv.map(String::from));
+        let flag = |name: &str, value: Option<&str>| Arg::Flag {
+            name: name.to_owned(),
+            value: value.map(String::from),
+        };
+        assert_eq!(
+            args,
+            vec![
+                flag("depth", Some("2")),
+                flag("json", None),
+                Arg::Positional("foo//...".to_owned()),
+                flag("depth", Some("-1")),
+                flag("unknown", None),
+            ]
+        );
+        assert_eq!(
+            args.iter().map(Arg::to_arg).collect::<Vec<_>>(),
+            vec!["--depth=2", "--json", "foo//...", "--depth=-1", "--unknown"]
+        );
+    }
+
+    #[test]
+    fn test_anonymize() {
+        let anonymizer = Anonymizer::new(&CellInfo::testing()).unwrap();
+        let a = anonymizer.pattern("foo//bar/baz:qux");
+        assert_eq!(a, anonymizer.pattern("foo//bar/baz:qux"));
+        assert!(!a.contains("bar") && !a.contains("qux"));
+        let other = Anonymizer::new(&CellInfo::testing()).unwrap();
+        assert_ne!(a, other.pattern("foo//bar/baz:qux"));
+
+        // The structure of paths and patterns is kept, so they still match
+        let (package, name) = a.split_once(':').unwrap();
+        assert_eq!(anonymizer.pattern("foo//bar/baz"), package);
+        assert_eq!(anonymizer.pattern("foo//bar/baz:"), format!("{package}:"));
+        let dir = anonymizer.pattern("foo//bar/...");
+        assert!(package.starts_with(dir.strip_suffix("...").unwrap()));
+        assert_eq!(
+            anonymizer.pattern("foo//bar/baz:qux (abc)"),
+            format!("{package}:{name} (abc)")
+        );
+
+        let file = anonymizer.pattern("foo//bar/baz/file.bzl");
+        assert!(file.starts_with(package) && file.ends_with(".bzl"));
+        assert_eq!(
+            anonymizer.pattern("foo//bar/baz/BUCK"),
+            format!("{package}/BUCK")
+        );
+        assert_eq!(
+            anonymizer.pattern("foo//bar/**/*.py"),
+            format!("{}**/*.py", dir.strip_suffix("...").unwrap())
+        );
+    }
+}
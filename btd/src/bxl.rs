@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Write the impacted targets for a BXL script to consume, e.g. `btd/bxl/impacted.bxl`.
+//!
+//! The target set file has one cell-qualified label per line, so Buck2 can expand it as
+//! an argument file, e.g. `buck2 bxl ... -- --targets @impacted.txt`. The metadata
+//! sidecar is a JSON object from each label to its record in the `--json` output.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context as _;
+
+use crate::buck::targets::BuckTarget;
+use crate::diff::ImpactReason;
+use crate::output::Output;
+use crate::propagate::propagated_labels;
+use crate::propagate::PropagatedLabels;
+
+/// Write the labels of the targets in `levels`, nearest first, one per line.
+pub fn write_targets(
+    mut out: impl Write,
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+) -> io::Result<()> {
+    for (x, _) in levels.iter().flatten() {
+        writeln!(out, "{}", x.label())?;
+    }
+    out.flush()
+}
+
+/// Write the output record of each target in `levels`, keyed by its label.
+pub fn write_metadata(
+    mut out: impl Write,
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    propagated: &PropagatedLabels,
+) -> anyhow::Result<()> {
+    let mut res = BTreeMap::new();
+    for (depth, level) in levels.iter().enumerate() {
+        for (x, reason) in level {
+            let labels = propagated_labels(propagated, x);
+            res.insert(
+                x.label().as_str().to_owned(),
+                Output::from_target(x, depth as u64, &labels, reason.clone()),
+            );
+        }
+    }
+    serde_json::to_writer(&mut out, &res)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Write the target set to `file`, and the metadata to `metadata`, if given.
+pub fn write_files(
+    file: &Path,
+    metadata: Option<&Path>,
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    propagated: &PropagatedLabels,
+) -> anyhow::Result<()> {
+    let out = BufWriter::new(
+        File::create(file).with_context(|| format!("When creating `{}`", file.display()))?,
+    );
+    write_targets(out, levels)
+        .with_context(|| format!("When writing target set to `{}`", file.display()))?;
+    if let Some(metadata) = metadata {
+        let out = BufWriter::new(
+            File::create(metadata)
+                .with_context(|| format!("When creating `{}`", metadata.display()))?,
+        );
+        write_metadata(out, levels, propagated)
+            .with_context(|| format!("When writing metadata to `{}`", metadata.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::arena::Deps;
+    use crate::buck::targets::Targets;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::CellPath;
+    use crate::buck::types::TargetLabel;
+    use crate::changes::Changes;
+    use crate::diff;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_write_bxl() {
+        let file = CellPath::new("foo//bar/lib.cpp");
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(BuckTarget {
+                inputs: Box::new([file.clone()]),
+                ..BuckTarget::testing("lib", "foo//bar", "prelude//rules.bzl:cxx_library")
+            }),
+            TargetsEntry::Target(BuckTarget {
+                deps: Deps::from([TargetLabel::new("foo//bar:lib")]),
+                ..BuckTarget::testing("bin", "foo//baz", "prelude//rules.bzl:cxx_binary")
+            }),
+        ]);
+        let changes = Changes::testing(&[Status::Modified(file)]);
+        let immediate = diff::immediate_target_changes(&targets, &targets, &changes, false);
+        let levels = diff::recursive_target_changes(&targets, &immediate, None, |_| true);
+
+        let mut out = Vec::new();
+        write_targets(&mut out, &levels).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "foo//bar:lib\nfoo//baz:bin\n"
+        );
+
+        let mut out = Vec::new();
+        write_metadata(&mut out, &levels, &PropagatedLabels::new()).unwrap();
+        let metadata: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(metadata["foo//bar:lib"]["depth"], 0);
+        assert_eq!(metadata["foo//baz:bin"]["depth"], 1);
+        assert_eq!(metadata["foo//baz:bin"]["type"], "cxx_binary");
+        assert_eq!(
+            metadata["foo//baz:bin"]["reason"]["affected_dep"],
+            "foo//bar:lib"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Cache the impacted targets on disk, so a query repeated against the same targets,
+//! e.g. by a retried CI job, skips the traversal.
+//!
+//! Each entry is a JSON file named by the hash of the diff targets, the targets which
+//! changed, and the options affecting the traversal. Entries older than the TTL are
+//! ignored, and the oldest are evicted once the cache is too big. Problems with the
+//! cache are logged, but never fail the query, which is then computed as normal.
+
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Context as _;
+use tracing::info;
+use tracing::warn;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::diff::GraphImpact;
+use crate::diff::ImpactReason;
+use crate::rdeps;
+
+type Levels<'a> = Vec<Vec<(&'a BuckTarget, ImpactReason)>>;
+
+/// Bumped whenever the entries change, so those written by older versions are missed.
+const VERSION: u32 = 2;
+
+pub struct ImpactCache {
+    dir: PathBuf,
+    ttl: Duration,
+    /// The most bytes the entries can take up, before the oldest are evicted.
+    max_size: u64,
+}
+
+impl ImpactCache {
+    pub fn new(dir: PathBuf, ttl: Duration, max_size: u64) -> Self {
+        Self { dir, ttl, max_size }
+    }
+
+    /// The key of the impact of `seeds` on `diff`, where `options` are everything else
+    /// changing the result of the traversal.
+    pub fn key(diff: &Targets, seeds: &GraphImpact, options: impl Hash) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        VERSION.hash(&mut hasher);
+        rdeps::fingerprint(diff).hash(&mut hasher);
+        for (x, reason) in seeds.iter() {
+            x.label().as_str().hash(&mut hasher);
+            hash_reason(&reason, &mut hasher);
+        }
+        for x in seeds.removed() {
+            x.label().as_str().hash(&mut hasher);
+        }
+        options.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn file(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}.json"))
+    }
+
+    /// The cached impact for `key`, if there is a fresh entry for it.
+    pub fn get<'a>(&self, key: u64, diff: &'a Targets) -> Option<Levels<'a>> {
+        match self.read(key, diff) {
+            Ok(res) => {
+                info!(
+                    "Impact cache {} for {key:016x}",
+                    if res.is_some() { "hit" } else { "miss" }
+                );
+                res
+            }
+            Err(e) => {
+                warn!("Ignoring the impact cache, {e:#}");
+                None
+            }
+        }
+    }
+
+    fn read<'a>(&self, key: u64, diff: &'a Targets) -> anyhow::Result<Option<Levels<'a>>> {
+        let file = self.file(key);
+        if !file.exists() || !self.is_fresh(&file)? {
+            return Ok(None);
+        }
+        let data = fs::read(&file).with_context(|| format!("When reading `{}`", file.display()))?;
+        let levels: Vec<Vec<(TargetLabel, ImpactReason)>> = serde_json::from_slice(&data)
+            .with_context(|| format!("When parsing `{}`", file.display()))?;
+        // The key covers the targets, so they should all be there, but check to be safe
+        let targets = diff.targets_by_label();
+        Ok(levels
+            .into_iter()
+            .map(|level| {
+                level
+                    .into_iter()
+                    .map(|(label, reason)| Some((*targets.get(&label)?, reason)))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .collect())
+    }
+
+    fn is_fresh(&self, file: &Path) -> anyhow::Result<bool> {
+        let modified = fs::metadata(file)?.modified()?;
+        Ok(SystemTime::now()
+            .duration_since(modified)
+            .is_ok_and(|age| age <= self.ttl))
+    }
+
+    /// Store the impact for `key`, then evict entries which are stale or over the size.
+    pub fn put(&self, key: u64, levels: &[Vec<(&BuckTarget, ImpactReason)>]) {
+        if let Err(e) = self.write(key, levels).and_then(|_| self.evict()) {
+            warn!("Failed to update the impact cache, {e:#}");
+        }
+    }
+
+    fn write(&self, key: u64, levels: &[Vec<(&BuckTarget, ImpactReason)>]) -> anyhow::Result<()> {
+        let levels = levels
+            .iter()
+            .map(|level| {
+                level
+                    .iter()
+                    .map(|(x, reason)| (x.label(), reason))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("When creating `{}`", self.dir.display()))?;
+        // Write then rename, so concurrent jobs never read a partial entry
+        let file = self.file(key);
+        let tmp = file.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec(&levels)?)
+            .with_context(|| format!("When writing `{}`", tmp.display()))?;
+        fs::rename(&tmp, &file).with_context(|| format!("When writing `{}`", file.display()))?;
+        Ok(())
+    }
+
+    fn evict(&self) -> anyhow::Result<()> {
+        let mut entries = Vec::new();
+        for x in fs::read_dir(&self.dir)? {
+            let path = x?.path();
+            if path.extension().is_some_and(|x| x == "json") {
+                let metadata = fs::metadata(&path)?;
+                entries.push((metadata.modified()?, metadata.len(), path));
+            }
+        }
+        // Newest first, keeping as many as fit
+        entries.sort_by_key(|x| Reverse(x.0));
+        let mut size = 0;
+        for (_, len, path) in entries {
+            size += len;
+            if size > self.max_size || !self.is_fresh(&path)? {
+                fs::remove_file(&path)
+                    .with_context(|| format!("When evicting `{}`", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hash_reason(reason: &ImpactReason, hasher: &mut impl Hasher) {
+    reason.affected_dep.hash(hasher);
+    reason.root_cause.0.hash(hasher);
+    reason.root_cause.1.to_string().hash(hasher);
+    reason.via_hint.hash(hasher);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+    use crate::diff::RootImpactKind;
+
+    fn targets() -> Targets {
+        Targets::new(
+            ["a", "b"]
+                .map(|x| {
+                    TargetsEntry::Target(BuckTarget::testing(
+                        x,
+                        "foo//bar",
+                        "prelude//rules.bzl:cxx_library",
+                    ))
+                })
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_impact_cache() {
+        let dir = TempDir::new().unwrap();
+        let cache = ImpactCache::new(dir.path().to_owned(), Duration::from_secs(60), 1 << 20);
+        let diff = targets();
+        let xs = diff.targets().collect::<Vec<_>>();
+        let seeds = GraphImpact::from_recursive(vec![(
+            xs[0],
+            ImpactReason::new(xs[0], RootImpactKind::Inputs),
+        )]);
+        let key = ImpactCache::key(&diff, &seeds, Some(3));
+        assert_ne!(key, ImpactCache::key(&diff, &seeds, Some(4)));
+        assert_eq!(cache.get(key, &diff), None);
+
+        let levels = vec![
+            seeds.iter().collect::<Vec<_>>(),
+            vec![(xs[1], ImpactReason::new(xs[0], RootImpactKind::Inputs))],
+        ];
+        cache.put(key, &levels);
+        assert_eq!(cache.get(key, &diff), Some(levels));
+
+        // Stale entries are ignored, then evicted
+        let stale = ImpactCache::new(dir.path().to_owned(), Duration::ZERO, 1 << 20);
+        File::options()
+            .write(true)
+            .open(cache.file(key))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stale.get(key, &diff), None);
+        stale.put(key + 1, &[]);
+        assert!(!cache.file(key).exists());
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let dir = TempDir::new().unwrap();
+        // Each entry is `[]`, so only two fit
+        let cache = ImpactCache::new(dir.path().to_owned(), Duration::from_secs(60), 5);
+        for key in 0..3 {
+            cache.put(key, &[]);
+            File::options()
+                .write(true)
+                .open(cache.file(key))
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(10 - key))
+                .unwrap();
+        }
+        cache.put(3, &[]);
+        let kept = (0..4)
+            .filter(|x| cache.file(*x).exists())
+            .collect::<Vec<_>>();
+        assert_eq!(kept, vec![2, 3]);
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Sort the changed files into categories, e.g. docs or CI config, and choose how
+//! each category impacts targets, from a JSON file passed with `--change-policy`, e.g.
+//!
+//! ```json
+//! {
+//!     "docs": {"policy": "ignore"},
+//!     "ci_config": {"globs": [".github/**", "ci/**"], "policy": "attribute-globally"},
+//!     "generated_snapshot": {"policy": "attribute-to-package"}
+//! }
+//! ```
+//!
+//! A file is in the first category it matches, in the order `build_file`, `ci_config`,
+//! `generated_snapshot`, `docs`, falling back to `source`. The `globs` are relative to
+//! the root of the repo, and default to [`FileCategory::default_globs`]. Without `globs`,
+//! build files are those with the `[buildfile]` names of their cell.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use td_util::prelude::*;
+use tracing::info;
+
+use crate::buck::cells::CellInfo;
+use crate::buck::glob::GlobSpec;
+use crate::buck::types::CellPath;
+use crate::buck::types::Glob;
+use crate::buck::types::ProjectRelativePath;
+use crate::changes::Changes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileCategory {
+    BuildFile,
+    CiConfig,
+    GeneratedSnapshot,
+    Docs,
+    Source,
+}
+
+impl FileCategory {
+    /// In the order files are matched against them.
+    const ALL: [Self; 5] = [
+        Self::BuildFile,
+        Self::CiConfig,
+        Self::GeneratedSnapshot,
+        Self::Docs,
+        Self::Source,
+    ];
+
+    /// The globs used when the policy doesn't give any. Build files are recognised
+    /// by name instead, and everything else is a source.
+    pub fn default_globs(self) -> &'static [&'static str] {
+        match self {
+            Self::BuildFile | Self::Source => &[],
+            Self::CiConfig => &[
+                ".github/**",
+                ".circleci/**",
+                ".buildkite/**",
+                ".gitlab-ci.yml",
+                ".travis.yml",
+            ],
+            Self::GeneratedSnapshot => &["**/__snapshots__/**", "**/*.snap"],
+            Self::Docs => &["**/*.md", "**/*.rst", "docs/**", "**/docs/**"],
+        }
+    }
+}
+
+/// How changes to the files in a category impact targets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// Impact the targets using the file, as if there were no policy.
+    #[default]
+    Normal,
+    /// Impact nothing.
+    Ignore,
+    /// Also impact every target in the package owning the file.
+    AttributeToPackage,
+    /// Impact every target.
+    AttributeGlobally,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CategoryConfig {
+    globs: Option<Vec<Glob>>,
+    policy: Policy,
+}
+
+struct Category {
+    category: FileCategory,
+    /// `None` for build files recognised by name, and for sources, which match anything.
+    globs: Option<GlobSpec>,
+    policy: Policy,
+}
+
+/// The changed files whose policy impacts more than the targets using them,
+/// for [`ImmediateOptions`](crate::diff::ImmediateOptions).
+#[derive(Debug, Clone, Default)]
+pub struct PolicyImpact {
+    /// Files impacting every target in the package owning them.
+    pub package_files: HashSet<CellPath>,
+    /// Whether any file impacts every target.
+    pub global: bool,
+}
+
+/// Categorizes changed files, or does nothing if no category has a policy.
+#[derive(Default)]
+pub struct ChangePolicy {
+    categories: Vec<Category>,
+}
+
+impl ChangePolicy {
+    pub fn read_file(file: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading change policy `{}`", file.display()))?;
+        Self::parse(&data)
+            .with_context(|| format!("When parsing change policy `{}`", file.display()))
+    }
+
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let mut config: HashMap<FileCategory, CategoryConfig> = serde_json::from_str(data)?;
+        let categories = FileCategory::ALL
+            .into_iter()
+            .map(|category| {
+                let config = config.remove(&category).unwrap_or_default();
+                let globs = match config.globs {
+                    Some(globs) => Some(globs),
+                    None if category.default_globs().is_empty() => None,
+                    None => Some(category.default_globs().map(|x| Glob::new(x))),
+                };
+                Category {
+                    category,
+                    globs: globs.map(|x| GlobSpec::new(&x)),
+                    policy: config.policy,
+                }
+            })
+            .collect();
+        Ok(Self { categories })
+    }
+
+    fn categorize(
+        &self,
+        cells: &CellInfo,
+        path: &CellPath,
+        project: &ProjectRelativePath,
+    ) -> anyhow::Result<&Category> {
+        for x in &self.categories {
+            let matches = match (&x.globs, x.category) {
+                (Some(globs), _) => globs.matches(project),
+                (None, FileCategory::BuildFile) => path.is_target_file(cells)?,
+                (None, _) => true,
+            };
+            if matches {
+                return Ok(x);
+            }
+        }
+        unreachable!("every file is at least a source")
+    }
+
+    /// Drop the ignored files from `changes`, and find those with a wider impact.
+    pub fn apply(
+        &self,
+        cells: &CellInfo,
+        changes: Changes,
+    ) -> anyhow::Result<(Changes, PolicyImpact)> {
+        let mut impact = PolicyImpact::default();
+        if self.categories.iter().all(|x| x.policy == Policy::Normal) {
+            return Ok((changes, impact));
+        }
+        let mut ignored = HashSet::new();
+        let mut counts: HashMap<FileCategory, usize> = HashMap::new();
+        for (path, project) in changes.cell_paths().zip(changes.project_paths()) {
+            let category = self.categorize(cells, path, project)?;
+            *counts.entry(category.category).or_default() += 1;
+            match category.policy {
+                Policy::Normal => {}
+                Policy::Ignore => {
+                    ignored.insert(path.clone());
+                }
+                Policy::AttributeToPackage => {
+                    impact.package_files.insert(path.clone());
+                }
+                Policy::AttributeGlobally => impact.global = true,
+            }
+        }
+        for category in FileCategory::ALL {
+            if let Some(count) = counts.get(&category) {
+                info!("Changed files categorized as {category:?}: {count}");
+            }
+        }
+        let changes = if ignored.is_empty() {
+            changes
+        } else {
+            changes.filter_by_cell_path(|x| !ignored.contains(x))
+        };
+        Ok((changes, impact))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::Targets;
+    use crate::buck::targets::TargetsEntry;
+    use crate::diff;
+    use crate::diff::ImmediateOptions;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_change_policy() {
+        let policy = ChangePolicy::parse(
+            &serde_json::json!({
+                "docs": {"policy": "ignore"},
+                "build_file": {"policy": "attribute-to-package"},
+                "ci_config": {"globs": ["ci/**"], "policy": "attribute-globally"},
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let cells = CellInfo::testing();
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(BuckTarget {
+                inputs: Box::new([CellPath::new("root//foo/lib.c")]),
+                ..BuckTarget::testing("lib", "root//foo", "prelude//rules.bzl:cxx_library")
+            }),
+            TargetsEntry::Target(BuckTarget::testing(
+                "other",
+                "root//foo",
+                "prelude//rules.bzl:cxx_library",
+            )),
+            TargetsEntry::Target(BuckTarget::testing(
+                "bin",
+                "root//bar",
+                "prelude//rules.bzl:cxx_binary",
+            )),
+        ]);
+        let impacted = |files: &[&str]| {
+            let changes = Changes::testing(
+                &files
+                    .iter()
+                    .map(|x| Status::Modified(CellPath::new(x)))
+                    .collect::<Vec<_>>(),
+            );
+            let (changes, change_policy) = policy.apply(&cells, changes).unwrap();
+            let options = ImmediateOptions {
+                change_policy,
+                ..ImmediateOptions::default()
+            };
+            let immediate =
+                diff::immediate_target_changes_with(&targets, &targets, &changes, &options);
+            let mut res = immediate
+                .iter()
+                .map(|(x, _)| x.name.as_str().to_owned())
+                .collect::<Vec<_>>();
+            res.sort();
+            res
+        };
+        assert_eq!(impacted(&["root//foo/lib.c"]), vec!["lib"]);
+        assert!(impacted(&["root//foo/README.md", "root//docs/lib.c"]).is_empty());
+        assert_eq!(impacted(&["root//foo/BUCK"]), vec!["lib", "other"]);
+        assert_eq!(
+            impacted(&["root//ci/build.yml"]),
+            vec!["bin", "lib", "other"]
+        );
+        // Not in `ci/`, so only the default globs of other categories apply
+        assert!(impacted(&["root//.github/workflows/ci.yml"]).is_empty());
+
+        assert!(ChangePolicy::parse(r#"{"docs": {"policy": "drop"}}"#).is_err());
+        assert!(ChangePolicy::parse(r#"{"tests": {"policy": "ignore"}}"#).is_err());
+    }
+}
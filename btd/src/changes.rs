@@ -7,14 +7,28 @@
  * of this source tree.
  */
 
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
 
+use anyhow::Context as _;
+use clap::ValueEnum;
+use td_util::command::with_command;
 use td_util::prelude::*;
+use tempfile::TempDir;
+use thiserror::Error;
+use tracing::debug;
+use tracing::warn;
 
 use crate::buck::cells::CellInfo;
+use crate::buck::targets::Targets;
 use crate::buck::types::CellPath;
 use crate::buck::types::Package;
 use crate::buck::types::ProjectRelativePath;
+use crate::diff::RootImpactKind;
 use crate::sapling::status::Status;
 
 #[derive(Default, Debug)]
@@ -55,6 +69,10 @@ impl Changes {
         self.paths.is_empty()
     }
 
+    pub fn status_paths(&self) -> impl Iterator<Item = &Status<(CellPath, ProjectRelativePath)>> {
+        self.paths.iter()
+    }
+
     pub fn status_cell_paths(&self) -> impl Iterator<Item = Status<&CellPath>> {
         self.paths.iter().map(|x| x.map(|x| &x.0))
     }
@@ -75,17 +93,623 @@ impl Changes {
         self.contains_cell_path(&package.as_cell_path())
     }
 
-    pub fn filter_by_cell_path(&self, f: impl Fn(&CellPath) -> bool) -> Changes {
+    pub fn filter(&self, f: impl Fn(&CellPath, &ProjectRelativePath) -> bool) -> Changes {
         let paths = self
             .paths
             .iter()
-            .filter(|x| f(&x.get().0))
+            .filter(|x| f(&x.get().0, &x.get().1))
             .cloned()
             .collect();
         Self::from_paths(paths)
     }
 
+    pub fn filter_by_cell_path(&self, f: impl Fn(&CellPath) -> bool) -> Changes {
+        self.filter(|x, _| f(x))
+    }
+
     pub fn filter_by_extension(&self, f: impl Fn(Option<&str>) -> bool) -> Changes {
         self.filter_by_cell_path(|x| f(x.extension()))
     }
+
+    /// The package owning each changed file, which is the nearest enclosing directory
+    /// in `packages`, so a file within a subpackage belongs only to the subpackage.
+    /// Files for which `skip` returns `true` are ignored.
+    pub fn owning_packages(
+        &self,
+        packages: &HashSet<Package>,
+        skip: impl Fn(&CellPath) -> bool,
+    ) -> HashSet<Package> {
+        let mut res = HashSet::new();
+        for path in self.cell_paths() {
+            if skip(path) {
+                continue;
+            }
+            let mut dir = path.parent();
+            loop {
+                let package = dir.as_package();
+                if packages.contains(&package) {
+                    res.insert(package);
+                    break;
+                }
+                if dir.path().as_str().is_empty() {
+                    break;
+                }
+                dir = dir.parent();
+            }
+        }
+        res
+    }
+
+    /// Also report each changed file by its path with symlinks resolved, if that is
+    /// different and still within the repo at `root`, so a file changed through a
+    /// symlinked directory is attributed to the targets using its real path.
+    pub fn resolve_symlinks(self, cells: &CellInfo, root: &Path) -> anyhow::Result<Changes> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("When resolving the repo root `{}`", root.display()))?;
+        let mut seen = self.project_paths().cloned().collect::<HashSet<_>>();
+        let mut resolved = Vec::new();
+        for x in &self.paths {
+            if let Some(path) = resolve_symlinks(&root, &x.get().1) {
+                if seen.insert(path.clone()) {
+                    debug!("Changed file `{}` resolves to `{path}`", x.get().1);
+                    resolved.push(x.try_map(|_| anyhow::Ok((cells.unresolve(&path)?, path)))?);
+                }
+            }
+        }
+        if resolved.is_empty() {
+            return Ok(self);
+        }
+        let mut paths = self.paths;
+        paths.extend(resolved);
+        Ok(Self::from_paths(paths))
+    }
+
+    /// Rewrite changed paths which only differ in case from a file or package in `targets`
+    /// to use the case Buck does, for checkouts on case-insensitive file systems, e.g. on
+    /// macOS or Windows. Other files in a package get the case of the package directory.
+    /// Paths matching several files which only differ in case are left alone.
+    pub fn fold_case(self, cells: &CellInfo, targets: &Targets) -> anyhow::Result<Changes> {
+        // Lowercase path to the path, or `None` if several paths have that lowercase
+        fn insert(map: &mut HashMap<String, Option<ProjectRelativePath>>, x: ProjectRelativePath) {
+            match map.entry(x.as_str().to_lowercase()) {
+                Entry::Vacant(e) => {
+                    e.insert(Some(x));
+                }
+                Entry::Occupied(mut e) => {
+                    if e.get().as_ref() != Some(&x) {
+                        e.insert(None);
+                    }
+                }
+            }
+        }
+
+        let mut files = HashMap::new();
+        let inputs = targets.targets().flat_map(|x| x.inputs.iter());
+        for x in inputs.chain(targets.imports().map(|x| &x.file)) {
+            if let Ok(x) = cells.resolve(x) {
+                insert(&mut files, x);
+            }
+        }
+        let mut dirs = HashMap::new();
+        let packages = targets
+            .targets()
+            .map(|x| &x.package)
+            .collect::<HashSet<_>>();
+        for x in packages {
+            if let Ok(x) = cells.resolve(&x.as_cell_path()) {
+                insert(&mut dirs, x);
+            }
+        }
+
+        let fold = |path: &ProjectRelativePath| {
+            if let Some(x) = files.get(&path.as_str().to_lowercase()) {
+                return x.clone();
+            }
+            let (dir, name) = path.as_str().rsplit_once('/')?;
+            Some(dirs.get(&dir.to_lowercase())?.as_ref()?.join(name))
+        };
+        let paths = self.paths.into_try_map(|x| {
+            x.into_try_map(|(cell_path, path)| match fold(&path) {
+                Some(folded) if folded != path => {
+                    debug!("Changed file `{path}` matches `{folded}` ignoring case");
+                    anyhow::Ok((cells.unresolve(&folded)?, folded))
+                }
+                _ => Ok((cell_path, path)),
+            })
+        })?;
+        Ok(Self::from_paths(paths))
+    }
+}
+
+/// `path` within the canonical `root` with any symlinks resolved, if that is different.
+/// Files which no longer exist are resolved through their directory.
+fn resolve_symlinks(root: &Path, path: &ProjectRelativePath) -> Option<ProjectRelativePath> {
+    let full = root.join(path.as_str());
+    let resolved = match full.canonicalize() {
+        Ok(x) => x,
+        Err(_) => full.parent()?.canonicalize().ok()?.join(full.file_name()?),
+    };
+    let resolved = resolved
+        .strip_prefix(root)
+        .ok()?
+        .to_str()?
+        .replace('\\', "/");
+    (resolved != path.as_str()).then(|| ProjectRelativePath::new(&resolved))
+}
+
+/// How changed files are attributed to the targets they impact.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attribution {
+    /// A file only impacts the targets which list it among their inputs.
+    #[default]
+    Strict,
+    /// Additionally, a changed file which no target accounts for impacts
+    /// every target in the package that owns it.
+    Ownership,
+}
+
+/// How changes to `PACKAGE` and buckconfig files impact targets. These files can
+/// alter every target beneath them, which the target hashes don't always reflect.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageChangePolicy {
+    /// Rely on the target hashes and package values alone.
+    #[default]
+    Ignore,
+    /// A `PACKAGE` file impacts all targets in its directory and below,
+    /// a buckconfig file all targets in its cell.
+    Subtree,
+    /// Either kind of file impacts all targets in its cell.
+    Cell,
+}
+
+impl PackageChangePolicy {
+    /// The directories whose targets are impacted by `path` changing, and why.
+    pub fn impacted_scope(self, path: &CellPath) -> Option<(Package, RootImpactKind)> {
+        let cell = || Package::new(&format!("{}//", path.cell()));
+        match self {
+            Self::Ignore => None,
+            _ if path.is_buckconfig_file() => Some((cell(), RootImpactKind::Buckconfig)),
+            Self::Subtree if path.is_package_file() => {
+                Some((path.parent().as_package(), RootImpactKind::PackageFile))
+            }
+            Self::Cell if path.is_package_file() => Some((cell(), RootImpactKind::PackageFile)),
+            _ => None,
+        }
+    }
+}
+
+/// The source control systems we can ask for the changes directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scm {
+    Sapling,
+    Git,
+}
+
+#[derive(Error, Debug)]
+enum ScmError {
+    #[error("Unexpected `{scm:?}` status line: `{line}`")]
+    UnexpectedLine { scm: Scm, line: String },
+    #[error("Not in a Sapling or git checkout")]
+    NoCheckout,
+    #[error("Expected `{0}` to name a single revision")]
+    NotOneRevision(String),
+    #[cfg(not(feature = "eden"))]
+    #[error("`--eden` needs BTD built with the `eden` feature")]
+    EdenNotBuilt,
+}
+
+impl Scm {
+    fn command(self, rev: &str) -> Command {
+        match self {
+            Scm::Sapling => {
+                let mut command = Command::new("sl");
+                // `--copies` prints the source of copies/renames on the line after the addition.
+                command.args([
+                    "status",
+                    "-amr",
+                    "--copies",
+                    "--root-relative",
+                    "--rev",
+                    rev,
+                ]);
+                command
+            }
+            Scm::Git => {
+                let mut command = Command::new("git");
+                // `-z` separates the fields with NUL, rather than quoting unusual paths
+                command.args([
+                    "diff",
+                    "-z",
+                    "--name-status",
+                    "--find-renames",
+                    "--find-copies",
+                    rev,
+                ]);
+                command
+            }
+        }
+    }
+
+    fn parse(self, data: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+        match self {
+            Scm::Sapling => parse_sapling_status(data),
+            Scm::Git => parse_git_name_status(data),
+        }
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Scm::Sapling => "sl",
+            Scm::Git => "git",
+        }
+    }
+
+    fn output(self, command: Command) -> anyhow::Result<String> {
+        with_command(command, |mut command| {
+            let res = command.output()?;
+            res.status.exit_ok().with_context(|| {
+                format!("{self:?} stderr: {}", String::from_utf8_lossy(&res.stderr))
+            })?;
+            Ok(String::from_utf8(res.stdout)?)
+        })
+    }
+
+    fn run(self, args: &[&str]) -> anyhow::Result<String> {
+        let mut command = Command::new(self.program());
+        command.args(args);
+        self.output(command)
+    }
+
+    /// Ask this source control system for the files changed between `rev` and the working copy.
+    pub fn status(self, rev: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+        let stdout = self.output(self.command(rev))?;
+        self.parse(&stdout)
+    }
+
+    /// The source control system of the current directory, preferring Sapling.
+    pub fn detect() -> anyhow::Result<Self> {
+        [Scm::Sapling, Scm::Git]
+            .into_iter()
+            .find(|scm| match scm {
+                Scm::Sapling => scm.run(&["root"]).is_ok(),
+                Scm::Git => scm.run(&["rev-parse", "--git-dir"]).is_ok(),
+            })
+            .ok_or_else(|| ScmError::NoCheckout.into())
+    }
+
+    /// The full hash of the revision `rev` names, e.g. `.^` or a short hash.
+    pub fn resolve(self, rev: &str) -> anyhow::Result<String> {
+        let stdout = match self {
+            Scm::Sapling => self.run(&["log", "--rev", rev, "--template", "{node}\n"])?,
+            Scm::Git => self.run(&["rev-parse", "--verify", &format!("{rev}^{{commit}}")])?,
+        };
+        match stdout.lines().collect::<Vec<_>>()[..] {
+            [node] => Ok(node.to_owned()),
+            _ => Err(ScmError::NotOneRevision(rev.to_owned()).into()),
+        }
+    }
+
+    /// The root of the checkout.
+    pub fn root(self) -> anyhow::Result<PathBuf> {
+        let stdout = match self {
+            Scm::Sapling => self.run(&["root"])?,
+            Scm::Git => self.run(&["rev-parse", "--show-toplevel"])?,
+        };
+        Ok(PathBuf::from(stdout.trim()))
+    }
+
+    /// The contents of `path` at revision `rev`.
+    pub fn cat(self, rev: &str, path: &ProjectRelativePath) -> anyhow::Result<String> {
+        match self {
+            // `path:` makes the path relative to the root, rather than the current directory
+            Scm::Sapling => self.run(&["cat", "--rev", rev, &format!("path:{path}")]),
+            Scm::Git => self.run(&["show", &format!("{rev}:{path}")]),
+        }
+    }
+
+    /// Check out `rev` in a new temporary directory, sharing this repository, so the
+    /// working copy is left alone. A Git worktree, or a Sapling share.
+    pub fn worktree(self, rev: &str) -> anyhow::Result<Worktree> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("checkout");
+        let path_str = path.to_string_lossy();
+        match self {
+            Scm::Sapling => {
+                let root = self.root()?;
+                self.run(&[
+                    "--config",
+                    "extensions.share=",
+                    "share",
+                    "--noupdate",
+                    &root.to_string_lossy(),
+                    &path_str,
+                ])?;
+                self.run(&["--cwd", &path_str, "goto", rev])?;
+            }
+            Scm::Git => {
+                self.run(&["worktree", "add", "--quiet", "--detach", &path_str, rev])?;
+            }
+        }
+        Ok(Worktree {
+            scm: self,
+            path,
+            _dir: dir,
+        })
+    }
+}
+
+/// A temporary checkout from [`Scm::worktree`], deleted when dropped.
+pub struct Worktree {
+    scm: Scm,
+    path: PathBuf,
+    _dir: TempDir,
+}
+
+impl Worktree {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        // A share is only known to itself, so deleting the directory is enough
+        if self.scm == Scm::Git {
+            let path = self.path.to_string_lossy();
+            if let Err(e) = self.scm.run(&["worktree", "remove", "--force", &path]) {
+                warn!("Could not remove the worktree `{path}`: {e:#}");
+            }
+        }
+    }
+}
+
+/// Get the changes since `rev` from the source control system of the current directory,
+/// see [`Scm::detect`]. Git is only asked if this is not a Sapling checkout, so a
+/// failure of Sapling is reported rather than hidden by a fallback.
+pub fn changes_from_scm(rev: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    let scm = Scm::detect()?;
+    scm.status(rev)
+        .with_context(|| format!("When asking {scm:?} for the changes since `{rev}`"))
+}
+
+/// The full hash of the revision `rev` names, asking the source control system of the
+/// current directory.
+pub fn resolve_revision(rev: &str) -> anyhow::Result<String> {
+    Scm::detect()?.resolve(rev)
+}
+
+/// Get the changes since `rev` from EdenFS, falling back to [`changes_from_scm`]
+/// if Eden can't answer, e.g. as this is not an Eden checkout.
+#[cfg(feature = "eden")]
+pub fn changes_from_eden(rev: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    match crate::eden::changes_from_eden(rev) {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            warn!("Eden could not compute changes, falling back to source control: {e:#}");
+            changes_from_scm(rev)
+        }
+    }
+}
+
+#[cfg(not(feature = "eden"))]
+pub fn changes_from_eden(_rev: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    Err(ScmError::EdenNotBuilt.into())
+}
+
+/// Run `f` on a temporary checkout of `rev`, given its root, deleting it afterwards.
+/// The working copy is never changed, so it may have uncommitted changes, and is
+/// never left at `rev` if BTD is killed.
+pub fn with_scm_worktree<T>(
+    rev: &str,
+    f: impl FnOnce(&Path) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let worktree = Scm::detect()?
+        .worktree(rev)
+        .with_context(|| format!("When checking out `{rev}`"))?;
+    f(worktree.path())
+}
+
+/// Parse `sl status --copies`. Renames show up as an added file (followed by an
+/// indented line naming the source) plus a removal of the source, so both ends
+/// of a move are already recorded. Copies only produce the addition.
+fn parse_sapling_status(data: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    data.lines()
+        .filter(|line| !line.starts_with(' '))
+        .map(Status::from_str)
+        .collect()
+}
+
+/// Parse `git diff -z --name-status --find-renames --find-copies`, where every field
+/// ends with a NUL, so paths are never quoted.
+/// A rename `R<score>\0old\0new\0` becomes a removal of `old` and an addition of `new`,
+/// a copy `C<score>\0old\0new\0` just an addition of `new`.
+fn parse_git_name_status(data: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    let mut res = Vec::new();
+    let mut fields = data.split('\0').filter(|x| !x.is_empty());
+    while let Some(typ) = fields.next() {
+        let err = || ScmError::UnexpectedLine {
+            scm: Scm::Git,
+            line: typ.to_owned(),
+        };
+        let mut path = || fields.next().map(ProjectRelativePath::new).ok_or_else(err);
+        match typ.chars().next() {
+            Some('M' | 'T' | 'U') => res.push(Status::Modified(path()?)),
+            Some('A') => res.push(Status::Added(path()?)),
+            Some('D') => res.push(Status::Removed(path()?)),
+            Some('R') => {
+                res.push(Status::Removed(path()?));
+                res.push(Status::Added(path()?));
+            }
+            Some('C') => {
+                path()?;
+                res.push(Status::Added(path()?));
+            }
+            _ => return Err(err().into()),
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sapling_status() {
+        let src =
+            "M proj/foo.rs\nA new/name.rs\n  old/name.rs\nA copy.rs\n  orig.rs\nR old/name.rs\n";
+        assert_eq!(
+            parse_sapling_status(src).unwrap(),
+            vec![
+                Status::Modified(ProjectRelativePath::new("proj/foo.rs")),
+                Status::Added(ProjectRelativePath::new("new/name.rs")),
+                Status::Added(ProjectRelativePath::new("copy.rs")),
+                Status::Removed(ProjectRelativePath::new("old/name.rs")),
+            ]
+        );
+        assert!(parse_sapling_status("? unknown.rs").is_err());
+    }
+
+    #[test]
+    fn test_parse_git_name_status() {
+        let src = "M\0proj/foo.rs\0A\0baz.txt\0D\0quux.js\0R087\0old/name.rs\0new/name.rs\0C100\0orig.rs\0copy.rs\0";
+        assert_eq!(
+            parse_git_name_status(src).unwrap(),
+            vec![
+                Status::Modified(ProjectRelativePath::new("proj/foo.rs")),
+                Status::Added(ProjectRelativePath::new("baz.txt")),
+                Status::Removed(ProjectRelativePath::new("quux.js")),
+                Status::Removed(ProjectRelativePath::new("old/name.rs")),
+                Status::Added(ProjectRelativePath::new("new/name.rs")),
+                Status::Added(ProjectRelativePath::new("copy.rs")),
+            ]
+        );
+        // Which without `-z` git would quote as `"caf\303\251 \"x\"\tz.rs"`
+        assert_eq!(
+            parse_git_name_status("M\0caf\u{e9} \"x\"\tz.rs\0").unwrap(),
+            vec![Status::Modified(ProjectRelativePath::new(
+                "caf\u{e9} \"x\"\tz.rs"
+            ))]
+        );
+        assert!(parse_git_name_status("R100\0only_one.rs\0").is_err());
+        assert!(parse_git_name_status("X\0foo.rs\0").is_err());
+    }
+
+    #[test]
+    fn test_owning_packages() {
+        let changes = Changes::testing(&[
+            Status::Modified(CellPath::new("foo//bar/data.txt")),
+            Status::Modified(CellPath::new("foo//bar/baz/qux/data.txt")),
+            Status::Added(CellPath::new("foo//top.txt")),
+            Status::Removed(CellPath::new("foo//bar/src.cpp")),
+            Status::Modified(CellPath::new("other//file.txt")),
+        ]);
+        let packages = HashSet::from([
+            Package::new("foo//bar"),
+            Package::new("foo//bar/baz"),
+            Package::new("foo//"),
+        ]);
+        let res = changes.owning_packages(&packages, |x| x.as_str() == "foo//bar/src.cpp");
+        assert_eq!(res, packages);
+
+        let res = changes.owning_packages(&packages, |x| x.as_str() != "foo//bar/baz/qux/data.txt");
+        assert_eq!(res, HashSet::from([Package::new("foo//bar/baz")]));
+    }
+
+    #[test]
+    fn test_impacted_scope() {
+        let package = CellPath::new("foo//bar/PACKAGE");
+        let config = CellPath::new("foo//.buckconfig");
+        let source = CellPath::new("foo//bar/src.cpp");
+        let root = || Package::new("foo//");
+        assert_eq!(PackageChangePolicy::Ignore.impacted_scope(&package), None);
+        assert_eq!(PackageChangePolicy::Ignore.impacted_scope(&config), None);
+        assert_eq!(
+            PackageChangePolicy::Subtree.impacted_scope(&package),
+            Some((Package::new("foo//bar"), RootImpactKind::PackageFile))
+        );
+        assert_eq!(
+            PackageChangePolicy::Subtree.impacted_scope(&config),
+            Some((root(), RootImpactKind::Buckconfig))
+        );
+        assert_eq!(
+            PackageChangePolicy::Cell.impacted_scope(&package),
+            Some((root(), RootImpactKind::PackageFile))
+        );
+        assert_eq!(PackageChangePolicy::Cell.impacted_scope(&source), None);
+    }
+
+    fn project_paths(changes: &Changes) -> Vec<&str> {
+        changes.project_paths().map(|x| x.as_str()).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("fbcode/real")).unwrap();
+        std::fs::write(root.path().join("fbcode/real/src.rs"), "").unwrap();
+        std::os::unix::fs::symlink("real", root.path().join("fbcode/link")).unwrap();
+        let changes = Changes::new(
+            &CellInfo::testing(),
+            vec![
+                Status::Modified(ProjectRelativePath::new("fbcode/link/src.rs")),
+                Status::Removed(ProjectRelativePath::new("fbcode/link/gone.rs")),
+                Status::Modified(ProjectRelativePath::new("fbcode/real/src.rs")),
+            ],
+        )
+        .unwrap();
+        let changes = changes
+            .resolve_symlinks(&CellInfo::testing(), root.path())
+            .unwrap();
+        assert_eq!(
+            project_paths(&changes),
+            [
+                "fbcode/link/src.rs",
+                "fbcode/link/gone.rs",
+                "fbcode/real/src.rs",
+                "fbcode/real/gone.rs"
+            ]
+        );
+        assert!(changes.contains_cell_path(&CellPath::new("fbcode//real/gone.rs")));
+    }
+
+    #[test]
+    fn test_fold_case() {
+        use crate::buck::targets::BuckTarget;
+        use crate::buck::targets::TargetsEntry;
+
+        let target = |package: &str, inputs: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                inputs: inputs.iter().map(|x| CellPath::new(x)).collect(),
+                ..BuckTarget::testing("x", package, "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("fbcode//Foo", &["fbcode//Foo/Src.rs"]),
+            target("fbcode//bar", &["fbcode//bar/readme", "fbcode//bar/README"]),
+        ]);
+        let changes = Changes::new(
+            &CellInfo::testing(),
+            vec![
+                Status::Modified(ProjectRelativePath::new("fbcode/foo/src.rs")),
+                Status::Added(ProjectRelativePath::new("fbcode/foo/new.rs")),
+                Status::Modified(ProjectRelativePath::new("fbcode/bar/Readme")),
+                Status::Modified(ProjectRelativePath::new("fbcode/baz/other.rs")),
+            ],
+        )
+        .unwrap();
+        let changes = changes.fold_case(&CellInfo::testing(), &targets).unwrap();
+        assert_eq!(
+            project_paths(&changes),
+            [
+                "fbcode/Foo/Src.rs",
+                "fbcode/Foo/new.rs",
+                "fbcode/bar/Readme",
+                "fbcode/baz/other.rs"
+            ]
+        );
+        assert!(changes.contains_cell_path(&CellPath::new("fbcode//Foo/Src.rs")));
+    }
 }
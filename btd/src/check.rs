@@ -23,16 +23,26 @@ use crate::buck::types::Package;
 use crate::buck::types::TargetLabel;
 use crate::buck::types::TargetPattern;
 use crate::changes::Changes;
+use crate::diff::BrokenPackagePolicy;
 use crate::diff::ImpactReason;
+use crate::package_error::ErrorCategory;
 
 #[derive(Debug, Error, Serialize)]
 pub enum ValidationError {
-    #[error("Package `{package}` failed with error produced by Buck2:\n{error}")]
-    PackageFailed { package: Package, error: String },
+    #[error("Package `{package}` failed with {category} error produced by Buck2:\n{error}")]
+    PackageFailed {
+        package: Package,
+        category: ErrorCategory,
+        error: String,
+    },
     #[error(
-        "Package `{package}` failed with error produced by Buck2 (it also failed in the base revision, so perhaps rebase):\n{error}"
+        "Package `{package}` failed with {category} error produced by Buck2 (it also failed in the base revision, so perhaps rebase):\n{error}"
     )]
-    PreexistingPackageFailed { package: Package, error: String },
+    PreexistingPackageFailed {
+        package: Package,
+        category: ErrorCategory,
+        error: String,
+    },
     #[error("Target `{deleted}` was deleted but is referenced by `{referenced_by}`")]
     TargetDeleted {
         deleted: TargetLabel,
@@ -47,6 +57,20 @@ pub enum ValidationError {
     },
 }
 
+/// Whether `err` is a package error of a category in `tolerated`, warning about it if so.
+fn is_tolerated(err: &ValidationError, tolerated: &[ErrorCategory]) -> bool {
+    match err {
+        ValidationError::PackageFailed { category, .. }
+        | ValidationError::PreexistingPackageFailed { category, .. }
+            if tolerated.contains(category) =>
+        {
+            warn!("Tolerating {category} error: {err}");
+            true
+        }
+        _ => false,
+    }
+}
+
 fn in_universe(universe: &[TargetPattern], dep: &TargetLabel) -> bool {
     universe.iter().any(|p| p.matches(dep))
 }
@@ -60,6 +84,7 @@ pub fn dump_all_errors(graph: &Targets, universe: &[TargetPattern]) -> Vec<Valid
         .errors()
         .map(|err| ValidationError::PackageFailed {
             package: err.package.clone(),
+            category: ErrorCategory::classify(&err.error),
             error: err.error.clone(),
         })
         .collect();
@@ -86,6 +111,20 @@ pub fn dump_all_errors(graph: &Targets, universe: &[TargetPattern]) -> Vec<Valid
 /// 2. The errors are in a package that you changed, because that will probably stop
 ///    accurate tests being run for your code.
 pub fn check_errors(base: &Targets, diff: &Targets, changes: &Changes) -> Vec<ValidationError> {
+    check_errors_with(base, diff, changes, BrokenPackagePolicy::Fail, &[])
+}
+
+/// Like [`check_errors`], but unless `policy` is [`BrokenPackagePolicy::Fail`], only warn
+/// about packages which fail after the change but not before it, since
+/// [`immediate_target_changes_with`](crate::diff::immediate_target_changes_with)
+/// accounts for their targets. Errors of a category in `tolerated` are only warned about.
+pub fn check_errors_with(
+    base: &Targets,
+    diff: &Targets,
+    changes: &Changes,
+    policy: BrokenPackagePolicy,
+    tolerated: &[ErrorCategory],
+) -> Vec<ValidationError> {
     let mut diff_errors = HashMap::new();
     let mut errors_tree = PackageResolver::new();
     for err in diff.errors() {
@@ -110,9 +149,16 @@ pub fn check_errors(base: &Targets, diff: &Targets, changes: &Changes) -> Vec<Va
         .iter()
         .map(|(package, error)| ValidationError::PackageFailed {
             package: (*package).clone(),
+            category: ErrorCategory::classify(error),
             error: (*error).clone(),
         })
+        .filter(|err| !is_tolerated(err, tolerated))
         .collect();
+    if policy != BrokenPackagePolicy::Fail {
+        for err in res.drain(..) {
+            warn!("Treating targets as {policy:?}: {err}");
+        }
+    }
 
     // If there are errors which you caused, and also preexisting errors that happen to impact you
     // then the first are ones you can directly fix, the second are more of a pain and hopefully will
@@ -124,11 +170,16 @@ pub fn check_errors(base: &Targets, diff: &Targets, changes: &Changes) -> Vec<Va
     let mut bad_packages = HashSet::with_hasher(BuildNoHash::default());
     for path in changes.cell_paths() {
         if let Some((package, err)) = errors_tree.get(&path.as_package()).pop() {
-            if bad_packages.insert(package) {
-                res.push(ValidationError::PreexistingPackageFailed {
+            // Any newly broken packages left are tolerated by the policy
+            if !diff_errors.contains_key(package) && bad_packages.insert(package) {
+                let err = ValidationError::PreexistingPackageFailed {
                     package: (*package).clone(),
+                    category: ErrorCategory::classify(err),
                     error: (*err).clone(),
-                })
+                };
+                if !is_tolerated(&err, tolerated) {
+                    res.push(err);
+                }
             }
         }
     }
@@ -202,6 +253,7 @@ mod tests {
     use td_util::prelude::*;
 
     use super::*;
+    use crate::buck::arena::Deps;
     use crate::buck::targets::BuckError;
     use crate::buck::targets::BuckTarget;
     use crate::buck::targets::TargetsEntry;
@@ -244,6 +296,71 @@ mod tests {
         // This one is debatable, the error changed between base and diff, but is in the same package.
         // Because error messages might be non-deterministic we should keep it.
         assert_eq!(errs(&[err_bar1], &[err_bar0]).len(), 0);
+
+        // Unless the targets of newly broken packages are accounted for
+        let targets = |xs: &[&TargetsEntry]| Targets::new(xs.iter().copied().cloned().collect());
+        for policy in [BrokenPackagePolicy::Removed, BrokenPackagePolicy::Changed] {
+            let errs = check_errors_with(
+                &targets(&[]),
+                &targets(&[err_bar0, err_baz]),
+                &Changes::testing(&[Status::Modified(CellPath::new("foo//baz/file.txt"))]),
+                policy,
+                &[],
+            );
+            assert_eq!(errs.len(), 0);
+        }
+    }
+
+    #[test]
+    fn test_check_errors_tolerated() {
+        let error = |package: &str, error: &str| {
+            TargetsEntry::Error(BuckError {
+                package: Package::new(package),
+                error: error.to_owned(),
+            })
+        };
+        let diff = Targets::new(vec![
+            error("foo//bar", "Parse error: unexpected new line"),
+            error("foo//baz", "Permission denied (os error 13)"),
+        ]);
+        let errs = |tolerated: &[ErrorCategory]| {
+            check_errors_with(
+                &Targets::new(Vec::new()),
+                &diff,
+                &Changes::default(),
+                BrokenPackagePolicy::Fail,
+                tolerated,
+            )
+            .map(|x| match x {
+                ValidationError::PackageFailed {
+                    package, category, ..
+                } => (package.to_string(), *category),
+                _ => panic!("Unexpected error: {x}"),
+            })
+        };
+
+        let mut all = errs(&[]);
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            all,
+            vec![
+                ("foo//bar".to_owned(), ErrorCategory::Parse),
+                ("foo//baz".to_owned(), ErrorCategory::Io)
+            ]
+        );
+        assert_eq!(
+            errs(&[ErrorCategory::Io]),
+            vec![("foo//bar".to_owned(), ErrorCategory::Parse)]
+        );
+        assert!(errs(&[ErrorCategory::Io, ErrorCategory::Parse]).is_empty());
+
+        // Including preexisting errors in changed packages
+        let changes = Changes::testing(&[Status::Modified(CellPath::new("foo//baz/file.txt"))]);
+        let check = |tolerated: &[ErrorCategory]| {
+            check_errors_with(&diff, &diff, &changes, BrokenPackagePolicy::Fail, tolerated).len()
+        };
+        assert_eq!(check(&[]), 1);
+        assert_eq!(check(&[ErrorCategory::Io]), 0);
     }
 
     #[test]
@@ -364,6 +481,7 @@ mod tests {
                     ImpactReason {
                         affected_dep: "".to_owned(),
                         root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                        via_hint: false,
                     },
                 )],
                 &[TargetPattern::new("foo//...")],
@@ -389,6 +507,7 @@ mod tests {
                     ImpactReason {
                         affected_dep: "".to_owned(),
                         root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                        via_hint: false,
                     }
                 )],
                 &[TargetPattern::new("foo//...")],
@@ -414,6 +533,7 @@ mod tests {
                     ImpactReason {
                         affected_dep: "".to_owned(),
                         root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                        via_hint: false,
                     }
                 )],
                 &[TargetPattern::new("foo//...")],
@@ -438,6 +558,7 @@ mod tests {
                     ImpactReason {
                         affected_dep: "".to_owned(),
                         root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                        via_hint: false,
                     }
                 )],
                 &[TargetPattern::new("foo//...")],
@@ -462,6 +583,7 @@ mod tests {
                     ImpactReason {
                         affected_dep: "".to_owned(),
                         root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                        via_hint: false,
                     }
                 )],
                 &[TargetPattern::new("foo//...")],
@@ -488,18 +610,18 @@ mod tests {
         });
         let good0 = TargetsEntry::Target(BuckTarget::testing("target0", "foo//good", "rule"));
         let good1 = TargetsEntry::Target(BuckTarget {
-            deps: Box::new([Package::new("foo//good").join(&TargetName::new("target0"))]),
+            deps: Deps::from([Package::new("foo//good").join(&TargetName::new("target0"))]),
             ..BuckTarget::testing("target1", "foo//good", "rule")
         });
         let dangling0 = TargetsEntry::Target(BuckTarget {
-            deps: Box::new([
+            deps: Deps::from([
                 Package::new("foo//good").join(&TargetName::new("target0")),
                 Package::new("foo//good").join(&TargetName::new("missing")),
             ]),
             ..BuckTarget::testing("target-with-dangling", "foo//good", "rule")
         });
         let dangling1 = TargetsEntry::Target(BuckTarget {
-            deps: Box::new([Package::new("outside//bar").join(&TargetName::new("target0"))]),
+            deps: Deps::from([Package::new("outside//bar").join(&TargetName::new("target0"))]),
             ..BuckTarget::testing("other-with-dangling", "foo//good", "rule")
         });
         let targets = vec![error0, error1, error2, good0, good1, dangling0, dangling1];
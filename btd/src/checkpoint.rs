@@ -0,0 +1,191 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Save the progress of a run after each phase, so that for an enormous change, e.g. a
+//! prelude refactor, a rerun after a failure or timeout resumes from the last phase
+//! completed rather than starting over.
+//!
+//! Each run has a directory named by the hash of its inputs, holding a snapshot of the
+//! base and diff targets once read, and the impacted targets once traversed, in the same
+//! format as `--cache-dir`. Nothing is saved part way through a phase, so an interrupted
+//! traversal starts again from the immediate changes. The directory is removed when the
+//! run completes.
+
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context as _;
+use tracing::info;
+
+use crate::buck::targets::Targets;
+use crate::cache::ImpactCache;
+use crate::snapshot;
+use crate::snapshot::Fnv;
+use crate::snapshot::Header;
+
+/// A phase whose result is saved.
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Base,
+    Diff,
+}
+
+impl Phase {
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Base => "base.snapshot",
+            Self::Diff => "diff.snapshot",
+        }
+    }
+
+    fn header_file_name(self) -> &'static str {
+        match self {
+            Self::Base => "base.header.json",
+            Self::Diff => "diff.header.json",
+        }
+    }
+}
+
+pub struct Checkpoint {
+    dir: PathBuf,
+}
+
+impl Checkpoint {
+    /// The checkpoint in `root` for a run with these `args`, `changes` and input `files`.
+    /// The files are identified by their size and modification time, so regenerating one,
+    /// e.g. the `--base` targets, starts the run over.
+    pub fn new(
+        root: &Path,
+        args: &[String],
+        changes: impl Hash,
+        files: &[&Path],
+    ) -> anyhow::Result<Self> {
+        // Stable across Rust versions, so a rebuilt BTD still finds the checkpoint
+        let mut hasher = Fnv::default();
+        args.hash(&mut hasher);
+        changes.hash(&mut hasher);
+        for file in files {
+            // A missing file, e.g. `-` for stdin, can't be identified, so is only named
+            if let Ok(metadata) = fs::metadata(file) {
+                metadata.len().hash(&mut hasher);
+                let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+                modified.hash(&mut hasher);
+            }
+            file.hash(&mut hasher);
+        }
+        let dir = root.join(format!("{:016x}", hasher.finish()));
+        fs::create_dir_all(&dir).with_context(|| format!("When creating `{}`", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// The targets saved after `phase`, if an earlier run with the same inputs completed it,
+    /// with the header of the snapshot they were read from, if any.
+    pub fn load(&self, phase: Phase) -> anyhow::Result<Option<(Targets, Option<Header>)>> {
+        let file = self.dir.join(phase.file_name());
+        if !file.exists() {
+            return Ok(None);
+        }
+        info!("Resuming from checkpoint `{}`", file.display());
+        let targets = snapshot::read_file(&file)?;
+        let header_file = self.dir.join(phase.header_file_name());
+        let header = if header_file.exists() {
+            let data = fs::read(&header_file)
+                .with_context(|| format!("When reading `{}`", header_file.display()))?;
+            Some(serde_json::from_slice(&data)?)
+        } else {
+            None
+        };
+        Ok(Some((targets, header)))
+    }
+
+    /// Save the targets at the end of `phase`, unless they were loaded from the checkpoint,
+    /// with the `header` of the snapshot they were read from, if any.
+    pub fn save(
+        &self,
+        phase: Phase,
+        targets: &Targets,
+        header: Option<&Header>,
+    ) -> anyhow::Result<()> {
+        let file = self.dir.join(phase.file_name());
+        if file.exists() {
+            return Ok(());
+        }
+        // Written first, so it is there whenever the targets are
+        if let Some(header) = header {
+            let header_file = self.dir.join(phase.header_file_name());
+            fs::write(&header_file, serde_json::to_vec(header)?)
+                .with_context(|| format!("When writing `{}`", header_file.display()))?;
+        }
+        // Write then rename, so being killed part way doesn't leave a corrupt snapshot
+        let tmp = file.with_extension("tmp");
+        snapshot::write_file(targets, &tmp)?;
+        fs::rename(&tmp, &file).with_context(|| format!("When writing `{}`", file.display()))
+    }
+
+    /// Where the impacted targets are saved, once traversed.
+    pub fn impact_cache(&self) -> ImpactCache {
+        ImpactCache::new(self.dir.join("impact"), Duration::MAX, u64::MAX)
+    }
+
+    /// Remove the checkpoint, once the run has completed.
+    pub fn finish(self) -> anyhow::Result<()> {
+        fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("When removing checkpoint `{}`", self.dir.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::TargetsEntry;
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = TempDir::new().unwrap();
+        let args = ["--base".to_owned(), "base.jsonl".to_owned()];
+        let checkpoint = Checkpoint::new(dir.path(), &args, ["foo/bar.rs"], &[]).unwrap();
+        assert!(checkpoint.load(Phase::Base).unwrap().is_none());
+
+        let targets = Targets::new(vec![TargetsEntry::Target(BuckTarget::testing(
+            "lib",
+            "foo//bar",
+            "prelude//rules.bzl:cxx_library",
+        ))]);
+        let header = Header {
+            revision: Some("0123456789abcdef0123456789abcdef01234567".to_owned()),
+            ..Header::default()
+        };
+        checkpoint
+            .save(Phase::Base, &targets, Some(&header))
+            .unwrap();
+
+        // A rerun with the same inputs resumes, but not with different ones
+        let rerun = Checkpoint::new(dir.path(), &args, ["foo/bar.rs"], &[]).unwrap();
+        let (resumed, resumed_header) = rerun.load(Phase::Base).unwrap().unwrap();
+        assert_eq!(resumed_header, Some(header));
+        assert_eq!(
+            resumed.entries().collect::<Vec<_>>(),
+            targets.entries().collect::<Vec<_>>()
+        );
+        assert!(rerun.load(Phase::Diff).unwrap().is_none());
+        let other = Checkpoint::new(dir.path(), &args, ["foo/baz.rs"], &[]).unwrap();
+        assert!(other.load(Phase::Base).unwrap().is_none());
+
+        rerun.finish().unwrap();
+        assert!(checkpoint.load(Phase::Base).unwrap().is_none());
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Classify impacted targets as tests or builds, and tests by type, e.g. `unit`
+//! or `e2e`, so CI can schedule each kind of test separately.
+//!
+//! A target is a test if its rule type ends in `test` (e.g. `cxx_test`) or is listed
+//! in `test_rule_types`. Its test type comes from the first of its labels found in
+//! `labels`, then its rule type in `rule_types`, falling back to `default_test_type`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::buck::labels::Labels;
+use crate::buck::targets::BuckTarget;
+
+/// The mapping used to classify targets, read from JSON. Missing fields are
+/// left at their defaults.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassifyConfig {
+    /// Short rule types which are tests, besides those ending in `test`.
+    pub test_rule_types: Vec<String>,
+    /// The test type of tests with each label.
+    pub labels: HashMap<String, String>,
+    /// The test type of tests with each short rule type, e.g. `python_test`.
+    pub rule_types: HashMap<String, String>,
+    /// The test type of tests matching neither `labels` nor `rule_types`.
+    pub default_test_type: Option<String>,
+}
+
+impl Default for ClassifyConfig {
+    fn default() -> Self {
+        Self {
+            test_rule_types: Vec::new(),
+            labels: ["unit", "integration", "e2e"]
+                .map(|x| (x.to_owned(), x.to_owned()))
+                .into_iter()
+                .collect(),
+            rule_types: HashMap::new(),
+            default_test_type: Some("unit".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetClass {
+    Build,
+    Test,
+}
+
+/// The classification of a target, flattened into its output record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Class {
+    class: TargetClass,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    test_type: Option<String>,
+}
+
+impl Class {
+    pub fn is_test(&self) -> bool {
+        self.class == TargetClass::Test
+    }
+}
+
+/// Classifies targets, or does nothing if it has no config.
+#[derive(Debug, Default)]
+pub struct Classifier {
+    config: Option<ClassifyConfig>,
+}
+
+impl Classifier {
+    pub fn new(config: ClassifyConfig) -> Self {
+        Self {
+            config: Some(config),
+        }
+    }
+
+    pub fn read_file(file: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading classify config `{}`", file.display()))?;
+        let config = serde_json::from_str(&data)
+            .with_context(|| format!("When parsing classify config `{}`", file.display()))?;
+        Ok(Self::new(config))
+    }
+
+    /// Classify a target with the short rule type `rule_type`, or `None` if there is no config.
+    pub fn classify(&self, rule_type: &str, labels: &Labels) -> Option<Class> {
+        let config = self.config.as_ref()?;
+        if !rule_type.ends_with("test") && !config.test_rule_types.iter().any(|x| x == rule_type) {
+            return Some(Class {
+                class: TargetClass::Build,
+                test_type: None,
+            });
+        }
+        let test_type = labels
+            .iter()
+            .find_map(|x| config.labels.get(x.as_str()))
+            .or_else(|| config.rule_types.get(rule_type))
+            .or(config.default_test_type.as_ref());
+        Some(Class {
+            class: TargetClass::Test,
+            test_type: test_type.cloned(),
+        })
+    }
+
+    /// Is `x` a test, by the config if there is one, or else if its rule type ends in `test`.
+    pub fn is_test(&self, x: &BuckTarget) -> bool {
+        match self.classify(x.rule_type.short(), &x.labels) {
+            Some(class) => class.is_test(),
+            None => x.rule_type.short().ends_with("test"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let classify = |classifier: &Classifier, rule_type: &str, labels: &[&str]| {
+            serde_json::to_value(classifier.classify(rule_type, &Labels::new(labels))).unwrap()
+        };
+
+        let default = Classifier::new(ClassifyConfig::default());
+        assert_eq!(
+            classify(&default, "cxx_library", &["e2e"]),
+            serde_json::json!({"class": "build"})
+        );
+        assert_eq!(
+            classify(&default, "cxx_test", &[]),
+            serde_json::json!({"class": "test", "test_type": "unit"})
+        );
+        assert_eq!(
+            classify(&default, "python_test", &["slow", "e2e", "unit"]),
+            serde_json::json!({"class": "test", "test_type": "e2e"})
+        );
+
+        let config: ClassifyConfig = serde_json::from_value(serde_json::json!({
+            "test_rule_types": ["sh_binary_check"],
+            "rule_types": {"python_test": "integration"},
+            "labels": {"smoke": "e2e"},
+        }))
+        .unwrap();
+        let custom = Classifier::new(config);
+        assert_eq!(
+            classify(&custom, "python_test", &["unit"]),
+            serde_json::json!({"class": "test", "test_type": "integration"})
+        );
+        assert_eq!(
+            classify(&custom, "python_test", &["smoke"]),
+            serde_json::json!({"class": "test", "test_type": "e2e"})
+        );
+        assert_eq!(
+            classify(&custom, "sh_binary_check", &[]),
+            serde_json::json!({"class": "test", "test_type": "unit"})
+        );
+
+        assert_eq!(
+            classify(&Classifier::default(), "cxx_test", &[]),
+            serde_json::Value::Null
+        );
+    }
+}
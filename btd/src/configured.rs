@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Read configured target graphs, as output by `buck2 cquery`, where a target
+//! appears once for each configuration it is used in.
+//!
+//! Each configuration of a target becomes a separate target, whose name is followed
+//! by the configuration hash, e.g. `foo//bar:baz (0123abcd)`, and dependencies are
+//! renamed to match. Targets are therefore keyed by label and configuration, so a
+//! change that only affects one configuration only impacts that configuration.
+//! Note that `ci_deps` patterns still refer to unconfigured targets, so don't match.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::Context as _;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::targets::TargetsEntry;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetName;
+
+#[derive(Debug, Error)]
+enum ConfiguredError {
+    #[error("Expected a configured label like `foo//bar:baz (cfg#hash)`. Got `{0}`")]
+    NotConfigured(String),
+    #[error("Expected the attributes of `{0}` to be an object")]
+    NotAnObject(String),
+}
+
+/// Split a configured label like `foo//bar:baz (cfg//platform:linux#0123abcd)` into
+/// the label and the configuration hash.
+fn split_configured(label: &str) -> Option<(&str, &str)> {
+    let (label, cfg) = label.strip_suffix(')')?.rsplit_once(" (")?;
+    Some((label, cfg.rsplit_once('#').map_or(cfg, |x| x.1)))
+}
+
+/// Give a configured label the name BTD uses for it, leaving unconfigured labels alone.
+///
+/// ```
+/// use btd::configured::configured_label;
+/// assert_eq!(
+///     configured_label("foo//bar:baz (cfg//platform:linux#0123abcd)").as_str(),
+///     "foo//bar:baz (0123abcd)"
+/// );
+/// assert_eq!(configured_label("foo//bar:baz").as_str(), "foo//bar:baz");
+/// ```
+pub fn configured_label(label: &str) -> TargetLabel {
+    match split_configured(label) {
+        Some((label, hash)) => TargetLabel::new(&format!("{label} ({hash})")),
+        None => TargetLabel::new(label),
+    }
+}
+
+/// Read the JSON output of `buck2 cquery --json --output-all-attributes`, which must
+/// include `buck.inputs`. If there is no `buck.target_hash`, the hash of the attributes is used.
+pub fn read_file(file: &Path) -> anyhow::Result<Targets> {
+    let f = || -> anyhow::Result<Targets> {
+        let entries = serde_json::from_reader(BufReader::new(File::open(file)?))?;
+        from_json(entries)
+    };
+    f().with_context(|| format!("When reading configured targets `{}`", file.display()))
+}
+
+/// Convert the map from configured label to attributes output by `buck2 cquery`.
+pub fn from_json(entries: BTreeMap<String, Value>) -> anyhow::Result<Targets> {
+    let mut res = Vec::with_capacity(entries.len());
+    for (label, mut value) in entries {
+        let Some((_, hash)) = split_configured(&label) else {
+            return Err(ConfiguredError::NotConfigured(label).into());
+        };
+        let Some(attributes) = value.as_object_mut() else {
+            return Err(ConfiguredError::NotAnObject(label).into());
+        };
+        if !attributes.contains_key("buck.target_hash") {
+            let mut hasher = DefaultHasher::new();
+            serde_json::to_string(attributes)?.hash(&mut hasher);
+            attributes.insert(
+                "buck.target_hash".to_owned(),
+                Value::String(format!("{:016x}", hasher.finish())),
+            );
+        }
+        let mut target: BuckTarget =
+            serde_json::from_value(value).with_context(|| format!("When parsing `{label}`"))?;
+        target.name = TargetName::new(&format!("{} ({hash})", target.name.as_str()));
+        target.deps = target
+            .deps
+            .iter()
+            .map(|x| configured_label(x.as_str()))
+            .collect();
+        res.push(TargetsEntry::Target(target));
+    }
+    Ok(Targets::new(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::types::CellPath;
+    use crate::changes::Changes;
+    use crate::diff;
+    use crate::sapling::status::Status;
+
+    fn cquery(hash: &str) -> Targets {
+        let value = serde_json::json!({
+            "foo//bar:lib (cfg//:linux#aaaa)": {
+                "name": "lib",
+                "buck.package": "foo//bar",
+                "buck.type": "prelude//rules.bzl:cxx_library",
+                "buck.deps": [],
+                "buck.inputs": ["foo//bar/linux.cpp"],
+            },
+            "foo//bar:lib (cfg//:mac#bbbb)": {
+                "name": "lib",
+                "buck.package": "foo//bar",
+                "buck.type": "prelude//rules.bzl:cxx_library",
+                "buck.deps": [],
+                "buck.inputs": ["foo//bar/mac.cpp"],
+                "buck.target_hash": hash,
+            },
+            "foo//bar:bin (cfg//:mac#bbbb)": {
+                "name": "bin",
+                "buck.package": "foo//bar",
+                "buck.type": "prelude//rules.bzl:cxx_binary",
+                "buck.deps": ["foo//bar:lib (cfg//:mac#bbbb)"],
+                "buck.inputs": [],
+            },
+        });
+        from_json(serde_json::from_value(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_configured_impact() {
+        let base = cquery("1");
+        let impacted = |diff: &Targets, changes: &Changes| {
+            let immediate = diff::immediate_target_changes(&base, diff, changes, false);
+            diff::recursive_target_changes(diff, &immediate, None, |_| true)
+                .iter()
+                .flatten()
+                .map(|(x, _)| x.label().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Only the mac configuration uses `mac.cpp`.
+        let changes = Changes::testing(&[Status::Modified(CellPath::new("foo//bar/mac.cpp"))]);
+        assert_eq!(
+            impacted(&base, &changes),
+            vec!["foo//bar:lib (bbbb)", "foo//bar:bin (bbbb)"]
+        );
+        let changes = Changes::testing(&[Status::Modified(CellPath::new("foo//bar/linux.cpp"))]);
+        assert_eq!(impacted(&base, &changes), vec!["foo//bar:lib (aaaa)"]);
+        assert_eq!(
+            impacted(&cquery("2"), &Changes::default()),
+            vec!["foo//bar:lib (bbbb)", "foo//bar:bin (bbbb)"]
+        );
+    }
+
+    #[test]
+    fn test_not_configured() {
+        let value = serde_json::json!({"foo//bar:lib": {}});
+        assert!(from_json(serde_json::from_value(value).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Find cycles in the dependency graph. Buck2 never produces them, but malformed
+//! or hand-edited targets files might, and then the impact is hard to reason about.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Display;
+
+use serde::Serialize;
+use thiserror::Error;
+use tracing::error;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+
+/// Targets which each depend on the next, with the last depending on the first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Cycle(pub Vec<TargetLabel>);
+
+impl Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for x in &self.0 {
+            write!(f, "{x} -> ")?;
+        }
+        write!(f, "{}", self.0[0])
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CycleError {
+    #[error(
+        "Found {0} dependency cycles, e.g. `{1}`. Pass `--allow-cycles` to impact every target in a cycle once"
+    )]
+    Cycles(usize, Cycle),
+}
+
+/// Fail if there are any cycles in `targets`, logging them all.
+pub fn check_cycles(targets: &Targets) -> Result<(), CycleError> {
+    let cycles = find_cycles(targets);
+    for x in &cycles {
+        error!("Dependency cycle: {x}");
+    }
+    match cycles.first() {
+        None => Ok(()),
+        Some(first) => Err(CycleError::Cycles(cycles.len(), first.clone())),
+    }
+}
+
+/// Find a cycle in each strongly connected component of the `deps` graph, sorted.
+/// Each cycle is a shortest one through the smallest target in the component,
+/// starting from that target, so the result doesn't depend on the order of `targets`.
+pub fn find_cycles(targets: &Targets) -> Vec<Cycle> {
+    let nodes: Vec<&BuckTarget> = targets.targets().collect();
+    let index: HashMap<TargetLabel, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.label(), i))
+        .collect();
+    let edge = |v: usize, i: usize| -> Option<Option<usize>> {
+        nodes[v].deps.get(i).map(|x| index.get(x).copied())
+    };
+
+    // Tarjan's algorithm, iteratively, to cope with deep graphs.
+    const UNVISITED: usize = usize::MAX;
+    let mut order = vec![UNVISITED; nodes.len()];
+    let mut low = vec![0; nodes.len()];
+    let mut on_stack = vec![false; nodes.len()];
+    let mut stack = Vec::new();
+    let mut next = 0;
+    let mut res = Vec::new();
+
+    for root in 0..nodes.len() {
+        if order[root] != UNVISITED {
+            continue;
+        }
+        // The node being visited, and the index of the next edge to follow.
+        let mut calls = vec![(root, 0)];
+        order[root] = next;
+        low[root] = next;
+        next += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some(&(v, i)) = calls.last() {
+            match edge(v, i) {
+                Some(w) => {
+                    calls.last_mut().unwrap().1 += 1;
+                    let Some(w) = w else { continue };
+                    if order[w] == UNVISITED {
+                        order[w] = next;
+                        low[w] = next;
+                        next += 1;
+                        stack.push(w);
+                        on_stack[w] = true;
+                        calls.push((w, 0));
+                    } else if on_stack[w] {
+                        low[v] = low[v].min(order[w]);
+                    }
+                }
+                None => {
+                    calls.pop();
+                    if let Some(&(u, _)) = calls.last() {
+                        low[u] = low[u].min(low[v]);
+                    }
+                    if low[v] == order[v] {
+                        let mut component = Vec::new();
+                        loop {
+                            let w = stack.pop().unwrap();
+                            on_stack[w] = false;
+                            component.push(w);
+                            if w == v {
+                                break;
+                            }
+                        }
+                        let self_loop = nodes[v].deps.iter().any(|x| index.get(x) == Some(&v));
+                        if component.len() > 1 || self_loop {
+                            res.push(shortest_cycle(&nodes, &index, &component));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    res.sort();
+    res
+}
+
+/// A shortest cycle through the smallest target of a strongly connected `component`.
+fn shortest_cycle(
+    nodes: &[&BuckTarget],
+    index: &HashMap<TargetLabel, usize>,
+    component: &[usize],
+) -> Cycle {
+    let members: HashSet<usize> = component.iter().copied().collect();
+    let start = *component
+        .iter()
+        .min_by_key(|x| nodes[**x].label_key())
+        .unwrap();
+
+    // Breadth first search back to `start`, recording how we reached each target.
+    let mut parent: HashMap<usize, usize> = HashMap::new();
+    let mut todo = VecDeque::from([start]);
+    while let Some(v) = todo.pop_front() {
+        let mut deps = nodes[v]
+            .deps
+            .iter()
+            .filter_map(|x| index.get(x).copied())
+            .filter(|x| members.contains(x))
+            .collect::<Vec<_>>();
+        deps.sort_by_key(|x| nodes[*x].label_key());
+        for w in deps {
+            if w == start {
+                let mut path = vec![v];
+                while let Some(&p) = parent.get(path.last().unwrap()) {
+                    path.push(p);
+                }
+                path.reverse();
+                return Cycle(path.into_iter().map(|x| nodes[x].label()).collect());
+            }
+            if let Entry::Vacant(e) = parent.entry(w) {
+                e.insert(v);
+                todo.push_back(w);
+            }
+        }
+    }
+    unreachable!("every target in a strongly connected component is on a cycle")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+
+    fn targets(edges: &[(&str, &[&str])]) -> Targets {
+        Targets::new(
+            edges
+                .iter()
+                .map(|(name, deps)| {
+                    TargetsEntry::Target(BuckTarget {
+                        deps: deps
+                            .iter()
+                            .map(|x| TargetLabel::new(&format!("foo//:{x}")))
+                            .collect(),
+                        ..BuckTarget::testing(name, "foo//", "prelude//rules.bzl:cxx_library")
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        assert_eq!(
+            find_cycles(&targets(&[("a", &["b"]), ("b", &["c"]), ("c", &[])])),
+            Vec::new()
+        );
+
+        let res = find_cycles(&targets(&[
+            ("d", &["b"]),
+            ("b", &["c", "missing"]),
+            ("c", &["d", "b"]),
+            ("self", &["self"]),
+            ("other", &["d"]),
+        ]));
+        assert_eq!(
+            res.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+            vec![
+                "foo//:b -> foo//:c -> foo//:b",
+                "foo//:self -> foo//:self",
+            ]
+        );
+        assert!(check_cycles(&targets(&[("a", &["a"])])).is_err());
+        assert!(check_cycles(&targets(&[("a", &[])])).is_ok());
+    }
+}
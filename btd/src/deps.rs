@@ -0,0 +1,207 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Walk the dependencies of targets in a targets file or snapshot, the forward
+//! counterpart to `btd rdeps`, without needing Buck.
+
+use std::collections::HashSet;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use td_util::json;
+use thiserror::Error;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetPattern;
+
+/// Print the dependencies of the targets matching the patterns, nearest first,
+/// one per line. Only the direct dependencies, unless `--transitive` or `--depth`.
+#[derive(clap::Args, Debug)]
+pub struct DepsArgs {
+    /// Targets file to query, either the JSON output of `buck2 targets` or a snapshot.
+    #[arg(long, value_name = "FILE")]
+    targets: PathBuf,
+
+    /// Patterns for the targets whose dependencies to find, e.g. `foo//bar:baz`.
+    #[arg(value_name = "TARGET_PATTERN", required = true)]
+    patterns: Vec<String>,
+
+    /// Follow the dependencies all the way down.
+    #[arg(long, conflicts_with = "depth")]
+    transitive: bool,
+
+    /// Go this many levels, e.g. `2` for the dependencies of the direct dependencies.
+    #[arg(long)]
+    depth: Option<usize>,
+
+    /// Only traverse and report targets matching these patterns, as with `btd --universe`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    universe: Vec<String>,
+
+    /// Don't report targets matching these patterns, as with `btd --exclude`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    exclude: Vec<String>,
+
+    /// Print each target as a JSON object with its `depth`, and whether it is `missing`.
+    #[arg(long)]
+    json_lines: bool,
+}
+
+#[derive(Debug, Error)]
+enum DepsError {
+    #[error("No targets match `{0}`")]
+    NoMatch(String),
+}
+
+/// A dependency of the targets queried.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Dep {
+    pub target: TargetLabel,
+    /// `1` for the direct dependencies.
+    pub depth: usize,
+    /// The dependency isn't in the targets, so its own dependencies are unknown.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub missing: bool,
+}
+
+/// The targets `roots` transitively depend on, nearest first, going up to `depth`
+/// levels. The `roots` themselves are not included, nor are the dependencies
+/// for which `keep` returns `false`, which are not traversed either.
+pub fn transitive_deps(
+    targets: &Targets,
+    roots: &[&BuckTarget],
+    depth: Option<usize>,
+    keep: impl Fn(&TargetLabel) -> bool,
+) -> Vec<Dep> {
+    let by_label = targets.targets_by_label();
+    let mut done = roots.iter().map(|x| x.label()).collect::<HashSet<_>>();
+    let mut todo = roots.to_vec();
+    let mut res = Vec::new();
+    let mut level = 0;
+    while !todo.is_empty() && depth.map_or(true, |depth| level < depth) {
+        level += 1;
+        let mut next = todo
+            .iter()
+            .flat_map(|x| x.deps.iter())
+            .filter(|x| keep(x) && done.insert((*x).clone()))
+            .cloned()
+            .collect::<Vec<_>>();
+        next.sort_by_cached_key(|x| x.key());
+        todo.clear();
+        for target in next {
+            let found = by_label.get(&target);
+            todo.extend(found);
+            res.push(Dep {
+                target,
+                depth: level,
+                missing: found.is_none(),
+            });
+        }
+    }
+    res
+}
+
+pub fn main(args: DepsArgs) -> anyhow::Result<()> {
+    let parse = |xs: &[String]| {
+        xs.iter()
+            .map(|x| TargetPattern::new(x).parse())
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let patterns = parse(&args.patterns)?;
+    let universe = parse(&args.universe)?;
+    let exclude = parse(&args.exclude)?;
+
+    let targets = Targets::from_file(&args.targets)?.restrict(&universe);
+    let roots = targets
+        .targets()
+        .filter(|x| patterns.iter().any(|p| p.matches(&x.label())))
+        .collect::<Vec<_>>();
+    if roots.is_empty() {
+        return Err(DepsError::NoMatch(args.patterns.join(" ")).into());
+    }
+    let depth = if args.transitive {
+        None
+    } else {
+        Some(args.depth.unwrap_or(1))
+    };
+    let in_universe =
+        |x: &TargetLabel| universe.is_empty() || universe.iter().any(|p| p.matches(x));
+    let mut res = transitive_deps(&targets, &roots, depth, in_universe);
+    res.retain(|x| !exclude.iter().any(|p| p.matches(&x.target)));
+
+    let mut out = BufWriter::new(stdout().lock());
+    if args.json_lines {
+        json::write_json_lines(out, &res)?;
+    } else {
+        for x in &res {
+            writeln!(out, "{}", x.target)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use td_util::prelude::*;
+
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+
+    #[test]
+    fn test_transitive_deps() {
+        let target = |name: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &["foo//bar:missing"]),
+            target("util", &[]),
+            target("bin", &["foo//bar:util", "foo//bar:lib"]),
+            target("test", &["foo//bar:bin", "foo//bar:util"]),
+        ]);
+        let by_label = targets.targets_by_label();
+        let query = |roots: &[&str], depth, keep: &dyn Fn(&TargetLabel) -> bool| {
+            let roots = roots.map(|x| by_label[&TargetLabel::new(x)]);
+            transitive_deps(&targets, &roots, depth, keep)
+                .map(|x| (x.target.to_string(), x.depth, x.missing))
+        };
+        let dep = |x: &str, depth| (format!("foo//bar:{x}"), depth, x == "missing");
+        let all = |_: &TargetLabel| true;
+
+        assert_eq!(
+            query(&["foo//bar:test"], None, &all),
+            vec![
+                dep("bin", 1),
+                dep("util", 1),
+                dep("lib", 2),
+                dep("missing", 3)
+            ]
+        );
+        assert_eq!(
+            query(&["foo//bar:test"], Some(1), &all),
+            vec![dep("bin", 1), dep("util", 1)]
+        );
+        assert_eq!(
+            query(&["foo//bar:test", "foo//bar:lib"], None, &all),
+            vec![dep("bin", 1), dep("missing", 1), dep("util", 1)]
+        );
+        assert_eq!(
+            query(&["foo//bar:test"], None, &|x| x.as_str() != "foo//bar:bin"),
+            vec![dep("util", 1)]
+        );
+    }
+}
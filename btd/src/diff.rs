@@ -12,21 +12,27 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::mem;
 
-use tracing::warn;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use td_util::prelude::*;
 
 use crate::buck::config::should_exclude_bzl_file_from_transitive_impact_tracing;
 use crate::buck::glob::GlobSpec;
-use crate::buck::target_map::TargetMap;
+use crate::buck::targets::Attributes;
 use crate::buck::targets::BuckTarget;
 use crate::buck::targets::Targets;
 use crate::buck::types::CellPath;
 use crate::buck::types::Glob;
 use crate::buck::types::Package;
+use crate::buck::types::ParsedTargetPattern;
 use crate::buck::types::RuleType;
-use crate::buck::types::TargetLabel;
 use crate::buck::types::TargetLabelKeyRef;
-use crate::buck::types::TargetName;
+use crate::change_policy::PolicyImpact;
+use crate::changes::Attribution;
 use crate::changes::Changes;
+use crate::changes::PackageChangePolicy;
+use crate::interrupt;
+use crate::rdeps::Rdeps;
 
 /// Given the state, which .bzl files have changed, either directly or by transitive dependencies
 fn changed_bzl_files<'a>(
@@ -102,6 +108,27 @@ impl<'a> GraphImpact<'a> {
         }
     }
 
+    /// Add `targets` as changed recursively, unless they already are. Those which only
+    /// changed non-recursively now change recursively instead.
+    pub fn add_recursive(&mut self, targets: Vec<(&'a BuckTarget, ImpactReason)>) {
+        let mut seen = self
+            .recursive
+            .iter()
+            .map(|(x, _)| x.label())
+            .collect::<HashSet<_>>();
+        let added = targets
+            .into_iter()
+            .filter(|(x, _)| seen.insert(x.label()))
+            .collect::<Vec<_>>();
+        if added.is_empty() {
+            return;
+        }
+        let labels = added.iter().map(|(x, _)| x.label()).collect::<HashSet<_>>();
+        self.non_recursive
+            .retain(|(x, _)| !labels.contains(&x.label()));
+        self.recursive.extend(added);
+    }
+
     pub fn len(&self) -> usize {
         self.recursive.len() + self.non_recursive.len()
     }
@@ -112,6 +139,11 @@ impl<'a> GraphImpact<'a> {
             .chain(self.non_recursive.iter())
             .cloned()
     }
+
+    /// The targets in the base graph missing from the diff graph, sorted by label.
+    pub fn removed(&self) -> impl Iterator<Item = &'a BuckTarget> + '_ {
+        self.removed.iter().map(|(x, _)| *x)
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -123,6 +155,10 @@ pub struct ImpactReason {
     /// The target name of the dependency which actually changed,
     /// and the type of change that we detected in it.
     pub root_cause: (String, RootImpactKind), // root_target_name, reason
+    /// Whether the change only reached this target through a dependency hint, i.e. a
+    /// `ci_hint` target or `ci_deps`, rather than a dependency Buck knows about.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub via_hint: bool,
 }
 
 impl ImpactReason {
@@ -133,6 +169,7 @@ impl ImpactReason {
                 format!("{}:{}", target.package.as_str(), target.name.as_str()),
                 kind,
             ),
+            via_hint: false,
         }
     }
 }
@@ -162,8 +199,191 @@ pub enum RootImpactKind {
     PackageValues,
     /// The target is removed
     Remove,
-    /// When we want to manually rerun the target.
+    /// When we want to manually rerun the target, e.g. it matched `--changed-targets`.
     ManualForRerun,
+    /// A file in the target's package changed, which no target lists as an input.
+    Ownership,
+    /// A `PACKAGE` file applying to the target changed.
+    PackageFile,
+    /// A buckconfig file in the target's cell changed.
+    Buckconfig,
+    /// A changed file impacts the target because of the policy for its category,
+    /// see [`ChangePolicy`](crate::change_policy::ChangePolicy).
+    ChangePolicy,
+    /// The target's package fails to evaluate after the change, but didn't before.
+    BrokenPackage,
+    /// The target's build file loads a changed `.bzl` file, directly or transitively.
+    LoadedBzl,
+    /// A custom impact rule seeded the target, see [`ImpactRule`](crate::impact_rule::ImpactRule).
+    ImpactRule,
+    /// The target was asked about as if it changed, e.g. by a `why` query of the
+    /// WebAssembly bindings.
+    Query,
+}
+
+/// Settings controlling which targets count as immediately changed.
+#[derive(Debug, Clone, Default)]
+pub struct ImmediateOptions {
+    /// Look for prelude rule changes and dirty inputs in response.
+    pub track_prelude_changes: bool,
+    pub attribution: Attribution,
+    pub package_change_policy: PackageChangePolicy,
+    pub attribute_diff: AttributeDiff,
+    /// Treat existing targets matching these patterns as changed, even if nothing
+    /// about them did, to ask what would be impacted if they changed.
+    pub changed_targets: Vec<ParsedTargetPattern>,
+    /// Changed files impacting more than the targets using them.
+    pub change_policy: PolicyImpact,
+    pub broken_package_policy: BrokenPackagePolicy,
+    /// A changed `.bzl` file impacts every target whose build file loads it, directly
+    /// or transitively, not just those whose rule it defines.
+    pub follow_loads: bool,
+}
+
+/// What to do with the targets of a package which evaluated before the change,
+/// but fails to evaluate after it.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokenPackagePolicy {
+    /// Report the error and fail, see [`check_errors_with`](crate::check::check_errors_with).
+    #[default]
+    Fail,
+    /// Treat its targets as removed, impacting whatever depended on them.
+    Removed,
+    /// Treat its targets, as they were before the change, as changed.
+    Changed,
+}
+
+/// Compare targets whose hash changed attribute by attribute, so insignificant edits
+/// (e.g. to `metadata`, or reordering `srcs`) don't count as changes. Only applies
+/// with [`DiffMode::Attributes`], and when both targets carry their
+/// [`Attributes`](crate::buck::targets::Attributes), otherwise the hash decides.
+///
+/// The attributes BTD interprets are named `type`, `oncall`, `deps`, `inputs`,
+/// `labels`, `ci_srcs`, `ci_deps`, `tests`, `toolchain_deps`, `exec_deps` and
+/// `visibility`.
+#[derive(Debug, Clone, Default)]
+pub struct AttributeDiff {
+    /// With [`DiffMode::Hash`], the other settings are ignored.
+    pub mode: DiffMode,
+    /// Attributes whose changes don't matter.
+    pub ignore: Vec<String>,
+    /// List attributes whose order doesn't matter.
+    pub unordered: Vec<String>,
+}
+
+/// How to decide whether a target present in both graphs changed.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffMode {
+    /// Only compare the hashes, never looking at the attributes.
+    #[default]
+    Hash,
+    /// Compare the attributes when both targets carry them, so a target whose hash
+    /// changed without any significant attribute changing, e.g. because of something
+    /// else Buck2 hashes, isn't changed. Otherwise compare the hashes.
+    Attributes,
+}
+
+impl AttributeDiff {
+    fn is_ignored(&self, name: &str) -> bool {
+        self.ignore.iter().any(|x| x == name)
+    }
+
+    fn is_unordered(&self, name: &str) -> bool {
+        self.unordered.iter().any(|x| x == name)
+    }
+
+    /// Whether `old` and `new` differ in a significant attribute, or `None` if
+    /// that can't be determined and the hash should be used instead.
+    pub fn changed(&self, old: &BuckTarget, new: &BuckTarget) -> Option<bool> {
+        if self.mode == DiffMode::Hash || old.attributes.is_empty() || new.attributes.is_empty() {
+            return None;
+        }
+        let same = |name: &str, old: Vec<&str>, new: Vec<&str>| {
+            self.is_ignored(name) || self.same_list(name, old, new)
+        };
+        let same_known = same(
+            "type",
+            vec![old.rule_type.as_str()],
+            vec![new.rule_type.as_str()],
+        ) && same(
+            "oncall",
+            old.oncall.iter().map(|x| x.as_str()).collect(),
+            new.oncall.iter().map(|x| x.as_str()).collect(),
+        ) && same(
+            "deps",
+            old.deps.map(|x| x.as_str()),
+            new.deps.map(|x| x.as_str()),
+        ) && same(
+            "inputs",
+            old.inputs.map(|x| x.as_str()),
+            new.inputs.map(|x| x.as_str()),
+        ) && same(
+            "labels",
+            old.labels.map(|x| x.as_str()),
+            new.labels.map(|x| x.as_str()),
+        ) && same(
+            "ci_srcs",
+            old.ci_srcs.map(|x| x.as_str()),
+            new.ci_srcs.map(|x| x.as_str()),
+        ) && same(
+            "ci_deps",
+            old.ci_deps.map(|x| x.as_str()),
+            new.ci_deps.map(|x| x.as_str()),
+        ) && same(
+            "tests",
+            old.tests.map(|x| x.as_str()),
+            new.tests.map(|x| x.as_str()),
+        ) && same(
+            "toolchain_deps",
+            old.toolchain_deps.map(|x| x.as_str()),
+            new.toolchain_deps.map(|x| x.as_str()),
+        ) && same(
+            "exec_deps",
+            old.exec_deps.map(|x| x.as_str()),
+            new.exec_deps.map(|x| x.as_str()),
+        ) && same(
+            "visibility",
+            old.visibility.map(|x| x.as_str()),
+            new.visibility.map(|x| x.as_str()),
+        );
+        Some(!same_known || !self.same_attributes(&old.attributes, &new.attributes))
+    }
+
+    fn same_list<T: Ord>(&self, name: &str, mut old: Vec<T>, mut new: Vec<T>) -> bool {
+        if self.is_unordered(name) {
+            old.sort();
+            new.sort();
+        }
+        old == new
+    }
+
+    fn same_attributes(&self, old: &Attributes, new: &Attributes) -> bool {
+        let mut old = old
+            .iter()
+            .filter(|(name, _)| !self.is_ignored(name.as_str()));
+        let mut new = new
+            .iter()
+            .filter(|(name, _)| !self.is_ignored(name.as_str()));
+        loop {
+            match (old.next(), new.next()) {
+                (None, None) => return true,
+                (Some((name, a)), Some((b_name, b)))
+                    if name == b_name && self.same_value(name.as_str(), a, b) => {}
+                _ => return false,
+            }
+        }
+    }
+
+    fn same_value(&self, name: &str, old: &serde_json::Value, new: &serde_json::Value) -> bool {
+        match (old, new) {
+            (serde_json::Value::Array(old), serde_json::Value::Array(new))
+                if self.is_unordered(name) =>
+            {
+                self.same_list(name, old.map(|x| x.to_string()), new.map(|x| x.to_string()))
+            }
+            _ => old == new,
+        }
+    }
 }
 
 pub fn immediate_target_changes<'a>(
@@ -171,25 +391,84 @@ pub fn immediate_target_changes<'a>(
     diff: &'a Targets,
     changes: &Changes,
     track_prelude_changes: bool,
+) -> GraphImpact<'a> {
+    immediate_target_changes_with(
+        base,
+        diff,
+        changes,
+        &ImmediateOptions {
+            track_prelude_changes,
+            ..ImmediateOptions::default()
+        },
+    )
+}
+
+pub fn immediate_target_changes_with<'a>(
+    base: &'a Targets,
+    diff: &'a Targets,
+    changes: &Changes,
+    options: &ImmediateOptions,
 ) -> GraphImpact<'a> {
     // Find those targets which are different
-    let mut old = base.targets_by_label_key();
+    let old = base.targets_by_label_key();
 
     // Find those .bzl files that have changed, including transitive changes
-    let bzl_change = changed_bzl_files(diff, changes, track_prelude_changes);
+    let bzl_change = changed_bzl_files(diff, changes, options.track_prelude_changes);
+
+    // Find those packages whose build file loads a changed .bzl file
+    let load_change: HashSet<&Package> = if options.follow_loads && !bzl_change.is_empty() {
+        diff.imports()
+            .filter(|x| x.imports.iter().any(|x| bzl_change.contains(x)))
+            .filter_map(|x| x.package.as_ref())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    // Find those packages owning a changed file that nothing else accounts for
+    let owned_change = match options.attribution {
+        Attribution::Strict => HashSet::new(),
+        Attribution::Ownership => owned_packages(diff, changes),
+    };
+
+    // Find those packages owning a changed file the change policy attributes to its package
+    let policy_change = if options.change_policy.package_files.is_empty() {
+        HashSet::new()
+    } else {
+        let packages = diff.targets().map(|x| x.package.clone()).collect();
+        changes
+            .filter_by_cell_path(|x| options.change_policy.package_files.contains(x))
+            .owning_packages(&packages, |_| false)
+    };
+
+    // Find those directories where a `PACKAGE` or buckconfig file changed
+    let scope_change = changes
+        .cell_paths()
+        .filter_map(|x| options.package_change_policy.impacted_scope(x))
+        .collect::<Vec<_>>();
+
+    // Whether a changed file matches each distinct `ci_srcs`. Targets in the same package
+    // often share their `ci_srcs`, so this compiles and matches each set of globs only once.
+    let ci_srcs_change: HashMap<&[Glob], bool> = if changes.is_empty() {
+        HashMap::new()
+    } else {
+        diff.targets()
+            .map(|x| &x.ci_srcs[..])
+            .filter(|x| !x.is_empty())
+            .collect::<HashSet<_>>()
+            .into_par_iter()
+            .map(|x| (x, is_changed_ci_srcs(x, changes)))
+            .collect()
+    };
 
     // Track the reason we determined a target to have changed
     let some_if = |reason, changed| if changed { Some(reason) } else { None };
 
-    let mut res = GraphImpact::default();
-    for target in diff.targets() {
-        let old_target = match old.remove(&target.label_key()) {
-            Some(x) => x,
-            None => {
-                res.recursive
-                    .push((target, ImpactReason::new(target, RootImpactKind::New)));
-                continue;
-            }
+    // Whether the target changed, recursively or not, and why
+    let compare = |target: &'a BuckTarget| {
+        let old_target = match old.get(&target.label_key()) {
+            Some(x) => *x,
+            None => return Some((target, true, RootImpactKind::New)),
         };
 
         // "hidden feature" that allows using btd to find rdeps of a "package" (directory)
@@ -199,8 +478,17 @@ pub fn immediate_target_changes<'a>(
             changes.contains_package(&target.package),
         );
 
-        // Did the hash of the target change
-        let change_hash = || some_if(RootImpactKind::Hash, old_target.hash != target.hash);
+        // Did the hash of the target change, in a way that matters
+        let change_hash = || {
+            some_if(
+                RootImpactKind::Hash,
+                old_target.hash != target.hash
+                    && options
+                        .attribute_diff
+                        .changed(old_target, target)
+                        .unwrap_or(true),
+            )
+        };
         // Did the package values change
         let change_package_values = || {
             some_if(
@@ -218,7 +506,7 @@ pub fn immediate_target_changes<'a>(
         let change_ci_srcs = || {
             some_if(
                 RootImpactKind::CiSrcs,
-                is_changed_ci_srcs(&target.ci_srcs, changes),
+                ci_srcs_change.get(&target.ci_srcs[..]) == Some(&true),
             )
         };
         // Did the rule we point at change
@@ -228,50 +516,204 @@ pub fn immediate_target_changes<'a>(
                 !bzl_change.is_empty() && bzl_change.contains(&target.rule_type.file()),
             )
         };
+        // Does the build file load a changed .bzl file
+        let change_loads = || {
+            some_if(
+                RootImpactKind::LoadedBzl,
+                !load_change.is_empty() && load_change.contains(&target.package),
+            )
+        };
+        // Is the target beneath a changed `PACKAGE` or buckconfig file
+        let change_scope = || {
+            scope_change
+                .iter()
+                .find(|(dir, _)| dir.contains(&target.package))
+                .map(|(_, reason)| *reason)
+        };
+        // Does the package own a changed file no target lists as an input
+        let change_ownership = || {
+            some_if(
+                RootImpactKind::Ownership,
+                !owned_change.is_empty() && owned_change.contains(&target.package),
+            )
+        };
+        // Does the change policy attribute a changed file to the target
+        let change_policy = || {
+            some_if(
+                RootImpactKind::ChangePolicy,
+                options.change_policy.global
+                    || (!policy_change.is_empty() && policy_change.contains(&target.package)),
+            )
+        };
+        // Were we asked to pretend the target changed
+        let change_requested = || {
+            some_if(
+                RootImpactKind::ManualForRerun,
+                options
+                    .changed_targets
+                    .iter()
+                    .any(|x| x.matches(&target.label())),
+            )
+        };
 
-        if let Some(reason) = change_package
+        change_package
             .or_else(change_hash)
             .or_else(change_inputs)
             .or_else(change_ci_srcs)
             .or_else(change_rule)
-        {
-            res.recursive
-                .push((target, ImpactReason::new(target, reason)));
-        } else if let Some(reason) = change_package_values() {
-            res.non_recursive
-                .push((target, ImpactReason::new(target, reason)));
+            .or_else(change_loads)
+            .or_else(change_scope)
+            .or_else(change_ownership)
+            .or_else(change_policy)
+            .or_else(change_requested)
+            .map(|reason| (target, true, reason))
+            .or_else(|| change_package_values().map(|reason| (target, false, reason)))
+    };
+
+    // Compare the targets in parallel. Targets are listed by package, so each task
+    // mostly covers whole packages.
+    let targets = diff.targets().collect::<Vec<_>>();
+    let changed = targets
+        .par_iter()
+        .with_min_len(MIN_TARGETS_PER_TASK)
+        .filter_map(|x| compare(x))
+        .collect::<Vec<_>>();
+
+    let mut res = GraphImpact::default();
+    let mut matched = targets.len();
+    for (target, recursive, reason) in changed {
+        if reason == RootImpactKind::New {
+            matched -= 1;
+        }
+        let reason = ImpactReason::new(target, reason);
+        if recursive {
+            res.recursive.push((target, reason));
+        } else {
+            res.non_recursive.push((target, reason));
         }
     }
 
-    // We remove targets from `old` when iterating `diff` above.
-    // At this point, only removed targets are left in `old`.
-    res.removed = old
-        .into_values()
-        .map(|target| (target, ImpactReason::new(target, RootImpactKind::Remove)))
-        .collect();
+    // The targets only in `base` are removed, including those whose package is now broken.
+    let broken = match options.broken_package_policy {
+        BrokenPackagePolicy::Changed => broken_packages(base, diff),
+        BrokenPackagePolicy::Fail | BrokenPackagePolicy::Removed => HashSet::new(),
+    };
+    // Usually every old target is still there, so save working out which aren't
+    let removed = if matched < old.len() {
+        let new: HashSet<_> = targets.iter().map(|x| x.label_key()).collect();
+        old.into_values()
+            .filter(|x| !new.contains(&x.label_key()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    for target in removed {
+        if !broken.is_empty() && broken.contains(&target.package) {
+            res.recursive.push((
+                target,
+                ImpactReason::new(target, RootImpactKind::BrokenPackage),
+            ));
+        } else {
+            res.removed
+                .push((target, ImpactReason::new(target, RootImpactKind::Remove)));
+        }
+    }
 
     // Sort to ensure deterministic output
-    res.recursive.sort_by_key(|(t, _)| t.label_key());
-    res.non_recursive.sort_by_key(|(t, _)| t.label_key());
-    res.removed.sort_by_key(|(t, _)| t.label_key());
+    res.recursive.sort_by_key(|(t, _)| t.order_key());
+    res.non_recursive.sort_by_key(|(t, _)| t.order_key());
+    res.removed.sort_by_key(|(t, _)| t.order_key());
     res
 }
 
-fn hint_applies_to(target: &BuckTarget) -> Option<(&Package, TargetName)> {
-    // for hints, the name will be `foo//bar:ci_hint@baz` which means
-    // we need to test `foo//bar:baz`.
-    Some((
-        &target.package,
-        TargetName::new(target.name.as_str().strip_prefix("ci_hint@")?),
-    ))
+/// The packages which fail to evaluate in `diff`, but not in `base`.
+fn broken_packages<'a>(base: &Targets, diff: &'a Targets) -> HashSet<&'a Package> {
+    let before: HashSet<&Package> = base.errors().map(|x| &x.package).collect();
+    diff.errors()
+        .map(|x| &x.package)
+        .filter(|x| !before.contains(x))
+        .collect()
+}
+
+/// The packages owning a changed file which isn't accounted for by the targets,
+/// i.e. is not an input, build file, `PACKAGE` file or `.bzl` file.
+fn owned_packages(diff: &Targets, changes: &Changes) -> HashSet<Package> {
+    let mut known: HashSet<&CellPath> = HashSet::new();
+    let mut packages = HashSet::new();
+    for target in diff.targets() {
+        known.extend(target.inputs.iter());
+        packages.insert(target.package.clone());
+    }
+    for import in diff.imports() {
+        known.insert(&import.file);
+        known.extend(import.imports.iter());
+    }
+    changes.owning_packages(&packages, |x| {
+        known.contains(x) || x.is_package_file() || x.extension() == Some("bzl")
+    })
+}
+
+/// Whether `rule_type` is one of `terminal`, given by short name (e.g. `config_setting`)
+/// or in full. Targets of terminal rules are reported when impacted, but whatever
+/// depends on them isn't, to avoid the fan-out of rules like `platform`.
+pub fn is_terminal_rule(rule_type: &RuleType, terminal: &[String]) -> bool {
+    terminal.iter().any(|x| rule_type.matches(x))
 }
 
+/// Targets compared by each parallel task in `immediate_target_changes_with`.
+const MIN_TARGETS_PER_TASK: usize = 1024;
+
+/// Frontier targets handled by each parallel task in `recursive_target_changes_with`,
+/// so small frontiers aren't split into tasks too small to be worth it.
+const MIN_FRONTIER_PER_TASK: usize = 256;
+
 pub fn recursive_target_changes<'a>(
     diff: &'a Targets,
     changes: &GraphImpact<'a>,
     depth: Option<usize>,
-    follow_rule_type: impl Fn(&RuleType) -> bool,
+    follow_rule_type: impl Fn(&RuleType) -> bool + Sync,
 ) -> Vec<Vec<(&'a BuckTarget, ImpactReason)>> {
+    let mut result = Vec::new();
+    recursive_target_changes_with(diff, changes, depth, follow_rule_type, |level| {
+        result.push(level)
+    });
+    result
+}
+
+/// Like `recursive_target_changes`, but hands each level to `on_level` as soon as it
+/// has been computed, rather than collecting them all. Levels are produced in order,
+/// starting at depth 0, and are each sorted.
+pub fn recursive_target_changes_with<'a>(
+    diff: &'a Targets,
+    changes: &GraphImpact<'a>,
+    depth: Option<usize>,
+    follow_rule_type: impl Fn(&RuleType) -> bool + Sync,
+    on_level: impl FnMut(Vec<(&'a BuckTarget, ImpactReason)>),
+) {
+    recursive_target_changes_with_rdeps(
+        diff,
+        None,
+        changes,
+        depth,
+        true,
+        follow_rule_type,
+        on_level,
+    )
+}
+
+/// Like [`recursive_target_changes_with`], but using `rdeps` if given, e.g. loaded from
+/// an index, rather than building them from `diff`. Unless `sort`, levels are left in
+/// the order they were found, which is deterministic but depends on the graph.
+pub fn recursive_target_changes_with_rdeps<'a>(
+    diff: &'a Targets,
+    rdeps: Option<&Rdeps<'a>>,
+    changes: &GraphImpact<'a>,
+    depth: Option<usize>,
+    sort: bool,
+    follow_rule_type: impl Fn(&RuleType) -> bool + Sync,
+    mut on_level: impl FnMut(Vec<(&'a BuckTarget, ImpactReason)>),
+) {
+    let max_levels = depth.unwrap_or(usize::MAX);
     // Just an optimisation, but saves building the reverse mapping
     if changes.recursive.is_empty() && changes.removed.is_empty() {
         let mut res = if changes.non_recursive.is_empty() {
@@ -281,49 +723,18 @@ pub fn recursive_target_changes<'a>(
         };
         // We use a empty list sentinel to show nothing missing
         res.push(Vec::new());
-        res.truncate(depth.unwrap_or(usize::MAX));
-        return res;
+        res.into_iter().take(max_levels).for_each(on_level);
+        return;
     }
 
-    // We expect most things will have at least one dependency, so a reasonable approximate size
-    let mut rdeps: TargetMap<&BuckTarget> = TargetMap::with_capacity(diff.len_targets_upperbound());
-    let mut hints: HashMap<(&Package, TargetName), TargetLabel> = HashMap::new();
-    for target in diff.targets() {
-        for d in target.deps.iter() {
-            rdeps.insert(d, target)
-        }
-        for d in target.ci_deps.iter() {
-            if let Some(label) = d.as_target_label() {
-                if label.is_package_relative() {
-                    rdeps.insert(&target.package.join(&label.target_name()), target);
-                } else {
-                    rdeps.insert(&label, target);
-                }
-            } else {
-                rdeps.insert_pattern(d, target);
-            }
-        }
-        if target.rule_type.short() == "ci_hint" {
-            match hint_applies_to(target) {
-                Some(dest) => {
-                    hints.insert(dest, target.label());
-                }
-                None => warn!("`ci_hint` target has invalid name: `{}`", target.label()),
-            }
+    let built;
+    let rdeps = match rdeps {
+        Some(rdeps) => rdeps,
+        None => {
+            built = Rdeps::new(diff);
+            &built
         }
-    }
-    // We record the hints going through (while we don't have the targets to hand),
-    // then fill them in later with this loop
-    if !hints.is_empty() {
-        for target in diff.targets() {
-            if let Some(hint) = hints.remove(&(&target.package, target.name.clone())) {
-                rdeps.insert(&hint, target);
-                if hints.is_empty() {
-                    break;
-                }
-            }
-        }
-    }
+    };
 
     // The code below is carefully optimised to avoid multiple lookups and reuse memory allocations.
     // We use `done` to record which elements have been queued for adding to the results, to avoid duplicates.
@@ -350,59 +761,79 @@ pub fn recursive_target_changes<'a>(
         )
         .collect();
 
-    let mut result = Vec::new();
-
     // Track targets depending on removed targets, but we don't add removed targets
     // to results
     let mut todo_silent: Vec<(&BuckTarget, ImpactReason)> = changes.removed.clone();
     let mut next_silent: Vec<(&BuckTarget, ImpactReason)> = Vec::new();
 
-    fn add_result<'a>(
-        results: &mut Vec<Vec<(&'a BuckTarget, ImpactReason)>>,
-        mut items: Vec<(&'a BuckTarget, ImpactReason)>,
-    ) {
-        // Sort to ensure deterministic output
-        items.sort_by_key(|(x, _)| x.label_key());
-        results.push(items);
-    }
+    let mut add_result = |mut items: Vec<(&'a BuckTarget, ImpactReason)>| {
+        // Sort to ensure a canonical output
+        if sort {
+            items.sort_by_key(|(x, _)| x.order_key());
+        }
+        on_level(items);
+    };
 
-    for _ in 0..depth.unwrap_or(usize::MAX) {
+    for _ in 0..max_levels {
         if todo.is_empty() && todo_silent.is_empty() {
             if !non_recursive_changes.is_empty() {
-                add_result(&mut result, non_recursive_changes);
+                add_result(non_recursive_changes);
             }
             break;
         }
+        if interrupt::interrupted() {
+            // Stop exploring, the `add_result` below reports the targets already found
+            non_recursive_changes.append(&mut todo);
+            todo = non_recursive_changes;
+            break;
+        }
 
         let mut next = Vec::new();
 
-        for (lbl, reason) in todo.iter().chain(todo_silent.iter()) {
-            if follow_rule_type(&lbl.rule_type) {
+        // Look up the rdeps of the whole frontier in parallel, dropping those already
+        // explored, which are most of them on a big graph. The order is preserved,
+        // so claiming the rest in order gives the same result as a sequential search.
+        let candidates: Vec<(&BuckTarget, ImpactReason)> = todo
+            .par_iter()
+            .chain(todo_silent.par_iter())
+            .with_min_len(MIN_FRONTIER_PER_TASK)
+            .filter(|(lbl, _)| follow_rule_type(&lbl.rule_type))
+            .flat_map_iter(|(lbl, reason)| {
                 let updated_reason = ImpactReason {
                     affected_dep: format!("{}:{}", lbl.package.as_str(), lbl.name.as_str()),
                     root_cause: reason.root_cause.clone(),
+                    via_hint: reason.via_hint,
                 };
-                for rdep in rdeps.get(&lbl.label()) {
-                    match done.entry(rdep.label_key()) {
-                        Entry::Vacant(e) => {
-                            next.push((*rdep, updated_reason.clone()));
-                            e.insert(true);
-                        }
-                        Entry::Occupied(mut e) => {
-                            if !e.get() {
-                                next_silent.push((*rdep, updated_reason.clone()));
-                                e.insert(true);
-                            }
-                        }
+                rdeps
+                    .get(&lbl.label())
+                    .filter(|rdep| done.get(&rdep.label_key()) != Some(&true))
+                    .map(|rdep| {
+                        let mut reason = updated_reason.clone();
+                        reason.via_hint |= is_hint_edge(lbl, rdep);
+                        (rdep, reason)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        for (rdep, reason) in candidates {
+            match done.entry(rdep.label_key()) {
+                Entry::Vacant(e) => {
+                    next.push((rdep, reason));
+                    e.insert(true);
+                }
+                Entry::Occupied(mut e) => {
+                    if !e.get() {
+                        next_silent.push((rdep, reason));
+                        e.insert(true);
                     }
                 }
             }
         }
         if !non_recursive_changes.is_empty() {
             non_recursive_changes.extend(todo.iter().cloned());
-            add_result(&mut result, mem::take(&mut non_recursive_changes));
+            add_result(mem::take(&mut non_recursive_changes));
         } else if !todo.is_empty() {
-            add_result(&mut result, mem::take(&mut todo));
+            add_result(mem::take(&mut todo));
         }
         todo = next;
 
@@ -413,16 +844,41 @@ pub fn recursive_target_changes<'a>(
 
     // an empty todo list might be added to the result here, indicating to
     // the user (in Text output mode) that there are no additional levels
-    add_result(&mut result, todo);
-    result
+    add_result(todo);
+}
+
+/// Whether `rdep` only depends on `target` through a dependency hint, as `target` is a
+/// `ci_hint` for it or matches its `ci_deps`, rather than through an edge Buck knows about.
+fn is_hint_edge(target: &BuckTarget, rdep: &BuckTarget) -> bool {
+    let is_hint = target.rule_type.short() == "ci_hint";
+    if !is_hint && rdep.ci_deps.is_empty() {
+        return false;
+    }
+    let label = target.label();
+    if rdep.deps.contains(&label)
+        || rdep.toolchain_deps.contains(&label)
+        || rdep.exec_deps.contains(&label)
+        || target.tests.contains(&rdep.label())
+    {
+        return false;
+    }
+    is_hint
+        || rdep.ci_deps.iter().any(|x| match x.as_target_label() {
+            Some(x) if x.is_package_relative() => rdep.package.join(&x.target_name()) == label,
+            Some(x) => x == label,
+            None => x.matches(&label),
+        })
 }
 
 #[cfg(test)]
 mod tests {
     use td_util::prelude::*;
+    use td_util::string::InternString;
 
     use super::*;
+    use crate::buck::arena::Deps;
     use crate::buck::labels::Labels;
+    use crate::buck::targets::BuckError;
     use crate::buck::targets::BuckImport;
     use crate::buck::targets::TargetsEntry;
     use crate::buck::types::Package;
@@ -431,6 +887,7 @@ mod tests {
     use crate::buck::types::TargetLabel;
     use crate::buck::types::TargetName;
     use crate::buck::types::TargetPattern;
+    use crate::rdeps::Edges;
     use crate::sapling::status::Status;
 
     #[test]
@@ -528,6 +985,52 @@ mod tests {
         assert_eq!(non_recursive.map(|x| x.as_str()), &["foo//bar:zzz",]);
     }
 
+    #[test]
+    fn test_immediate_changes_many_targets() {
+        // Enough targets to be compared across parallel tasks
+        let n = MIN_TARGETS_PER_TASK * 4;
+        let target = |i: usize, hash: &str| {
+            TargetsEntry::Target(BuckTarget {
+                inputs: Box::new([CellPath::new(&format!("foo//p{}/{i}.c", i % 7))]),
+                hash: TargetHash::new(hash),
+                ..BuckTarget::testing(
+                    &format!("t{i}"),
+                    &format!("foo//p{}", i % 7),
+                    "prelude//rules.bzl:cxx_library",
+                )
+            })
+        };
+        let base = Targets::new((0..n).map(|i| target(i, "old")).collect());
+        // Drop the first, add one past the end, and change the hash of every 1000th
+        let diff = Targets::new(
+            (1..=n)
+                .map(|i| target(i, if i % 1000 == 0 { "new" } else { "old" }))
+                .collect(),
+        );
+        let res = immediate_target_changes(
+            &base,
+            &diff,
+            &Changes::testing(&[Status::Modified(CellPath::new("foo//p3/10.c"))]),
+            false,
+        );
+        let names = |xs: &[(&BuckTarget, ImpactReason)]| {
+            xs.map(|(x, r)| format!("{}:{}", x.name.as_str(), r.root_cause.1))
+        };
+        // In the output order, by package then name
+        assert_eq!(
+            names(&res.recursive),
+            vec![
+                "t4096:new",
+                "t10:inputs",
+                "t4000:hash",
+                "t3000:hash",
+                "t2000:hash",
+                "t1000:hash",
+            ]
+        );
+        assert_eq!(names(&res.removed), vec!["t0:remove"]);
+    }
+
     #[test]
     fn test_immediate_changes_with_removed() {
         fn target(
@@ -679,6 +1182,7 @@ mod tests {
                 ImpactReason {
                     affected_dep: "".to_owned(),
                     root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                    via_hint: false,
                 },
             )],
             ..Default::default()
@@ -727,6 +1231,7 @@ mod tests {
                 ImpactReason {
                     affected_dep: "".to_owned(),
                     root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                    via_hint: false,
                 },
             )],
             non_recursive: vec![(
@@ -734,6 +1239,7 @@ mod tests {
                 ImpactReason {
                     affected_dep: "".to_owned(),
                     root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                    via_hint: false,
                 },
             )],
             ..Default::default()
@@ -775,6 +1281,7 @@ mod tests {
             ImpactReason {
                 affected_dep: "".to_owned(),
                 root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
             },
         )]);
         let res = recursive_target_changes(&diff, &changes, Some(3), |_| true);
@@ -789,6 +1296,155 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_recursive_changes_order() {
+        let target = |label: &str, deps: &[&str]| {
+            let (package, name) = label.split_once(':').unwrap();
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, package, "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let diff = Targets::new(vec![
+            target("foo//:lib", &[]),
+            target("foo-bar//:a", &["foo//:lib"]),
+            target("foo//baz:a", &["foo//:lib"]),
+            target("foo//:b", &["foo//:lib"]),
+        ]);
+        let changes = GraphImpact::from_recursive(vec![(
+            diff.targets().next().unwrap(),
+            ImpactReason::new(diff.targets().next().unwrap(), RootImpactKind::Inputs),
+        )]);
+        let levels = |sort: bool| {
+            let mut res = Vec::new();
+            recursive_target_changes_with_rdeps(
+                &diff,
+                None,
+                &changes,
+                None,
+                sort,
+                |_| true,
+                |level| res.push(level.map(|(x, _)| x.label().to_string())),
+            );
+            res
+        };
+        // By cell first, so `foo//` comes before `foo-bar//`
+        assert_eq!(
+            levels(true)[1],
+            vec!["foo//:b", "foo//baz:a", "foo-bar//:a"]
+        );
+        let mut unsorted = levels(false);
+        assert_ne!(unsorted[1], levels(true)[1]);
+        unsorted[1].sort();
+        assert_eq!(unsorted[1], vec!["foo-bar//:a", "foo//:b", "foo//baz:a"]);
+    }
+
+    #[test]
+    fn test_toolchain_change() {
+        let target = |package: &str, name: &str, hash: &str| BuckTarget {
+            hash: TargetHash::new(hash),
+            ..BuckTarget::testing(name, package, "prelude//rules.bzl:cxx_library")
+        };
+        let cxx = || Box::new([TargetLabel::new("prelude//toolchains:cxx")]);
+        let graph = |toolchain_hash| {
+            Targets::new(vec![
+                TargetsEntry::Target(target("prelude//toolchains", "cxx", toolchain_hash)),
+                TargetsEntry::Target(BuckTarget {
+                    toolchain_deps: cxx(),
+                    ..target("foo//", "lib", "1")
+                }),
+                TargetsEntry::Target(BuckTarget {
+                    exec_deps: cxx(),
+                    ..target("foo//", "gen", "2")
+                }),
+                TargetsEntry::Target(BuckTarget {
+                    deps: Deps::from([TargetLabel::new("foo//:lib")]),
+                    ..target("foo//", "bin", "3")
+                }),
+            ])
+        };
+        let (base, diff) = (graph("old"), graph("new"));
+        let changes = immediate_target_changes(&base, &diff, &Changes::default(), false);
+        let impacted = |rdeps: Option<&Rdeps>| {
+            let mut res = Vec::new();
+            recursive_target_changes_with_rdeps(
+                &diff,
+                rdeps,
+                &changes,
+                None,
+                true,
+                |_| true,
+                |level| res.push(level.map(|(x, _)| x.label().to_string())),
+            );
+            res
+        };
+
+        // Changing the toolchain only impacts itself, unless its users are followed
+        assert_eq!(impacted(None), vec![vec!["prelude//toolchains:cxx"]]);
+        let rdeps = Rdeps::with_edges(
+            &diff,
+            Edges {
+                toolchain_deps: true,
+                ..Edges::default()
+            },
+        );
+        assert_eq!(
+            impacted(Some(&rdeps)),
+            vec![
+                vec!["prelude//toolchains:cxx"],
+                vec!["foo//:gen", "foo//:lib"],
+                vec!["foo//:bin"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_terminal_rules() {
+        let pkg = Package::new("foo//");
+        let target = |name: &str, deps: &[&str], rule_type: &str| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| pkg.join(&TargetName::new(x))).collect(),
+                ..BuckTarget::testing(name, pkg.as_str(), rule_type)
+            })
+        };
+        let diff = Targets::new(vec![
+            target("lib", &[], "prelude//rules.bzl:cxx_library"),
+            target("setting", &["lib"], "prelude//rules.bzl:config_setting"),
+            target("bin", &["setting"], "prelude//rules.bzl:cxx_binary"),
+            target("platform", &[], "prelude//rules.bzl:platform"),
+            target("test", &["platform"], "prelude//rules.bzl:cxx_test"),
+        ]);
+        fn changed<'a>(diff: &'a Targets, names: &[&str]) -> GraphImpact<'a> {
+            GraphImpact {
+                recursive: diff
+                    .targets()
+                    .filter(|x| names.contains(&x.name.as_str()))
+                    .map(|x| (x, ImpactReason::new(x, RootImpactKind::Inputs)))
+                    .collect(),
+                ..Default::default()
+            }
+        }
+        let terminal = vec![
+            "config_setting".to_owned(),
+            "prelude//rules.bzl:platform".to_owned(),
+        ];
+        let impacted = |changes: &GraphImpact| {
+            recursive_target_changes(&diff, changes, None, |x| !is_terminal_rule(x, &terminal))
+                .into_iter()
+                .flatten()
+                .map(|(x, _)| x.name.as_str().to_owned())
+                .collect::<Vec<_>>()
+        };
+        // Reached by the traversal, reported but not followed
+        assert_eq!(impacted(&changed(&diff, &["lib"])), vec!["lib", "setting"]);
+        // Changed itself, likewise
+        assert_eq!(impacted(&changed(&diff, &["platform"])), vec!["platform"]);
+        assert_eq!(
+            impacted(&changed(&diff, &["lib", "platform"])),
+            vec!["lib", "platform", "setting"]
+        );
+    }
+
     #[test]
     fn test_recursive_with_removed_targets() {
         fn target(name: &str, deps: &[&str]) -> TargetsEntry {
@@ -856,6 +1512,7 @@ mod tests {
             ImpactReason {
                 affected_dep: "".to_owned(),
                 root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
             },
         )]);
         let res = recursive_target_changes(&diff, &changes, Some(1), |_| true);
@@ -867,6 +1524,40 @@ mod tests {
         assert_eq!(res, vec![vec!["dep"], vec!["bar"]]);
     }
 
+    #[test]
+    fn test_recursive_ci_deps_patterns() {
+        let target = |name: &str, package: &str, ci_deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                ci_deps: ci_deps.iter().map(|x| TargetPattern::new(x)).collect(),
+                ..BuckTarget::testing(name, package, "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let diff = Targets::new(vec![
+            target("server", "code//server", &[]),
+            target("test", "code//client", &["code//server:server"]),
+            target("e2e", "code//e2e", &["code//server/..."]),
+            target("other", "code//e2e", &["code//serverless/..."]),
+        ]);
+
+        let change_target =
+            BuckTarget::testing("server", "code//server", "prelude//rules.bzl:cxx_library");
+        let changes = GraphImpact::from_recursive(vec![(
+            &change_target,
+            ImpactReason {
+                affected_dep: "".to_owned(),
+                root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
+            },
+        )]);
+        let res = recursive_target_changes(&diff, &changes, Some(1), |_| true);
+        let res = res.map(|xs| {
+            let mut xs = xs.map(|(x, _)| x.name.as_str());
+            xs.sort();
+            xs
+        });
+        assert_eq!(res, vec![vec!["server"], vec!["e2e", "test"]]);
+    }
+
     #[test]
     fn test_recursive_changes_returns_unique_targets() {
         fn target(name: &str, deps: &[&str]) -> TargetsEntry {
@@ -893,6 +1584,7 @@ mod tests {
                         ImpactReason {
                             affected_dep: "".to_owned(),
                             root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                            via_hint: false,
                         },
                     )
                 })
@@ -903,6 +1595,44 @@ mod tests {
         assert_eq!(res, vec![vec!["a", "b"], vec!["c", "d"], vec![]]);
     }
 
+    #[test]
+    fn test_recursive_changes_wide_frontier() {
+        fn target(name: &str, deps: &[String]) -> TargetsEntry {
+            let pkg = Package::new("foo//");
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| pkg.join(&TargetName::new(x))).collect(),
+                ..BuckTarget::testing(name, pkg.as_str(), "prelude//rules.bzl:cxx_library")
+            })
+        }
+        // Enough targets at each level to be split across parallel tasks
+        let n = MIN_FRONTIER_PER_TASK * 4;
+        let mids = (0..n).map(|i| format!("mid{i:04}")).collect::<Vec<_>>();
+        let mut entries = vec![target("root", &[])];
+        for (i, x) in mids.iter().enumerate() {
+            entries.push(target(x, &["root".to_owned()]));
+            entries.push(target(&format!("top{i:04}"), &mids[i..(i + 2).min(n)]));
+        }
+        let diff = Targets::new(entries);
+
+        let changes = GraphImpact::from_recursive(vec![(
+            diff.targets().next().unwrap(),
+            ImpactReason {
+                affected_dep: "".to_owned(),
+                root_cause: ("foo//:root".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
+            },
+        )]);
+        let res = recursive_target_changes(&diff, &changes, None, |_| true);
+        assert_eq!(res.map(|x| x.len()), vec![1, n, n, 0]);
+        // Each top is reached first from the mid with the same number
+        for (x, reason) in &res[2] {
+            assert_eq!(
+                reason.affected_dep,
+                format!("foo//:mid{}", x.name.as_str().strip_prefix("top").unwrap())
+            );
+        }
+    }
+
     #[test]
     fn test_prelude_rule_changes() {
         // prelude.bzl imports rules.bzl which imports foo.bzl
@@ -1003,6 +1733,57 @@ mod tests {
         check("prelude//utils.bzl", true, 2);
     }
 
+    #[test]
+    fn test_follow_loads() {
+        // The build file of code//bar loads macros.bzl, which loads utils.bzl
+        let import = |file: &str, imports: &[&str], package: Option<&str>| {
+            TargetsEntry::Import(BuckImport {
+                file: CellPath::new(file),
+                imports: imports.iter().map(|x| CellPath::new(x)).collect(),
+                package: package.map(Package::new),
+            })
+        };
+        let targets = Targets::new(vec![
+            import("code//bar/BUCK", &["code//macros.bzl"], Some("code//bar")),
+            import("code//baz/BUCK", &[], Some("code//baz")),
+            import("code//macros.bzl", &["code//utils.bzl"], None),
+            import("code//utils.bzl", &[], None),
+            TargetsEntry::Target(BuckTarget::testing(
+                "foo",
+                "code//bar",
+                "prelude//rules.bzl:genrule",
+            )),
+            TargetsEntry::Target(BuckTarget::testing(
+                "qux",
+                "code//baz",
+                "prelude//rules.bzl:genrule",
+            )),
+        ]);
+        let check = |file, follow_loads| {
+            immediate_target_changes_with(
+                &targets,
+                &targets,
+                &Changes::testing(&[Status::Modified(CellPath::new(file))]),
+                &ImmediateOptions {
+                    follow_loads,
+                    ..ImmediateOptions::default()
+                },
+            )
+            .iter()
+            .map(|(x, reason)| (x.label().to_string(), reason.root_cause.1))
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(check("code//utils.bzl", false), vec![]);
+        for file in ["code//macros.bzl", "code//utils.bzl"] {
+            assert_eq!(
+                check(file, true),
+                vec![("code//bar:foo".to_owned(), RootImpactKind::LoadedBzl)]
+            );
+        }
+        // Only loading a changed file counts, not being the changed file
+        assert_eq!(check("code//bar/BUCK", true), vec![]);
+    }
+
     #[test]
     fn test_file_deps() {
         // prelude.bzl imports rules.bzl which imports foo.bzl
@@ -1027,6 +1808,353 @@ mod tests {
         check("test/foo.txt", 1);
     }
 
+    #[test]
+    fn test_file_deps_shared() {
+        let target = |name: &str, globs: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                ci_srcs: globs.iter().map(|x| Glob::new(x)).collect(),
+                ..BuckTarget::testing(name, "code//bar", "prelude//rules.bzl:genrule")
+            })
+        };
+        // Targets sharing the same `ci_srcs` are all impacted.
+        let targets = Targets::new(vec![
+            target("a", &["test/**/*.txt", "!test/skip/*"]),
+            target("b", &["test/**/*.txt", "!test/skip/*"]),
+            target("c", &["data/*"]),
+            target("d", &[]),
+        ]);
+        let check = |file, expect: &[&str]| {
+            let changes =
+                Changes::testing(&[Status::Modified(CellPath::new(&format!("root//{file}")))]);
+            let res = immediate_target_changes(&targets, &targets, &changes, false);
+            assert_eq!(
+                res.iter()
+                    .map(|(x, _)| x.name.as_str().to_owned())
+                    .collect::<Vec<_>>(),
+                expect
+            );
+        };
+        check("test/data/foo.txt", &["a", "b"]);
+        check("test/skip/foo.txt", &[]);
+        check("data/foo.bin", &["c"]);
+    }
+
+    #[test]
+    fn test_ownership_attribution() {
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(BuckTarget {
+                inputs: Box::new([CellPath::new("code//bar/src.cpp")]),
+                ..BuckTarget::testing("lib", "code//bar", "prelude//rules.bzl:cxx_library")
+            }),
+            TargetsEntry::Target(BuckTarget::testing(
+                "test",
+                "code//bar",
+                "prelude//rules.bzl:cxx_test",
+            )),
+            TargetsEntry::Target(BuckTarget::testing(
+                "sub",
+                "code//bar/sub",
+                "prelude//rules.bzl:cxx_library",
+            )),
+            TargetsEntry::Import(BuckImport {
+                file: CellPath::new("code//bar/BUCK"),
+                imports: Box::new([CellPath::new("code//defs.bzl")]),
+                package: Some(Package::new("code//bar")),
+            }),
+        ]);
+        let check = |file: &str, attribution, expect: &[&str]| {
+            let res = immediate_target_changes_with(
+                &targets,
+                &targets,
+                &Changes::testing(&[Status::Modified(CellPath::new(file))]),
+                &ImmediateOptions {
+                    attribution,
+                    ..ImmediateOptions::default()
+                },
+            );
+            assert_eq!(
+                res.iter()
+                    .map(|(x, _)| x.label().to_string())
+                    .collect::<Vec<_>>(),
+                expect
+            );
+        };
+        let both = &["code//bar:lib", "code//bar:test"];
+        check("code//bar/data.txt", Attribution::Strict, &[]);
+        check("code//bar/data.txt", Attribution::Ownership, both);
+        check("code//bar/dir/data.txt", Attribution::Ownership, both);
+        check(
+            "code//bar/sub/data.txt",
+            Attribution::Ownership,
+            &["code//bar/sub:sub"],
+        );
+        check(
+            "code//bar/src.cpp",
+            Attribution::Ownership,
+            &["code//bar:lib"],
+        );
+        check("code//bar/BUCK", Attribution::Ownership, &[]);
+        check("code//bar/PACKAGE", Attribution::Ownership, &[]);
+        check("code//defs.bzl", Attribution::Ownership, &[]);
+        check("code//data.txt", Attribution::Ownership, &[]);
+    }
+
+    #[test]
+    fn test_changed_targets() {
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(BuckTarget::testing(
+                "lib",
+                "code//bar",
+                "prelude//rules.bzl:cxx_library",
+            )),
+            TargetsEntry::Target(BuckTarget {
+                deps: Deps::from([TargetLabel::new("code//bar:lib")]),
+                ..BuckTarget::testing("bin", "code//baz", "prelude//rules.bzl:cxx_binary")
+            }),
+        ]);
+        let check = |patterns: &[&str], expect: &[(&str, u64)]| {
+            let immediate = immediate_target_changes_with(
+                &targets,
+                &targets,
+                &Changes::default(),
+                &ImmediateOptions {
+                    changed_targets: patterns.map(|x| TargetPattern::new(x).parse().unwrap()),
+                    ..ImmediateOptions::default()
+                },
+            );
+            assert!(immediate
+                .iter()
+                .all(|(_, x)| x.root_cause.1 == RootImpactKind::ManualForRerun));
+            let res = recursive_target_changes(&targets, &immediate, None, |_| true);
+            assert_eq!(
+                res.iter()
+                    .enumerate()
+                    .flat_map(|(depth, xs)| xs
+                        .iter()
+                        .map(move |(x, _)| (x.label().to_string(), depth as u64)))
+                    .collect::<Vec<_>>(),
+                expect.map(|(x, depth)| ((*x).to_owned(), *depth))
+            );
+        };
+        check(&[], &[]);
+        check(
+            &["code//bar:lib"],
+            &[("code//bar:lib", 0), ("code//baz:bin", 1)],
+        );
+        check(&["code//baz/..."], &[("code//baz:bin", 0)]);
+        check(&["code//missing:lib"], &[]);
+    }
+
+    #[test]
+    fn test_package_change_policy() {
+        use PackageChangePolicy::*;
+        use RootImpactKind::Buckconfig;
+        use RootImpactKind::PackageFile;
+
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(BuckTarget::testing(
+                "a",
+                "code//",
+                "prelude//rules.bzl:genrule",
+            )),
+            TargetsEntry::Target(BuckTarget::testing(
+                "b",
+                "code//bar",
+                "prelude//rules.bzl:genrule",
+            )),
+            TargetsEntry::Target(BuckTarget::testing(
+                "c",
+                "code//bar/baz",
+                "prelude//rules.bzl:genrule",
+            )),
+            TargetsEntry::Target(BuckTarget::testing(
+                "d",
+                "other//bar",
+                "prelude//rules.bzl:genrule",
+            )),
+        ]);
+        let check = |file: &str, package_change_policy, expect: &[(&str, RootImpactKind)]| {
+            let res = immediate_target_changes_with(
+                &targets,
+                &targets,
+                &Changes::testing(&[Status::Modified(CellPath::new(file))]),
+                &ImmediateOptions {
+                    package_change_policy,
+                    ..ImmediateOptions::default()
+                },
+            );
+            assert_eq!(
+                res.iter()
+                    .map(|(x, reason)| (x.label().to_string(), reason.root_cause.1))
+                    .collect::<Vec<_>>(),
+                expect.map(|(x, reason)| (x.to_string(), *reason))
+            );
+        };
+        let cell = |reason| {
+            vec![
+                ("code//:a", reason),
+                ("code//bar:b", reason),
+                ("code//bar/baz:c", reason),
+            ]
+        };
+        check("code//bar/PACKAGE", Ignore, &[]);
+        check("code//.buckconfig", Ignore, &[]);
+        check(
+            "code//bar/PACKAGE",
+            Subtree,
+            &[
+                ("code//bar:b", PackageFile),
+                ("code//bar/baz:c", PackageFile),
+            ],
+        );
+        check("code//bar/PACKAGE", Cell, &cell(PackageFile));
+        check("code//.buckconfig", Subtree, &cell(Buckconfig));
+        check("code//mode/dev.bcfg", Cell, &cell(Buckconfig));
+        check("code//bar/src.txt", Cell, &[]);
+    }
+
+    #[test]
+    fn test_attribute_diff() {
+        fn target(hash: &str, deps: &[&str], attributes: serde_json::Value) -> Targets {
+            let attributes = attributes
+                .as_object()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (InternString::new(k), v.clone()))
+                .collect();
+            Targets::new(vec![TargetsEntry::Target(BuckTarget {
+                hash: TargetHash::new(hash),
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                attributes: Attributes::new(attributes),
+                ..BuckTarget::testing("foo", "code//bar", "prelude//rules.bzl:genrule")
+            })])
+        }
+        let attribute_diff = AttributeDiff {
+            mode: DiffMode::Attributes,
+            ignore: vec!["metadata".to_owned()],
+            unordered: vec!["srcs".to_owned(), "deps".to_owned()],
+        };
+        let base = target(
+            "1",
+            &["code//:a", "code//:b"],
+            serde_json::json!({"srcs": ["a", "b"], "cmd": "x", "metadata": 1}),
+        );
+        let check = |diff: &Targets, attribute_diff: &AttributeDiff| {
+            immediate_target_changes_with(
+                &base,
+                diff,
+                &Changes::testing(&[]),
+                &ImmediateOptions {
+                    attribute_diff: attribute_diff.clone(),
+                    ..ImmediateOptions::default()
+                },
+            )
+            .len()
+        };
+
+        // Only reordered or ignored attributes change
+        let same = target(
+            "2",
+            &["code//:b", "code//:a"],
+            serde_json::json!({"srcs": ["b", "a"], "cmd": "x", "metadata": 2}),
+        );
+        assert_eq!(check(&same, &attribute_diff), 0);
+        assert_eq!(check(&same, &AttributeDiff::default()), 1);
+        let hash_only = AttributeDiff {
+            mode: DiffMode::Hash,
+            ..attribute_diff.clone()
+        };
+        assert_eq!(check(&same, &hash_only), 1);
+        assert_eq!(check(&base, &hash_only), 0);
+
+        // Only the hash changes
+        let rehashed = target(
+            "2",
+            &["code//:a", "code//:b"],
+            serde_json::json!({"srcs": ["a", "b"], "cmd": "x", "metadata": 1}),
+        );
+        assert_eq!(check(&rehashed, &AttributeDiff::default()), 1);
+        assert_eq!(check(&rehashed, &hash_only), 1);
+        let attributes = AttributeDiff {
+            mode: DiffMode::Attributes,
+            ..AttributeDiff::default()
+        };
+        assert_eq!(check(&rehashed, &attributes), 0);
+
+        // A significant attribute changes, is added, or is removed
+        for attributes in [
+            serde_json::json!({"srcs": ["a", "b"], "cmd": "y"}),
+            serde_json::json!({"srcs": ["a", "b"], "cmd": "x", "out": "z"}),
+            serde_json::json!({"srcs": ["a", "b"]}),
+            serde_json::json!({"srcs": ["a", "a", "b"], "cmd": "x"}),
+        ] {
+            let diff = target("2", &["code//:a", "code//:b"], attributes);
+            assert_eq!(check(&diff, &attribute_diff), 1);
+        }
+        let diff = target(
+            "2",
+            &["code//:a"],
+            serde_json::json!({"srcs": ["a", "b"], "cmd": "x"}),
+        );
+        assert_eq!(check(&diff, &attribute_diff), 1);
+        let mut restricted = base.targets().next().unwrap().clone();
+        restricted.hash = TargetHash::new("2");
+        restricted.visibility = Box::new([TargetPattern::new("code//bar/...")]);
+        let diff = Targets::new(vec![TargetsEntry::Target(restricted)]);
+        assert_eq!(check(&diff, &attribute_diff), 1);
+
+        // Without attributes, fall back to the hash
+        let diff = target("2", &["code//:a", "code//:b"], serde_json::json!({}));
+        assert_eq!(check(&diff, &attribute_diff), 1);
+    }
+
+    #[test]
+    fn test_broken_package_policy() {
+        let lib = BuckTarget::testing("lib", "foo//lib", "prelude//rules.bzl:cxx_library");
+        let bin = BuckTarget {
+            deps: Deps::from([TargetLabel::new("foo//lib:lib")]),
+            ..BuckTarget::testing("bin", "foo//bin", "prelude//rules.bzl:cxx_binary")
+        };
+        let base = Targets::new(vec![
+            TargetsEntry::Target(lib),
+            TargetsEntry::Target(bin.clone()),
+        ]);
+        let diff = Targets::new(vec![
+            TargetsEntry::Error(BuckError {
+                package: Package::new("foo//lib"),
+                error: "Broken".to_owned(),
+            }),
+            TargetsEntry::Target(bin),
+        ]);
+        let check = |policy| {
+            let immediate = immediate_target_changes_with(
+                &base,
+                &diff,
+                &Changes::testing(&[]),
+                &ImmediateOptions {
+                    broken_package_policy: policy,
+                    ..ImmediateOptions::default()
+                },
+            );
+            recursive_target_changes(&diff, &immediate, None, |_| true)
+                .iter()
+                .flatten()
+                .map(|(x, reason)| (x.label().to_string(), reason.root_cause.1))
+                .collect::<Vec<_>>()
+        };
+
+        let removed = vec![("foo//bin:bin".to_owned(), RootImpactKind::Remove)];
+        assert_eq!(check(BrokenPackagePolicy::Fail), removed);
+        assert_eq!(check(BrokenPackagePolicy::Removed), removed);
+        assert_eq!(
+            check(BrokenPackagePolicy::Changed),
+            vec![
+                ("foo//lib:lib".to_owned(), RootImpactKind::BrokenPackage),
+                ("foo//bin:bin".to_owned(), RootImpactKind::BrokenPackage),
+            ]
+        );
+    }
+
     #[test]
     fn test_package_values() {
         // prelude.bzl imports rules.bzl which imports foo.bzl
@@ -1057,12 +2185,12 @@ mod tests {
         // Or because the graph is broken but Buck2 won't see that with streaming targets.
         let targets = Targets::new(vec![
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([TargetLabel::new("foo//:b")]),
+                deps: Deps::from([TargetLabel::new("foo//:b")]),
                 inputs: Box::new([src.clone()]),
                 ..BuckTarget::testing("a", "foo//", "")
             }),
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([TargetLabel::new("foo//:a")]),
+                deps: Deps::from([TargetLabel::new("foo//:a")]),
                 ..BuckTarget::testing("b", "foo//", "")
             }),
         ]);
@@ -1082,6 +2210,7 @@ mod tests {
             ImpactReason {
                 affected_dep: "".to_owned(),
                 root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
             },
         ));
         assert_eq!(
@@ -1114,6 +2243,7 @@ mod tests {
             ImpactReason {
                 affected_dep: "".to_owned(),
                 root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
             },
         )]);
         let res = recursive_target_changes(&diff, &changes, Some(3), |_| true);
@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Compare the impacted targets of two BTD runs, e.g. before and after a rebase.
+//!
+//! Either output may be in any of the formats BTD writes: text, `--json`,
+//! `--json-lines` or `--output-format v2`, parsed as for `btd setop`. Depths are
+//! only compared when both outputs have them.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+use td_util::json;
+use thiserror::Error;
+
+use crate::buck::types::TargetLabel;
+use crate::setop::ImpactSet;
+
+/// Compare two BTD outputs, printing each target added, removed, or impacted at a
+/// different depth in `NEW` as a line of JSON. Fails if there were differences,
+/// according to `--fail-on`.
+#[derive(clap::Args, Debug)]
+pub struct DiffOutputsArgs {
+    /// Output of the earlier BTD run.
+    #[arg(value_name = "OLD")]
+    old: PathBuf,
+
+    /// Output of the later BTD run.
+    #[arg(value_name = "NEW")]
+    new: PathBuf,
+
+    /// Which differences make the command fail.
+    #[arg(long, value_enum, default_value_t)]
+    fail_on: FailOn,
+}
+
+/// Which differences between the outputs are failures.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailOn {
+    /// Any difference, including a change of depth.
+    #[default]
+    Any,
+    /// Only targets impacted in `NEW` but not `OLD`.
+    Added,
+    /// Never fail because of differences.
+    Never,
+}
+
+/// A difference between two BTD outputs.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Difference {
+    Added {
+        target: TargetLabel,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        depth: Option<u64>,
+    },
+    Removed {
+        target: TargetLabel,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        depth: Option<u64>,
+    },
+    DepthChanged {
+        target: TargetLabel,
+        old: u64,
+        new: u64,
+    },
+}
+
+#[derive(Debug, Error)]
+enum DiffOutputsError {
+    #[error("Found {0} differences between the outputs")]
+    Differences(usize),
+}
+
+/// The impacted targets in a BTD output, with their depth if the format has one.
+/// A target reported more than once keeps its smallest depth.
+pub fn parse_output(src: &str) -> BTreeMap<TargetLabel, Option<u64>> {
+    ImpactSet::parse(src)
+        .targets
+        .into_iter()
+        .map(|(target, record)| {
+            let depth = record.get("depth").and_then(Value::as_u64);
+            (target, depth)
+        })
+        .collect()
+}
+
+/// The differences going from `old` to `new`, ordered by target.
+pub fn diff_outputs(
+    old: &BTreeMap<TargetLabel, Option<u64>>,
+    new: &BTreeMap<TargetLabel, Option<u64>>,
+) -> Vec<Difference> {
+    let mut res = Vec::new();
+    for (target, depth) in old {
+        match new.get(target) {
+            None => res.push(Difference::Removed {
+                target: target.clone(),
+                depth: *depth,
+            }),
+            Some(new_depth) => {
+                if let (Some(old), Some(new)) = (*depth, *new_depth) {
+                    if old != new {
+                        res.push(Difference::DepthChanged {
+                            target: target.clone(),
+                            old,
+                            new,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    for (target, depth) in new {
+        if !old.contains_key(target) {
+            res.push(Difference::Added {
+                target: target.clone(),
+                depth: *depth,
+            });
+        }
+    }
+    res.sort_by_cached_key(|x| match x {
+        Difference::Added { target, .. }
+        | Difference::Removed { target, .. }
+        | Difference::DepthChanged { target, .. } => target.key(),
+    });
+    res
+}
+
+fn read_output(file: &Path) -> anyhow::Result<BTreeMap<TargetLabel, Option<u64>>> {
+    let src =
+        fs::read_to_string(file).with_context(|| format!("When reading `{}`", file.display()))?;
+    Ok(parse_output(&src))
+}
+
+pub fn main(args: DiffOutputsArgs) -> anyhow::Result<()> {
+    let differences = diff_outputs(&read_output(&args.old)?, &read_output(&args.new)?);
+    json::write_json_lines(BufWriter::new(stdout().lock()), &differences)?;
+    let failures = match args.fail_on {
+        FailOn::Any => differences.len(),
+        FailOn::Added => differences
+            .iter()
+            .filter(|x| matches!(x, Difference::Added { .. }))
+            .count(),
+        FailOn::Never => 0,
+    };
+    if failures == 0 {
+        Ok(())
+    } else {
+        Err(DiffOutputsError::Differences(failures).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let expected = BTreeMap::from([
+            (TargetLabel::new("foo//bar:a"), Some(0)),
+            (TargetLabel::new("foo//bar:b"), Some(1)),
+        ]);
+        let lines = "{\"target\":\"foo//bar:a\",\"depth\":0}\n\
+            {\"target\":\"foo//bar:b\",\"depth\":2}\n\
+            {\"target\":\"foo//bar:b\",\"depth\":1}\n\
+            {\"truncated\":false}\n";
+        assert_eq!(parse_output(lines), expected);
+        let array = "[\n  {\"target\":\"foo//bar:a\",\"depth\":0},\n  \
+            {\"target\":\"foo//bar:b\",\"depth\":1}\n]\n";
+        assert_eq!(parse_output(array), expected);
+        let v2 = "{\"version\":2,\"targets\":[{\"target\":\"foo//bar:a\",\"depth\":0},\
+            {\"target\":\"foo//bar:b\",\"depth\":1}],\"truncated\":false}";
+        assert_eq!(parse_output(v2), expected);
+        assert_eq!(
+            parse_output("foo//bar:a\n\nfoo//bar:b\n"),
+            expected.keys().map(|x| (x.clone(), None)).collect()
+        );
+        let text = "Level 0\n  foo//bar:a\nLevel 1\n  foo//bar:b\nQuarantined\n  foo//bar:q\n\
+            Removed\n  foo//bar:r\nTruncated\n";
+        assert_eq!(parse_output(text), expected);
+    }
+
+    #[test]
+    fn test_diff_outputs() {
+        let output = |xs: &[(&str, Option<u64>)]| {
+            xs.iter()
+                .map(|(x, depth)| (TargetLabel::new(x), *depth))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let old = output(&[
+            ("foo//bar:a", Some(0)),
+            ("foo//bar:b", Some(1)),
+            ("foo//bar:c", Some(2)),
+            ("foo//bar:d", None),
+        ]);
+        let new = output(&[
+            ("foo//bar:a", Some(0)),
+            ("foo//bar:b", Some(2)),
+            ("foo//bar:d", Some(1)),
+            ("foo//bar:e", Some(3)),
+        ]);
+        assert_eq!(
+            diff_outputs(&old, &new),
+            vec![
+                Difference::DepthChanged {
+                    target: TargetLabel::new("foo//bar:b"),
+                    old: 1,
+                    new: 2,
+                },
+                Difference::Removed {
+                    target: TargetLabel::new("foo//bar:c"),
+                    depth: Some(2),
+                },
+                Difference::Added {
+                    target: TargetLabel::new("foo//bar:e"),
+                    depth: Some(3),
+                },
+            ]
+        );
+        assert_eq!(diff_outputs(&new, &new), Vec::new());
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Write the impacted targets as a GraphViz DOT graph, to debug unexpected fan-out.
+//!
+//! Edges point in the direction the impact flows, from a changed file to the target
+//! that has it as an input, and from a target to those that depend on it.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context as _;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::TargetLabel;
+use crate::changes::Changes;
+use crate::diff::ImpactReason;
+
+/// Fill colour of targets which changed themselves.
+const CHANGED_COLOR: &str = "salmon";
+/// Fill colour of targets impacted through their dependencies.
+const IMPACTED_COLOR: &str = "lightblue";
+
+fn quote(x: &str) -> String {
+    format!("\"{}\"", x.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Write the impacted targets in `levels`, and the changed files which caused them, as DOT.
+pub fn write_dot(
+    mut out: impl Write,
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    changes: &Changes,
+) -> io::Result<()> {
+    let impacted: HashSet<TargetLabel> = levels.iter().flatten().map(|(x, _)| x.label()).collect();
+
+    writeln!(out, "digraph impact {{")?;
+    writeln!(out, "  rankdir=LR;")?;
+    writeln!(out, "  node [shape=box, style=filled];")?;
+
+    let mut files = HashSet::new();
+    for (depth, level) in levels.iter().enumerate() {
+        for (x, _) in level {
+            let label = quote(x.label().as_str());
+            let color = if depth == 0 {
+                CHANGED_COLOR
+            } else {
+                IMPACTED_COLOR
+            };
+            writeln!(out, "  {label} [fillcolor={color}];")?;
+            if depth == 0 {
+                for file in x.inputs.iter().filter(|x| changes.contains_cell_path(x)) {
+                    let file = quote(file.as_str());
+                    if files.insert(file.clone()) {
+                        writeln!(out, "  {file} [shape=note, fillcolor=white];")?;
+                    }
+                    writeln!(out, "  {file} -> {label} [label=inputs];")?;
+                }
+            }
+        }
+    }
+
+    for level in levels {
+        for (x, _) in level {
+            let label = quote(x.label().as_str());
+            let deps = x.deps.iter().map(|d| (d.clone(), "deps"));
+            let ci_deps = x.ci_deps.iter().filter_map(|d| {
+                let dep = d.as_target_label()?;
+                if dep.is_package_relative() {
+                    Some((x.package.join(&dep.target_name()), "ci_deps"))
+                } else {
+                    Some((dep, "ci_deps"))
+                }
+            });
+            for (dep, kind) in deps.chain(ci_deps) {
+                if impacted.contains(&dep) {
+                    writeln!(out, "  {} -> {label} [label={kind}];", quote(dep.as_str()))?;
+                }
+            }
+        }
+    }
+
+    writeln!(out, "}}")?;
+    out.flush()
+}
+
+/// Write the DOT graph of the impacted targets to `file`.
+pub fn write_file(
+    file: &Path,
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    changes: &Changes,
+) -> anyhow::Result<()> {
+    let out = BufWriter::new(File::create(file)?);
+    write_dot(out, levels, changes)
+        .with_context(|| format!("When writing graph to `{}`", file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::arena::Deps;
+    use crate::buck::targets::Targets;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::CellPath;
+    use crate::diff;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_write_dot() {
+        let file = CellPath::new("foo//bar/lib.cpp");
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(BuckTarget {
+                inputs: Box::new([file.clone(), CellPath::new("foo//bar/lib.h")]),
+                ..BuckTarget::testing("lib", "foo//bar", "prelude//rules.bzl:cxx_library")
+            }),
+            TargetsEntry::Target(BuckTarget {
+                deps: Deps::from([TargetLabel::new("foo//bar:lib")]),
+                ..BuckTarget::testing("bin", "foo//bar", "prelude//rules.bzl:cxx_binary")
+            }),
+            TargetsEntry::Target(BuckTarget::testing(
+                "other",
+                "foo//bar",
+                "prelude//rules.bzl:cxx_binary",
+            )),
+        ]);
+        let changes = Changes::testing(&[Status::Modified(file)]);
+        let immediate = diff::immediate_target_changes(&targets, &targets, &changes, false);
+        let levels = diff::recursive_target_changes(&targets, &immediate, None, |_| true);
+
+        let mut out = Vec::new();
+        write_dot(&mut out, &levels, &changes).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"digraph impact {
+  rankdir=LR;
+  node [shape=box, style=filled];
+  "foo//bar:lib" [fillcolor=salmon];
+  "foo//bar/lib.cpp" [shape=note, fillcolor=white];
+  "foo//bar/lib.cpp" -> "foo//bar:lib" [label=inputs];
+  "foo//bar:bin" [fillcolor=lightblue];
+  "foo//bar:lib" -> "foo//bar:bin" [label=deps];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("foo//bar:baz"), "\"foo//bar:baz\"");
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+}
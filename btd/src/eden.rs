@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Get the files changed since a revision on an EdenFS checkout without walking the
+//! working copy, which `sl status` can end up doing.
+//!
+//! We ask Watchman for the changes since the merge base with the revision. On an Eden
+//! mount Watchman answers from Eden's journal of changed files, along with the files
+//! changed between the merge base and the current commit. If it can't, e.g. because
+//! it just started watching, it reports a fresh instance and we give up.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use td_util::command::with_command;
+use thiserror::Error;
+
+use crate::buck::types::ProjectRelativePath;
+use crate::changes::Scm;
+use crate::sapling::status::Status;
+
+#[derive(Debug, Error)]
+enum EdenError {
+    #[error("Watchman failed: {0}")]
+    Watchman(String),
+    #[error("Watchman has no history for the checkout, so would report every file")]
+    FreshInstance,
+    #[error("The checkout at `{0}` is not on EdenFS")]
+    NotEden(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    is_fresh_instance: bool,
+    #[serde(default)]
+    files: Vec<File>,
+}
+
+#[derive(Debug, Deserialize)]
+struct File {
+    name: String,
+    exists: bool,
+    /// Whether the file was created since the merge base.
+    #[serde(default)]
+    new: bool,
+}
+
+fn query(root: &Path, rev: &str) -> serde_json::Value {
+    serde_json::json!([
+        "query",
+        root,
+        {
+            "since": {"scm": {"mergebase-with": rev}},
+            "expression": ["not", ["type", "d"]],
+            "fields": ["name", "exists", "new"],
+            "empty_on_fresh_instance": true,
+        }
+    ])
+}
+
+fn parse_response(data: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    let res: Response = serde_json::from_str(data)?;
+    if let Some(error) = res.error {
+        return Err(EdenError::Watchman(error).into());
+    }
+    if res.is_fresh_instance {
+        return Err(EdenError::FreshInstance.into());
+    }
+    let mut files = res.files;
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files
+        .into_iter()
+        .map(|x| {
+            let path = ProjectRelativePath::new(&x.name);
+            match (x.exists, x.new) {
+                (false, _) => Status::Removed(path),
+                (true, true) => Status::Added(path),
+                (true, false) => Status::Modified(path),
+            }
+        })
+        .collect())
+}
+
+/// Ask Eden, through Watchman, for the files changed since `rev`.
+pub fn changes_from_eden(rev: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    let root = Scm::Sapling.root()?;
+    if !root.join(".eden").exists() {
+        return Err(EdenError::NotEden(root.display().to_string()).into());
+    }
+    let mut command = Command::new("watchman");
+    command.args(["--json-command", "--no-pretty"]);
+    let stdout = with_command(command, |mut command| {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Dropping stdin once written closes it, so Watchman reads the whole query
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(query(&root, rev).to_string().as_bytes())?;
+        let res = child.wait_with_output()?;
+        res.status.exit_ok().with_context(|| {
+            format!("Watchman stderr: {}", String::from_utf8_lossy(&res.stderr))
+        })?;
+        Ok(String::from_utf8(res.stdout)?)
+    })?;
+    parse_response(&stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let res = parse_response(
+            r#"{"version": "2024.01.01.00", "clock": "c:1:2", "is_fresh_instance": false,
+                "files": [
+                    {"name": "foo/b.rs", "exists": true, "new": false},
+                    {"name": "foo/a.rs", "exists": false, "new": false},
+                    {"name": "foo/c.rs", "exists": true, "new": true}
+                ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            res,
+            vec![
+                Status::Removed(ProjectRelativePath::new("foo/a.rs")),
+                Status::Modified(ProjectRelativePath::new("foo/b.rs")),
+                Status::Added(ProjectRelativePath::new("foo/c.rs")),
+            ]
+        );
+        assert!(parse_response(r#"{"is_fresh_instance": true, "files": []}"#).is_err());
+        assert!(parse_response(r#"{"error": "unable to resolve root"}"#).is_err());
+    }
+}
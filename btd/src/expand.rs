@@ -0,0 +1,200 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Expand target patterns against a targets file or snapshot, as `buck2 targets`
+//! would, without needing Buck, e.g. for offline tools built on snapshots.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetPattern;
+
+/// Print the targets matching the patterns, one per line, in the order BTD outputs
+/// targets.
+#[derive(clap::Args, Debug)]
+pub struct ExpandArgs {
+    /// Targets file to expand against, either the JSON output of `buck2 targets` or a
+    /// snapshot.
+    #[arg(long, value_name = "FILE")]
+    targets: PathBuf,
+
+    /// Patterns to expand, e.g. `foo//bar:baz`, `foo//bar:` or `foo//bar/...`.
+    #[arg(value_name = "TARGET_PATTERN", required = true)]
+    patterns: Vec<String>,
+
+    /// Print the target each `alias` stands for instead, following chains of aliases.
+    #[arg(long)]
+    resolve_aliases: bool,
+}
+
+#[derive(Debug, Error)]
+enum ExpandError {
+    #[error("No target `{0}` in the targets")]
+    UnknownTarget(String),
+    #[error("The aliases starting from `{0}` form a cycle")]
+    AliasCycle(TargetLabel),
+}
+
+/// The targets matching any of `patterns`, in output order. Like Buck, fails if a
+/// pattern naming a specific target matches nothing, but not if a package is empty.
+pub fn expand<'a>(
+    targets: &'a Targets,
+    patterns: &[TargetPattern],
+) -> anyhow::Result<Vec<&'a BuckTarget>> {
+    let parsed = patterns
+        .iter()
+        .map(|x| x.parse())
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut matched = vec![false; parsed.len()];
+    let mut res = Vec::new();
+    for target in targets.targets() {
+        let label = target.label();
+        let mut any = false;
+        for (p, matched) in parsed.iter().zip(matched.iter_mut()) {
+            if p.matches(&label) {
+                *matched = true;
+                any = true;
+            }
+        }
+        if any {
+            res.push(target);
+        }
+    }
+    for ((p, matched), pattern) in parsed.iter().zip(matched).zip(patterns) {
+        if p.is_specific_target() && !matched {
+            return Err(ExpandError::UnknownTarget(pattern.as_str().to_owned()).into());
+        }
+    }
+    res.sort_by(|a, b| a.order_key().cmp(&b.order_key()));
+    Ok(res)
+}
+
+/// Follow `label` through any aliases to the target they stand for, which is `label`
+/// itself if it isn't an alias. An alias of a target missing from `by_label` resolves
+/// to that target.
+pub fn resolve_alias(
+    by_label: &HashMap<TargetLabel, &BuckTarget>,
+    label: &TargetLabel,
+) -> anyhow::Result<TargetLabel> {
+    let mut seen = HashSet::new();
+    let mut label = label.clone();
+    while let Some(actual) = by_label.get(&label).and_then(|x| x.alias_of()) {
+        if !seen.insert(label.clone()) {
+            return Err(ExpandError::AliasCycle(label).into());
+        }
+        label = actual;
+    }
+    Ok(label)
+}
+
+pub fn main(args: ExpandArgs) -> anyhow::Result<()> {
+    let targets = Targets::from_file(&args.targets)?;
+    let patterns = args
+        .patterns
+        .iter()
+        .map(|x| TargetPattern::new(x))
+        .collect::<Vec<_>>();
+    let mut res = expand(&targets, &patterns)?
+        .iter()
+        .map(|x| x.label())
+        .collect::<Vec<_>>();
+    if args.resolve_aliases {
+        let by_label = targets.targets_by_label();
+        let mut seen = HashSet::new();
+        let mut resolved = Vec::with_capacity(res.len());
+        for x in &res {
+            let x = resolve_alias(&by_label, x)?;
+            // An alias and what it stands for may both match
+            if seen.insert(x.clone()) {
+                resolved.push(x);
+            }
+        }
+        res = resolved;
+    }
+
+    let mut out = BufWriter::new(stdout().lock());
+    for x in &res {
+        writeln!(out, "{x}")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use td_util::prelude::*;
+
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+
+    #[test]
+    fn test_expand() {
+        let target = |name: &str, package: &str, rule_type: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, package, rule_type)
+            })
+        };
+        let alias = |name: &str, actual: &str| target(name, "foo//bar", "alias", &[actual]);
+        let targets = Targets::new(vec![
+            target("lib", "foo//bar", "prelude//rules.bzl:cxx_library", &[]),
+            alias("lib_alias", "foo//bar:lib"),
+            alias("alias_alias", "foo//bar:lib_alias"),
+            alias("missing_alias", "foo//bar:missing"),
+            alias("cycle_a", "foo//bar:cycle_b"),
+            alias("cycle_b", "foo//bar:cycle_a"),
+            target("bin", "foo//bar/baz", "prelude//rules.bzl:cxx_binary", &[]),
+            target("baz", "foo//baz", "prelude//rules.bzl:cxx_binary", &[]),
+        ]);
+        let query = |patterns: &[&str]| {
+            let patterns = patterns.map(|x| TargetPattern::new(x));
+            expand(&targets, &patterns).map(|xs| xs.map(|x| x.label().to_string()))
+        };
+
+        assert_eq!(
+            query(&["foo//bar/...", "foo//baz"]).unwrap(),
+            vec![
+                "foo//bar:alias_alias",
+                "foo//bar:cycle_a",
+                "foo//bar:cycle_b",
+                "foo//bar:lib",
+                "foo//bar:lib_alias",
+                "foo//bar:missing_alias",
+                "foo//bar/baz:bin",
+                "foo//baz:baz",
+            ]
+        );
+        assert_eq!(
+            query(&["foo//bar/baz:", "foo//bar:lib"]).unwrap(),
+            vec!["foo//bar:lib", "foo//bar/baz:bin"]
+        );
+        assert_eq!(query(&["foo//qux:"]).unwrap(), Vec::<String>::new());
+        assert!(query(&["foo//bar:missing"]).is_err());
+
+        let by_label = targets.targets_by_label();
+        let resolve =
+            |x: &str| resolve_alias(&by_label, &TargetLabel::new(x)).map(|x| x.to_string());
+        assert_eq!(resolve("foo//bar:alias_alias").unwrap(), "foo//bar:lib");
+        assert_eq!(resolve("foo//bar:lib").unwrap(), "foo//bar:lib");
+        assert_eq!(
+            resolve("foo//bar:missing_alias").unwrap(),
+            "foo//bar:missing"
+        );
+        assert!(resolve("foo//bar:cycle_a").is_err());
+    }
+}
@@ -0,0 +1,402 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Filter expressions selecting which impacted targets to output, modelled on the
+//! filters of `buck2 uquery`, e.g.
+//! `kind("rust_.*") and not attrfilter(labels, "ci_disabled")`.
+//!
+//! The functions are:
+//!
+//! * `kind(REGEX)`, targets whose short rule type (e.g. `rust_test`) contains a
+//!   match of `REGEX`.
+//! * `attrfilter(NAME, VALUE)`, targets whose attribute `NAME` is `VALUE`, or a
+//!   list containing it.
+//! * `attrregexfilter(NAME, REGEX)`, targets whose attribute `NAME`, or one of its
+//!   elements, contains a match of `REGEX`.
+//!
+//! Combined with `and`, `or` and `not` (binding tightest), and grouped with
+//! parentheses. Arguments are either quoted with `"`, with `\` escaping the next
+//! character, or bare words of letters, digits and `_.-/:*`.
+//!
+//! The attributes `name`, `labels`, `oncall`, `deps`, `ci_deps`, `tests` and
+//! `visibility` come from the target itself, any other from the attributes BTD
+//! doesn't interpret, which the targets files must contain.
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::buck::targets::BuckTarget;
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("Expected {expected} at position {position} of filter `{filter}`")]
+    Expected {
+        expected: &'static str,
+        position: usize,
+        filter: String,
+    },
+    #[error("Unknown filter function `{0}`, expected `kind`, `attrfilter` or `attrregexfilter`")]
+    UnknownFunction(String),
+    #[error("Filter function `{name}` takes {expected} arguments, got {actual}")]
+    Arguments {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Invalid regex `{0}` in filter")]
+    Regex(String, #[source] regex::Error),
+}
+
+/// A parsed filter expression.
+#[derive(Debug, Clone)]
+pub enum Filter {
+    Kind(Regex),
+    AttrFilter(String, String),
+    AttrRegexFilter(String, Regex),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            filter,
+            tokens: tokenize(filter)?,
+            index: 0,
+        };
+        let res = parser.or()?;
+        if parser.index < parser.tokens.len() {
+            return Err(parser.expected("`and`, `or` or the end"));
+        }
+        Ok(res)
+    }
+
+    pub fn matches(&self, target: &BuckTarget) -> bool {
+        match self {
+            Self::Kind(regex) => regex.is_match(target.rule_type.short()),
+            Self::AttrFilter(name, value) => match builtin_attribute(target, name) {
+                Some(xs) => xs.contains(&value.as_str()),
+                None => target.attributes.matches(name, value),
+            },
+            Self::AttrRegexFilter(name, regex) => match builtin_attribute(target, name) {
+                Some(xs) => xs.iter().any(|x| regex.is_match(x)),
+                None => match target.attributes.get(name) {
+                    Some(serde_json::Value::Array(xs)) => xs.iter().any(|x| json_matches(regex, x)),
+                    Some(x) => json_matches(regex, x),
+                    None => false,
+                },
+            },
+            Self::Not(x) => !x.matches(target),
+            Self::And(a, b) => a.matches(target) && b.matches(target),
+            Self::Or(a, b) => a.matches(target) || b.matches(target),
+        }
+    }
+}
+
+/// The values of the attributes BTD interprets itself, or `None` for any other.
+fn builtin_attribute<'a>(target: &'a BuckTarget, name: &str) -> Option<Vec<&'a str>> {
+    Some(match name {
+        "name" => vec![target.name.as_str()],
+        "labels" => target.labels.iter().map(|x| x.as_str()).collect(),
+        "oncall" => target.oncall.iter().map(|x| x.as_str()).collect(),
+        "deps" => target.deps.iter().map(|x| x.as_str()).collect(),
+        "ci_deps" => target.ci_deps.iter().map(|x| x.as_str()).collect(),
+        "tests" => target.tests.iter().map(|x| x.as_str()).collect(),
+        "toolchain_deps" => target.toolchain_deps.iter().map(|x| x.as_str()).collect(),
+        "exec_deps" => target.exec_deps.iter().map(|x| x.as_str()).collect(),
+        "visibility" => target.visibility.iter().map(|x| x.as_str()).collect(),
+        _ => return None,
+    })
+}
+
+/// Strings are matched as they are, anything else by its JSON, e.g. `true` or `3`.
+fn json_matches(regex: &Regex, x: &serde_json::Value) -> bool {
+    match x {
+        serde_json::Value::String(x) => regex.is_match(x),
+        x => regex.is_match(&x.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close,
+    Comma,
+}
+
+/// The tokens of `filter`, with the position each starts at.
+fn tokenize(filter: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || "_.-/:*".contains(c);
+    let mut res = Vec::new();
+    let unclosed = || expected("a closing `\"`", filter.len(), filter);
+    let mut chars = filter.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => {}
+            '(' => res.push((i, Token::Open)),
+            ')' => res.push((i, Token::Close)),
+            ',' => res.push((i, Token::Comma)),
+            '"' => {
+                let mut x = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => x.push(c),
+                            None => return Err(unclosed()),
+                        },
+                        Some((_, c)) => x.push(c),
+                        None => return Err(unclosed()),
+                    }
+                }
+                res.push((i, Token::Quoted(x)));
+            }
+            _ if is_word(c) => {
+                let mut x = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| is_word(*c)) {
+                    x.push(c);
+                }
+                res.push((i, Token::Word(x)));
+            }
+            _ => return Err(expected("a word, string, `(`, `)` or `,`", i, filter)),
+        }
+    }
+    Ok(res)
+}
+
+fn expected(expected: &'static str, position: usize, filter: &str) -> FilterError {
+    FilterError::Expected {
+        expected,
+        position,
+        filter: filter.to_owned(),
+    }
+}
+
+struct Parser<'a> {
+    filter: &'a str,
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|x| &x.1)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let res = self.tokens.get(self.index).map(|x| x.1.clone());
+        self.index += 1;
+        res
+    }
+
+    /// Consume the next token if it is the keyword `word`.
+    fn keyword(&mut self, word: &str) -> bool {
+        let res = matches!(self.peek(), Some(Token::Word(x)) if x == word);
+        if res {
+            self.index += 1;
+        }
+        res
+    }
+
+    fn expect(&mut self, token: Token, expected: &'static str) -> Result<(), FilterError> {
+        if self.peek() == Some(&token) {
+            self.index += 1;
+            Ok(())
+        } else {
+            Err(self.expected(expected))
+        }
+    }
+
+    fn expected(&self, expected: &'static str) -> FilterError {
+        let position = self
+            .tokens
+            .get(self.index)
+            .map_or(self.filter.len(), |x| x.0);
+        self::expected(expected, position, self.filter)
+    }
+
+    fn or(&mut self) -> Result<Filter, FilterError> {
+        let mut res = self.and()?;
+        while self.keyword("or") {
+            res = Filter::Or(Box::new(res), Box::new(self.and()?));
+        }
+        Ok(res)
+    }
+
+    fn and(&mut self) -> Result<Filter, FilterError> {
+        let mut res = self.unary()?;
+        while self.keyword("and") {
+            res = Filter::And(Box::new(res), Box::new(self.unary()?));
+        }
+        Ok(res)
+    }
+
+    fn unary(&mut self) -> Result<Filter, FilterError> {
+        if self.keyword("not") {
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.index += 1;
+            let res = self.or()?;
+            self.expect(Token::Close, "`)`")?;
+            return Ok(res);
+        }
+        let Some(Token::Word(name)) = self.peek().cloned() else {
+            return Err(self.expected("a filter function, `not` or `(`"));
+        };
+        self.index += 1;
+        let args = self.arguments()?;
+        let arity = |expected: usize| {
+            if args.len() == expected {
+                Ok(())
+            } else {
+                Err(FilterError::Arguments {
+                    name: name.clone(),
+                    expected,
+                    actual: args.len(),
+                })
+            }
+        };
+        let regex = |x: &str| Regex::new(x).map_err(|e| FilterError::Regex(x.to_owned(), e));
+        match name.as_str() {
+            "kind" => {
+                arity(1)?;
+                Ok(Filter::Kind(regex(&args[0])?))
+            }
+            "attrfilter" => {
+                arity(2)?;
+                Ok(Filter::AttrFilter(args[0].clone(), args[1].clone()))
+            }
+            "attrregexfilter" => {
+                arity(2)?;
+                Ok(Filter::AttrRegexFilter(args[0].clone(), regex(&args[1])?))
+            }
+            _ => Err(FilterError::UnknownFunction(name)),
+        }
+    }
+
+    /// The parenthesised, comma separated arguments of a function.
+    fn arguments(&mut self) -> Result<Vec<String>, FilterError> {
+        self.expect(Token::Open, "`(`")?;
+        let mut res = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::Word(x) | Token::Quoted(x)) => res.push(x),
+                _ => {
+                    self.index -= 1;
+                    return Err(self.expected("an argument"));
+                }
+            }
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(Token::Close) => return Ok(res),
+                _ => {
+                    self.index -= 1;
+                    return Err(self.expected("`,` or `)`"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use td_util::string::InternString;
+
+    use super::*;
+    use crate::buck::labels::Labels;
+    use crate::buck::targets::Attributes;
+
+    fn target(name: &str, rule_type: &str, labels: &[&str]) -> BuckTarget {
+        BuckTarget {
+            labels: Labels::new(labels),
+            attributes: Attributes::new(vec![
+                (InternString::new("env"), serde_json::json!(["prod", "dev"])),
+                (InternString::new("shards"), serde_json::json!(3)),
+            ]),
+            ..BuckTarget::testing(name, "foo//bar", &format!("prelude//rules.bzl:{rule_type}"))
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let targets = [
+            target("lib", "rust_library", &[]),
+            target("test", "rust_test", &["ci_disabled"]),
+            target("cpp", "cxx_test", &["unit"]),
+        ];
+        let run = |filter: &str| {
+            let filter = Filter::parse(filter).unwrap();
+            targets
+                .iter()
+                .filter(|x| filter.matches(x))
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(r#"kind("rust_.*")"#), vec!["lib", "test"]);
+        assert_eq!(run(r#"kind("_test$")"#), vec!["test", "cpp"]);
+        assert_eq!(run(r#"attrfilter(labels, "ci_disabled")"#), vec!["test"]);
+        assert_eq!(
+            run(r#"kind("rust_.*") and not attrfilter(labels, "ci_disabled")"#),
+            vec!["lib"]
+        );
+        assert_eq!(
+            run("attrfilter(name, lib) or attrfilter(labels, unit)"),
+            vec!["lib", "cpp"]
+        );
+        assert_eq!(
+            run("not (attrfilter(name, lib) or attrfilter(name, cpp))"),
+            vec!["test"]
+        );
+        assert_eq!(
+            run("attrfilter(name, lib) or attrfilter(name, test) and kind(cxx)"),
+            vec!["lib"]
+        );
+        assert_eq!(run("attrfilter(env, dev)").len(), 3);
+        assert_eq!(run("attrfilter(shards, 3)").len(), 3);
+        assert_eq!(run(r#"attrregexfilter(env, "^pr")"#).len(), 3);
+        assert_eq!(run("attrregexfilter(shards, 4)").len(), 0);
+        assert_eq!(
+            run(r#"attrregexfilter(name, "^(lib|cpp)$")"#),
+            vec!["lib", "cpp"]
+        );
+        assert_eq!(run(r#"attrfilter(missing, "a \"b\"")"#).len(), 0);
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        let err = |filter: &str| Filter::parse(filter).unwrap_err().to_string();
+        assert_eq!(
+            err("kind(a"),
+            "Expected `,` or `)` at position 6 of filter `kind(a`"
+        );
+        assert_eq!(
+            err("kind(a) kind(b)"),
+            "Expected `and`, `or` or the end at position 8 of filter `kind(a) kind(b)`"
+        );
+        assert_eq!(
+            err(r#"kind("a)"#),
+            "Expected a closing `\"` at position 8 of filter `kind(\"a)`"
+        );
+        assert_eq!(
+            err("rdeps(a)"),
+            "Unknown filter function `rdeps`, expected `kind`, `attrfilter` or `attrregexfilter`"
+        );
+        assert_eq!(
+            err("attrfilter(a)"),
+            "Filter function `attrfilter` takes 2 arguments, got 1"
+        );
+        assert_eq!(err("kind(\"(\")"), "Invalid regex `(` in filter");
+        assert!(Filter::parse("not").is_err());
+        assert!(Filter::parse("").is_err());
+    }
+}
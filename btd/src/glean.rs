@@ -64,7 +64,7 @@ fn merge<'a>(
             }
         }
         if !res1.is_empty() {
-            res1.sort_by_key(|(x, _)| x.label_key());
+            res1.sort_by_key(|(x, _)| x.order_key());
             res.push(res1)
         }
     }
@@ -76,6 +76,7 @@ mod tests {
     use td_util::prelude::*;
 
     use super::*;
+    use crate::buck::arena::Deps;
     use crate::buck::targets::TargetsEntry;
     use crate::buck::types::CellPath;
     use crate::buck::types::TargetLabel;
@@ -97,18 +98,18 @@ mod tests {
                 ..BuckTarget::testing("exporter", "root//", other)
             }),
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([TargetLabel::new("root//:exporter")]),
+                deps: Deps::from([TargetLabel::new("root//:exporter")]),
                 ..BuckTarget::testing("lib2", "root//", cxx_lib)
             }),
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([
+                deps: Deps::from([
                     TargetLabel::new("root//:lib1"),
                     TargetLabel::new("root//:lib2"),
                 ]),
                 ..BuckTarget::testing("bin1", "root//", cxx_exe)
             }),
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([TargetLabel::new("root//:lib2")]),
+                deps: Deps::from([TargetLabel::new("root//:lib2")]),
                 ..BuckTarget::testing("bin2", "root//", cxx_exe)
             }),
             TargetsEntry::Target(BuckTarget {
@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Coalesce the impacted targets to coarser units, for consumers which only
+//! care about which packages or directories are impacted, or which oncalls own them.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fmt::Display;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::Oncall;
+use crate::buck::types::Package;
+use crate::diff::ImpactReason;
+
+/// What each item of the output stands for.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    /// Each impacted target.
+    #[default]
+    Targets,
+    /// Each package containing an impacted target.
+    Packages,
+    /// The fewest directories containing every impacted package, i.e. packages
+    /// beneath another impacted package are left out.
+    Directories,
+    /// Each oncall owning an impacted target, with the number of impacted targets it owns.
+    Oncalls,
+}
+
+/// The impacted targets owned by an oncall, or without one if `oncall` is `None`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct OncallImpact {
+    pub oncall: Option<Oncall>,
+    pub targets: usize,
+}
+
+impl Display for OncallImpact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let oncall = self.oncall.as_ref().map_or("<none>", |x| x.as_str());
+        write!(f, "{oncall} {}", self.targets)
+    }
+}
+
+/// The impacted packages or directories, sorted and without duplicates. With `patterns`
+/// they are given as target patterns, `foo//bar:` for a package and `foo//bar/...`
+/// for a directory.
+///
+/// Must not be called with [`Granularity::Targets`], which needs no coalescing, or
+/// [`Granularity::Oncalls`], which is counted by [`group_by_oncall`].
+pub fn coalesce(
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    granularity: Granularity,
+    patterns: bool,
+) -> Vec<String> {
+    let packages: BTreeSet<&Package> = levels.iter().flatten().map(|(x, _)| &x.package).collect();
+    match granularity {
+        Granularity::Targets => unreachable!("targets are not coalesced"),
+        Granularity::Oncalls => unreachable!("oncalls are counted by `group_by_oncall`"),
+        Granularity::Packages => packages
+            .into_iter()
+            .map(|x| {
+                if patterns {
+                    x.as_pattern().as_str().to_owned()
+                } else {
+                    x.as_str().to_owned()
+                }
+            })
+            .collect(),
+        Granularity::Directories => {
+            // Sorted, so a package comes before those in its subdirectories
+            let mut res: BTreeSet<&str> = BTreeSet::new();
+            for x in packages {
+                if !ancestors(x.as_str()).any(|dir| res.contains(dir)) {
+                    res.insert(x.as_str());
+                }
+            }
+            res.into_iter()
+                .map(|x| {
+                    if !patterns {
+                        x.to_owned()
+                    } else if x.ends_with('/') {
+                        format!("{x}...")
+                    } else {
+                        format!("{x}/...")
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+/// The number of impacted targets owned by each oncall, most first, so the changes
+/// with the largest blast radius can be routed to the oncalls they affect most.
+pub fn group_by_oncall(levels: &[Vec<(&BuckTarget, ImpactReason)>]) -> Vec<OncallImpact> {
+    let mut counts: BTreeMap<Option<&str>, usize> = BTreeMap::new();
+    for (x, _) in levels.iter().flatten() {
+        *counts
+            .entry(x.oncall.as_ref().map(|x| x.as_str()))
+            .or_default() += 1;
+    }
+    let mut res = counts
+        .into_iter()
+        .map(|(oncall, targets)| OncallImpact {
+            oncall: oncall.map(Oncall::new),
+            targets,
+        })
+        .collect::<Vec<_>>();
+    // Stable, so ties stay sorted by oncall
+    res.sort_by_key(|x| Reverse(x.targets));
+    res
+}
+
+/// The packages containing `package`, excluding itself, e.g. `foo//` and `foo//bar`
+/// for `foo//bar/baz`.
+fn ancestors(package: &str) -> impl Iterator<Item = &str> {
+    let root = package.find("//").map_or(0, |i| i + 2);
+    let cell = (root < package.len()).then(|| &package[..root]);
+    cell.into_iter().chain(
+        package[root..]
+            .match_indices('/')
+            .map(move |(i, _)| &package[..root + i]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::RootImpactKind;
+
+    #[test]
+    fn test_coalesce() {
+        let targets = [
+            BuckTarget::testing("a", "foo//bar", "prelude//rules.bzl:cxx_library"),
+            BuckTarget::testing("b", "foo//bar", "prelude//rules.bzl:cxx_library"),
+            BuckTarget::testing("c", "foo//bar/baz", "prelude//rules.bzl:cxx_library"),
+            BuckTarget::testing("d", "foo//bar-baz", "prelude//rules.bzl:cxx_library"),
+            BuckTarget::testing("e", "bar//", "prelude//rules.bzl:cxx_library"),
+            BuckTarget::testing("f", "bar//qux", "prelude//rules.bzl:cxx_library"),
+        ];
+        let reason = |x| ImpactReason::new(x, RootImpactKind::Inputs);
+        let levels = vec![
+            targets[..3].iter().map(|x| (x, reason(x))).collect(),
+            targets[3..].iter().map(|x| (x, reason(x))).collect(),
+        ];
+
+        assert_eq!(
+            coalesce(&levels, Granularity::Packages, false),
+            vec![
+                "bar//",
+                "bar//qux",
+                "foo//bar",
+                "foo//bar-baz",
+                "foo//bar/baz"
+            ]
+        );
+        assert_eq!(
+            coalesce(&levels, Granularity::Packages, true),
+            vec![
+                "bar//:",
+                "bar//qux:",
+                "foo//bar:",
+                "foo//bar-baz:",
+                "foo//bar/baz:"
+            ]
+        );
+        assert_eq!(
+            coalesce(&levels, Granularity::Directories, false),
+            vec!["bar//", "foo//bar", "foo//bar-baz"]
+        );
+        assert_eq!(
+            coalesce(&levels, Granularity::Directories, true),
+            vec!["bar//...", "foo//bar/...", "foo//bar-baz/..."]
+        );
+    }
+
+    #[test]
+    fn test_group_by_oncall() {
+        let target = |name: &str, oncall: Option<&str>| BuckTarget {
+            oncall: oncall.map(Oncall::new),
+            ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+        };
+        let targets = [
+            target("a", Some("team_b")),
+            target("b", None),
+            target("c", Some("team_a")),
+            target("d", Some("team_b")),
+            target("e", Some("team_c")),
+        ];
+        let reason = |x| ImpactReason::new(x, RootImpactKind::Inputs);
+        let levels = vec![
+            targets[..2].iter().map(|x| (x, reason(x))).collect(),
+            targets[2..].iter().map(|x| (x, reason(x))).collect(),
+        ];
+        let res = group_by_oncall(&levels);
+        assert_eq!(
+            res.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+            vec!["team_b 2", "<none> 1", "team_a 1", "team_c 1"]
+        );
+        assert_eq!(
+            serde_json::to_value(&res[..2]).unwrap(),
+            serde_json::json!([
+                {"oncall": "team_b", "targets": 2},
+                {"oncall": null, "targets": 1},
+            ])
+        );
+    }
+}
@@ -19,10 +19,13 @@ use td_util::no_hash::BuildNoHash;
 use crate::buck::targets::BuckTarget;
 use crate::buck::targets::Targets;
 use crate::buck::types::TargetLabel;
-use crate::buck::types::TargetLabelKeyRef;
+use crate::classify::Classifier;
 use crate::diff::ImpactReason;
 use crate::output::Output;
 use crate::output::OutputFormat;
+use crate::owners::Owners;
+use crate::propagate::propagated_labels;
+use crate::propagate::PropagatedLabels;
 
 pub struct GraphSize {
     base: TargetsSize,
@@ -81,7 +84,9 @@ impl GraphSize {
     pub fn print_recursive_changes(
         &mut self,
         changes: &[Vec<(&BuckTarget, ImpactReason)>],
-        sudos: &HashSet<TargetLabelKeyRef>,
+        propagated: &PropagatedLabels,
+        owners: &Owners,
+        classifier: &Classifier,
         output: OutputFormat,
     ) {
         let items = changes
@@ -89,12 +94,14 @@ impl GraphSize {
             .enumerate()
             .flat_map(|(depth, xs)| {
                 xs.iter()
-                    .map(move |&(x, ref r)| (depth, x, sudos.contains(&x.label_key()), r.clone()))
+                    .map(move |&(x, ref r)| (depth, x, propagated_labels(propagated, x), r.clone()))
             })
             .collect::<Vec<_>>()
             .into_par_iter()
-            .map(|(depth, x, uses_sudo, reason)| OutputWithSize {
-                output: Output::from_target(x, depth as u64, uses_sudo, reason),
+            .map(|(depth, x, labels, reason)| OutputWithSize {
+                output: Output::from_target(x, depth as u64, &labels, reason)
+                    .with_owners(owners.get(&x.package))
+                    .with_class(classifier),
                 before_size: self.base.get(&x.label()),
                 after_size: self.diff.get(&x.label()),
             })
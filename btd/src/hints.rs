@@ -0,0 +1,198 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Dependencies Buck doesn't know about, e.g. a service reading a config file at
+//! runtime, or the consumers of generated code, from a checked-in hints file.
+//!
+//! Each line is a source followed by the targets depending on it, e.g.
+//! `fbcode/foo/config/*.json fbcode//foo:server fbcode//bar:client`.
+//! A source containing `//` is a target pattern, otherwise it is a glob of
+//! paths relative to the root of the repo. Lines starting with `#` are comments.
+//!
+//! The hints are added to the dependent targets as `ci_deps` and `ci_srcs`,
+//! so they are followed exactly like those attributes.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context as _;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::buck::targets::Targets;
+use crate::buck::types::Glob;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetPattern;
+
+#[derive(Debug, Error)]
+enum HintsError {
+    #[error("Line {0}: `{1}` has no dependent targets")]
+    NoDependents(usize, String),
+    #[error("Line {0}: dependent `{1}` must be a target, like `cell//pkg:name`")]
+    NotATarget(usize, String),
+}
+
+/// The extra dependencies of each dependent target.
+#[derive(Debug, Default)]
+struct Extra {
+    ci_srcs: Vec<Glob>,
+    ci_deps: Vec<TargetPattern>,
+}
+
+#[derive(Debug, Default)]
+pub struct DependencyHints {
+    dependents: HashMap<TargetLabel, Extra>,
+}
+
+impl DependencyHints {
+    pub fn read_file(file: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading dependency hints `{}`", file.display()))?;
+        Self::parse(&data)
+            .with_context(|| format!("When parsing dependency hints `{}`", file.display()))
+    }
+
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let mut res = Self::default();
+        for (i, line) in data.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            let Some(source) = words.next().filter(|x| !x.starts_with('#')) else {
+                continue;
+            };
+            let mut any = false;
+            for dependent in words {
+                let pattern = TargetPattern::new(dependent);
+                let Some(label) = pattern.as_target_label() else {
+                    return Err(HintsError::NotATarget(i + 1, dependent.to_owned()).into());
+                };
+                let extra = res.dependents.entry(label).or_default();
+                if source.contains("//") {
+                    extra.ci_deps.push(TargetPattern::new(source));
+                } else {
+                    extra.ci_srcs.push(Glob::new(source));
+                }
+                any = true;
+            }
+            if !any {
+                return Err(HintsError::NoDependents(i + 1, source.to_owned()).into());
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dependents.is_empty()
+    }
+
+    /// Add the hints to the dependent targets. Hints they already have are skipped,
+    /// so applying them twice changes nothing.
+    pub fn apply(&self, mut targets: Targets) -> Targets {
+        if self.is_empty() {
+            return targets;
+        }
+        let mut found = 0;
+        for target in targets.targets_mut() {
+            let Some(extra) = self.dependents.get(&target.label()) else {
+                continue;
+            };
+            found += 1;
+            target.ci_srcs = merge(&target.ci_srcs, &extra.ci_srcs);
+            target.ci_deps = merge(&target.ci_deps, &extra.ci_deps);
+        }
+        if found < self.dependents.len() {
+            warn!(
+                "{} targets in the dependency hints were not found",
+                self.dependents.len() - found
+            );
+        }
+        targets
+    }
+}
+
+fn merge<T: Clone + PartialEq>(old: &[T], extra: &[T]) -> Box<[T]> {
+    let mut res = old.to_vec();
+    for x in extra {
+        if !res.contains(x) {
+            res.push(x.clone());
+        }
+    }
+    res.into_boxed_slice()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::CellPath;
+    use crate::changes::Changes;
+    use crate::diff;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_dependency_hints() {
+        let hints = DependencyHints::parse(
+            "# Runtime dependencies\n\
+             foo/config/*.json foo//bar:server\n\
+             \n\
+             foo//codegen:schema foo//bar:client foo//bar:server\n",
+        )
+        .unwrap();
+        let targets = || {
+            Targets::new(vec![
+                TargetsEntry::Target(BuckTarget {
+                    inputs: Box::new([CellPath::new("foo//codegen/schema.thrift")]),
+                    ..BuckTarget::testing("schema", "foo//codegen", "prelude//rules.bzl:thrift")
+                }),
+                TargetsEntry::Target(BuckTarget::testing(
+                    "server",
+                    "foo//bar",
+                    "prelude//rules.bzl:cxx_binary",
+                )),
+                TargetsEntry::Target(BuckTarget::testing(
+                    "client",
+                    "foo//bar",
+                    "prelude//rules.bzl:cxx_binary",
+                )),
+            ])
+        };
+        let targets = hints.apply(hints.apply(targets()));
+        let server = targets
+            .targets()
+            .find(|x| x.name.as_str() == "server")
+            .unwrap();
+        assert_eq!(&*server.ci_srcs, &[Glob::new("foo/config/*.json")]);
+        assert_eq!(
+            &*server.ci_deps,
+            &[TargetPattern::new("foo//codegen:schema")]
+        );
+
+        let impacted = |file: &str| {
+            let changes = Changes::testing(&[Status::Modified(CellPath::new(file))]);
+            let immediate = diff::immediate_target_changes(&targets, &targets, &changes, false);
+            diff::recursive_target_changes(&targets, &immediate, None, |_| true)
+                .iter()
+                .flatten()
+                .map(|(x, _)| x.label().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            impacted("root//foo/config/prod.json"),
+            vec!["foo//bar:server"]
+        );
+        assert_eq!(
+            impacted("foo//codegen/schema.thrift"),
+            vec!["foo//codegen:schema", "foo//bar:client", "foo//bar:server"]
+        );
+
+        assert!(DependencyHints::parse("foo/config.json").is_err());
+        assert!(DependencyHints::parse("foo/config.json foo//bar/...").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Drop changed files which should never impact anything, e.g. generated snapshots,
+//! lockfiles and docs, before they are attributed to targets. The patterns come from
+//! a `.btdignore` file, in the same syntax as `.gitignore`, and `--ignore-pattern`.
+
+use std::path::Path;
+
+use ignore::gitignore::Gitignore;
+use ignore::gitignore::GitignoreBuilder;
+use tracing::info;
+
+use crate::buck::types::ProjectRelativePath;
+use crate::changes::Changes;
+
+/// Read when no `--ignore-file` is given, if it exists in the current directory,
+/// which is normally the root of the repo.
+pub const DEFAULT_FILE: &str = ".btdignore";
+
+pub struct IgnoreFile(Gitignore);
+
+impl IgnoreFile {
+    /// The patterns in `file` (or `.btdignore`, if it exists), followed by `patterns`,
+    /// so a later `!pattern` can bring back a file an earlier pattern ignored.
+    pub fn new(file: Option<&Path>, patterns: &[String]) -> anyhow::Result<Self> {
+        // Changed paths are relative to the repo root, as are the patterns
+        let mut builder = GitignoreBuilder::new(".");
+        let file = file.or_else(|| Some(Path::new(DEFAULT_FILE)).filter(|x| x.exists()));
+        if let Some(file) = file {
+            if let Some(e) = builder.add(file) {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("When reading ignore file `{}`", file.display())));
+            }
+        }
+        for pattern in patterns {
+            builder.add_line(None, pattern)?;
+        }
+        Ok(Self(builder.build()?))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether `path`, or any directory containing it, is ignored.
+    pub fn is_ignored(&self, path: &ProjectRelativePath) -> bool {
+        self.0
+            .matched_path_or_any_parents(path.as_str(), false)
+            .is_ignore()
+    }
+
+    /// Drop the ignored files from `changes`.
+    pub fn apply(&self, changes: Changes) -> Changes {
+        if self.is_empty() {
+            return changes;
+        }
+        let before = changes.status_paths().count();
+        let res = changes.filter(|_, path| !self.is_ignored(path));
+        info!(
+            "Ignored {} of {before} changed files",
+            before - res.status_paths().count()
+        );
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::buck::cells::CellInfo;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_ignore_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(".btdignore");
+        fs::write(
+            &file,
+            "# Generated\n*.snap\nCargo.lock\ndocs/\n!docs/BUCK\n",
+        )
+        .unwrap();
+        let ignore = IgnoreFile::new(Some(&file), &["/third-party/**/*.md".to_owned()]).unwrap();
+        let ignored = |x| ignore.is_ignored(&ProjectRelativePath::new(x));
+        assert!(ignored("foo/bar/test.snap"));
+        assert!(ignored("Cargo.lock"));
+        assert!(ignored("foo/Cargo.lock"));
+        assert!(ignored("docs/guide/intro.md"));
+        assert!(!ignored("docs/BUCK"));
+        assert!(ignored("third-party/foo/README.md"));
+        assert!(!ignored("foo/third-party/README.md"));
+        assert!(!ignored("foo/bar.rs"));
+
+        let changes = Changes::new(
+            &CellInfo::testing(),
+            vec![
+                Status::Modified(ProjectRelativePath::new("fbcode/foo/bar.rs")),
+                Status::Added(ProjectRelativePath::new("fbcode/foo/bar.snap")),
+            ],
+        )
+        .unwrap();
+        let changes = ignore.apply(changes);
+        assert_eq!(
+            changes
+                .project_paths()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>(),
+            ["fbcode/foo/bar.rs"]
+        );
+
+        let missing = IgnoreFile::new(Some(&dir.path().join("missing")), &[]);
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Custom impact rules, for repos whose build has impact BTD can't see in the graph,
+//! without patching the diff itself.
+//!
+//! A rule can hook into three phases: seeding extra targets as changed, adding edges to
+//! follow when traversing the reverse dependencies, and adjusting the impacted targets
+//! before they are output. A binary wrapping BTD registers its rules alongside
+//! [`Registry::builtin`] and runs [`main_with_registry`](crate::main_with_registry),
+//! and each run enables those it wants with `--impact-rule NAME`. With the library,
+//! [`Config::impact_rule`](crate::api::Config::impact_rule) applies a rule directly.
+
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::changes::Changes;
+use crate::diff::ImpactReason;
+use crate::diff::RootImpactKind;
+
+#[derive(Debug, Error)]
+enum ImpactRuleError {
+    #[error("Unknown impact rule `{0}`, expected one of: {1}")]
+    Unknown(String, String),
+}
+
+/// A custom rule for what a change impacts. Every hook does nothing by default.
+pub trait ImpactRule: Send + Sync {
+    /// The name to enable the rule with, as `--impact-rule NAME`.
+    fn name(&self) -> &str;
+
+    /// Targets in `diff` which changed because of `changes`, besides those BTD finds.
+    /// Their impact is followed as for any changed target.
+    fn seed<'a>(&self, _diff: &'a Targets, _changes: &Changes) -> Vec<&'a BuckTarget> {
+        Vec::new()
+    }
+
+    /// Labels which `target` also counts as depending on, so impacting any of them
+    /// impacts `target`.
+    fn extra_deps(&self, _target: &BuckTarget) -> Vec<TargetLabel> {
+        Vec::new()
+    }
+
+    /// Adjust the impacted targets, by depth, before they are output, e.g. to drop some.
+    fn output<'a>(&self, _levels: &mut Vec<Vec<(&'a BuckTarget, ImpactReason)>>) {}
+}
+
+/// A change to a Thrift schema impacts every `thrift_library`, as the code generated
+/// for one may depend on schemas it doesn't list, through includes resolved by the
+/// compiler.
+struct ThriftSchema;
+
+impl ImpactRule for ThriftSchema {
+    fn name(&self) -> &str {
+        "thrift_schema"
+    }
+
+    fn seed<'a>(&self, diff: &'a Targets, changes: &Changes) -> Vec<&'a BuckTarget> {
+        if !changes
+            .cell_paths()
+            .any(|x| x.extension() == Some("thrift"))
+        {
+            return Vec::new();
+        }
+        diff.targets()
+            .filter(|x| x.rule_type.short() == "thrift_library")
+            .collect()
+    }
+}
+
+/// The impact rules which can be enabled by name.
+#[derive(Default)]
+pub struct Registry(Vec<Box<dyn ImpactRule>>);
+
+impl Registry {
+    /// The rules built into BTD.
+    pub fn builtin() -> Self {
+        let mut res = Self::default();
+        res.register(Box::new(ThriftSchema));
+        res
+    }
+
+    /// Add `rule`, replacing any rule with the same name.
+    pub fn register(&mut self, rule: Box<dyn ImpactRule>) {
+        self.0.retain(|x| x.name() != rule.name());
+        self.0.push(rule);
+    }
+
+    /// The rules named by `names`, in the order given, failing on any name not registered.
+    pub fn select(mut self, names: &[String]) -> anyhow::Result<ImpactRules> {
+        let mut res = Vec::with_capacity(names.len());
+        for name in names {
+            match self.0.iter().position(|x| x.name() == name) {
+                Some(i) => res.push(self.0.remove(i)),
+                // Enabled twice
+                None if res.iter().any(|x| x.name() == name) => {}
+                None => {
+                    let mut known = self
+                        .0
+                        .iter()
+                        .chain(&res)
+                        .map(|x| x.name())
+                        .collect::<Vec<_>>();
+                    known.sort();
+                    return Err(ImpactRuleError::Unknown(name.clone(), known.join(", ")).into());
+                }
+            }
+        }
+        Ok(ImpactRules(res))
+    }
+}
+
+/// The impact rules enabled for a run, applying each hook of every rule in turn.
+#[derive(Default)]
+pub struct ImpactRules(Vec<Box<dyn ImpactRule>>);
+
+impl ImpactRules {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Enable `rule` too, after those already enabled.
+    pub fn push(&mut self, rule: Box<dyn ImpactRule>) {
+        self.0.push(rule);
+    }
+
+    /// The targets seeded by any rule, each once, in the order they were first seeded.
+    pub fn seed<'a>(
+        &self,
+        diff: &'a Targets,
+        changes: &Changes,
+    ) -> Vec<(&'a BuckTarget, ImpactReason)> {
+        let mut seen = HashSet::new();
+        self.0
+            .iter()
+            .flat_map(|x| x.seed(diff, changes))
+            .filter(|x| seen.insert(x.label()))
+            .map(|x| (x, ImpactReason::new(x, RootImpactKind::ImpactRule)))
+            .collect()
+    }
+
+    pub fn extra_deps(&self, target: &BuckTarget) -> Vec<TargetLabel> {
+        self.0.iter().flat_map(|x| x.extra_deps(target)).collect()
+    }
+
+    pub fn output<'a>(&self, levels: &mut Vec<Vec<(&'a BuckTarget, ImpactReason)>>) {
+        for x in &self.0 {
+            x.output(levels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use td_util::prelude::*;
+
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::CellPath;
+    use crate::diff;
+    use crate::diff::GraphImpact;
+    use crate::rdeps::Edges;
+    use crate::rdeps::Rdeps;
+    use crate::sapling::status::Status;
+
+    /// Binaries depend on `foo//config:flags`, though they don't list it, and tests are
+    /// never reported.
+    struct Flags;
+
+    impl ImpactRule for Flags {
+        fn name(&self) -> &str {
+            "flags"
+        }
+
+        fn extra_deps(&self, target: &BuckTarget) -> Vec<TargetLabel> {
+            if target.rule_type.short() == "cxx_binary" {
+                vec![TargetLabel::new("foo//config:flags")]
+            } else {
+                Vec::new()
+            }
+        }
+
+        fn output<'a>(&self, levels: &mut Vec<Vec<(&'a BuckTarget, ImpactReason)>>) {
+            for level in levels {
+                level.retain(|(x, _)| x.rule_type.short() != "cxx_test");
+            }
+        }
+    }
+
+    #[test]
+    fn test_impact_rules() {
+        let target = |name: &str, rule_type: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", rule_type)
+            })
+        };
+        let flags = BuckTarget::testing("flags", "foo//config", "prelude//rules.bzl:config");
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(flags.clone()),
+            target("if", "prelude//rules.bzl:thrift_library", &[]),
+            target("bin", "prelude//rules.bzl:cxx_binary", &[]),
+            target("test", "prelude//rules.bzl:cxx_test", &["foo//bar:bin"]),
+        ]);
+
+        let mut registry = Registry::builtin();
+        registry.register(Box::new(Flags));
+        assert!(Registry::builtin().select(&["missing".to_owned()]).is_err());
+        let rules = registry
+            .select(&["thrift_schema".to_owned(), "flags".to_owned()])
+            .unwrap();
+
+        let changes = Changes::testing(&[Status::Modified(CellPath::new("foo//bar/if.thrift"))]);
+        let seeded = rules.seed(&targets, &changes);
+        assert_eq!(
+            seeded.map(|(x, r)| (x.name.as_str(), r.root_cause.1)),
+            vec![("if", RootImpactKind::ImpactRule)]
+        );
+        assert!(rules.seed(&targets, &Changes::default()).is_empty());
+
+        // Changing the flags impacts the binary through the extra dep, and its test
+        // through the binary, but the test isn't output
+        let mut immediate = GraphImpact::from_recursive(vec![(
+            &flags,
+            ImpactReason::new(&flags, RootImpactKind::Hash),
+        )]);
+        immediate.add_recursive(seeded);
+        let rdeps = Rdeps::with_rules(&targets, Edges::default(), &rules);
+        let mut levels = Vec::new();
+        diff::recursive_target_changes_with_rdeps(
+            &targets,
+            Some(&rdeps),
+            &immediate,
+            None,
+            true,
+            |_| true,
+            |level| levels.push(level),
+        );
+        rules.output(&mut levels);
+        assert_eq!(
+            levels.map(|level| level.map(|(x, _)| x.name.as_str())),
+            vec![vec!["if", "flags"], vec!["bin"], vec![]]
+        );
+    }
+}
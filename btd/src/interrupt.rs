@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Stop the traversal on SIGTERM or SIGINT, e.g. when CI times a run out, so the
+//! impacted targets found so far are still written, marked as `partial`.
+//!
+//! The traversal only asks an [`Interrupt`], so platforms without signals, e.g.
+//! WebAssembly, can stop it some other way with [`set_source`].
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+/// The exit code after writing partial results, distinct from success and failure.
+pub const EXIT_CODE: i32 = 3;
+
+/// Whether a long running traversal should stop early.
+pub trait Interrupt: Send + Sync {
+    fn interrupted(&self) -> bool;
+}
+
+impl Interrupt for AtomicBool {
+    fn interrupted(&self) -> bool {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+static SOURCE: OnceLock<Arc<dyn Interrupt>> = OnceLock::new();
+
+/// From now on, ask `source` whether to stop. Returns `false`, leaving things as they
+/// were, if a source was already set, e.g. by [`install`].
+pub fn set_source(source: Arc<dyn Interrupt>) -> bool {
+    SOURCE.set(source).is_ok()
+}
+
+/// From now on, record SIGTERM and SIGINT rather than dying from them. A second
+/// signal exits straight away, with the usual `128 + signal` exit code.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn install() -> anyhow::Result<()> {
+    use signal_hook::consts::SIGINT;
+    use signal_hook::consts::SIGTERM;
+
+    if SOURCE.get().is_some() {
+        return Ok(());
+    }
+    let flag = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        // Registered first, so it only sees the flag set by an earlier signal
+        signal_hook::flag::register_conditional_shutdown(signal, 128 + signal, flag.clone())?;
+        signal_hook::flag::register(signal, flag.clone())?;
+    }
+    set_source(flag);
+    Ok(())
+}
+
+/// There are no signals to record, see [`set_source`] instead.
+#[cfg(target_arch = "wasm32")]
+pub fn install() -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Whether the source set by [`install`] or [`set_source`] says to stop.
+pub fn interrupted() -> bool {
+    SOURCE.get().is_some_and(|x| x.interrupted())
+}
@@ -12,61 +12,194 @@
 
 #![feature(exit_status_error)]
 #![feature(lazy_cell)]
-#![forbid(unsafe_code)]
+#![deny(unsafe_code)]
 // Things we disagree with
 #![allow(clippy::len_without_is_empty)]
 
+pub mod api;
+pub mod audit;
+pub mod blast_radius;
 pub mod buck;
+pub mod bundle;
+pub mod bxl;
+pub mod cache;
+pub mod change_policy;
 pub mod changes;
 pub mod check;
+pub mod checkpoint;
+pub mod classify;
+pub mod configured;
+pub mod cycles;
+pub mod deps;
 pub mod diff;
+pub mod diff_outputs;
+pub mod dot;
+#[cfg(feature = "eden")]
+pub mod eden;
+pub mod expand;
+pub mod filter;
 pub mod glean;
+pub mod granularity;
 pub mod graph_size;
+pub mod hints;
+pub mod ignore_file;
+pub mod impact_rule;
+pub mod interrupt;
+pub mod minimize;
+pub mod normalize;
 pub mod output;
+pub mod owners;
+pub mod package_error;
+pub mod propagate;
+// The `pyo3` macros expand to unsafe code
+#[cfg(feature = "btd-py")]
+#[allow(unsafe_code)]
+pub mod python;
+pub mod quarantine;
+pub mod range;
+pub mod ranker;
+pub mod rdeps;
 pub mod rerun;
 pub mod sapling;
+pub mod schedulable;
+pub mod score;
+#[cfg(unix)]
+pub mod serve;
+pub mod setop;
+pub mod snapshot;
+pub mod stats;
 pub mod sudo;
+pub mod testing;
+pub mod validate;
+pub mod visibility;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod why;
 
+use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::stdout;
+use std::io::BufWriter;
+use std::io::Write;
 use std::mem::ManuallyDrop;
 use std::ops::Deref;
+use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 use std::time::Instant;
 
 use anyhow::Context as _;
 use buck::types::Package;
+use clap::CommandFactory;
 use clap::Parser;
+use clap::Subcommand;
 use serde::Serialize;
+use td_util::command::RetryPolicy;
 use td_util::json;
 use td_util::prelude::*;
+use td_util::string::InternString;
 use tempfile::NamedTempFile;
 use thiserror::Error;
 use tracing::error;
 use tracing::info;
+use tracing::info_span;
+use tracing::warn;
 
+use crate::audit::AuditArgs;
+use crate::blast_radius::BlastRadiusArgs;
 use crate::buck::cells::CellInfo;
 use crate::buck::run::Buck2;
 use crate::buck::targets::BuckTarget;
+use crate::buck::targets::ReadOptions;
 use crate::buck::targets::Targets;
-use crate::buck::types::TargetLabelKeyRef;
+use crate::buck::types::ParsedTargetPattern;
+use crate::buck::types::RuleType;
+use crate::buck::types::TargetLabel;
 use crate::buck::types::TargetPattern;
+use crate::bundle::RecordOptions;
+use crate::cache::ImpactCache;
+use crate::change_policy::ChangePolicy;
+use crate::change_policy::PolicyImpact;
+use crate::changes::Attribution;
 use crate::changes::Changes;
+use crate::changes::PackageChangePolicy;
 use crate::check::ValidationError;
+use crate::checkpoint::Checkpoint;
+use crate::checkpoint::Phase;
+use crate::classify::Classifier;
+use crate::classify::ClassifyConfig;
+use crate::deps::DepsArgs;
+use crate::diff::AttributeDiff;
+use crate::diff::BrokenPackagePolicy;
+use crate::diff::DiffMode;
+use crate::diff::ImmediateOptions;
 use crate::diff::ImpactReason;
 use crate::diff::RootImpactKind;
+use crate::diff_outputs::DiffOutputsArgs;
+use crate::expand::ExpandArgs;
+use crate::filter::Filter;
+use crate::granularity::Granularity;
 use crate::graph_size::GraphSize;
+use crate::hints::DependencyHints;
+use crate::ignore_file::IgnoreFile;
+use crate::impact_rule::Registry;
+use crate::minimize::MinimizeArgs;
+use crate::normalize::FileContents;
+use crate::output::DocumentV2;
+use crate::output::JsonLinesWriter;
+use crate::output::Metadata;
 use crate::output::Output;
+use crate::output::OutputEncoding;
 use crate::output::OutputFormat;
+use crate::output::OutputSchema;
+use crate::output::Partial;
+use crate::output::Quarantined;
+use crate::output::Removed;
+use crate::output::RemovedTarget;
+use crate::output::Truncated;
+use crate::owners::Owners;
+use crate::package_error::ErrorCategory;
+use crate::package_error::PackageError;
+use crate::propagate::propagated_labels;
+use crate::propagate::Direction;
+use crate::propagate::PropagatedLabels;
+use crate::quarantine::Quarantine;
+use crate::range::RangeArgs;
+use crate::ranker::ShardArgs;
+use crate::rdeps::Edges;
+use crate::rdeps::Rdeps;
+use crate::rdeps::RdepsArgs;
 use crate::rerun::PackageStatus;
+use crate::sapling::status::read_paths;
 use crate::sapling::status::read_status;
+use crate::schedulable::Unschedulable;
+use crate::schedulable::UnschedulablePolicy;
+use crate::score::Budget;
+use crate::score::ScoreWeights;
+use crate::score::Scorer;
+#[cfg(unix)]
+use crate::serve::ServeArgs;
+use crate::setop::SetopArgs;
+use crate::snapshot::SnapshotArgs;
+use crate::stats::Stats;
+use crate::testing::graph_gen;
+use crate::testing::graph_gen::GraphSpec;
+use crate::validate::ValidateArgs;
+use crate::visibility::VisibilityCheck;
 
 /// Buck-based target determinator.
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 pub struct Args {
-    /// File containing the output of `buck2 audit cell` in the root of the repo.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// File containing the output of `buck2 audit cell` in the root of the repo,
+    /// or the `.buckconfig` in the root of the repo to read the cells from directly.
     /// Otherwise will run the Buck command to figure it out.
     #[arg(long, value_name = "FILE")]
     cells: Option<PathBuf>,
@@ -77,43 +210,335 @@ pub struct Args {
     config: Option<PathBuf>,
 
     /// File containing the output of `hg status` for the relevant diff.
+    #[arg(
+        long,
+        value_name = "FILE",
+        required_unless_present_any = [
+            "changes_from_scm",
+            "changed_targets",
+            "simulate_changes",
+            "replay_bundle"
+        ]
+    )]
+    changes: Option<PathBuf>,
+
+    /// Ask source control (Sapling, or git outside a Sapling checkout) for the files
+    /// changed since revision `REV`, rather than reading them from `--changes`.
+    #[arg(long, value_name = "REV", conflicts_with = "changes")]
+    changes_from_scm: Option<String>,
+
+    /// With `--changes-from-scm`, ask EdenFS for the changes from its journal, through
+    /// Watchman, rather than `sl status`, which can be slow on big checkouts. Falls back
+    /// to `sl status` if Eden can't answer. Needs the `eden` feature.
+    #[arg(long, requires = "changes_from_scm")]
+    eden: bool,
+
+    /// File listing paths relative to the root of the repo, one per line, to pretend
+    /// were modified. Their impact is computed on the `--base` targets alone, without
+    /// needing `--diff` or running Buck, e.g. to see what touching a header would break.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["changes", "changes_from_scm", "diff"]
+    )]
+    simulate_changes: Option<PathBuf>,
+
+    /// Drop modified build files, `PACKAGE` files and `.bzl` files from the changes when
+    /// only their comments or whitespace changed. The contents before the change come
+    /// from `--changes-from-scm` or `--file-contents-command`.
+    #[arg(long)]
+    ignore_cosmetic_changes: bool,
+
+    /// A command printing the contents of a file for `--ignore-cosmetic-changes`, run as
+    /// `COMMAND base PATH` or `COMMAND diff PATH`, with `PATH` relative to the repo root.
+    #[arg(long, value_name = "COMMAND", requires = "ignore_cosmetic_changes")]
+    file_contents_command: Option<String>,
+
+    /// A file of patterns for changed files to ignore, in the same syntax as `.gitignore`,
+    /// e.g. `*.snap` or `docs/`. Defaults to `.btdignore`, if it exists.
     #[arg(long, value_name = "FILE")]
-    changes: PathBuf,
+    ignore_file: Option<PathBuf>,
 
-    /// File containing the JSON output from `buck2 targets` base the change.
+    /// A pattern for changed files to ignore, as a line of `--ignore-file`, applied after it.
+    /// May be repeated.
+    #[arg(long, value_name = "PATTERN")]
+    ignore_pattern: Vec<String>,
+
+    /// Also attribute each changed file by its path with symlinks resolved, in the checkout
+    /// rooted at `DIR`, so a change made through a symlinked directory reaches its targets.
+    #[arg(long, value_name = "DIR")]
+    resolve_symlinks: Option<PathBuf>,
+
+    /// Match changed paths to the paths Buck reports ignoring case, for checkouts on
+    /// case-insensitive file systems, e.g. on macOS or Windows.
+    #[arg(long)]
+    case_insensitive_paths: bool,
+
+    /// A JSON file sorting changed files into categories, e.g. `docs` or `ci_config`,
+    /// each with a policy: `ignore`, `attribute-to-package` or `attribute-globally`.
+    /// See `src/change_policy.rs` for the format.
     #[arg(long, value_name = "FILE")]
-    base: PathBuf,
+    change_policy: Option<PathBuf>,
+
+    /// Patterns for targets to treat as changed, alongside any changed files, e.g.
+    /// `foo//lib:core`, to ask what would be impacted if they changed.
+    /// Reported with the reason `manual_for_rerun`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    changed_targets: Vec<String>,
+
+    /// File containing the JSON output from `buck2 targets` base the change.
+    /// With `-` it is read from stdin, e.g. piped from `buck2 targets --streaming`.
+    #[arg(
+        long,
+        value_name = "FILE",
+        required_unless_present_any = ["base_from_scm", "replay_bundle"]
+    )]
+    base: Option<PathBuf>,
+
+    /// Rather than reading `--base`, check out the `--changes-from-scm` revision in a
+    /// temporary Git worktree or Sapling share, and run `buck2 targets` on the universe
+    /// there. The working copy is left alone. Together with leaving out `--diff`, BTD
+    /// runs Buck for both states itself.
+    #[arg(
+        long,
+        requires = "changes_from_scm",
+        conflicts_with_all = ["base", "configured"]
+    )]
+    base_from_scm: bool,
+
+    /// The revision the `--base` targets are of, defaulting to `--changes-from-scm`.
+    /// If `--base` is a snapshot, it must have been written for this revision, with
+    /// `btd snapshot --revision`, and be intact, or BTD fails rather than use a stale graph.
+    #[arg(long, value_name = "REV")]
+    base_revision: Option<String>,
+
+    /// Don't check a `--base` snapshot against the base revision, e.g. in an emergency
+    /// when the right snapshot isn't available.
+    #[arg(long)]
+    skip_verification: bool,
 
     /// File containing the JSON output from `buck2 targets` diff the change.
     /// If left missing, will call `buck2 targets` on the appropriate subset.
+    /// With `-` it is read from stdin, like `--base`, but only one of them can be.
     #[arg(long, value_name = "FILE")]
     diff: Option<PathBuf>,
 
+    /// `--base` and `--diff` are the JSON output of `buck2 cquery --output-all-attributes`,
+    /// so each configuration of a target is considered separately, and reported
+    /// with its configuration hash, e.g. `foo//bar:baz (0123abcd)`.
+    #[arg(long, requires = "diff")]
+    configured: bool,
+
     /// Patterns that represent which targets are of interest, e.g. `fbcode//...`.
+    /// Only targets matching a pattern are traversed and reported, so impact that
+    /// leaves the universe and comes back is not found.
+    /// Target names may use `*` as a wildcard, e.g. `fbcode//foo:test_*`.
     #[arg(long, value_name = "TARGET_PATTERN")]
     universe: Vec<String>,
 
+    /// Patterns for targets to leave out of the output, e.g. `fbcode//experimental/...`.
+    /// Applied after traversal, so targets depending on excluded targets are still reported.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    exclude: Vec<String>,
+
+    /// Leave out of the output the targets whose attribute `NAME` is `VALUE`, or a list
+    /// containing it, e.g. `--exclude-attribute env=dev`. Applied like
+    /// `--exclude`, and only to attributes BTD doesn't interpret itself, which the
+    /// targets files must contain, e.g. from `supertd targets --all-attributes`.
+    #[arg(long, value_name = "NAME=VALUE")]
+    exclude_attribute: Vec<String>,
+
+    /// Only output the targets matching the filter expression `EXPR`, e.g.
+    /// `kind("rust_.*") and not attrfilter(labels, ci_disabled)`, with the `kind`,
+    /// `attrfilter` and `attrregexfilter` functions of `buck2 uquery`, combined with
+    /// `and`, `or` and `not`. Applied like `--exclude`.
+    #[arg(long, value_name = "EXPR")]
+    filter: Option<String>,
+
+    /// Add the attribute `NAME` of each impacted target to its `attributes` in the v2
+    /// output, so attributes BTD doesn't know about still reach the caller.
+    #[arg(long, value_name = "NAME")]
+    output_attribute: Vec<String>,
+
+    /// Rule types at which to stop following the impact, e.g. `config_setting` or
+    /// `prelude//rules.bzl:platform`. Impacted targets of these rules are reported,
+    /// but the targets depending on them are not.
+    #[arg(long, value_name = "RULE_TYPE", conflicts_with = "glean")]
+    terminal_rules: Vec<String>,
+
+    /// Also report the targets named in the `tests` attribute of each impacted target,
+    /// even if they don't depend on it.
+    #[arg(long, conflicts_with_all = ["glean", "load_index"])]
+    follow_tests: bool,
+
+    /// Also report the targets building with an impacted target as a toolchain, i.e.
+    /// naming it in their `toolchain_deps` or `exec_deps` attributes.
+    #[arg(long, conflicts_with_all = ["glean", "load_index"])]
+    follow_toolchain_deps: bool,
+
+    /// Custom impact rules to apply, by name, e.g. `thrift_schema`, which impacts every
+    /// `thrift_library` when a `.thrift` file changes. Binaries wrapping BTD can register
+    /// their own.
+    #[arg(long, value_name = "RULE", conflicts_with_all = ["glean", "load_index"])]
+    impact_rule: Vec<String>,
+
+    /// Rule types to drop from both graphs once read, e.g. `filegroup`, given by short name
+    /// or in full. Targets of these rules are never reported, and neither is impact
+    /// through them, unless `--stitch-ignored-deps`.
+    #[arg(long, value_name = "RULE_TYPE")]
+    ignore_rule_types: Vec<String>,
+
+    /// With `--ignore-rule-types`, targets depending on a dropped target depend on its
+    /// deps instead, so changes below it still impact them.
+    #[arg(long, requires = "ignore_rule_types")]
+    stitch_ignored_deps: bool,
+
+    /// Drop `alias` targets from both graphs once read, making the targets depending on
+    /// an alias depend on what it stands for instead, so they are impacted directly. With
+    /// `--output-format v2`, `aliases` lists the aliases of the impacted targets.
+    #[arg(long)]
+    collapse_aliases: bool,
+
     // Like `universe`, but without a flag - eventually we'll probably delete --universe.
     /// Patterns that represent which targets are of interest, e.g. `fbcode//...`.
     #[arg(value_name = "TARGET_PATTERN")]
     universe2: Vec<String>,
 
-    /// Number of levels of dependency to explore (default to no limit)
-    #[arg(long, value_name = "INT")]
+    /// Number of levels of dependency to explore (default to no limit).
+    /// Each reported target records its `depth`, the distance from the nearest changed target.
+    #[arg(long, value_name = "INT", visible_alias = "max-depth")]
     depth: Option<usize>,
 
+    /// Don't sort the impacted targets at each depth by cell, package and name, which
+    /// saves time on huge impacts. They are then in the order they were found.
+    #[arg(long)]
+    no_sort: bool,
+
     /// Print out the information in JSON format
     #[arg(long)]
     json: bool,
 
-    /// Print out the information in JSON lines format
+    /// Print out the information in JSON lines format, writing out each level of
+    /// impacted targets as soon as it is computed.
     #[arg(long, conflicts_with = "json")]
     json_lines: bool,
 
+    /// The schema of the output. `v2` prints a single JSON document, with the full
+    /// rule type and a categorised reason for each target.
+    #[arg(long, value_enum, default_value_t, conflicts_with = "graph_size")]
+    output_format: OutputSchema,
+
+    /// How to encode the `v2` output: `json`, or a binary schema from `btd/schema` for
+    /// consumers which want one.
+    #[arg(long, value_enum, default_value_t)]
+    output_encoding: OutputEncoding,
+
+    /// In the `v2` output, list the targets which changed themselves, at depth `0`, as
+    /// `immediate_changes`, and those impacted through their dependencies as
+    /// `transitive_changes`, rather than together as `targets`.
+    #[arg(long)]
+    split_immediate: bool,
+
+    /// Report impacted `packages`, or the fewest `directories` containing every impacted
+    /// package, rather than each impacted target. `oncalls` reports each oncall owning
+    /// an impacted target, with how many it owns.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["output_format", "why", "graph_size"]
+    )]
+    granularity: Granularity,
+
+    /// Print at most `N` impacted targets: those which changed first, then by depth, then
+    /// by label. If any are left out, the output is marked `truncated`, so callers know to
+    /// fall back to building everything.
+    #[arg(long, value_name = "N", conflicts_with_all = ["why", "graph_size", "granularity"])]
+    max_output: Option<usize>,
+
+    /// Also list the targets in `--base` but not in `--diff`, with their rule type and
+    /// package, after the impacted targets (or as `removed` with `--output-format v2`).
+    #[arg(long, conflicts_with_all = ["why", "graph_size", "granularity"])]
+    include_removed: bool,
+
+    /// A file of patterns for flaky tests, one per line, each optionally followed by the
+    /// date its quarantine expires, `YYYY-MM-DD`. Impacted tests matching an unexpired
+    /// pattern are listed as `quarantined` after the impacted targets, rather than among
+    /// them, so CI can run them without blocking on them.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["why", "graph_size", "granularity"])]
+    quarantine: Option<PathBuf>,
+
+    /// What to do with impacted targets labelled as not for CI to schedule, by default
+    /// `ci_skip`, `disabled`, `do_not_schedule` or `manual`: `keep` them, `remove` them,
+    /// or `demote` them to be listed as `quarantined`.
+    #[arg(long, value_enum, default_value_t)]
+    unschedulable: UnschedulablePolicy,
+
+    /// Also treat targets with these labels as not for CI to schedule, with
+    /// `--unschedulable`.
+    #[arg(long, value_name = "LABEL")]
+    respect_labels: Vec<String>,
+
+    /// Schedule targets with these labels after all, even if they are among the
+    /// conventional ones, with `--unschedulable`.
+    #[arg(long, value_name = "LABEL")]
+    ignore_labels: Vec<String>,
+
+    /// With `--granularity`, print target patterns: `foo//bar:` for a package and
+    /// `foo//bar/...` for a directory.
+    #[arg(long, requires = "granularity")]
+    patterns: bool,
+
     /// Look for prelude rule changes and dirty inputs in response.
     #[arg(long)]
     track_prelude_rule_changes: bool,
 
+    /// A changed `.bzl` file impacts every target in the packages whose build file
+    /// loads it, directly or through other `.bzl` files, with the reason `loaded_bzl`.
+    #[arg(long)]
+    follow_loads: bool,
+
+    /// How changed files impact targets. With `ownership`, a changed file which is
+    /// not an input of any target impacts all targets in the package owning it.
+    #[arg(long, value_enum, default_value_t)]
+    attribution: Attribution,
+
+    /// How changes to `PACKAGE` and buckconfig files impact targets: all those beneath
+    /// the `PACKAGE` file (and in the cell of the buckconfig) with `subtree`, all those
+    /// in the same cell with `cell`, or only those whose hashes change with `ignore`.
+    #[arg(long, value_enum, default_value_t)]
+    package_change_policy: PackageChangePolicy,
+
+    /// What to do when a package evaluated before the change but fails after it: report
+    /// the error and `fail`, or treat its targets as `removed` or `changed`.
+    #[arg(long, value_enum, default_value_t)]
+    broken_package_policy: BrokenPackagePolicy,
+
+    /// Only warn about packages failing with errors of `CATEGORY`, rather than failing,
+    /// e.g. `io` to ride out a flaky file system. Buck2's error messages are
+    /// categorised as `parse`, `missing_dep`, `io` or `other`.
+    #[arg(long, value_enum, value_name = "CATEGORY")]
+    tolerate_package_error: Vec<ErrorCategory>,
+
+    /// When a target's hash changes, but it still has the same attributes other than
+    /// `NAME` (e.g. `metadata`), don't treat it as changed. Needs targets files with
+    /// all attributes, e.g. from `supertd targets --all-attributes`.
+    #[arg(long, value_name = "NAME")]
+    ignore_attribute: Vec<String>,
+
+    /// Treat the list attribute `NAME` (e.g. `srcs`) as unchanged if only its order changes.
+    /// Needs targets files with all attributes, like `--ignore-attribute`.
+    #[arg(long, value_name = "NAME")]
+    unordered_attribute: Vec<String>,
+
+    /// Decide whether a target whose hash changed really changed by comparing its
+    /// `attributes`, so a new hash with no significant attribute changed isn't a change,
+    /// or by its `hash` alone, never reading the attributes. Defaults to `attributes`
+    /// with `--ignore-attribute` or `--unordered-attribute`, otherwise `hash`.
+    /// Comparing attributes needs targets files with all attributes.
+    #[arg(long, value_enum)]
+    diff_mode: Option<DiffMode>,
+
     /// The command for running Buck
     #[arg(long, default_value = "buck2")]
     buck: String,
@@ -126,6 +551,23 @@ pub struct Args {
     #[arg(long)]
     isolation_dir: Option<String>,
 
+    /// How many times to rerun a failed Buck command, e.g. when the daemon dies.
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    buck_retries: usize,
+
+    /// Seconds to wait before the first rerun of a Buck command, doubling before each one after.
+    #[arg(long, default_value_t = 1.0, value_name = "SECONDS")]
+    buck_retry_backoff: f64,
+
+    /// Only rerun Buck commands which exit with this code. May be repeated.
+    #[arg(long, value_name = "CODE")]
+    buck_retry_exit_code: Vec<i32>,
+
+    /// Only rerun Buck commands whose stderr contains this text. May be repeated, and
+    /// combined with `--buck-retry-exit-code`, rerunning commands matching either.
+    #[arg(long, value_name = "TEXT")]
+    buck_retry_stderr: Vec<String>,
+
     /// Arguments passed on to Buck (as `--flagfile`)
     #[arg(long)]
     flagfile: Vec<String>,
@@ -134,6 +576,17 @@ pub struct Args {
     #[arg(long)]
     check_dangling: bool,
 
+    /// Don't fail if the dependency graph has cycles. Every target in a cycle is still
+    /// impacted, but only once, at the depth where it is first reached.
+    #[arg(long)]
+    allow_cycles: bool,
+
+    /// Check dependencies against the `visibility` of the targets they depend on. A
+    /// dependency on a target which isn't visible can't exist in a successful build, so
+    /// usually means the targets are inconsistent.
+    #[arg(long, value_enum, default_value_t = VisibilityCheck::Off)]
+    check_visibility: VisibilityCheck,
+
     /// Glean-specific approach to chasing dependencies.
     #[arg(long)]
     glean: bool,
@@ -151,8 +604,179 @@ pub struct Args {
     write_errors_to_file: Option<PathBuf>,
 
     /// If a target depends on a target with the label `uses_sudo`, should we propagate the label.
+    /// Equivalent to `--propagate-label=uses_sudo`.
     #[arg(long)]
     propagate_uses_sudo: bool,
+
+    /// Add `LABEL` to the output labels of every target which depends, transitively,
+    /// on a target with that label. Can be passed multiple times.
+    #[arg(long, value_name = "LABEL")]
+    propagate_label: Vec<String>,
+
+    /// Add `LABEL` to the output labels of every transitive dependency of a target
+    /// with that label. Can be passed multiple times.
+    #[arg(long, value_name = "LABEL")]
+    propagate_label_down: Vec<String>,
+
+    /// Rather than printing the impacted targets, explain why `TARGET` was impacted,
+    /// as a shortest chain from a changed target.
+    #[arg(long, value_name = "TARGET", conflicts_with_all = ["glean", "graph_size"])]
+    why: Option<String>,
+
+    /// Also write the impacted targets, the changed files that caused them and the
+    /// edges between them to `FILE` as a GraphViz DOT graph.
+    #[arg(long, value_name = "FILE")]
+    graph_out: Option<PathBuf>,
+
+    /// Also write the impacted targets to `FILE` for a BXL script, e.g. `btd/bxl/impacted.bxl`,
+    /// one label per line, so it can be passed to Buck2 as `@FILE`.
+    #[arg(long, value_name = "FILE")]
+    bxl_out: Option<PathBuf>,
+
+    /// With `--bxl-out`, also write a JSON object from each impacted target to its
+    /// record in the `--json` output to `FILE`.
+    #[arg(long, value_name = "FILE", requires = "bxl_out")]
+    bxl_metadata: Option<PathBuf>,
+
+    /// A `CODEOWNERS`-style file mapping directories to owners. Each impacted target
+    /// is reported with the `owners` of the directory containing its package.
+    #[arg(long, value_name = "FILE")]
+    owners_file: Option<PathBuf>,
+
+    /// A file of dependencies Buck doesn't know about, each line a file glob or target
+    /// pattern followed by the targets depending on it. They are added to the dependent
+    /// targets as `ci_srcs` and `ci_deps` before diffing.
+    #[arg(long, value_name = "FILE")]
+    dependency_hints: Option<PathBuf>,
+
+    /// Report whether each impacted target is a `test` or a `build` as its `class`,
+    /// along with the `test_type` of tests, e.g. `unit`, `integration` or `e2e`.
+    #[arg(long)]
+    classify: bool,
+
+    /// A JSON file configuring `--classify`, with the fields `test_rule_types`, `labels`,
+    /// `rule_types` and `default_test_type`. Implies `--classify`.
+    #[arg(long, value_name = "FILE")]
+    classify_config: Option<PathBuf>,
+
+    /// Add a `score` to each impacted test in the `v2` output, higher for tests closer
+    /// to the change, which fail more often, or are smaller, to run the best tests first.
+    #[arg(long)]
+    score: bool,
+
+    /// A JSON file of weights for `--score`, with the fields `depth`, `failure_rate` and
+    /// `size`. Implies `--score`.
+    #[arg(long, value_name = "FILE")]
+    score_config: Option<PathBuf>,
+
+    /// The historical failure rate of tests for `--score`, either CSV lines of
+    /// `target,rate` or a JSON object from target to rate. Implies `--score`.
+    #[arg(long, value_name = "FILE")]
+    failure_rates: Option<PathBuf>,
+
+    /// Only output the `N` highest scoring impacted tests, listing the rest as `skipped`
+    /// in the `v2` output. Implies `--score`.
+    #[arg(long, value_name = "N")]
+    target_budget: Option<usize>,
+
+    /// Only output the highest scoring impacted tests which run in `SECONDS` in total,
+    /// according to `--durations`, listing the rest as `skipped` in the `v2` output.
+    /// Implies `--score`.
+    #[arg(long, value_name = "SECONDS", requires = "durations")]
+    time_budget: Option<f64>,
+
+    /// The duration of tests in seconds for `--time-budget`, either CSV lines of
+    /// `target,seconds` or a JSON object from target to seconds. Tests not listed are
+    /// assumed to take the mean duration.
+    #[arg(long, value_name = "FILE", requires = "time_budget")]
+    durations: Option<PathBuf>,
+
+    /// Write an index of the targets depending on each target in the diff targets to `FILE`,
+    /// so later runs against the same targets can load it with `--load-index`.
+    #[arg(long, value_name = "FILE")]
+    save_index: Option<PathBuf>,
+
+    /// Load the index written by `--save-index`, rather than working out which targets
+    /// depend on each target, which takes a while on big graphs. The diff targets must
+    /// be the same as when it was saved.
+    #[arg(long, value_name = "FILE")]
+    load_index: Option<PathBuf>,
+
+    /// Cache the impacted targets in `DIR`, keyed by a hash of the diff targets and the
+    /// targets which changed, so repeating a query, e.g. in a retried CI job, skips the
+    /// traversal.
+    #[arg(long, value_name = "DIR", conflicts_with = "glean")]
+    cache_dir: Option<PathBuf>,
+
+    /// How long entries in `--cache-dir` are used for.
+    #[arg(long, value_name = "SECONDS", default_value_t = 24 * 60 * 60)]
+    cache_ttl: u64,
+
+    /// Evict the oldest entries in `--cache-dir` once they take up more than `BYTES`.
+    #[arg(long, value_name = "BYTES", default_value_t = 1 << 30)]
+    cache_max_size: u64,
+
+    /// Save the progress of the run in `DIR` after each phase: the base and diff targets
+    /// once read, then the impacted targets. A rerun with the same arguments and changes,
+    /// e.g. after a timeout on an enormous change, resumes from the last phase completed,
+    /// but a traversal interrupted part way starts over. The progress is removed once the
+    /// run completes.
+    #[arg(long, value_name = "DIR", conflicts_with = "glean")]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// Write statistics to `FILE` as JSON: the number of impacted targets by rule type,
+    /// cell and depth, the size of the graphs, and how long each phase took.
+    #[arg(long, value_name = "FILE")]
+    stats: Option<PathBuf>,
+
+    /// Count how often each string is interned, and add the `N` most common to
+    /// `--stats`, to see what takes up memory on large graphs. Slows down parsing.
+    #[arg(long, value_name = "N", requires = "stats")]
+    stats_top_strings: Option<usize>,
+
+    /// Record everything this run reads into the directory `DIR`: the cells, changes,
+    /// targets and arguments, so it can be reproduced elsewhere with `--replay-bundle`.
+    #[arg(long, value_name = "DIR")]
+    record_bundle: Option<PathBuf>,
+
+    /// With `--record-bundle`, only record the impacted targets rather than both graphs.
+    #[arg(long, requires = "record_bundle")]
+    bundle_subset: bool,
+
+    /// With `--record-bundle`, replace the names of paths, packages and targets with
+    /// hashes, and leave out the attributes BTD doesn't interpret, so it can be shared.
+    #[arg(long, requires = "record_bundle")]
+    bundle_anonymize: bool,
+
+    /// Run from a bundle written by `--record-bundle`, with the arguments it was recorded
+    /// with, followed by any given here, e.g. `--json`.
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["cells", "config", "changes", "changes_from_scm", "base", "diff"]
+    )]
+    replay_bundle: Option<PathBuf>,
+}
+
+/// Modes other than computing the impacted targets.
+#[derive(Subcommand)]
+enum Command {
+    Snapshot(SnapshotArgs),
+    Validate(ValidateArgs),
+    Range(RangeArgs),
+    Deps(DepsArgs),
+    Rdeps(RdepsArgs),
+    Expand(ExpandArgs),
+    Audit(AuditArgs),
+    BlastRadius(BlastRadiusArgs),
+    Shard(ShardArgs),
+    DiffOutputs(DiffOutputsArgs),
+    Setop(SetopArgs),
+    Minimize(MinimizeArgs),
+    #[cfg(unix)]
+    Serve(ServeArgs),
+    #[command(hide = true)]
+    Generate(GraphSpec),
 }
 
 /// Rather than waiting to deallocate all our big JSON objects, we just forget them with `ManuallyDrop`.
@@ -162,8 +786,60 @@ fn leak_targets(targets: Targets) -> impl Deref<Target = Targets> {
 }
 
 pub fn main(args: Args) -> anyhow::Result<()> {
+    main_with_registry(args, Registry::builtin())
+}
+
+/// Like [`main`], but enabling the `--impact-rule`s from `registry`, e.g. the builtin
+/// rules and those of a binary wrapping BTD, see [`impact_rule`].
+pub fn main_with_registry(mut args: Args, registry: Registry) -> anyhow::Result<()> {
+    if let Some(command) = args.command.take() {
+        return match command {
+            Command::Snapshot(args) => snapshot::main(args),
+            Command::Validate(args) => validate::main(args),
+            Command::Range(args) => range::main(args),
+            Command::Deps(args) => deps::main(args),
+            Command::Rdeps(args) => rdeps::main(args),
+            Command::Expand(args) => expand::main(args),
+            Command::Audit(args) => audit::main(args),
+            Command::BlastRadius(args) => blast_radius::main(args),
+            Command::Shard(args) => ranker::main(args),
+            Command::DiffOutputs(args) => diff_outputs::main(args),
+            Command::Setop(args) => setop::main(args),
+            Command::Minimize(args) => minimize::main(args),
+            #[cfg(unix)]
+            Command::Serve(args) => serve::main(args),
+            Command::Generate(args) => graph_gen::main(args),
+        };
+    }
+
+    if let Some(dir) = &args.replay_bundle {
+        let argv = bundle::replay_args(dir, &Args::command(), &bundle::cli_args()?)?;
+        return main_with_registry(Args::try_parse_from(argv)?, registry);
+    }
+
     let output_format = OutputFormat::from_args(&args);
-    let mut buck2 = Buck2::new(args.buck.clone(), args.isolation_dir);
+    if args.output_encoding != OutputEncoding::Json && args.output_format != OutputSchema::V2 {
+        return Err(EncodingError::NotV2.into());
+    }
+    if args.split_immediate && args.output_format != OutputSchema::V2 {
+        return Err(SplitError::NotV2.into());
+    }
+    let attribute_diff = attribute_diff(&args)?;
+    // Only keep the attributes if something looks at them
+    let read_options = ReadOptions {
+        attributes: attribute_diff.mode == DiffMode::Attributes
+            || !args.output_attribute.is_empty()
+            || !args.exclude_attribute.is_empty()
+            || args.filter.is_some(),
+        ..ReadOptions::default()
+    };
+    let encoder = args.output_encoding.encoder()?;
+    let mut buck2 = Buck2::new(args.buck.clone(), args.isolation_dir).with_retry(RetryPolicy {
+        attempts: args.buck_retries + 1,
+        backoff: Duration::from_secs_f64(args.buck_retry_backoff),
+        exit_codes: args.buck_retry_exit_code,
+        stderr: args.buck_retry_stderr,
+    });
 
     // All the arguments we should pass on to Buck, when we call it using sensible arguments
     let buck_args = args
@@ -173,8 +849,16 @@ pub fn main(args: Args) -> anyhow::Result<()> {
         .chain(args.buck_arg)
         .collect::<Vec<_>>();
 
+    if args.stats_top_strings.is_some() {
+        InternString::track_uses(true);
+    }
     let t = Instant::now();
-    let step = |name| info!("Starting {} at {:.3}s", name, t.elapsed().as_secs_f64());
+    // When each step started, for `--stats`
+    let phases = RefCell::new(Vec::new());
+    let step = |name: &str| {
+        info!("Starting {} at {:.3}s", name, t.elapsed().as_secs_f64());
+        phases.borrow_mut().push((name.to_owned(), t.elapsed()));
+    };
 
     step("reading cells");
     let mut cells = match &args.cells {
@@ -189,111 +873,663 @@ pub fn main(args: Args) -> anyhow::Result<()> {
     }
 
     step("reading changes");
-    let changes = Changes::new(&cells, read_status(&args.changes)?)?;
+    let status = match (
+        &args.changes,
+        &args.changes_from_scm,
+        &args.simulate_changes,
+    ) {
+        (Some(file), _, _) => read_status(file)?,
+        (None, Some(rev), _) if args.eden => changes::changes_from_eden(rev)?,
+        (None, Some(rev), _) => changes::changes_from_scm(rev)?,
+        (None, None, Some(file)) => read_paths(file)?,
+        // Only `--changed-targets`
+        (None, None, None) => Vec::new(),
+    };
+    let changes = Changes::new(&cells, status)?;
+    let changes = match &args.resolve_symlinks {
+        Some(root) => {
+            step("resolving symlinks");
+            changes.resolve_symlinks(&cells, root)?
+        }
+        None => changes,
+    };
+    let changes = if args.ignore_cosmetic_changes {
+        step("ignoring cosmetic changes");
+        let contents = FileContents::new(
+            args.file_contents_command.as_deref(),
+            args.changes_from_scm.as_deref(),
+        )?;
+        normalize::drop_cosmetic_changes(&cells, changes, &contents)?
+    } else {
+        changes
+    };
+    let ignore = IgnoreFile::new(args.ignore_file.as_deref(), &args.ignore_pattern)?;
+    let changes = ignore.apply(changes);
+    // Before the change policy, which the bundle applies again when replayed
+    let recorded_changes = args
+        .record_bundle
+        .as_ref()
+        .map(|_| changes.status_paths().cloned().collect::<Vec<_>>());
+    let (changes, change_policy) = match &args.change_policy {
+        Some(file) => {
+            step("applying change policy");
+            ChangePolicy::read_file(file)?.apply(&cells, changes)?
+        }
+        None => (changes, PolicyImpact::default()),
+    };
+    let checkpoint = match &args.checkpoint_dir {
+        Some(dir) => {
+            let files = [&args.base, &args.diff]
+                .into_iter()
+                .flatten()
+                .map(|x| x.as_path())
+                .collect::<Vec<_>>();
+            let changed = changes.status_paths().collect::<Vec<_>>();
+            Some(Checkpoint::new(dir, &bundle::cli_args()?, changed, &files)?)
+        }
+        None => None,
+    };
+    step("validating universe");
+    let universe_filter = validate_universe(args.universe.into_iter().chain(args.universe2))?;
+    let universe = universe_filter.map(|x| x.to_buck_pattern());
+    let exclude = Exclude::new(
+        &args.exclude,
+        &args.exclude_attribute,
+        args.filter.as_deref(),
+    )?;
+    let changed_targets = args
+        .changed_targets
+        .iter()
+        .map(|x| TargetPattern::new(x).parse())
+        .collect::<Result<Vec<_>, _>>()?;
+    let rules = registry.select(&args.impact_rule)?;
+    let hints = match &args.dependency_hints {
+        Some(file) => {
+            step("reading dependency hints");
+            DependencyHints::read_file(file)?
+        }
+        None => DependencyHints::default(),
+    };
+    // Spans for each phase, exported to OpenTelemetry with the `otlp` feature
+    let span = info_span!("parse-base").entered();
     step("reading base");
-    let base = leak_targets(Targets::from_file(&args.base)?);
+    let stdin = Path::new("-");
+    if args.base.as_deref() == Some(stdin) && args.diff.as_deref() == Some(stdin) {
+        return Err(StdinError::BaseAndDiff.into());
+    }
+    let read_targets = |file: &Path, revision: Option<&str>| {
+        if args.configured {
+            Ok((configured::read_file(file)?, None))
+        } else {
+            Targets::from_file_with(
+                file,
+                ReadOptions {
+                    revision,
+                    ..read_options
+                },
+            )
+        }
+    };
+    let saved = match &checkpoint {
+        Some(checkpoint) => checkpoint.load(Phase::Base)?,
+        None => None,
+    };
+    let base_revision = args
+        .base_revision
+        .as_deref()
+        .or(args.changes_from_scm.as_deref());
+    // The header of each input which is a snapshot, for the `metadata` of the output
+    let (base, base_header) = match (saved, &args.base) {
+        (Some(saved), _) => saved,
+        (None, Some(file)) => {
+            let verify = base_revision.filter(|_| !args.skip_verification);
+            let (base, header) = read_targets(file, verify)?;
+            if let (Some(rev), Some(_)) = (base_revision, &header) {
+                if args.skip_verification {
+                    warn!("Not verifying the `--base` snapshot against revision `{rev}`");
+                }
+            }
+            (base, header)
+        }
+        (None, None) => {
+            let rev = args
+                .changes_from_scm
+                .as_deref()
+                .expect("clap requires `--changes-from-scm`");
+            if universe.is_empty() {
+                return Err(UniverseError::NoUniverseForBase.into());
+            }
+            step("running base targets");
+            let file = NamedTempFile::new()?;
+            changes::with_scm_worktree(rev, |dir| {
+                let mut buck2 = buck2.in_dir(dir);
+                let res = buck2
+                    .targets(&buck_args, &universe, file.path())
+                    .with_context(|| format!("When running `{}` at `{rev}`", args.buck));
+                // The daemon for the checkout would otherwise outlive it
+                if let Err(e) = buck2.kill() {
+                    warn!("Could not stop the Buck2 daemon for `{rev}`: {e:#}");
+                }
+                res
+            })?;
+            Targets::from_file_with(file.path(), read_options)?
+        }
+    };
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint.save(Phase::Base, &base, base_header.as_ref())?;
+    }
+    let ignore_rule_types = |targets: Targets| {
+        targets.ignore_rule_types(&args.ignore_rule_types, args.stitch_ignored_deps)
+    };
+    let collapse_aliases = |targets: Targets| {
+        if args.collapse_aliases {
+            step("collapsing aliases");
+            targets.collapse_aliases()
+        } else {
+            Ok((targets, BTreeMap::new()))
+        }
+    };
+    // What each alias in the graph we report on stands for, with `--collapse-aliases`
+    let (base, mut aliases) = collapse_aliases(base)?;
+    let base = hints.apply(ignore_rule_types(base).restrict(&universe_filter));
+    let base = leak_targets(visibility::check_visibility(
+        base,
+        args.check_visibility,
+        "base",
+    ));
+    // Needs the base, so done after reading it
+    let changes = if args.case_insensitive_paths {
+        step("folding case");
+        changes.fold_case(&cells, &base)?
+    } else {
+        changes
+    };
 
-    step("validating universe");
-    let universe = validate_universe(args.universe.into_iter().chain(args.universe2))?;
-
-    let diff = leak_targets(match &args.diff {
-        None => {
-            step("computing rerun");
-            let rerun = compute_rerun(&base, &changes, &mut buck2, &cells, &universe)?;
-            let ask_buck = match &rerun {
-                None => universe.clone(),
-                Some(x) => x.modified.map(|x| x.as_pattern()),
-            };
-            if args.print_rerun {
-                print_rerun(&rerun);
-                return Ok(());
+    drop(span);
+
+    let span = info_span!("parse-diff").entered();
+    // When simulating changes, the base is also the diff
+    let mut diff_header = None;
+    let diff = if args.simulate_changes.is_some() {
+        None
+    } else {
+        let saved = match &checkpoint {
+            Some(checkpoint) => checkpoint.load(Phase::Diff)?,
+            None => None,
+        };
+        let (diff, header) = match (saved, &args.diff) {
+            (Some(saved), _) => saved,
+            (None, None) => {
+                step("computing rerun");
+                let rerun = compute_rerun(&base, &changes, &mut buck2, &cells, &universe)?;
+                let ask_buck = match &rerun {
+                    None => universe.clone(),
+                    Some(x) => x.modified.map(|x| x.as_pattern()),
+                };
+                if args.print_rerun {
+                    print_rerun(&rerun);
+                    return Ok(());
+                }
+                let new = if ask_buck.is_empty() {
+                    Targets::new(Vec::new())
+                } else {
+                    step("running targets");
+                    let file = NamedTempFile::new()?;
+                    buck2
+                        .targets(&buck_args, &ask_buck, file.path())
+                        .with_context(|| format!("When running `{}`", args.buck))?;
+                    step("reading diff");
+                    Targets::from_file_with(file.path(), read_options)?.0
+                };
+                let diff = match &rerun {
+                    None => new,
+                    Some(rerun) => {
+                        step("merging diff");
+                        base.update(new, &rerun.deleted)
+                    }
+                };
+                (diff, None)
             }
-            let new = if ask_buck.is_empty() {
-                Targets::new(Vec::new())
-            } else {
-                step("running targets");
-                let file = NamedTempFile::new()?;
-                buck2
-                    .targets(&buck_args, &ask_buck, file.path())
-                    .with_context(|| format!("When running `{}`", args.buck))?;
+            (None, Some(diff)) => {
                 step("reading diff");
-                Targets::from_file(file.path())?
-            };
-            match &rerun {
-                None => new,
-                Some(rerun) => {
-                    step("merging diff");
-                    base.update(new, &rerun.deleted)
-                }
+                read_targets(diff, None)?
             }
+        };
+        if let Some(checkpoint) = &checkpoint {
+            checkpoint.save(Phase::Diff, &diff, header.as_ref())?;
         }
-        Some(diff) => {
-            step("reading diff");
-            Targets::from_file(diff)?
-        }
-    });
+        diff_header = header;
+        let (diff, diff_aliases) = collapse_aliases(diff)?;
+        aliases = diff_aliases;
+        let diff = ignore_rule_types(diff).restrict(&universe_filter);
+        let diff = hints.apply(diff);
+        Some(leak_targets(visibility::check_visibility(
+            diff,
+            args.check_visibility,
+            "diff",
+        )))
+    };
+    let diff: &Targets = diff.as_deref().unwrap_or(&base);
+    drop(span);
+
+    let span = info_span!("diff").entered();
+    if !args.allow_cycles {
+        step("checking for cycles");
+        cycles::check_cycles(diff)?;
+    }
+    // The edges to follow besides `deps`, which need a map of the rdeps built for them
+    let edges = Edges {
+        tests: args.follow_tests,
+        toolchain_deps: args.follow_toolchain_deps,
+    };
 
     step("immediate changes");
-    let immediate =
-        diff::immediate_target_changes(&base, &diff, &changes, args.track_prelude_rule_changes);
+    let mut immediate = diff::immediate_target_changes_with(
+        &base,
+        diff,
+        &changes,
+        &ImmediateOptions {
+            track_prelude_changes: args.track_prelude_rule_changes,
+            attribution: args.attribution,
+            package_change_policy: args.package_change_policy,
+            attribute_diff,
+            changed_targets,
+            change_policy,
+            broken_package_policy: args.broken_package_policy,
+            follow_loads: args.follow_loads,
+        },
+    );
+    if !rules.is_empty() {
+        step("impact rules");
+        immediate.add_recursive(rules.seed(diff, &changes));
+    }
+
+    if let Some(dir) = &args.record_bundle {
+        step("recording bundle");
+        let subset = args.bundle_subset.then(|| {
+            let mut res = immediate
+                .removed()
+                .map(|x| x.label())
+                .collect::<HashSet<_>>();
+            let rdeps = (edges != Edges::default() || !rules.is_empty())
+                .then(|| Rdeps::with_rules(diff, edges, &rules));
+            diff::recursive_target_changes_with_rdeps(
+                diff,
+                rdeps.as_ref(),
+                &immediate,
+                args.depth,
+                false,
+                |x| !diff::is_terminal_rule(x, &args.terminal_rules),
+                |level| res.extend(level.iter().map(|(x, _)| x.label())),
+            );
+            res
+        });
+        bundle::record(
+            dir,
+            &cells,
+            recorded_changes.as_deref().unwrap_or_default(),
+            (&base, args.simulate_changes.is_none().then_some(diff)),
+            &Args::command(),
+            &RecordOptions {
+                subset: subset.as_ref(),
+                anonymize: args.bundle_anonymize,
+                args: &bundle::cli_args()?,
+            },
+        )
+        .with_context(|| format!("When recording a bundle to `{}`", dir.display()))?;
+    }
 
     // Perform inline error validation when we're not collecting errors
     // for downstream reporting.
     if args.write_errors_to_file.is_none() {
         let immediate_targets_only = immediate.iter().collect::<Vec<_>>();
         step("error validation");
-        check_empty(&check::check_errors(&base, &diff, &changes))?;
+        check_empty(&check::check_errors_with(
+            &base,
+            diff,
+            &changes,
+            args.broken_package_policy,
+            &args.tolerate_package_error,
+        ))?;
         if args.check_dangling {
             step("dangling check");
             check_empty(&check::check_dangling(
                 &base,
-                &diff,
+                diff,
                 &immediate_targets_only,
                 &universe,
             ))
             .context("Dangling target check failed")?;
         }
     }
-    let recursive = if args.glean {
-        step("glean changes");
-        glean::glean_changes(&base, &diff, &changes, args.depth)
+    drop(span);
+
+    let span = info_span!("traverse").entered();
+    // From here on a signal stops the traversal, and what was found so far is written
+    interrupt::install()?;
+    let mut propagate = args.propagate_label.map(|x| (x.as_str(), Direction::Rdeps));
+    if args.propagate_uses_sudo {
+        propagate.push(("uses_sudo", Direction::Rdeps));
+    }
+    propagate.extend(
+        args.propagate_label_down
+            .iter()
+            .map(|x| (x.as_str(), Direction::Deps)),
+    );
+    let propagated = if propagate.is_empty() {
+        PropagatedLabels::new()
     } else {
-        step("recursive changes");
-        diff::recursive_target_changes(&diff, &immediate, args.depth, |_| true)
+        step("propagating labels");
+        propagate::propagate_labels_with(diff, &propagate)
     };
-    let sudos = if args.propagate_uses_sudo {
-        step("recursive sudo labels");
-        sudo::requires_sudo_recursively(&diff)
+    let owners = match &args.owners_file {
+        Some(file) => {
+            step("reading owners");
+            Owners::read_file(file, &cells)?
+        }
+        None => Owners::default(),
+    };
+    let classifier = match &args.classify_config {
+        Some(file) => Classifier::read_file(file)?,
+        None if args.classify => Classifier::new(ClassifyConfig::default()),
+        None => Classifier::default(),
+    };
+    let quarantine = match &args.quarantine {
+        Some(file) => Some(Quarantine::read_file(file)?),
+        None => None,
+    };
+    let unschedulable = Unschedulable::new(
+        args.unschedulable,
+        &args.respect_labels,
+        &args.ignore_labels,
+    );
+    let mut budget = Budget::new(args.target_budget);
+    if let (Some(max), Some(file)) = (args.time_budget, &args.durations) {
+        budget = budget.with_max_duration(max, Budget::read_durations(file)?);
+    }
+    if !budget.is_empty() && args.output_format != OutputSchema::V2 {
+        return Err(BudgetError::NotV2.into());
+    }
+    let scorer = if args.score
+        || args.score_config.is_some()
+        || args.failure_rates.is_some()
+        || !budget.is_empty()
+    {
+        let weights = match &args.score_config {
+            Some(file) => Scorer::read_weights(file)?,
+            None => ScoreWeights::default(),
+        };
+        let failure_rates = match &args.failure_rates {
+            Some(file) => Scorer::read_failure_rates(file)?,
+            None => HashMap::new(),
+        };
+        Scorer::new(weights, failure_rates)
     } else {
-        HashSet::new()
+        Scorer::default()
+    };
+    if let Some(file) = &args.save_index {
+        step("saving rdeps index");
+        Rdeps::save_index(file, diff)?;
+    }
+    let rdeps = match &args.load_index {
+        Some(file) => {
+            step("loading rdeps index");
+            Some(Rdeps::load_index(file, diff)?)
+        }
+        None if edges != Edges::default() || !rules.is_empty() => {
+            Some(Rdeps::with_rules(diff, edges, &rules))
+        }
+        None => None,
     };
-    step("printing changes");
-    if args.graph_size {
-        let mut graph = GraphSize::new(&base, &diff);
-        graph.print_recursive_changes(&recursive, &sudos, output_format);
+    let follow = |x: &RuleType| !diff::is_terminal_rule(x, &args.terminal_rules);
+    let removed = args.include_removed.then(|| {
+        immediate
+            .removed()
+            .filter(|x| !exclude.matches(x))
+            .map(RemovedTarget::from_target)
+            .collect::<Vec<_>>()
+    });
+    let mut summary = Summary::default();
+    let mut stats = args.stats.as_ref().map(|_| Stats::new(&base, diff));
+    let mut partial = false;
+    if output_format == OutputFormat::JsonLines
+        && args.output_format == OutputSchema::V1
+        && !args.glean
+        && !args.graph_size
+        && args.why.is_none()
+        && args.graph_out.is_none()
+        && args.bxl_out.is_none()
+        && args.quarantine.is_none()
+        && unschedulable.is_keep()
+        && args.granularity == Granularity::Targets
+        && args.cache_dir.is_none()
+        && args.checkpoint_dir.is_none()
+        && rules.is_empty()
+    {
+        // Stream the results, so we never hold the complete output in memory.
+        step("streaming recursive changes");
+        let mut out = JsonLinesWriter::new(BufWriter::new(stdout().lock()));
+        let mut depth = 0;
+        let mut written = 0;
+        let mut truncated = false;
+        let on_level = |level: Vec<_>| {
+            summary.add(&level);
+            if let Some(stats) = &mut stats {
+                stats.add_level(depth as usize, &level);
+            }
+            for (x, reason) in level {
+                if exclude.matches(x) {
+                    continue;
+                }
+                if args.max_output.is_some_and(|max| written >= max) {
+                    truncated = true;
+                    continue;
+                }
+                written += 1;
+                let labels = propagated_labels(&propagated, x);
+                out.write(
+                    &Output::from_target(x, depth, &labels, reason)
+                        .with_owners(owners.get(&x.package))
+                        .with_class(&classifier),
+                );
+            }
+            out.flush();
+            depth += 1;
+        };
+        diff::recursive_target_changes_with_rdeps(
+            diff,
+            rdeps.as_ref(),
+            &immediate,
+            args.depth,
+            !args.no_sort,
+            follow,
+            on_level,
+        );
+        partial = interrupt::interrupted();
+        if let Some(removed) = removed {
+            out.write(&Removed { removed });
+        }
+        if truncated {
+            warn!("Output truncated to {written} targets");
+            out.write(&Truncated { truncated: true });
+        }
+        if partial {
+            out.write(&Partial { partial: true });
+        }
+        out.finish()?;
     } else {
-        print_recursive_changes(&recursive, &sudos, output_format, |_, x| x);
+        let recursive = if args.glean {
+            step("glean changes");
+            glean::glean_changes(&base, diff, &changes, args.depth)
+        } else {
+            step("recursive changes");
+            let cache = match (&args.cache_dir, &checkpoint) {
+                (Some(dir), _) => Some(ImpactCache::new(
+                    dir.clone(),
+                    Duration::from_secs(args.cache_ttl),
+                    args.cache_max_size,
+                )),
+                // The last phase, so a rerun of a completed traversal only writes the output
+                (None, Some(checkpoint)) => Some(checkpoint.impact_cache()),
+                (None, None) => None,
+            };
+            let cache = cache.map(|cache| {
+                let options = (
+                    args.depth,
+                    args.no_sort,
+                    &args.terminal_rules,
+                    edges,
+                    &args.impact_rule,
+                );
+                (cache, ImpactCache::key(diff, &immediate, options))
+            });
+            let cached = cache
+                .as_ref()
+                .and_then(|(cache, key)| cache.get(*key, diff));
+            match cached {
+                Some(recursive) => recursive,
+                None => {
+                    let mut recursive = Vec::new();
+                    diff::recursive_target_changes_with_rdeps(
+                        diff,
+                        rdeps.as_ref(),
+                        &immediate,
+                        args.depth,
+                        !args.no_sort,
+                        follow,
+                        |level| recursive.push(level),
+                    );
+                    partial = interrupt::interrupted();
+                    // Never cache a partial result, it would be reused as if it were complete
+                    if let Some((cache, key)) = cache.as_ref().filter(|_| !partial) {
+                        cache.put(*key, &recursive);
+                    }
+                    recursive
+                }
+            }
+        };
+        recursive.iter().for_each(|level| summary.add(level));
+        if let Some(stats) = &mut stats {
+            for (depth, level) in recursive.iter().enumerate() {
+                stats.add_level(depth, level);
+            }
+        }
+        drop(span);
+
+        let _span = info_span!("output").entered();
+        if let Some(file) = &args.graph_out {
+            step("writing graph");
+            dot::write_file(file, &recursive, &changes)?;
+        }
+        step("printing changes");
+        if let Some(target) = &args.why {
+            why::print_explanation(
+                &recursive,
+                &changes,
+                &TargetLabel::new(target),
+                output_format,
+            );
+        } else {
+            let mut recursive = exclude_targets(recursive, &exclude);
+            rules.output(&mut recursive);
+            let recursive = unschedulable.remove(recursive);
+            let (recursive, truncated) = truncate_levels(recursive, args.max_output);
+            let mut quarantined = quarantine
+                .as_ref()
+                .map(|x| x.select(&recursive, &classifier));
+            if let Some(demoted) = unschedulable.demoted(&recursive) {
+                quarantined.get_or_insert_with(HashSet::new).extend(demoted);
+            }
+            if let Some(file) = &args.bxl_out {
+                step("writing BXL target set");
+                bxl::write_files(file, args.bxl_metadata.as_deref(), &recursive, &propagated)?;
+            }
+            if args.granularity == Granularity::Oncalls {
+                print_coalesced(&granularity::group_by_oncall(&recursive), output_format);
+            } else if args.granularity != Granularity::Targets {
+                print_coalesced(
+                    &granularity::coalesce(&recursive, args.granularity, args.patterns),
+                    output_format,
+                );
+            } else if args.output_format == OutputSchema::V2 {
+                let metadata = Metadata {
+                    base: base_header,
+                    diff: diff_header,
+                };
+                DocumentV2::new(
+                    &recursive,
+                    &propagated,
+                    &owners,
+                    &classifier,
+                    &scorer,
+                    &args.output_attribute,
+                )
+                .with_truncated(truncated)
+                .with_partial(partial)
+                .with_removed(removed)
+                .with_metadata(metadata)
+                .with_quarantined(quarantined.as_ref())
+                .with_budget(&budget)
+                .with_aliases(args.collapse_aliases.then_some(&aliases))
+                .with_errors(diff.errors().map(PackageError::new).collect())
+                .with_split(args.split_immediate)
+                .write(stdout().lock(), &*encoder)?;
+            } else if args.graph_size {
+                let mut graph = GraphSize::new(&base, diff);
+                graph.print_recursive_changes(
+                    &recursive,
+                    &propagated,
+                    &owners,
+                    &classifier,
+                    output_format,
+                );
+            } else {
+                print_recursive_changes(
+                    &recursive,
+                    &propagated,
+                    quarantined.as_ref(),
+                    removed,
+                    truncated,
+                    partial,
+                    output_format,
+                    |x, out| {
+                        out.with_owners(owners.get(&x.package))
+                            .with_class(&classifier)
+                    },
+                );
+            }
+        }
     }
     // We aggregate errors for post-commit validation so downstream systems
     // can log existing issues.
     if let Some(error_file) = args.write_errors_to_file {
         step("writing all errors to file");
         assert!(!universe.is_empty());
-        let errors = check::dump_all_errors(&diff, &universe);
+        let errors = check::dump_all_errors(diff, &universe);
 
         write_errors_to_file(&errors, error_file, output_format)?;
     }
+    if let (Some(file), Some(mut stats)) = (&args.stats, stats) {
+        stats.set_phases(&phases.borrow(), t.elapsed());
+        stats.set_interned(args.stats_top_strings.unwrap_or_default());
+        stats.write_file(file)?;
+    }
+    if let Some(checkpoint) = checkpoint.filter(|_| !partial) {
+        checkpoint.finish()?;
+    }
+    if partial {
+        warn!("Interrupted, so only the impacted targets found so far were written");
+        stdout().flush()?;
+        std::process::exit(interrupt::EXIT_CODE);
+    }
     let immediate_changes = immediate.len();
-    let total_changes = recursive.iter().map(|x| x.len()).sum::<usize>();
+    let Summary {
+        total_changes,
+        reason_counts,
+    } = summary;
     step(&format!(
         "finish with {immediate_changes} immediate changes, {total_changes} total changes"
     ));
-    // BTreeMap so that reasons are consistently ordered in logs
-    let mut reason_counts: BTreeMap<RootImpactKind, u64> = BTreeMap::new();
-    for (_, reason) in recursive.iter().flatten() {
-        let root_impact_kind = reason.root_cause.1;
-        *reason_counts.entry(root_impact_kind).or_default() += 1;
-    }
     td_util::scuba!(
         event: BTD_SUCCESS,
         duration: t.elapsed(),
@@ -301,11 +1537,29 @@ pub fn main(args: Args) -> anyhow::Result<()> {
             "immediate_changes": immediate_changes,
             "total_changes": total_changes,
             "reason_counts": reason_counts,
+            "buck2_retries": buck2.retries(),
         })
     );
     Ok(())
 }
 
+/// Counts of the impacted targets, accumulated a level at a time.
+#[derive(Default)]
+struct Summary {
+    total_changes: usize,
+    // BTreeMap so that reasons are consistently ordered in logs
+    reason_counts: BTreeMap<RootImpactKind, u64>,
+}
+
+impl Summary {
+    fn add(&mut self, level: &[(&BuckTarget, ImpactReason)]) {
+        self.total_changes += level.len();
+        for (_, reason) in level {
+            *self.reason_counts.entry(reason.root_cause.1).or_default() += 1;
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 struct Rerun {
     modified: Vec<Package>,
@@ -376,9 +1630,26 @@ fn compute_rerun(
     }
 }
 
+/// How to compare targets whose hash changed, from `--diff-mode`, `--ignore-attribute`
+/// and `--unordered-attribute`.
+fn attribute_diff(args: &Args) -> Result<AttributeDiff, DiffModeError> {
+    let refined = !args.ignore_attribute.is_empty() || !args.unordered_attribute.is_empty();
+    let mode = match args.diff_mode {
+        Some(DiffMode::Hash) if refined => return Err(DiffModeError::Hash),
+        Some(mode) => mode,
+        None if refined => DiffMode::Attributes,
+        None => DiffMode::Hash,
+    };
+    Ok(AttributeDiff {
+        mode,
+        ignore: args.ignore_attribute.clone(),
+        unordered: args.unordered_attribute.clone(),
+    })
+}
+
 fn validate_universe(
     universe_arg: impl Iterator<Item = String>,
-) -> anyhow::Result<Vec<TargetPattern>> {
+) -> anyhow::Result<Vec<ParsedTargetPattern>> {
     let mut universe = Vec::with_capacity(universe_arg.size_hint().0);
     for u in universe_arg {
         // `buck2 targets` will infer a default cell, but we also use these
@@ -386,7 +1657,7 @@ fn validate_universe(
         if u.starts_with("//") {
             return Err(UniverseError::MissingQualifier(u).into());
         }
-        let pattern = TargetPattern::new(&u);
+        let pattern = TargetPattern::new(&u).parse()?;
         // Specific patterns complicate filtering when we use `rerun` to
         // determine what packages were affected by the changeset.
         if pattern.is_specific_target() {
@@ -400,7 +1671,7 @@ fn validate_universe(
 #[derive(Debug, Error)]
 enum UniverseError {
     #[error(
-        "Universe should not use explicit targets, only patterns like `foo//bar/...` and `foo//bar:`. Got `{0}`"
+        "Universe should not use explicit targets, only patterns like `foo//bar/...`, `foo//bar:` and `foo//bar:test_*`. Got `{0}`"
     )]
     ExplicitTarget(String),
     #[error(
@@ -409,6 +1680,40 @@ enum UniverseError {
     MissingQualifier(String),
     #[error("No universe arguments or `--diff` argument, so don't know what to diff against")]
     NoUniverseOrDiff,
+    #[error("No universe arguments, so don't know which targets to run for `--base-from-scm`")]
+    NoUniverseForBase,
+}
+
+#[derive(Debug, Error)]
+enum StdinError {
+    #[error("Only one of `--base` and `--diff` can be `-`, to read from stdin")]
+    BaseAndDiff,
+}
+
+#[derive(Debug, Error)]
+enum EncodingError {
+    #[error("`--output-encoding` only applies to `--output-format v2`")]
+    NotV2,
+}
+
+#[derive(Debug, Error)]
+enum SplitError {
+    #[error("`--split-immediate` only applies to `--output-format v2`")]
+    NotV2,
+}
+
+#[derive(Debug, Error)]
+enum DiffModeError {
+    #[error(
+        "`--ignore-attribute` and `--unordered-attribute` can't be used with `--diff-mode hash`"
+    )]
+    Hash,
+}
+
+#[derive(Debug, Error)]
+enum BudgetError {
+    #[error("`--target-budget` and `--time-budget` need `--output-format v2` to score tests")]
+    NotV2,
 }
 
 #[derive(Debug, Error)]
@@ -440,30 +1745,162 @@ impl OutputFormat {
     }
 }
 
+/// The targets to leave out of the output, from `--exclude`, `--exclude-attribute`
+/// and `--filter`.
+struct Exclude {
+    patterns: Vec<ParsedTargetPattern>,
+    /// The attribute names and values.
+    attributes: Vec<(String, String)>,
+    /// Targets not matching it are left out.
+    filter: Option<Filter>,
+}
+
+impl Exclude {
+    fn new(
+        patterns: &[String],
+        attributes: &[String],
+        filter: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            patterns: patterns
+                .iter()
+                .map(|x| TargetPattern::new(x).parse())
+                .collect::<Result<Vec<_>, _>>()?,
+            attributes: attributes.try_map(|x| match x.split_once('=') {
+                Some((name, value)) => Ok((name.to_owned(), value.to_owned())),
+                None => Err(ExcludeError::MalformedAttribute(x.clone())),
+            })?,
+            filter: filter.map(Filter::parse).transpose()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.attributes.is_empty() && self.filter.is_none()
+    }
+
+    fn matches(&self, target: &BuckTarget) -> bool {
+        !self.is_empty()
+            && (self.patterns.iter().any(|p| p.matches(&target.label()))
+                || self
+                    .attributes
+                    .iter()
+                    .any(|(name, value)| target.attributes.matches(name, value))
+                || self.filter.as_ref().is_some_and(|x| !x.matches(target)))
+    }
+}
+
+#[derive(Debug, Error)]
+enum ExcludeError {
+    #[error("Expected `--exclude-attribute` to be `NAME=VALUE`, got `{0}`")]
+    MalformedAttribute(String),
+}
+
+/// Remove the targets matching `exclude`, keeping every level so depths are unchanged.
+fn exclude_targets<'a>(
+    mut levels: Vec<Vec<(&'a BuckTarget, ImpactReason)>>,
+    exclude: &Exclude,
+) -> Vec<Vec<(&'a BuckTarget, ImpactReason)>> {
+    if !exclude.is_empty() {
+        for level in &mut levels {
+            level.retain(|(x, _)| !exclude.matches(x));
+        }
+    }
+    levels
+}
+
+/// Keep the first `max` targets, in order of depth, which are already sorted by label
+/// within each level. Every level is kept, so depths are unchanged. Also returns
+/// whether any targets were left out.
+fn truncate_levels(
+    mut levels: Vec<Vec<(&BuckTarget, ImpactReason)>>,
+    max: Option<usize>,
+) -> (Vec<Vec<(&BuckTarget, ImpactReason)>>, bool) {
+    let mut remaining = max.unwrap_or(usize::MAX);
+    let mut truncated = false;
+    for level in &mut levels {
+        if level.len() > remaining {
+            level.truncate(remaining);
+            truncated = true;
+        }
+        remaining -= level.len();
+    }
+    if truncated {
+        warn!("Output truncated to {} targets", max.unwrap_or_default());
+    }
+    (levels, truncated)
+}
+
 fn print_recursive_changes<'a, T: Serialize + 'a>(
     changes: &[Vec<(&'a BuckTarget, ImpactReason)>],
-    sudos: &HashSet<TargetLabelKeyRef>,
+    propagated: &PropagatedLabels,
+    quarantined: Option<&HashSet<TargetLabel>>,
+    removed: Option<Vec<RemovedTarget<'a>>>,
+    truncated: bool,
+    partial: bool,
     output: OutputFormat,
     mut augment: impl FnMut(&'a BuckTarget, Output<'a>) -> T,
 ) {
+    let is_quarantined = |x: &BuckTarget| quarantined.is_some_and(|q| q.contains(&x.label()));
     if output == OutputFormat::Text {
         for (depth, xs) in changes.iter().enumerate() {
             println!("Level {}", depth);
-            for (x, _) in xs {
+            for (x, _) in xs.iter().filter(|(x, _)| !is_quarantined(x)) {
                 println!("  {}", x.label());
             }
         }
+        if quarantined.is_some() {
+            println!("Quarantined");
+            for (x, _) in changes.iter().flatten().filter(|(x, _)| is_quarantined(x)) {
+                println!("  {}", x.label());
+            }
+        }
+        if let Some(removed) = removed {
+            println!("Removed");
+            for x in removed {
+                println!("  {}", x.target);
+            }
+        }
+        if truncated {
+            println!("Truncated");
+        }
+        if partial {
+            println!("Partial");
+        }
     } else {
-        let items = changes
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum Item<'a, T> {
+            Target(T),
+            Quarantined(Quarantined<T>),
+            Removed(Removed<'a>),
+            Truncated(Truncated),
+            Partial(Partial),
+        }
+
+        let mut record = |depth: usize, x: &'a BuckTarget, reason: &ImpactReason| {
+            let labels = propagated_labels(propagated, x);
+            augment(
+                x,
+                Output::from_target(x, depth as u64, &labels, reason.clone()),
+            )
+        };
+        let targets = changes
             .iter()
             .enumerate()
-            .flat_map(|(depth, xs)| {
-                xs.iter()
-                    .map(move |&(x, ref r)| (depth, x, sudos.contains(&x.label_key()), r.clone()))
-            })
-            .map(|(depth, x, uses_sudo, reason)| {
-                augment(x, Output::from_target(x, depth as u64, uses_sudo, reason))
-            });
+            .flat_map(|(depth, xs)| xs.iter().map(move |&(x, ref r)| (depth, x, r)));
+        // Few enough to collect, so the rest can still be written as they are produced
+        let moved = targets
+            .clone()
+            .filter(|x| is_quarantined(x.1))
+            .map(|(depth, x, reason)| record(depth, x, reason))
+            .collect::<Vec<_>>();
+        let items = targets
+            .filter(|x| !is_quarantined(x.1))
+            .map(|(depth, x, reason)| Item::Target(record(depth, x, reason)))
+            .chain(quarantined.map(|_| Item::Quarantined(Quarantined { quarantined: moved })))
+            .chain(removed.map(|removed| Item::Removed(Removed { removed })))
+            .chain(truncated.then_some(Item::Truncated(Truncated { truncated: true })))
+            .chain(partial.then_some(Item::Partial(Partial { partial: true })));
 
         let out = stdout().lock();
         if output == OutputFormat::Json {
@@ -474,6 +1911,15 @@ fn print_recursive_changes<'a, T: Serialize + 'a>(
     }
 }
 
+fn print_coalesced<T: Display + Serialize>(items: &[T], output: OutputFormat) {
+    let out = stdout().lock();
+    match output {
+        OutputFormat::Text => items.iter().for_each(|x| println!("{x}")),
+        OutputFormat::Json => json::write_json_per_line(out, items).unwrap(),
+        OutputFormat::JsonLines => json::write_json_lines(out, items).unwrap(),
+    }
+}
+
 fn write_errors_to_file(
     errors: &[ValidationError],
     error_file: PathBuf,
@@ -496,3 +1942,33 @@ fn write_errors_to_file(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_mode() {
+        let mode = |extra: &[&str]| {
+            let argv = ["btd", "--changes", "changes.txt", "--base", "base.jsonl"];
+            let args = Args::try_parse_from(argv.iter().chain(extra)).unwrap();
+            attribute_diff(&args).map(|x| x.mode)
+        };
+        assert_eq!(mode(&[]).unwrap(), DiffMode::Hash);
+        assert_eq!(
+            mode(&["--ignore-attribute", "metadata"]).unwrap(),
+            DiffMode::Attributes
+        );
+        assert_eq!(
+            mode(&["--diff-mode", "attributes"]).unwrap(),
+            DiffMode::Attributes
+        );
+        assert_eq!(mode(&["--diff-mode", "hash"]).unwrap(), DiffMode::Hash);
+
+        // Only ignoring attributes while comparing by hash conflicts
+        let ignoring = |diff_mode| mode(&["--diff-mode", diff_mode, "--ignore-attribute", "x"]);
+        assert_eq!(ignoring("attributes").unwrap(), DiffMode::Attributes);
+        assert!(ignoring("hash").is_err());
+        assert!(mode(&["--diff-mode", "hash", "--unordered-attribute", "srcs"]).is_err());
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Shrink a bundle written by `--record-bundle` to a small reproducer, by delta
+//! debugging its changes and then its targets, while a predicate still holds.
+//!
+//! Each candidate is replayed by running BTD again, so crashes are caught too.
+//! A target is removed from both the base and diff targets at once, while the
+//! imports and package errors are always kept.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::Context as _;
+use td_util::command::with_command;
+use tempfile::TempDir;
+use thiserror::Error;
+use tracing::info;
+
+use crate::buck::types::TargetLabel;
+use crate::bundle;
+use crate::diff_outputs;
+
+/// Shrink the bundle `BUNDLE` to the fewest changes and targets for which the predicate
+/// still holds, writing the result to `--output`.
+#[derive(clap::Args, Debug)]
+#[command(group(clap::ArgGroup::new("predicate").required(true)))]
+pub struct MinimizeArgs {
+    /// Bundle written by `--record-bundle`.
+    #[arg(value_name = "BUNDLE")]
+    bundle: PathBuf,
+
+    /// Directory to write the minimized bundle to.
+    #[arg(long, value_name = "DIR")]
+    output: PathBuf,
+
+    /// Keep `TARGET` impacted when the bundle is replayed.
+    #[arg(long, value_name = "TARGET", group = "predicate")]
+    impacted: Option<String>,
+
+    /// Keep the replay failing, e.g. with an error or a crash.
+    #[arg(long, group = "predicate")]
+    fails: bool,
+
+    /// With `--fails`, only count failures whose stderr contains `TEXT`.
+    #[arg(long, value_name = "TEXT", requires = "fails")]
+    error_contains: Option<String>,
+
+    /// Keep `COMMAND DIR` succeeding, where `DIR` holds the candidate bundle.
+    #[arg(long, value_name = "COMMAND", group = "predicate")]
+    predicate_command: Option<String>,
+}
+
+#[derive(Debug, Error)]
+enum MinimizeError {
+    #[error("The predicate doesn't hold for the bundle `{0}`, so there is nothing to keep")]
+    NotInteresting(PathBuf),
+}
+
+/// What must stay true of the bundle as it shrinks.
+enum Predicate {
+    Impacted(TargetLabel),
+    Fails(Option<String>),
+    Command(String),
+}
+
+impl Predicate {
+    fn new(args: &MinimizeArgs) -> Self {
+        match (&args.impacted, &args.predicate_command) {
+            (Some(target), _) => Self::Impacted(TargetLabel::new(target)),
+            (None, Some(command)) => Self::Command(command.clone()),
+            (None, None) => Self::Fails(args.error_contains.clone()),
+        }
+    }
+
+    fn holds(&self, dir: &Path) -> anyhow::Result<bool> {
+        match self {
+            Self::Impacted(target) => {
+                let mut command = bundle::replay_command(dir)?;
+                command.stderr(Stdio::null());
+                let res = with_command(command, |mut command| Ok(command.output()?))?;
+                Ok(res.status.success()
+                    && diff_outputs::parse_output(&String::from_utf8_lossy(&res.stdout))
+                        .contains_key(target))
+            }
+            Self::Fails(text) => {
+                let mut command = bundle::replay_command(dir)?;
+                command.stdout(Stdio::null());
+                let res = with_command(command, |mut command| Ok(command.output()?))?;
+                Ok(!res.status.success()
+                    && text.as_ref().map_or(true, |text| {
+                        String::from_utf8_lossy(&res.stderr).contains(text.as_str())
+                    }))
+            }
+            Self::Command(program) => {
+                let mut command = Command::new(program);
+                command.arg(dir).stdout(Stdio::null()).stderr(Stdio::null());
+                Ok(with_command(command, |mut command| Ok(command.status()?))
+                    .with_context(|| format!("When running `{program}`"))?
+                    .success())
+            }
+        }
+    }
+}
+
+/// The smallest subset of `items` found for which `test` holds, assuming it holds for
+/// `items`, by delta debugging: remove ever smaller chunks while `test` still holds.
+/// The result is 1-minimal, i.e. removing any single item makes `test` fail.
+pub fn ddmin<T: Clone>(
+    mut items: Vec<T>,
+    mut test: impl FnMut(&[T]) -> anyhow::Result<bool>,
+) -> anyhow::Result<Vec<T>> {
+    if !items.is_empty() && test(&[])? {
+        return Ok(Vec::new());
+    }
+    let mut chunks = 2;
+    while items.len() >= 2 {
+        let size = items.len().div_ceil(chunks);
+        let mut reduced = false;
+        for start in (0..items.len()).step_by(size) {
+            let mut candidate = items[..start].to_vec();
+            candidate.extend_from_slice(&items[(start + size).min(items.len())..]);
+            if test(&candidate)? {
+                items = candidate;
+                chunks = (chunks - 1).max(2);
+                reduced = true;
+                break;
+            }
+        }
+        if !reduced {
+            if chunks >= items.len() {
+                break;
+            }
+            chunks = (chunks * 2).min(items.len());
+        }
+    }
+    Ok(items)
+}
+
+/// The label of a line of a targets file, if it is a target.
+fn line_label(line: &str) -> anyhow::Result<Option<String>> {
+    let value: serde_json::Value = serde_json::from_str(line)?;
+    Ok(
+        match (value["buck.package"].as_str(), value["name"].as_str()) {
+            (Some(package), Some(name)) => Some(format!("{package}:{name}")),
+            _ => None,
+        },
+    )
+}
+
+/// The lines of a targets file in the bundle, with the label of each target.
+fn read_targets(file: &Path) -> anyhow::Result<Vec<(Option<String>, String)>> {
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_to_string(file)
+        .with_context(|| format!("When reading `{}`", file.display()))?
+        .lines()
+        .map(|x| Ok((line_label(x)?, x.to_owned())))
+        .collect()
+}
+
+/// The files of the bundle which are minimized, with what is left of them.
+struct Candidate<'a> {
+    changes: &'a [String],
+    targets: &'a BTreeSet<&'a str>,
+}
+
+fn write_candidate(
+    dir: &Path,
+    base: &[(Option<String>, String)],
+    diff: Option<&[(Option<String>, String)]>,
+    candidate: &Candidate,
+) -> anyhow::Result<()> {
+    let lines = |xs: &[(Option<String>, String)]| {
+        let mut res = String::new();
+        for (label, line) in xs {
+            if label
+                .as_deref()
+                .map_or(true, |x| candidate.targets.contains(x))
+            {
+                res.push_str(line);
+                res.push('\n');
+            }
+        }
+        res
+    };
+    fs::write(dir.join(bundle::BASE), lines(base))?;
+    if let Some(diff) = diff {
+        fs::write(dir.join(bundle::DIFF), lines(diff))?;
+    }
+    let changes = candidate
+        .changes
+        .iter()
+        .map(|x| format!("{x}\n"))
+        .collect::<String>();
+    fs::write(dir.join(bundle::CHANGES), changes)?;
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(to).with_context(|| format!("When creating `{}`", to.display()))?;
+    for x in fs::read_dir(from).with_context(|| format!("When reading `{}`", from.display()))? {
+        let x = x?;
+        fs::copy(x.path(), to.join(x.file_name()))?;
+    }
+    Ok(())
+}
+
+pub fn main(args: MinimizeArgs) -> anyhow::Result<()> {
+    let predicate = Predicate::new(&args);
+    let work = TempDir::new()?;
+    let dir = work.path();
+    copy_dir(&args.bundle, dir)?;
+
+    let base = read_targets(&dir.join(bundle::BASE))?;
+    let diff = dir
+        .join(bundle::DIFF)
+        .exists()
+        .then(|| read_targets(&dir.join(bundle::DIFF)))
+        .transpose()?;
+    let changes = fs::read_to_string(dir.join(bundle::CHANGES))?
+        .lines()
+        .filter(|x| !x.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let all_targets = base
+        .iter()
+        .chain(diff.iter().flatten())
+        .filter_map(|(label, _)| label.as_deref())
+        .collect::<BTreeSet<_>>();
+
+    if !predicate.holds(dir)? {
+        return Err(MinimizeError::NotInteresting(args.bundle).into());
+    }
+    let mut tests = 0;
+    let mut test = |candidate: &Candidate| {
+        tests += 1;
+        write_candidate(dir, &base, diff.as_deref(), candidate)?;
+        predicate.holds(dir)
+    };
+
+    info!("Minimizing {} changes", changes.len());
+    let kept_changes = ddmin(changes.clone(), |changes| {
+        test(&Candidate {
+            changes,
+            targets: &all_targets,
+        })
+    })?;
+    info!("Minimizing {} targets", all_targets.len());
+    let kept_targets = ddmin(all_targets.iter().copied().collect(), |targets| {
+        test(&Candidate {
+            changes: &kept_changes,
+            targets: &targets.iter().copied().collect(),
+        })
+    })?;
+
+    write_candidate(
+        dir,
+        &base,
+        diff.as_deref(),
+        &Candidate {
+            changes: &kept_changes,
+            targets: &kept_targets.iter().copied().collect(),
+        },
+    )?;
+    copy_dir(dir, &args.output)?;
+    println!(
+        "Kept {} of {} changes and {} of {} targets, after {tests} replays",
+        kept_changes.len(),
+        changes.len(),
+        kept_targets.len(),
+        all_targets.len(),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ddmin() {
+        let items = (0..20).collect::<Vec<_>>();
+        let mut tests = 0;
+        let res = ddmin(items.clone(), |xs| {
+            tests += 1;
+            Ok(xs.contains(&3) && xs.contains(&17))
+        })
+        .unwrap();
+        assert_eq!(res, vec![3, 17]);
+        assert!(tests < 50, "took {tests} tests");
+
+        assert_eq!(
+            ddmin(items.clone(), |_| Ok(true)).unwrap(),
+            Vec::<i32>::new()
+        );
+        assert_eq!(ddmin(items, |xs| Ok(xs.len() >= 20)).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn test_line_label() {
+        assert_eq!(
+            line_label(r#"{"buck.package": "foo//bar", "name": "baz"}"#).unwrap(),
+            Some("foo//bar:baz".to_owned())
+        );
+        assert_eq!(
+            line_label(r#"{"buck.package": "foo//bar", "buck.error": "bad"}"#).unwrap(),
+            None
+        );
+    }
+}
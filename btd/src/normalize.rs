@@ -0,0 +1,277 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Drop the Starlark files (build files, `PACKAGE` files and `.bzl` files) from the
+//! changes when only their comments or whitespace changed, so they don't cause
+//! packages to be rerun or owned by a change policy.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Context as _;
+use td_util::command::with_command;
+use thiserror::Error;
+use tracing::info;
+
+use crate::buck::cells::CellInfo;
+use crate::buck::types::CellPath;
+use crate::buck::types::ProjectRelativePath;
+use crate::changes::Changes;
+use crate::changes::Scm;
+use crate::sapling::status::Status;
+
+#[derive(Error, Debug)]
+enum NormalizeError {
+    #[error("`--ignore-cosmetic-changes` needs `--changes-from-scm` or `--file-contents-command`")]
+    NoContents,
+    #[error("`{command}` failed for `{path}` with stderr: {stderr}")]
+    CommandFailed {
+        command: String,
+        path: ProjectRelativePath,
+        stderr: String,
+    },
+}
+
+/// Where to get the contents of a changed file from, before and after the change.
+pub enum FileContents {
+    /// Run this command with `base` or `diff` and the path relative to the root of the repo.
+    Command(String),
+    /// Ask source control for the base contents, and read the diff from the working copy.
+    Scm {
+        scm: Scm,
+        rev: String,
+        root: PathBuf,
+    },
+}
+
+impl FileContents {
+    pub fn new(command: Option<&str>, rev: Option<&str>) -> anyhow::Result<Self> {
+        match (command, rev) {
+            (Some(command), _) => Ok(Self::Command(command.to_owned())),
+            (None, Some(rev)) => {
+                let scm = Scm::detect()?;
+                Ok(Self::Scm {
+                    scm,
+                    rev: rev.to_owned(),
+                    root: scm.root()?,
+                })
+            }
+            (None, None) => Err(NormalizeError::NoContents.into()),
+        }
+    }
+
+    fn run(command: &str, state: &str, path: &ProjectRelativePath) -> anyhow::Result<String> {
+        let mut cmd = Command::new(command);
+        cmd.args([state, path.as_str()]);
+        with_command(cmd, |mut cmd| {
+            let res = cmd.output()?;
+            if !res.status.success() {
+                return Err(NormalizeError::CommandFailed {
+                    command: command.to_owned(),
+                    path: path.clone(),
+                    stderr: String::from_utf8_lossy(&res.stderr).into_owned(),
+                }
+                .into());
+            }
+            Ok(String::from_utf8(res.stdout)?)
+        })
+    }
+
+    fn base(&self, path: &ProjectRelativePath) -> anyhow::Result<String> {
+        match self {
+            Self::Command(command) => Self::run(command, "base", path),
+            Self::Scm { scm, rev, .. } => scm.cat(rev, path),
+        }
+    }
+
+    fn diff(&self, path: &ProjectRelativePath) -> anyhow::Result<String> {
+        match self {
+            Self::Command(command) => Self::run(command, "diff", path),
+            Self::Scm { root, .. } => Ok(fs::read_to_string(root.join(path.as_str()))?),
+        }
+    }
+}
+
+/// Whether `path` is a Starlark file whose changes we can normalize.
+fn is_starlark(cells: &CellInfo, path: &CellPath) -> anyhow::Result<bool> {
+    Ok(path.is_target_file(cells)? || path.is_package_file() || path.extension() == Some("bzl"))
+}
+
+/// Remove the modified Starlark files whose contents are the same before and after
+/// the change, once normalized by [`normalize_starlark`].
+pub fn drop_cosmetic_changes(
+    cells: &CellInfo,
+    changes: Changes,
+    contents: &FileContents,
+) -> anyhow::Result<Changes> {
+    let mut cosmetic = Vec::new();
+    for change in changes.status_paths() {
+        let Status::Modified((cell_path, path)) = change else {
+            continue;
+        };
+        if !is_starlark(cells, cell_path)? {
+            continue;
+        }
+        let base = contents
+            .base(path)
+            .with_context(|| format!("When reading the base contents of `{path}`"))?;
+        let diff = contents
+            .diff(path)
+            .with_context(|| format!("When reading the diff contents of `{path}`"))?;
+        if normalize_starlark(&base) == normalize_starlark(&diff) {
+            info!("Ignoring cosmetic change to `{cell_path}`");
+            cosmetic.push(cell_path.clone());
+        }
+    }
+    if cosmetic.is_empty() {
+        return Ok(changes);
+    }
+    Ok(changes.filter_by_cell_path(|x| !cosmetic.contains(x)))
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Strip the comments, blank lines and insignificant whitespace from Starlark source.
+/// Indentation at the start of a line is kept, as is everything within strings, so two
+/// files with the same normalized contents define the same things.
+///
+/// ```
+/// use btd::normalize::normalize_starlark;
+/// assert_eq!(
+///     normalize_starlark("load(':a.bzl', 'a')  # comment\n\na(\n    name = 'x',\n)\n"),
+///     normalize_starlark("load(':a.bzl','a')\na(name='x',)"),
+/// );
+/// ```
+pub fn normalize_starlark(src: &str) -> String {
+    let mut res = String::new();
+    // Bracket nesting, within which newlines and indentation don't matter
+    let mut depth = 0usize;
+    let mut line_start = true;
+    let mut indent = String::new();
+    // Whether whitespace separates the previous token from the next
+    let mut space = false;
+    let mut chars = src.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '#' => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            '\\' if chars.peek().is_some_and(|(_, c)| *c == '\n') => {
+                chars.next();
+                space = true;
+            }
+            '\n' if depth == 0 => {
+                if !line_start {
+                    res.push('\n');
+                }
+                line_start = true;
+                indent.clear();
+            }
+            _ if c.is_whitespace() => {
+                if line_start && depth == 0 {
+                    indent.push(c);
+                }
+                space = true;
+            }
+            _ => {
+                if line_start {
+                    res.push_str(&indent);
+                    line_start = false;
+                } else if space && res.ends_with(is_word) && is_word(c) {
+                    res.push(' ');
+                }
+                space = false;
+                match c {
+                    '(' | '[' | '{' => depth += 1,
+                    ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                    '"' | '\'' => {
+                        let end = string_end(src, i, c);
+                        res.push_str(&src[i..end]);
+                        while chars.next_if(|(j, _)| *j < end).is_some() {}
+                        continue;
+                    }
+                    _ => {}
+                }
+                res.push(c);
+            }
+        }
+    }
+    if !line_start {
+        res.push('\n');
+    }
+    res
+}
+
+/// The end of the string starting with the quote `quote` at `start`, or of `src`
+/// if it is unterminated.
+fn string_end(src: &str, start: usize, quote: char) -> usize {
+    let triple = format!("{quote}{quote}{quote}");
+    let (delim, body) = if src[start..].starts_with(&triple) {
+        (triple.as_str(), start + 3)
+    } else {
+        (&triple[..1], start + 1)
+    };
+    let mut chars = src[body..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if src[body + i..].starts_with(delim) {
+            return body + i + delim.len();
+        }
+    }
+    src.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_starlark() {
+        let src = "# header\n\nload(\":defs.bzl\", \"lib\")\n\nlib(\n    name = \"a\",  # why\n)\n";
+        assert_eq!(
+            normalize_starlark(src),
+            "load(\":defs.bzl\",\"lib\")\nlib(name=\"a\",)\n"
+        );
+        assert_eq!(
+            normalize_starlark("lib(name = \"a\", srcs = [\n  \"a.c\" ,\n])\t# end"),
+            "lib(name=\"a\",srcs=[\"a.c\",])\n"
+        );
+
+        // Strings and the spaces between words are significant
+        assert_eq!(normalize_starlark("x = 'it\\'s' # c"), "x='it\\'s'\n");
+        assert_ne!(
+            normalize_starlark("x = '# a'"),
+            normalize_starlark("x = ''")
+        );
+        assert_ne!(
+            normalize_starlark("x = 'a b'"),
+            normalize_starlark("x = 'ab'")
+        );
+        assert_ne!(
+            normalize_starlark("x = \"\"\"a\n b\"\"\""),
+            normalize_starlark("x = \"\"\"a\nb\"\"\"")
+        );
+        assert_eq!(
+            normalize_starlark("if a  and not b:\n  c()"),
+            "if a and not b:\n  c()\n"
+        );
+
+        // Indentation outside brackets is significant, blank lines aren't
+        assert_ne!(
+            normalize_starlark("def f():\n    a()\n    b()\n"),
+            normalize_starlark("def f():\n    a()\nb()\n")
+        );
+        assert_eq!(
+            normalize_starlark("def f():\n    a()\n\n    # c\n    b()\n"),
+            normalize_starlark("def f():\n    a()\n    b()")
+        );
+    }
+}
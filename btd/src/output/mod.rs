@@ -0,0 +1,1067 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+#[cfg(feature = "protobuf")]
+mod protobuf;
+#[cfg(any(feature = "thrift", feature = "protobuf"))]
+mod schema;
+#[cfg(feature = "thrift")]
+mod thrift;
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::Display;
+use std::io::Write;
+
+use clap::ValueEnum;
+use serde::Serialize;
+use td_util::string::InternString;
+use thiserror::Error;
+
+use crate::buck::labels::Labels;
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::Oncall;
+use crate::buck::types::Package;
+use crate::buck::types::RuleType;
+use crate::buck::types::TargetLabel;
+use crate::classify::Class;
+use crate::classify::Classifier;
+use crate::diff::ImpactReason;
+use crate::diff::RootImpactKind;
+use crate::owners::Owners;
+use crate::package_error::PackageError;
+use crate::propagate::propagated_labels;
+use crate::propagate::PropagatedLabels;
+use crate::score::Budget;
+use crate::score::Scorer;
+use crate::score::Skipped;
+use crate::snapshot::Header;
+
+#[derive(Debug, Serialize)]
+pub struct Output<'a> {
+    target: TargetLabel,
+    #[serde(rename = "type")]
+    typ: &'a str,
+    oncall: &'a Option<Oncall>,
+    /// Distance from the nearest changed target, `0` if the target changed itself.
+    depth: u64,
+    labels: Labels,
+    reason: ImpactReason,
+    /// The owners of the target's package, from `--owners-file`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    owners: Vec<InternString>,
+    /// Whether the target is a test or a build, and the type of test, from `--classify`.
+    #[serde(flatten)]
+    class: Option<Class>,
+}
+
+impl<'a> Output<'a> {
+    /// The `propagated` labels are added after the target's own labels.
+    pub fn from_target(
+        x: &'a BuckTarget,
+        depth: u64,
+        propagated: &Labels,
+        reason: ImpactReason,
+    ) -> Self {
+        Self {
+            target: x.label(),
+            typ: x.rule_type.short(),
+            oncall: &x.oncall,
+            depth,
+            // package values must come before target labels for overrides to work.
+            labels: x.package_values.labels.merge3(&x.labels, propagated),
+            reason,
+            owners: Vec::new(),
+            class: None,
+        }
+    }
+
+    pub fn with_owners(self, owners: &[InternString]) -> Self {
+        Self {
+            owners: owners.to_vec(),
+            ..self
+        }
+    }
+
+    pub fn with_class(self, classifier: &Classifier) -> Self {
+        Self {
+            class: classifier.classify(self.typ, &self.labels),
+            ..self
+        }
+    }
+}
+
+impl<'a> Display for Output<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+/// The schema of the impacted targets output. Once released, a schema doesn't change,
+/// so consumers can rely on it, and richer output is added as a new version.
+#[derive(ValueEnum, Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputSchema {
+    /// A list of [`Output`], printed as text, JSON or JSON lines.
+    #[default]
+    V1,
+    /// A single JSON [`DocumentV2`], whatever `--json` or `--json-lines` say.
+    V2,
+}
+
+/// How a [`DocumentV2`] is written, for consumers wanting binary schemas.
+#[derive(ValueEnum, Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputEncoding {
+    #[default]
+    Json,
+    /// Thrift's compact protocol, see `btd/schema/impacted.thrift`. Needs the `thrift`
+    /// feature.
+    ThriftCompact,
+    /// Protocol buffers, see `btd/schema/impacted.proto`. Needs the `protobuf` feature.
+    Protobuf,
+}
+
+#[derive(Debug, Error)]
+pub enum EncodingError {
+    #[error("`--output-encoding {0}` needs BTD built with the `{1}` feature")]
+    NotBuilt(&'static str, &'static str),
+}
+
+impl OutputEncoding {
+    pub fn encoder(self) -> Result<Box<dyn Encoder>, EncodingError> {
+        match self {
+            Self::Json => Ok(Box::new(JsonEncoder)),
+            #[cfg(feature = "thrift")]
+            Self::ThriftCompact => Ok(Box::new(thrift::ThriftCompactEncoder)),
+            #[cfg(not(feature = "thrift"))]
+            Self::ThriftCompact => Err(EncodingError::NotBuilt("thrift-compact", "thrift")),
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => Ok(Box::new(protobuf::ProtobufEncoder)),
+            #[cfg(not(feature = "protobuf"))]
+            Self::Protobuf => Err(EncodingError::NotBuilt("protobuf", "protobuf")),
+        }
+    }
+}
+
+/// Writes a [`DocumentV2`] in some encoding.
+pub trait Encoder {
+    fn encode(&self, doc: &DocumentV2, out: &mut dyn Write) -> anyhow::Result<()>;
+}
+
+/// Pretty printed JSON, followed by a newline.
+pub struct JsonEncoder;
+
+impl Encoder for JsonEncoder {
+    fn encode(&self, doc: &DocumentV2, out: &mut dyn Write) -> anyhow::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, doc)?;
+        out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// A target in the version 2 output schema.
+#[derive(Debug, Serialize)]
+pub struct OutputV2<'a> {
+    target: TargetLabel,
+    /// The full rule type, e.g. `prelude//rules.bzl:cxx_library`.
+    rule_type: &'a RuleType,
+    oncall: &'a Option<Oncall>,
+    /// Distance from the nearest changed target, `0` if the target changed itself.
+    depth: u64,
+    labels: Labels,
+    reason: ReasonV2,
+    /// The owners of the target's package, from `--owners-file`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    owners: Vec<InternString>,
+    /// Whether the target is a test or a build, and the type of test, from `--classify`.
+    #[serde(flatten)]
+    class: Option<Class>,
+    /// The attributes named by `--output-attribute` which the target has.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: BTreeMap<&'a str, &'a serde_json::Value>,
+    /// How valuable the test is to run, from `--score`. Only set for tests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+}
+
+/// Why a target was impacted, in the version 2 output schema.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ReasonV2 {
+    category: ReasonCategory,
+    /// The precise kind of change, e.g. `inputs`.
+    kind: RootImpactKind,
+    confidence: Confidence,
+    /// The target which changed, the same as `target` at depth `0`.
+    changed_target: String,
+    /// The dependency through which the change reached this target, absent at depth `0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    via: Option<String>,
+}
+
+/// A coarse grouping of [`RootImpactKind`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonCategory {
+    /// A file the target uses changed, e.g. a source or the `.bzl` file defining its rule.
+    ChangedFile,
+    /// The definition of the target changed, or it was added or removed.
+    ChangedTarget,
+    /// Something applying to the whole package or cell changed.
+    Package,
+}
+
+impl ReasonCategory {
+    pub fn new(kind: RootImpactKind) -> Self {
+        match kind {
+            RootImpactKind::Inputs
+            | RootImpactKind::CiSrcs
+            | RootImpactKind::Rule
+            | RootImpactKind::Ownership
+            | RootImpactKind::ChangePolicy
+            | RootImpactKind::ImpactRule => Self::ChangedFile,
+            RootImpactKind::New
+            | RootImpactKind::Hash
+            | RootImpactKind::Remove
+            | RootImpactKind::ManualForRerun
+            | RootImpactKind::BrokenPackage
+            | RootImpactKind::Query => Self::ChangedTarget,
+            RootImpactKind::Package
+            | RootImpactKind::PackageValues
+            | RootImpactKind::PackageFile
+            | RootImpactKind::Buckconfig
+            | RootImpactKind::LoadedBzl => Self::Package,
+        }
+    }
+}
+
+/// How certain it is that a target is impacted, decided by the [`RootImpactKind`] it
+/// was first reached from and the edges it was reached through, so schedulers can run
+/// heuristic-only targets later.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Confidence {
+    /// Reached through the inputs, deps or definitions of targets.
+    Exact,
+    /// Attributed by a heuristic: package ownership, a `--change-policy`, a file
+    /// matching the `ci_srcs` globs, e.g. from dependency hints, a `.bzl` file loaded
+    /// by the package, which might not have altered the target, or an `--impact-rule`.
+    /// Also anything only reached through a `ci_deps` or `ci_hint` dependency hint.
+    Heuristic,
+}
+
+impl Confidence {
+    pub fn new(reason: &ImpactReason) -> Self {
+        // However exact the root cause, a hint might not be a real dependency
+        if reason.via_hint {
+            return Self::Heuristic;
+        }
+        match reason.root_cause.1 {
+            RootImpactKind::Ownership
+            | RootImpactKind::ChangePolicy
+            | RootImpactKind::CiSrcs
+            | RootImpactKind::LoadedBzl
+            | RootImpactKind::ImpactRule => Self::Heuristic,
+            RootImpactKind::New
+            | RootImpactKind::Package
+            | RootImpactKind::Hash
+            | RootImpactKind::Inputs
+            | RootImpactKind::Rule
+            | RootImpactKind::PackageValues
+            | RootImpactKind::Remove
+            | RootImpactKind::ManualForRerun
+            | RootImpactKind::PackageFile
+            | RootImpactKind::Buckconfig
+            | RootImpactKind::BrokenPackage
+            | RootImpactKind::Query => Self::Exact,
+        }
+    }
+}
+
+impl<'a> OutputV2<'a> {
+    /// The `propagated` labels are added after the target's own labels.
+    pub fn from_target(
+        x: &'a BuckTarget,
+        depth: u64,
+        propagated: &Labels,
+        reason: ImpactReason,
+    ) -> Self {
+        let confidence = Confidence::new(&reason);
+        let (changed_target, kind) = reason.root_cause;
+        Self {
+            target: x.label(),
+            rule_type: &x.rule_type,
+            oncall: &x.oncall,
+            depth,
+            labels: x.package_values.labels.merge3(&x.labels, propagated),
+            reason: ReasonV2 {
+                category: ReasonCategory::new(kind),
+                kind,
+                confidence,
+                changed_target,
+                via: Some(reason.affected_dep).filter(|x| !x.is_empty()),
+            },
+            owners: Vec::new(),
+            class: None,
+            attributes: BTreeMap::new(),
+            score: None,
+        }
+    }
+
+    pub fn with_owners(self, owners: &[InternString]) -> Self {
+        Self {
+            owners: owners.to_vec(),
+            ..self
+        }
+    }
+
+    pub fn with_class(self, classifier: &Classifier) -> Self {
+        Self {
+            class: classifier.classify(self.rule_type.short(), &self.labels),
+            ..self
+        }
+    }
+
+    /// Score `x` if it is a test, according to its class if classified, otherwise
+    /// its rule type. Must come after [`with_class`](Self::with_class).
+    pub fn with_score(self, x: &BuckTarget, scorer: &Scorer) -> Self {
+        let is_test = match &self.class {
+            Some(class) => class.is_test(),
+            None => self.rule_type.short().ends_with("test"),
+        };
+        Self {
+            score: if is_test {
+                scorer.score(x, self.depth)
+            } else {
+                None
+            },
+            ..self
+        }
+    }
+
+    /// Add the attributes of `x` named in `names`, e.g. attributes BTD doesn't know about.
+    pub fn with_attributes(self, x: &'a BuckTarget, names: &'a [String]) -> Self {
+        Self {
+            attributes: names
+                .iter()
+                .filter_map(|name| Some((name.as_str(), x.attributes.get(name)?)))
+                .collect(),
+            ..self
+        }
+    }
+}
+
+/// The whole output in the version 2 schema.
+#[derive(Debug, Serialize)]
+pub struct DocumentV2<'a> {
+    /// Always `2`.
+    version: u32,
+    targets: Vec<OutputV2<'a>>,
+    /// Whether some impacted targets were left out, see [`Truncated`].
+    truncated: bool,
+    /// Whether the traversal was interrupted, see [`Partial`].
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+    /// The removed targets, with `--include-removed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed: Option<Vec<RemovedTarget<'a>>>,
+    /// The tests left out by `--target-budget` or `--time-budget`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped: Option<Vec<Skipped>>,
+    /// Where the graphs came from, see [`Metadata`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+    /// The impacted tests moved out of `targets` by `--quarantine`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quarantined: Option<Vec<OutputV2<'a>>>,
+    /// The aliases of the reported targets, dropped by `--collapse-aliases`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aliases: Option<Vec<Alias>>,
+    /// The targets which changed themselves, moved out of `targets` by
+    /// `--split-immediate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    immediate_changes: Option<Vec<OutputV2<'a>>>,
+    /// The targets impacted through their dependencies, moved out of `targets` by
+    /// `--split-immediate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transitive_changes: Option<Vec<OutputV2<'a>>>,
+    /// The packages which failed to evaluate after the change, see [`PackageError`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<PackageError>>,
+}
+
+impl<'a> DocumentV2<'a> {
+    pub fn new(
+        levels: &[Vec<(&'a BuckTarget, ImpactReason)>],
+        propagated: &PropagatedLabels,
+        owners: &Owners,
+        classifier: &Classifier,
+        scorer: &Scorer,
+        attributes: &'a [String],
+    ) -> Self {
+        let mut targets = Vec::with_capacity(levels.iter().map(|x| x.len()).sum());
+        for (depth, level) in levels.iter().enumerate() {
+            for (x, reason) in level {
+                let labels = propagated_labels(propagated, x);
+                targets.push(
+                    OutputV2::from_target(x, depth as u64, &labels, reason.clone())
+                        .with_owners(owners.get(&x.package))
+                        .with_class(classifier)
+                        .with_score(x, scorer)
+                        .with_attributes(x, attributes),
+                );
+            }
+        }
+        Self {
+            version: 2,
+            targets,
+            truncated: false,
+            partial: false,
+            removed: None,
+            skipped: None,
+            metadata: None,
+            quarantined: None,
+            aliases: None,
+            immediate_changes: None,
+            transitive_changes: None,
+            errors: None,
+        }
+    }
+
+    pub fn with_truncated(self, truncated: bool) -> Self {
+        Self { truncated, ..self }
+    }
+
+    pub fn with_partial(self, partial: bool) -> Self {
+        Self { partial, ..self }
+    }
+
+    pub fn with_removed(self, removed: Option<Vec<RemovedTarget<'a>>>) -> Self {
+        Self { removed, ..self }
+    }
+
+    /// Left out if neither graph was read from a snapshot.
+    pub fn with_metadata(self, metadata: Metadata) -> Self {
+        Self {
+            metadata: (metadata.base.is_some() || metadata.diff.is_some()).then_some(metadata),
+            ..self
+        }
+    }
+
+    /// Move the `quarantined` targets from `targets` to `quarantined`, before any budget,
+    /// so they don't count against it.
+    pub fn with_quarantined(self, quarantined: Option<&HashSet<TargetLabel>>) -> Self {
+        let Some(quarantined) = quarantined else {
+            return self;
+        };
+        let (moved, targets): (Vec<_>, Vec<_>) = self
+            .targets
+            .into_iter()
+            .partition(|x| quarantined.contains(&x.target));
+        Self {
+            targets,
+            quarantined: Some(moved),
+            ..self
+        }
+    }
+
+    /// Leave out the scored tests over the `budget`, listing them as `skipped`. Targets
+    /// without a score, like libraries, are always kept.
+    pub fn with_budget(mut self, budget: &Budget) -> Self {
+        if budget.is_empty() {
+            return self;
+        }
+        let scored = self
+            .targets
+            .iter()
+            .filter_map(|x| Some((x.target.clone(), x.score?)))
+            .collect::<Vec<_>>();
+        let skipped = budget.select(&scored);
+        let skipped_targets = skipped.iter().map(|x| &x.target).collect::<HashSet<_>>();
+        self.targets
+            .retain(|x| !skipped_targets.contains(&x.target));
+        Self {
+            skipped: Some(skipped),
+            ..self
+        }
+    }
+
+    /// List the `aliases` of the targets in `targets` or `quarantined`, given the target
+    /// each alias stands for. Must come after anything leaving targets out.
+    pub fn with_aliases(self, aliases: Option<&BTreeMap<TargetLabel, TargetLabel>>) -> Self {
+        let Some(aliases) = aliases else {
+            return self;
+        };
+        let reported = self
+            .targets
+            .iter()
+            .chain(self.quarantined.iter().flatten())
+            .map(|x| &x.target)
+            .collect::<HashSet<_>>();
+        let aliases = aliases
+            .iter()
+            .filter(|(_, target)| reported.contains(target))
+            .map(|(alias, target)| Alias {
+                alias: alias.clone(),
+                target: target.clone(),
+            })
+            .collect();
+        Self {
+            aliases: Some(aliases),
+            ..self
+        }
+    }
+
+    /// Left out if no package failed.
+    pub fn with_errors(self, errors: Vec<PackageError>) -> Self {
+        Self {
+            errors: (!errors.is_empty()).then_some(errors),
+            ..self
+        }
+    }
+
+    /// Move `targets` into `immediate_changes`, those at depth `0`, and
+    /// `transitive_changes`, the rest, so consumers needn't split them by `depth`. Must
+    /// come last, as it leaves `targets` empty.
+    pub fn with_split(self, split: bool) -> Self {
+        if !split {
+            return self;
+        }
+        let (immediate, transitive) = self.targets.into_iter().partition(|x| x.depth == 0);
+        Self {
+            targets: Vec::new(),
+            immediate_changes: Some(immediate),
+            transitive_changes: Some(transitive),
+            ..self
+        }
+    }
+
+    pub fn write(&self, mut out: impl Write, encoder: &dyn Encoder) -> anyhow::Result<()> {
+        encoder.encode(self, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// The headers of the `--base` and `--diff` snapshots, so results are traceable to the
+/// graphs which produced them.
+#[derive(Debug, Default, Serialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<Header>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<Header>,
+}
+
+/// An alias dropped by `--collapse-aliases`, and the target it stands for.
+#[derive(Debug, Serialize)]
+pub struct Alias {
+    pub alias: TargetLabel,
+    pub target: TargetLabel,
+}
+
+/// Written last in the version 1 JSON output when `--max-output` left impacted targets
+/// out, so callers know the output is incomplete and can fall back to building everything.
+#[derive(Debug, Serialize)]
+pub struct Truncated {
+    pub truncated: bool,
+}
+
+/// Written last in the version 1 JSON output when a signal stopped the traversal early,
+/// so only the impacted targets found by then were written, see `interrupt`.
+#[derive(Debug, Serialize)]
+pub struct Partial {
+    pub partial: bool,
+}
+
+/// A target in the base graph but not in the diff graph, as it was in the base,
+/// so consumers can retire e.g. its test results.
+#[derive(Debug, Serialize)]
+pub struct RemovedTarget<'a> {
+    pub target: TargetLabel,
+    /// The full rule type, e.g. `prelude//rules.bzl:cxx_library`.
+    rule_type: &'a RuleType,
+    package: &'a Package,
+}
+
+impl<'a> RemovedTarget<'a> {
+    pub fn from_target(x: &'a BuckTarget) -> Self {
+        Self {
+            target: x.label(),
+            rule_type: &x.rule_type,
+            package: &x.package,
+        }
+    }
+}
+
+/// Written after the impacted targets in the version 1 JSON output with `--quarantine`,
+/// listing the impacted tests it moved out of them, before [`Removed`].
+#[derive(Debug, Serialize)]
+pub struct Quarantined<T> {
+    pub quarantined: Vec<T>,
+}
+
+/// Written after the impacted targets in the version 1 JSON output with
+/// `--include-removed`, but before [`Truncated`].
+#[derive(Debug, Serialize)]
+pub struct Removed<'a> {
+    pub removed: Vec<RemovedTarget<'a>>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    JsonLines,
+}
+
+/// Write JSON lines as they are produced, rather than collecting them all first.
+/// The first error is remembered and returned by `finish`, after which nothing more is written.
+pub struct JsonLinesWriter<W: Write> {
+    out: W,
+    error: Option<anyhow::Error>,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, error: None }
+    }
+
+    fn attempt(&mut self, f: impl FnOnce(&mut W) -> anyhow::Result<()>) {
+        if self.error.is_none() {
+            if let Err(e) = f(&mut self.out) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    pub fn write(&mut self, x: &impl Serialize) {
+        self.attempt(|out| {
+            serde_json::to_writer(&mut *out, x)?;
+            out.write_all(b"\n")?;
+            Ok(())
+        })
+    }
+
+    /// Push everything written so far through to the underlying writer.
+    pub fn flush(&mut self) {
+        self.attempt(|out| Ok(out.flush()?))
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.flush();
+        match self.error {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::buck::arena::Deps;
+    use crate::buck::cells::CellInfo;
+    use crate::buck::targets::Attributes;
+    use crate::buck::targets::BuckError;
+    use crate::buck::targets::Targets;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::CellPath;
+    use crate::buck::types::Oncall;
+    use crate::buck::types::PackageValues;
+    use crate::buck::types::TargetHash;
+    use crate::buck::types::TargetPattern;
+    use crate::classify::ClassifyConfig;
+    use crate::diff::recursive_target_changes;
+    use crate::diff::GraphImpact;
+    use crate::diff::RootImpactKind;
+    use crate::diff_outputs;
+    use crate::score::ScoreWeights;
+
+    #[test]
+    fn test_read_targets() {
+        let json = serde_json::json!(
+            {
+                "target": "fbcode//me:test",
+                "type": "python_library",
+                "depth": 3,
+                "labels": ["my_label", "another_label"],
+                "oncall": "my_team",
+                "reason": {"affected_dep": "", "root_cause": ["fbcode//me:test", "inputs"]},            }
+        );
+
+        let target = BuckTarget {
+            deps: Deps::from([
+                TargetLabel::new("toolchains//:python"),
+                TargetLabel::new("fbcode//python:library"),
+            ]),
+            inputs: Box::new([CellPath::new("fbcode//me/file.bzl")]),
+            hash: TargetHash::new("43ce1a7a56f10225413a2991febb853a"),
+            labels: Labels::new(&["my_label", "another_label"]),
+            oncall: Some(Oncall::new("my_team")),
+            ..BuckTarget::testing("test", "fbcode//me", "prelude//rules.bzl:python_library")
+        };
+        let output = Output::from_target(
+            &target,
+            3,
+            &Labels::default(),
+            ImpactReason {
+                affected_dep: "".to_owned(),
+                root_cause: ("fbcode//me:test".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
+            },
+        );
+        assert_eq!(serde_json::to_value(&output).unwrap(), json);
+        assert_eq!(
+            serde_json::from_str::<Value>(&output.to_string()).unwrap(),
+            json
+        );
+        assert!(!output.to_string().contains('\n'));
+        let owners = [InternString::new("@my-team")];
+        let output = output.with_owners(&owners);
+        assert_eq!(
+            serde_json::to_value(&output).unwrap()["owners"],
+            serde_json::json!(["@my-team"])
+        );
+        let classifier = Classifier::new(ClassifyConfig::default());
+        let value = serde_json::to_value(output.with_class(&classifier)).unwrap();
+        assert_eq!(value["class"], "build");
+        assert_eq!(value.get("test_type"), None);
+
+        let target_no_oncall = BuckTarget {
+            oncall: None,
+            ..target
+        };
+        let json_no_oncall = serde_json::json!(
+            {
+                "target": "fbcode//me:test",
+                "type": "python_library",
+                "depth": 3,
+                "labels": ["my_label", "another_label"],
+                "oncall": Value::Null,
+                "reason":     ImpactReason {
+                    affected_dep: "".to_owned(),
+                    root_cause: ("fbcode//me:test".to_owned(), RootImpactKind::Inputs),
+                    via_hint: false,
+                },
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(Output::from_target(
+                &target_no_oncall,
+                3,
+                &Labels::default(),
+                ImpactReason {
+                    affected_dep: "".to_owned(),
+                    root_cause: ("fbcode//me:test".to_owned(), RootImpactKind::Inputs),
+                    via_hint: false,
+                },
+            ))
+            .unwrap(),
+            json_no_oncall
+        );
+    }
+
+    /// A document with every optional field set, serialized to JSON.
+    pub(super) fn full_document() -> Value {
+        let test = |name| BuckTarget {
+            oncall: Some(Oncall::new("my_team")),
+            labels: Labels::new(&["unit"]),
+            attributes: Attributes::new(vec![(
+                InternString::new("env"),
+                serde_json::json!({"A": "1"}),
+            )]),
+            ..BuckTarget::testing(name, "fbcode//me", "prelude//rules.bzl:cxx_test")
+        };
+        let (first, second, flaky) = (test("first"), test("second"), test("flaky"));
+        let reason = ImpactReason::new(&first, RootImpactKind::Inputs);
+        let levels = vec![
+            vec![(&first, reason.clone())],
+            vec![
+                (
+                    &second,
+                    ImpactReason {
+                        affected_dep: "fbcode//me:first".to_owned(),
+                        ..reason.clone()
+                        via_hint: false,
+                    },
+                ),
+                (
+                    &flaky,
+                    ImpactReason {
+                        affected_dep: "fbcode//me:first".to_owned(),
+                        ..reason
+                        via_hint: false,
+                    },
+                ),
+            ],
+        ];
+        let gone = BuckTarget::testing("gone", "fbcode//old", "prelude//rules.bzl:cxx_test");
+        let owners = Owners::parse("* @everyone", &CellInfo::testing()).unwrap();
+        let attributes = ["env".to_owned()];
+        let doc = DocumentV2::new(
+            &levels,
+            &PropagatedLabels::new(),
+            &owners,
+            &Classifier::new(ClassifyConfig::default()),
+            &Scorer::new(ScoreWeights::default(), HashMap::new()),
+            &attributes,
+        )
+        .with_truncated(true)
+        .with_partial(true)
+        .with_removed(Some(vec![RemovedTarget::from_target(&gone)]))
+        .with_metadata(Metadata {
+            base: Some(Header {
+                revision: Some("0123456789abcdef".to_owned()),
+                created: Some(1700000000),
+                universe: vec!["fbcode//...".to_owned()],
+                buck_version: Some("buck2 2023-11-10".to_owned()),
+            }),
+            diff: Some(Header::default()),
+        })
+        .with_quarantined(Some(&HashSet::from([flaky.label()])))
+        .with_budget(&Budget::new(Some(1)))
+        .with_aliases(Some(&BTreeMap::from([
+            (TargetLabel::new("fbcode//me:first_alias"), first.label()),
+            (TargetLabel::new("fbcode//me:gone_alias"), gone.label()),
+        ])))
+        .with_errors(vec![PackageError::new(&BuckError {
+            package: Package::new("fbcode//broken"),
+            error: "Parse error: unexpected new line".to_owned(),
+        })])
+        .with_split(true);
+        serde_json::to_value(&doc).unwrap()
+    }
+
+    #[test]
+    fn test_budget() {
+        let doc = full_document();
+        // Split after the budget, so only `first` is left, having changed itself
+        assert_eq!(doc["targets"], serde_json::json!([]));
+        assert_eq!(doc["transitive_changes"], serde_json::json!([]));
+        let targets = doc["immediate_changes"].as_array().unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0]["target"], "fbcode//me:first");
+        assert_eq!(targets[0]["score"], 1.5);
+        // Quarantined tests don't count against the budget
+        assert_eq!(doc["quarantined"][0]["target"], "fbcode//me:flaky");
+        assert_eq!(
+            doc["skipped"],
+            serde_json::json!([{
+                "target": "fbcode//me:second",
+                "score": 1.0,
+                "reason": "target_budget",
+            }])
+        );
+        // Only the aliases of targets still reported
+        assert_eq!(
+            doc["aliases"],
+            serde_json::json!([{"alias": "fbcode//me:first_alias", "target": "fbcode//me:first"}])
+        );
+    }
+
+    #[test]
+    fn test_document_v2() {
+        let lib = BuckTarget {
+            attributes: Attributes::new(vec![
+                (InternString::new("srcs"), serde_json::json!(["lib.c"])),
+                (InternString::new("metadata"), serde_json::json!({})),
+            ]),
+            ..BuckTarget::testing("lib", "fbcode//me", "prelude//rules.bzl:cxx_library")
+        };
+        let bin = BuckTarget {
+            oncall: Some(Oncall::new("my_team")),
+            ..BuckTarget::testing("bin", "fbcode//me", "prelude//rules.bzl:cxx_binary")
+        };
+        let reason = ImpactReason::new(&lib, RootImpactKind::Inputs);
+        let levels = vec![
+            vec![(&lib, reason.clone())],
+            vec![(
+                &bin,
+                ImpactReason {
+                    affected_dep: "fbcode//me:lib".to_owned(),
+                    ..reason
+                    via_hint: false,
+                },
+            )],
+        ];
+        let gone = BuckTarget::testing("gone", "fbcode//old", "prelude//rules.bzl:cxx_test");
+        let attributes = ["srcs".to_owned(), "missing".to_owned()];
+        let doc = DocumentV2::new(
+            &levels,
+            &PropagatedLabels::new(),
+            &Owners::default(),
+            &Classifier::default(),
+            &Scorer::default(),
+            &attributes,
+        )
+        .with_removed(Some(vec![RemovedTarget::from_target(&gone)]));
+        assert_eq!(
+            serde_json::to_value(&doc).unwrap(),
+            serde_json::json!({
+                "version": 2,
+                "targets": [
+                    {
+                        "target": "fbcode//me:lib",
+                        "rule_type": "prelude//rules.bzl:cxx_library",
+                        "oncall": null,
+                        "depth": 0,
+                        "labels": [],
+                        "reason": {
+                            "category": "changed_file",
+                            "kind": "inputs",
+                            "confidence": "exact",
+                            "changed_target": "fbcode//me:lib",
+                        },
+                        "attributes": {"srcs": ["lib.c"]},
+                    },
+                    {
+                        "target": "fbcode//me:bin",
+                        "rule_type": "prelude//rules.bzl:cxx_binary",
+                        "oncall": "my_team",
+                        "depth": 1,
+                        "labels": [],
+                        "reason": {
+                            "category": "changed_file",
+                            "kind": "inputs",
+                            "confidence": "exact",
+                            "changed_target": "fbcode//me:lib",
+                            "via": "fbcode//me:lib",
+                        },
+                    },
+                ],
+                "truncated": false,
+                "removed": [
+                    {
+                        "target": "fbcode//old:gone",
+                        "rule_type": "prelude//rules.bzl:cxx_test",
+                        "package": "fbcode//old",
+                    },
+                ],
+            })
+        );
+
+        // The same targets, by whether they changed themselves
+        let unsplit = diff_outputs::parse_output(&serde_json::to_string(&doc).unwrap());
+        let doc = serde_json::to_value(doc.with_split(true)).unwrap();
+        assert_eq!(doc["targets"], serde_json::json!([]));
+        assert_eq!(doc["immediate_changes"][0]["target"], "fbcode//me:lib");
+        assert_eq!(doc["transitive_changes"][0]["target"], "fbcode//me:bin");
+        // Which `btd diff-outputs` reads as the same impact
+        let split = diff_outputs::parse_output(&doc.to_string());
+        assert_eq!(split.len(), 2);
+        assert_eq!(diff_outputs::diff_outputs(&unsplit, &split), Vec::new());
+    }
+
+    #[test]
+    fn test_confidence() {
+        let lib = BuckTarget::testing("lib", "fbcode//me", "prelude//rules.bzl:cxx_library");
+        let confidence = |kind| {
+            let output =
+                OutputV2::from_target(&lib, 0, &Labels::default(), ImpactReason::new(&lib, kind));
+            serde_json::to_value(output).unwrap()["reason"]["confidence"].clone()
+        };
+        assert_eq!(confidence(RootImpactKind::Inputs), "exact");
+        assert_eq!(confidence(RootImpactKind::Ownership), "heuristic");
+        let reason = |kind| ImpactReason::new(&lib, kind);
+        assert_eq!(
+            Confidence::new(&reason(RootImpactKind::CiSrcs)),
+            Confidence::Heuristic
+        );
+        assert_eq!(
+            Confidence::new(&reason(RootImpactKind::Hash)),
+            Confidence::Exact
+        );
+    }
+
+    #[test]
+    fn test_confidence_via_hint() {
+        // Only `bin` and `ci_hint@other` really depend on `lib`, the rest are hinted
+        let lib = BuckTarget::testing("lib", "fbcode//me", "prelude//rules.bzl:cxx_library");
+        let on_lib = |name: &str, rule_type: &str| BuckTarget {
+            deps: Deps::from([lib.label()]),
+            ..BuckTarget::testing(name, "fbcode//me", rule_type)
+        };
+        let diff = Targets::new(vec![
+            TargetsEntry::Target(lib.clone()),
+            TargetsEntry::Target(on_lib("bin", "prelude//rules.bzl:cxx_binary")),
+            TargetsEntry::Target(BuckTarget {
+                ci_deps: Box::new([TargetPattern::new(":lib")]),
+                ..BuckTarget::testing("tool", "fbcode//me", "prelude//rules.bzl:python_binary")
+            }),
+            TargetsEntry::Target(BuckTarget {
+                deps: Deps::from([TargetLabel::new("fbcode//me:tool")]),
+                ..BuckTarget::testing("test", "fbcode//me", "prelude//rules.bzl:python_test")
+            }),
+            TargetsEntry::Target(on_lib(
+                "ci_hint@other",
+                "fbcode//target_determinator/macros/rules/ci_hint.bzl:ci_hint",
+            )),
+            TargetsEntry::Target(BuckTarget::testing(
+                "other",
+                "fbcode//me",
+                "prelude//rules.bzl:cxx_library",
+            )),
+        ]);
+        let changes = GraphImpact::from_recursive(vec![(
+            diff.targets().next().unwrap(),
+            ImpactReason::new(&lib, RootImpactKind::Inputs),
+        )]);
+        let confidence = recursive_target_changes(&diff, &changes, None, |_| true)
+            .into_iter()
+            .flatten()
+            .map(|(x, reason)| (x.name.as_str().to_owned(), Confidence::new(&reason)))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(confidence.len(), 6);
+        for name in ["lib", "bin", "ci_hint@other"] {
+            assert_eq!(confidence[name], Confidence::Exact, "{name}");
+        }
+        // Including `test`, whose own dep is real, but which the change reached by a hint
+        for name in ["tool", "test", "other"] {
+            assert_eq!(confidence[name], Confidence::Heuristic, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_json_lines_writer() {
+        let mut buffer = Vec::new();
+        let mut writer = JsonLinesWriter::new(&mut buffer);
+        writer.write(&1);
+        writer.flush();
+        writer.write(&"two");
+        writer.finish().unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "1\n\"two\"\n");
+    }
+
+    #[test]
+    fn test_label_ordering() {
+        let target = BuckTarget {
+            labels: Labels::new(&["target_label"]),
+            package_values: PackageValues::new(&["must-come-first"], serde_json::Value::Null),
+            ..BuckTarget::testing("test", "fbcode//me", "prelude//rules.bzl:python_library")
+        };
+        let output = Output::from_target(
+            &target,
+            3,
+            &Labels::default(),
+            ImpactReason {
+                affected_dep: "".to_owned(),
+                root_cause: ("".to_owned(), RootImpactKind::Inputs),
+                via_hint: false,
+            },
+        );
+        assert_eq!(
+            output.labels,
+            Labels::new(&["must-come-first", "target_label"])
+        );
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Write the version 2 output as a `btd.Document` protocol buffer, see
+//! `btd/schema/impacted.proto`.
+
+use std::io::Write;
+
+use serde_json::Value;
+
+use super::schema;
+use super::schema::Field;
+use super::schema::Type;
+use super::DocumentV2;
+use super::Encoder;
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LEN: u64 = 2;
+
+pub struct ProtobufEncoder;
+
+impl Encoder for ProtobufEncoder {
+    fn encode(&self, doc: &DocumentV2, out: &mut dyn Write) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        write_message(&mut buf, schema::DOCUMENT, &serde_json::to_value(doc)?);
+        out.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+fn write_tag(buf: &mut Vec<u8>, id: i16, wire_type: u64) {
+    schema::write_varint(buf, ((id as u64) << 3) | wire_type);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, id: i16, bytes: &[u8]) {
+    write_tag(buf, id, LEN);
+    schema::write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_message(buf: &mut Vec<u8>, fields: &[Field], value: &Value) {
+    for field in fields {
+        if let Some(x) = value.get(field.name).filter(|x| !x.is_null()) {
+            write_field(buf, field.id, &field.ty, x);
+        }
+    }
+}
+
+fn write_field(buf: &mut Vec<u8>, id: i16, ty: &Type, value: &Value) {
+    match ty {
+        Type::Bool => {
+            write_tag(buf, id, VARINT);
+            buf.push(value.as_bool().unwrap_or_default() as u8);
+        }
+        Type::I64 => {
+            write_tag(buf, id, VARINT);
+            schema::write_varint(buf, value.as_i64().unwrap_or_default() as u64);
+        }
+        Type::Double => {
+            write_tag(buf, id, FIXED64);
+            buf.extend_from_slice(&value.as_f64().unwrap_or_default().to_le_bytes());
+        }
+        Type::String => write_bytes(buf, id, value.as_str().unwrap_or_default().as_bytes()),
+        Type::Struct(fields) => {
+            let mut inner = Vec::new();
+            write_message(&mut inner, fields, value);
+            write_bytes(buf, id, &inner);
+        }
+        // Repeated fields aren't packed, as most are strings or messages anyway
+        Type::List(ty) => {
+            for x in value.as_array().into_iter().flatten() {
+                write_field(buf, id, ty, x);
+            }
+        }
+        // A map is a repeated message with the key as field 1 and the value as field 2
+        Type::JsonMap => {
+            for (k, v) in value.as_object().into_iter().flatten() {
+                let mut entry = Vec::new();
+                write_bytes(&mut entry, 1, k.as_bytes());
+                write_bytes(&mut entry, 2, v.to_string().as_bytes());
+                write_bytes(buf, id, &entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_message() {
+        let encode = |value: Value| {
+            let mut buf = Vec::new();
+            write_message(&mut buf, schema::DOCUMENT, &value);
+            buf
+        };
+        assert_eq!(
+            encode(serde_json::json!({"version": 2, "truncated": true, "removed": null})),
+            [0x08, 0x02, 0x18, 0x01]
+        );
+        assert_eq!(
+            encode(serde_json::json!({
+                "targets": [{"target": "a", "labels": ["x", "y"], "score": 1.0}],
+            })),
+            [
+                0x12, 0x12, // targets, 18 bytes
+                0x0a, 0x01, b'a', // target
+                0x2a, 0x01, b'x', 0x2a, 0x01, b'y', // labels
+                0x59, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, // score
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_document() {
+        let mut buf = Vec::new();
+        write_message(
+            &mut buf,
+            schema::DOCUMENT,
+            &super::super::tests::full_document(),
+        );
+        assert_eq!(&buf[..2], [0x08, 0x02]);
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! The field ids of the version 2 output in the binary encodings, matching
+//! `btd/schema/impacted.thrift` and `btd/schema/impacted.proto`.
+//!
+//! The encoders walk the JSON form of a [`DocumentV2`](super::DocumentV2), so a field
+//! added to it must also be added here, or it is left out of binary output.
+
+/// A field in the schema, named as in the JSON output.
+pub struct Field {
+    pub id: i16,
+    pub name: &'static str,
+    pub ty: Type,
+}
+
+pub enum Type {
+    Bool,
+    I64,
+    Double,
+    String,
+    Struct(&'static [Field]),
+    List(&'static Type),
+    /// A map from string to the JSON text of each value, for arbitrary attributes.
+    JsonMap,
+}
+
+const fn field(id: i16, name: &'static str, ty: Type) -> Field {
+    Field { id, name, ty }
+}
+
+const REASON: &[Field] = &[
+    field(1, "category", Type::String),
+    field(2, "kind", Type::String),
+    field(3, "confidence", Type::String),
+    field(4, "changed_target", Type::String),
+    field(5, "via", Type::String),
+];
+
+const TARGET: &[Field] = &[
+    field(1, "target", Type::String),
+    field(2, "rule_type", Type::String),
+    field(3, "oncall", Type::String),
+    field(4, "depth", Type::I64),
+    field(5, "labels", Type::List(&Type::String)),
+    field(6, "reason", Type::Struct(REASON)),
+    field(7, "owners", Type::List(&Type::String)),
+    field(8, "class", Type::String),
+    field(9, "test_type", Type::String),
+    field(10, "attributes", Type::JsonMap),
+    field(11, "score", Type::Double),
+];
+
+const REMOVED: &[Field] = &[
+    field(1, "target", Type::String),
+    field(2, "rule_type", Type::String),
+    field(3, "package", Type::String),
+];
+
+const SKIPPED: &[Field] = &[
+    field(1, "target", Type::String),
+    field(2, "score", Type::Double),
+    field(3, "duration", Type::Double),
+    field(4, "reason", Type::String),
+];
+
+const SNAPSHOT: &[Field] = &[
+    field(1, "revision", Type::String),
+    field(2, "created", Type::I64),
+    field(3, "universe", Type::List(&Type::String)),
+    field(4, "buck_version", Type::String),
+];
+
+const ALIAS: &[Field] = &[
+    field(1, "alias", Type::String),
+    field(2, "target", Type::String),
+];
+
+const PACKAGE_ERROR: &[Field] = &[
+    field(1, "package", Type::String),
+    field(2, "category", Type::String),
+    field(3, "error", Type::String),
+];
+
+const METADATA: &[Field] = &[
+    field(1, "base", Type::Struct(SNAPSHOT)),
+    field(2, "diff", Type::Struct(SNAPSHOT)),
+];
+
+pub const DOCUMENT: &[Field] = &[
+    field(1, "version", Type::I64),
+    field(2, "targets", Type::List(&Type::Struct(TARGET))),
+    field(3, "truncated", Type::Bool),
+    field(4, "removed", Type::List(&Type::Struct(REMOVED))),
+    field(5, "skipped", Type::List(&Type::Struct(SKIPPED))),
+    field(6, "partial", Type::Bool),
+    field(7, "metadata", Type::Struct(METADATA)),
+    field(8, "quarantined", Type::List(&Type::Struct(TARGET))),
+    field(9, "aliases", Type::List(&Type::Struct(ALIAS))),
+    field(10, "immediate_changes", Type::List(&Type::Struct(TARGET))),
+    field(11, "transitive_changes", Type::List(&Type::Struct(TARGET))),
+    field(12, "errors", Type::List(&Type::Struct(PACKAGE_ERROR))),
+];
+
+/// Write `x` as a base 128 varint, least significant group first, as both encodings do.
+pub fn write_varint(buf: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        buf.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    buf.push(x as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Check every field of `value` is in `fields`, so none are silently dropped.
+    fn check_fields(fields: &[Field], value: &Value) {
+        for (name, value) in value.as_object().unwrap() {
+            let field = fields
+                .iter()
+                .find(|x| x.name == name)
+                .unwrap_or_else(|| panic!("`{name}` is missing from the schema"));
+            check_type(&field.ty, value);
+        }
+    }
+
+    fn check_type(ty: &Type, value: &Value) {
+        match ty {
+            Type::Struct(fields) => check_fields(fields, value),
+            Type::List(ty) => value
+                .as_array()
+                .unwrap()
+                .iter()
+                .for_each(|x| check_type(ty, x)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_schema_covers_output() {
+        check_type(
+            &Type::Struct(DOCUMENT),
+            &super::super::tests::full_document(),
+        );
+    }
+
+    #[test]
+    fn test_unique_ids() {
+        for fields in [
+            DOCUMENT,
+            TARGET,
+            REASON,
+            REMOVED,
+            SKIPPED,
+            SNAPSHOT,
+            METADATA,
+            ALIAS,
+            PACKAGE_ERROR,
+        ] {
+            let mut ids = fields.iter().map(|x| x.id).collect::<Vec<_>>();
+            ids.dedup();
+            assert_eq!(ids.len(), fields.len());
+        }
+    }
+
+    #[test]
+    fn test_write_varint() {
+        let varint = |x| {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, x);
+            buf
+        };
+        assert_eq!(varint(0), [0]);
+        assert_eq!(varint(127), [0x7f]);
+        assert_eq!(varint(300), [0xac, 0x02]);
+    }
+}
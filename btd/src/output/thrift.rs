@@ -0,0 +1,166 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Write the version 2 output as a `Document` struct in Thrift's compact protocol,
+//! see `btd/schema/impacted.thrift`.
+
+use std::io::Write;
+
+use serde_json::Value;
+
+use super::schema;
+use super::schema::Field;
+use super::schema::Type;
+use super::DocumentV2;
+use super::Encoder;
+
+const STOP: u8 = 0;
+const BOOL_TRUE: u8 = 1;
+const BOOL_FALSE: u8 = 2;
+const I64: u8 = 6;
+const DOUBLE: u8 = 7;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const MAP: u8 = 11;
+const STRUCT: u8 = 12;
+
+pub struct ThriftCompactEncoder;
+
+impl Encoder for ThriftCompactEncoder {
+    fn encode(&self, doc: &DocumentV2, out: &mut dyn Write) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        write_struct(&mut buf, schema::DOCUMENT, &serde_json::to_value(doc)?);
+        out.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+fn type_id(ty: &Type) -> u8 {
+    match ty {
+        Type::Bool => BOOL_TRUE,
+        Type::I64 => I64,
+        Type::Double => DOUBLE,
+        Type::String => BINARY,
+        Type::Struct(_) => STRUCT,
+        Type::List(_) => LIST,
+        Type::JsonMap => MAP,
+    }
+}
+
+fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
+
+fn write_binary(buf: &mut Vec<u8>, bytes: &[u8]) {
+    schema::write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_struct(buf: &mut Vec<u8>, fields: &[Field], value: &Value) {
+    let mut last_id = 0;
+    for field in fields {
+        let Some(x) = value.get(field.name).filter(|x| !x.is_null()) else {
+            continue;
+        };
+        // A bool field has its value in the header, rather than after it
+        let ty = match field.ty {
+            Type::Bool if x.as_bool() != Some(true) => BOOL_FALSE,
+            _ => type_id(&field.ty),
+        };
+        let delta = field.id - last_id;
+        if (1..=15).contains(&delta) {
+            buf.push(((delta as u8) << 4) | ty);
+        } else {
+            buf.push(ty);
+            schema::write_varint(buf, zigzag(field.id.into()));
+        }
+        last_id = field.id;
+        if !matches!(field.ty, Type::Bool) {
+            write_value(buf, &field.ty, x);
+        }
+    }
+    buf.push(STOP);
+}
+
+fn write_value(buf: &mut Vec<u8>, ty: &Type, value: &Value) {
+    match ty {
+        Type::Bool => buf.push(if value.as_bool() == Some(true) {
+            BOOL_TRUE
+        } else {
+            BOOL_FALSE
+        }),
+        Type::I64 => schema::write_varint(buf, zigzag(value.as_i64().unwrap_or_default())),
+        Type::Double => buf.extend_from_slice(&value.as_f64().unwrap_or_default().to_le_bytes()),
+        Type::String => write_binary(buf, value.as_str().unwrap_or_default().as_bytes()),
+        Type::Struct(fields) => write_struct(buf, fields, value),
+        Type::List(ty) => {
+            let xs = value.as_array().map(Vec::as_slice).unwrap_or_default();
+            if xs.len() < 15 {
+                buf.push(((xs.len() as u8) << 4) | type_id(ty));
+            } else {
+                buf.push(0xf0 | type_id(ty));
+                schema::write_varint(buf, xs.len() as u64);
+            }
+            for x in xs {
+                write_value(buf, ty, x);
+            }
+        }
+        Type::JsonMap => {
+            let xs = value.as_object().into_iter().flatten().collect::<Vec<_>>();
+            schema::write_varint(buf, xs.len() as u64);
+            // An empty map has no key and value types
+            if !xs.is_empty() {
+                buf.push((BINARY << 4) | BINARY);
+            }
+            for (k, v) in xs {
+                write_binary(buf, k.as_bytes());
+                write_binary(buf, v.to_string().as_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: Value) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_struct(&mut buf, schema::DOCUMENT, &value);
+        buf
+    }
+
+    #[test]
+    fn test_write_struct() {
+        assert_eq!(
+            encode(serde_json::json!({"version": 2, "truncated": false, "removed": null})),
+            [0x16, 0x04, 0x22, STOP]
+        );
+        assert_eq!(
+            encode(serde_json::json!({
+                "targets": [{"target": "a", "labels": ["x"], "score": 1.0, "attributes": {}}],
+            })),
+            [
+                0x29, 0x1c, // targets, a list of one struct
+                0x18, 0x01, b'a', // target
+                0x49, 0x18, 0x01, b'x', // labels
+                0x5b, 0x00, // attributes
+                0x17, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, // score
+                STOP, STOP,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_document() {
+        let buf = encode(super::super::tests::full_document());
+        assert_eq!(&buf[..2], [0x16, 0x04]);
+        assert_eq!(buf.last(), Some(&STOP));
+    }
+}
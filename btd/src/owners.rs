@@ -0,0 +1,149 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Find the owners of a package from a `CODEOWNERS`-style file, so CI can notify
+//! the teams owning the impacted targets.
+//!
+//! Each line is a directory relative to the root of the repo, followed by its owners,
+//! e.g. `/fbcode/buck2/ @buck2-team alice@example.com`. A directory with no owners
+//! is unowned. Lines starting with `#` are comments, and `*` is the whole repo.
+//! A package is owned by the owners of the deepest directory containing it,
+//! with later lines for the same directory replacing earlier ones.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context as _;
+use td_util::string::InternString;
+use thiserror::Error;
+
+use crate::buck::cells::CellInfo;
+use crate::buck::types::Package;
+
+#[derive(Debug, Error)]
+enum OwnersError {
+    #[error("Line {0}: only directory patterns are supported, got `{1}`")]
+    UnsupportedPattern(usize, String),
+}
+
+/// A trie of directories, one path component per level.
+#[derive(Debug, Default)]
+struct Node {
+    owners: Option<Vec<InternString>>,
+    children: HashMap<String, Node>,
+}
+
+#[derive(Debug, Default)]
+pub struct Owners {
+    root: Node,
+    /// The directory each cell is rooted at, relative to the root of the repo.
+    cells: HashMap<String, String>,
+}
+
+impl Owners {
+    pub fn read_file(file: &Path, cells: &CellInfo) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading owners file `{}`", file.display()))?;
+        Self::parse(&data, cells)
+            .with_context(|| format!("When parsing owners file `{}`", file.display()))
+    }
+
+    pub fn parse(data: &str, cells: &CellInfo) -> anyhow::Result<Self> {
+        let mut res = Self {
+            root: Node::default(),
+            cells: cells
+                .cells()
+                .map(|(cell, path)| (cell.as_str().to_owned(), path.as_str().to_owned()))
+                .collect(),
+        };
+        for (i, line) in data.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            let Some(pattern) = words.next().filter(|x| !x.starts_with('#')) else {
+                continue;
+            };
+            let dir = pattern
+                .trim_end_matches("/**")
+                .trim_end_matches("/*")
+                .trim_matches('/');
+            let dir = if dir == "*" || dir == "**" { "" } else { dir };
+            if dir.contains(['*', '?', '[']) {
+                return Err(OwnersError::UnsupportedPattern(i + 1, pattern.to_owned()).into());
+            }
+            let mut node = &mut res.root;
+            for x in components(dir) {
+                node = node.children.entry(x.to_owned()).or_default();
+            }
+            node.owners = Some(words.map(InternString::new).collect());
+        }
+        Ok(res)
+    }
+
+    /// The owners of the directory containing `package`, empty if it is unowned.
+    pub fn get(&self, package: &Package) -> &[InternString] {
+        let Some((cell, path)) = package.as_str().split_once("//") else {
+            return &[];
+        };
+        let Some(cell) = self.cells.get(cell) else {
+            return &[];
+        };
+        let mut node = &self.root;
+        let mut res = node.owners.as_deref();
+        for x in components(cell).chain(components(path)) {
+            match node.children.get(x) {
+                None => break,
+                Some(child) => {
+                    node = child;
+                    res = node.owners.as_deref().or(res);
+                }
+            }
+        }
+        res.unwrap_or_default()
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|x| !x.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owners() {
+        let owners = Owners::parse(
+            "# The whole repo\n\
+             * @everyone\n\
+             /foo/ @foo-team\n\
+             foo/bar/** @bar-team alice\n\
+             fbcode/prelude\n\
+             \n\
+             foo/bar @bar-team\n",
+            &CellInfo::testing(),
+        )
+        .unwrap();
+        let get = |x: &str| {
+            owners
+                .get(&Package::new(x))
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(get("foo//"), vec!["@foo-team"]);
+        assert_eq!(get("foo//baz"), vec!["@foo-team"]);
+        assert_eq!(get("foo//bar/baz"), vec!["@bar-team"]);
+        assert_eq!(get("foo//barbaz"), vec!["@foo-team"]);
+        assert_eq!(get("fbcode//buck2"), vec!["@everyone"]);
+        assert_eq!(get("prelude//rules"), Vec::<&str>::new());
+        assert_eq!(get("unknown//foo"), Vec::<&str>::new());
+
+        assert!(Owners::parse("foo/*.rs @rust", &CellInfo::testing()).is_err());
+    }
+}
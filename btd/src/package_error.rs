@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Categorise the errors `buck2 targets` reports for packages which fail to evaluate,
+//! so each category can be handled differently, e.g. tolerating I/O errors from a
+//! flaky file system while still failing on syntax errors.
+//!
+//! Buck2 only gives the error as text, so the category is a best guess from the
+//! messages it is known to produce, falling back to `other`.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::buck::targets::BuckError;
+use crate::buck::types::Package;
+
+/// The kind of problem which stopped a package from evaluating.
+#[derive(ValueEnum, Serialize, parse_display::Display, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[display(style = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The build file, or a `.bzl` file it loads, failed to parse or evaluate.
+    Parse,
+    /// The package refers to something which doesn't exist, e.g. it loads a missing
+    /// file or symbol, or names an unknown cell or target.
+    MissingDep,
+    /// Reading a file failed, e.g. it is missing or unreadable.
+    Io,
+    /// Anything not recognised.
+    Other,
+}
+
+/// Checked in order, since an error often mentions several, e.g. a missing loaded file
+/// fails to be read, and a file failing to be read fails the evaluation.
+const PATTERNS: &[(ErrorCategory, &[&str])] = &[
+    (
+        ErrorCategory::MissingDep,
+        &[
+            "error loading `load`",
+            "module has no symbol",
+            "unknown cell",
+            "unknown target",
+        ],
+    ),
+    (
+        ErrorCategory::Io,
+        &[
+            "no such file or directory",
+            "permission denied",
+            "i/o error",
+            "io error",
+            "os error",
+        ],
+    ),
+    (
+        ErrorCategory::Parse,
+        &[
+            "parse error",
+            "syntax error",
+            "error parsing",
+            "error evaluating build file",
+            "traceback",
+        ],
+    ),
+];
+
+impl ErrorCategory {
+    /// Guess the category of the Buck2 error message `error`.
+    ///
+    /// ```
+    /// use btd::package_error::ErrorCategory;
+    /// assert_eq!(
+    ///     ErrorCategory::classify("Parse error: unexpected new line"),
+    ///     ErrorCategory::Parse
+    /// );
+    /// assert_eq!(ErrorCategory::classify("Out of memory"), ErrorCategory::Other);
+    /// ```
+    pub fn classify(error: &str) -> Self {
+        let error = error.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(_, patterns)| patterns.iter().any(|x| error.contains(x)))
+            .map_or(ErrorCategory::Other, |(category, _)| *category)
+    }
+}
+
+/// A package which failed to evaluate, as reported in the `errors` of the output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageError {
+    pub package: Package,
+    pub category: ErrorCategory,
+    /// The message Buck2 gave.
+    pub error: String,
+}
+
+impl PackageError {
+    pub fn new(err: &BuckError) -> Self {
+        Self {
+            package: err.package.clone(),
+            category: ErrorCategory::classify(&err.error),
+            error: err.error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let tests = [
+            (
+                "Error evaluating build file: `foo//bar:BUCK`\n\nTraceback (most recent call last):\n  * foo/bar/BUCK:3, in <module>\nerror: Variable `cxx_librray` not found",
+                ErrorCategory::Parse,
+            ),
+            (
+                "Error evaluating build file: `foo//bar:BUCK`\n\nCaused by:\n    0: Error loading `load` of `foo//defs/missing.bzl`\n    1: No such file or directory (os error 2)",
+                ErrorCategory::MissingDep,
+            ),
+            (
+                "Module has no symbol `rust_libary`, did you mean `rust_library`?",
+                ErrorCategory::MissingDep,
+            ),
+            (
+                "Error reading `foo/bar/BUCK`\n\nCaused by:\n    Permission denied (os error 13)",
+                ErrorCategory::Io,
+            ),
+            ("Something else went wrong", ErrorCategory::Other),
+        ];
+        for (error, category) in tests {
+            assert_eq!(ErrorCategory::classify(error), category, "{error}");
+        }
+
+        let err = PackageError::new(&BuckError {
+            package: Package::new("foo//bar"),
+            error: "Unknown target `baz` from package `foo//bar`".to_owned(),
+        });
+        assert_eq!(
+            serde_json::to_value(err).unwrap(),
+            serde_json::json!({
+                "package": "foo//bar",
+                "category": "missing_dep",
+                "error": "Unknown target `baz` from package `foo//bar`",
+            })
+        );
+    }
+}
@@ -0,0 +1,211 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Propagate labels through the dependency graph, e.g. a target depending on
+//! a target labelled `uses_sudo` also needs sudo to run.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use itertools::Either;
+use td_util::prelude::*;
+use td_util::string::InternString;
+
+use crate::buck::labels::Labels;
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetLabelKey;
+use crate::buck::types::TargetLabelKeyRef;
+
+/// For each propagated label, the targets which have it, directly or by propagation.
+pub type PropagatedLabels = HashMap<InternString, HashSet<TargetLabelKey>>;
+
+/// Which way labels flow through the dependency graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From a target to the targets which depend on it, e.g. `uses_sudo`.
+    Rdeps,
+    /// From a target to its dependencies, e.g. `ci_blocking`.
+    Deps,
+}
+
+/// For each of `labels`, find the targets which have that label or transitively
+/// depend on a target which has it.
+pub fn propagate_labels(targets: &Targets, labels: &[&str]) -> PropagatedLabels {
+    propagate_labels_with(targets, &labels.map(|x| (*x, Direction::Rdeps)))
+}
+
+/// For each label, find the targets which have that label or are reachable in the
+/// given direction from a target which has it. All the labels are propagated in a
+/// single traversal, sharing the graph, which is only built for directions requested.
+/// A label given in both directions ends up on the union of both.
+///
+/// Like `buck.deps`, this doesn't follow `ci_deps` or target patterns.
+pub fn propagate_labels_with(targets: &Targets, labels: &[(&str, Direction)]) -> PropagatedLabels {
+    let labels = labels.map(|(x, direction)| (InternString::new(x), *direction));
+    let wants = |direction| labels.iter().any(|x| x.1 == direction);
+
+    let mut rdeps: HashMap<&TargetLabel, Vec<&BuckTarget>> = HashMap::new();
+    if wants(Direction::Rdeps) {
+        rdeps.reserve(targets.len_targets_upperbound());
+        for target in targets.targets() {
+            for d in target.deps.iter() {
+                rdeps.entry(d).or_default().push(target);
+            }
+        }
+    }
+    let by_label = if wants(Direction::Deps) {
+        targets.targets_by_label()
+    } else {
+        HashMap::new()
+    };
+
+    // The targets reached for each entry in `labels`, and those still to explore.
+    let mut done: Vec<HashSet<TargetLabelKeyRef>> = vec![HashSet::new(); labels.len()];
+    let mut todo: Vec<(usize, &BuckTarget)> = Vec::new();
+    if !labels.is_empty() {
+        for target in targets.targets() {
+            for (i, (label, _)) in labels.iter().enumerate() {
+                if target.labels.iter().any(|x| x == label) && done[i].insert(target.label_key()) {
+                    todo.push((i, target));
+                }
+            }
+        }
+    }
+
+    while let Some((i, target)) = todo.pop() {
+        let next = match labels[i].1 {
+            Direction::Rdeps => {
+                Either::Left(rdeps.get(&target.label()).into_iter().flatten().copied())
+            }
+            Direction::Deps => {
+                Either::Right(target.deps.iter().filter_map(|x| by_label.get(x).copied()))
+            }
+        };
+        for x in next {
+            if done[i].insert(x.label_key()) {
+                todo.push((i, x));
+            }
+        }
+    }
+
+    let mut res = PropagatedLabels::new();
+    for ((label, _), done) in labels.into_iter().zip(done) {
+        res.entry(label)
+            .or_default()
+            .extend(done.into_iter().map(|x| x.to_key()));
+    }
+    res
+}
+
+/// The labels `target` gained by propagation, which it doesn't already have, sorted.
+pub fn propagated_labels(propagated: &PropagatedLabels, target: &BuckTarget) -> Labels {
+    if propagated.is_empty() {
+        return Labels::default();
+    }
+    let key = target.label_key().to_key();
+    let mut res = propagated
+        .iter()
+        .filter(|(label, targets)| {
+            targets.contains(&key) && !target.labels.iter().any(|x| x == *label)
+        })
+        .map(|(label, _)| label.as_str())
+        .collect::<Vec<_>>();
+    res.sort_unstable();
+    Labels::new(&res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::Package;
+    use crate::buck::types::TargetName;
+
+    fn target(name: &str, deps: &[&str], labels: &[&str]) -> TargetsEntry {
+        let pkg = Package::new("foo//");
+        TargetsEntry::Target(BuckTarget {
+            deps: deps.iter().map(|x| pkg.join(&TargetName::new(x))).collect(),
+            labels: Labels::new(labels),
+            ..BuckTarget::testing(name, pkg.as_str(), "prelude//rules.bzl:cxx_library")
+        })
+    }
+
+    #[test]
+    fn test_propagate_labels() {
+        let targets = Targets::new(vec![
+            target("gpu", &[], &["requires_gpu"]),
+            target("net", &[], &["network_access"]),
+            target("both", &["gpu", "net"], &[]),
+            target("top", &["both"], &["requires_gpu"]),
+            target("plain", &[], &[]),
+        ]);
+        let res = propagate_labels(&targets, &["requires_gpu", "network_access", "unused"]);
+        let keys = |names: &[&str]| {
+            names
+                .iter()
+                .map(|x| TargetLabel::new(x).key())
+                .collect::<HashSet<_>>()
+        };
+        let label = |x: &str| &res[&InternString::new(x)];
+        assert_eq!(
+            label("requires_gpu"),
+            &keys(&["foo//:both", "foo//:gpu", "foo//:top"])
+        );
+        assert_eq!(
+            label("network_access"),
+            &keys(&["foo//:both", "foo//:net", "foo//:top"])
+        );
+        assert!(label("unused").is_empty());
+
+        let by_label = targets.targets_by_label();
+        let labels = |name: &str| propagated_labels(&res, by_label[&TargetLabel::new(name)]);
+        assert_eq!(
+            labels("foo//:both"),
+            Labels::new(&["network_access", "requires_gpu"])
+        );
+        // Labels the target already has aren't repeated
+        assert_eq!(labels("foo//:top"), Labels::new(&["network_access"]));
+        assert_eq!(labels("foo//:plain"), Labels::default());
+    }
+
+    #[test]
+    fn test_propagate_labels_down() {
+        let targets = Targets::new(vec![
+            target("leaf", &[], &[]),
+            target("mid", &["leaf"], &[]),
+            target("root", &["mid"], &["ci_blocking"]),
+            target("other", &["leaf"], &["uses_sudo"]),
+            target("unrelated", &[], &[]),
+        ]);
+        let res = propagate_labels_with(
+            &targets,
+            &[
+                ("ci_blocking", Direction::Deps),
+                ("uses_sudo", Direction::Rdeps),
+                ("uses_sudo", Direction::Deps),
+            ],
+        );
+        let keys = |names: &[&str]| {
+            names
+                .iter()
+                .map(|x| TargetLabel::new(x).key())
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(
+            res[&InternString::new("ci_blocking")],
+            keys(&["foo//:leaf", "foo//:mid", "foo//:root"])
+        );
+        assert_eq!(
+            res[&InternString::new("uses_sudo")],
+            keys(&["foo//:leaf", "foo//:other"])
+        );
+    }
+}
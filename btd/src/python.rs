@@ -0,0 +1,113 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Python bindings for [`run_btd`], enabled with the `btd-py` feature, so Python
+//! CI scripts can run BTD without spawning it and parsing its JSON output.
+//!
+//! Build the extension module with
+//! `cargo rustc -p btd --lib --release --features btd-py --crate-type cdylib`,
+//! then import `libbtd.so` renamed to `btd.so`.
+
+use std::path::PathBuf;
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+
+use crate::api::run_btd;
+use crate::api::Config;
+use crate::api::ImpactedTarget as Impacted;
+use crate::buck::cells::CellInfo;
+use crate::buck::targets::Targets;
+use crate::changes::Changes;
+use crate::sapling::status::Status;
+
+/// A target impacted by the changes, see [`Impacted`].
+#[pyclass(frozen, get_all)]
+pub struct ImpactedTarget {
+    target: String,
+    rule_type: String,
+    oncall: Option<String>,
+    depth: u64,
+    labels: Vec<String>,
+    /// The target which changed, causing this one to be impacted.
+    root_cause: String,
+    /// How `root_cause` changed, e.g. `inputs`.
+    root_cause_kind: String,
+    /// The dependency through which the change reached this target, empty at depth `0`.
+    affected_dep: String,
+}
+
+#[pymethods]
+impl ImpactedTarget {
+    fn __repr__(&self) -> String {
+        format!("ImpactedTarget({}, depth={})", self.target, self.depth)
+    }
+}
+
+impl From<Impacted> for ImpactedTarget {
+    fn from(x: Impacted) -> Self {
+        Self {
+            target: x.target.to_string(),
+            rule_type: x.rule_type.as_str().to_owned(),
+            oncall: x.oncall.map(|x| x.as_str().to_owned()),
+            depth: x.depth,
+            labels: x.labels.iter().map(|x| x.as_str().to_owned()).collect(),
+            root_cause: x.reason.root_cause.0,
+            root_cause_kind: x.reason.root_cause.1.to_string(),
+            affected_dep: x.reason.affected_dep,
+        }
+    }
+}
+
+fn run(
+    base: PathBuf,
+    diff: PathBuf,
+    changes: Vec<String>,
+    cells: PathBuf,
+) -> anyhow::Result<Vec<ImpactedTarget>> {
+    let cells = CellInfo::new(&cells)?;
+    let status = changes
+        .iter()
+        .map(|x| Status::from_str(x))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let config = Config::new(
+        Targets::from_file(&base)?,
+        Targets::from_file(&diff)?,
+        Changes::new(&cells, status)?,
+    );
+    Ok(run_btd(config)?
+        .targets
+        .into_iter()
+        .map(Into::into)
+        .collect())
+}
+
+/// Compute the targets impacted by `changes`, given the `buck2 targets` output
+/// before and after them. Each change is a line of `hg status`, e.g. `M foo/bar.rs`,
+/// and `cells_path` is the output of `buck2 audit cell`.
+#[pyfunction]
+#[pyo3(signature = (base_targets_path, diff_targets_path, changes, *, cells_path))]
+fn run_change_detection(
+    py: Python<'_>,
+    base_targets_path: PathBuf,
+    diff_targets_path: PathBuf,
+    changes: Vec<String>,
+    cells_path: PathBuf,
+) -> PyResult<Vec<ImpactedTarget>> {
+    // Loading big targets files takes a while, so let other Python threads run
+    py.allow_threads(|| run(base_targets_path, diff_targets_path, changes, cells_path))
+        .map_err(|e| PyRuntimeError::new_err(format!("{e:#}")))
+}
+
+#[pymodule]
+fn btd(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<ImpactedTarget>()?;
+    m.add_function(wrap_pyfunction!(run_change_detection, m)?)?;
+    Ok(())
+}
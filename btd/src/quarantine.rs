@@ -0,0 +1,167 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Quarantine flaky tests: impacted tests matching a quarantine pattern are reported
+//! separately as `quarantined`, so CI can run them without blocking on them, while
+//! still keeping track of them.
+//!
+//! The quarantine file has a target pattern per line, optionally followed by the date
+//! its quarantine expires, e.g. `fbcode//foo:flaky_test 2024-03-31`, after which the
+//! test is selected as normal again. Lines starting with `#` are comments.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context as _;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::ParsedTargetPattern;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetPattern;
+use crate::classify::Classifier;
+use crate::diff::ImpactReason;
+
+#[derive(Debug, Error)]
+enum QuarantineError {
+    #[error("Expected `PATTERN` or `PATTERN YYYY-MM-DD` in the quarantine, got `{0}`")]
+    MalformedLine(String),
+    #[error("Expected a date as `YYYY-MM-DD` in the quarantine, got `{0}`")]
+    MalformedDate(String),
+}
+
+/// The patterns of the quarantined tests.
+#[derive(Debug, Default)]
+pub struct Quarantine(Vec<ParsedTargetPattern>);
+
+impl Quarantine {
+    /// Read the patterns from `file`, leaving out those which expired before today.
+    pub fn read_file(file: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading quarantine `{}`", file.display()))?;
+        let today = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / (24 * 60 * 60);
+        Self::parse(&data, today as i64)
+            .with_context(|| format!("When parsing quarantine `{}`", file.display()))
+    }
+
+    /// Parse the patterns in `data`, leaving out those which expired before `today`, in
+    /// days since the Unix epoch.
+    fn parse(data: &str, today: i64) -> anyhow::Result<Self> {
+        let mut res = Vec::new();
+        for line in data.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let pattern = words.next().unwrap();
+            let expires = words.next();
+            if words.next().is_some() {
+                return Err(QuarantineError::MalformedLine(line.to_owned()).into());
+            }
+            if let Some(expires) = expires {
+                if parse_date(expires)? < today {
+                    warn!("The quarantine of `{pattern}` expired on {expires}");
+                    continue;
+                }
+            }
+            res.push(TargetPattern::new(pattern).parse()?);
+        }
+        Ok(Self(res))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The impacted tests in `levels` which are quarantined.
+    pub fn select(
+        &self,
+        levels: &[Vec<(&BuckTarget, ImpactReason)>],
+        classifier: &Classifier,
+    ) -> HashSet<TargetLabel> {
+        levels
+            .iter()
+            .flatten()
+            .map(|(x, _)| x)
+            .filter(|x| classifier.is_test(x))
+            .map(|x| x.label())
+            .filter(|x| self.0.iter().any(|p| p.matches(x)))
+            .collect()
+    }
+}
+
+/// Parse `YYYY-MM-DD` as days since the Unix epoch, in the proleptic Gregorian calendar.
+fn parse_date(date: &str) -> anyhow::Result<i64> {
+    let err = || QuarantineError::MalformedDate(date.to_owned());
+    let mut parts = date.splitn(3, '-').map(|x| x.parse::<i64>().ok());
+    let (Some(Some(year)), Some(Some(month)), Some(Some(day))) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(err().into());
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(err().into());
+    }
+    // From Howard Hinnant's `days_from_civil`, with years starting in March, so the leap
+    // day is at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Ok(era * 146097 + day_of_era - 719468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::RootImpactKind;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2000-03-01").unwrap(), 11017);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 19782);
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let today = parse_date("2024-03-01").unwrap();
+        let data = "
+            # Flaky since the upgrade
+            foo//bar:flaky_test 2024-03-01
+            foo//old:expired_test 2024-02-29
+            foo//baz/...
+        ";
+        let quarantine = Quarantine::parse(data, today).unwrap();
+        assert!(Quarantine::parse("foo//bar:test 2024-03-01 extra", today).is_err());
+
+        let flaky = BuckTarget::testing("flaky_test", "foo//bar", "prelude//rules.bzl:cxx_test");
+        let expired =
+            BuckTarget::testing("expired_test", "foo//old", "prelude//rules.bzl:cxx_test");
+        let test = BuckTarget::testing("test", "foo//baz/qux", "prelude//rules.bzl:cxx_test");
+        let lib = BuckTarget::testing("lib", "foo//baz", "prelude//rules.bzl:cxx_library");
+        let reason = ImpactReason::new(&lib, RootImpactKind::Inputs);
+        let levels = vec![
+            vec![(&lib, reason.clone()), (&flaky, reason.clone())],
+            vec![(&expired, reason.clone()), (&test, reason)],
+        ];
+        // Only tests are quarantined, so not `lib`, even though it matches
+        assert_eq!(
+            quarantine.select(&levels, &Classifier::default()),
+            HashSet::from([flaky.label(), test.label()])
+        );
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Attribute the impact of a range of commits, e.g. a stack of diffs, to the
+//! commit that caused it.
+//!
+//! Each commit is diffed against the commit before it (the first against `--base`),
+//! so every targets file is parsed once, serving as the diff for one commit and
+//! the base for the next.
+
+use std::collections::HashSet;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use td_util::json;
+
+use crate::buck::cells::CellInfo;
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetLabelKeyRef;
+use crate::changes::Changes;
+use crate::diff;
+use crate::diff::ImpactReason;
+use crate::sapling::status::read_status;
+
+/// For a range of commits, print each impacted target once, as JSON lines,
+/// with the index of the earliest commit in the range which impacted it.
+#[derive(clap::Args, Debug)]
+pub struct RangeArgs {
+    /// File containing the output of `buck2 audit cell` in the root of the repo.
+    #[arg(long, value_name = "FILE")]
+    cells: PathBuf,
+
+    /// File containing the output of `buck2 audit config --cells --json` in the root of the repo.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Targets file before the first commit in the range.
+    #[arg(long, value_name = "FILE")]
+    base: PathBuf,
+
+    /// A commit in the range, as the `hg status` of that commit alone and the targets file
+    /// after it. Pass once per commit, oldest first.
+    #[arg(long, num_args = 2, value_names = ["CHANGES", "TARGETS"], required = true)]
+    commit: Vec<PathBuf>,
+
+    /// Number of levels of dependency to explore (default to no limit).
+    #[arg(long, value_name = "INT")]
+    depth: Option<usize>,
+}
+
+/// A target impacted by the range.
+#[derive(Debug, Serialize)]
+pub struct CommitImpact {
+    pub target: TargetLabel,
+    /// Index of the earliest commit which impacted the target, starting at `0`.
+    pub commit: usize,
+    /// Distance from the nearest target changed by that commit.
+    pub depth: u64,
+    pub reason: ImpactReason,
+}
+
+/// Attribute each target impacted by `commits` to the earliest commit impacting it.
+/// Each commit is the changes it made and the targets after it.
+pub fn attribute_commits(
+    base: &Targets,
+    commits: &[(Changes, Targets)],
+    depth: Option<usize>,
+) -> Vec<CommitImpact> {
+    let mut done: HashSet<TargetLabelKeyRef> = HashSet::new();
+    let mut res = Vec::new();
+    let mut before = base;
+    for (commit, (changes, after)) in commits.iter().enumerate() {
+        let immediate = diff::immediate_target_changes(before, after, changes, false);
+        let levels = diff::recursive_target_changes(after, &immediate, depth, |_| true);
+        for (depth, level) in levels.into_iter().enumerate() {
+            for (x, reason) in level {
+                if done.insert(x.label_key()) {
+                    res.push(CommitImpact {
+                        target: x.label(),
+                        commit,
+                        depth: depth as u64,
+                        reason,
+                    });
+                }
+            }
+        }
+        before = after;
+    }
+    res
+}
+
+pub fn main(args: RangeArgs) -> anyhow::Result<()> {
+    let mut cells = CellInfo::new(&args.cells)?;
+    if let Some(config) = &args.config {
+        cells.load_config_data(config)?;
+    }
+    let base = Targets::from_file(&args.base)?;
+    let commits = args
+        .commit
+        .chunks(2)
+        .map(|x| {
+            let changes = Changes::new(&cells, read_status(&x[0])?)?;
+            Ok((changes, Targets::from_file(&x[1])?))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let res = attribute_commits(&base, &commits, args.depth);
+    json::write_json_lines(BufWriter::new(stdout().lock()), res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::CellPath;
+    use crate::buck::types::TargetHash;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_attribute_commits() {
+        let target = |name: &str, deps: &[&str], hash: &str| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                inputs: Box::new([CellPath::new(&format!("foo//bar/{name}.cpp"))]),
+                hash: TargetHash::new(hash),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let graph = |lib_hash: &str| {
+            Targets::new(vec![
+                target("lib", &[], lib_hash),
+                target("bin", &["foo//bar:lib"], "1"),
+                target("other", &[], "1"),
+            ])
+        };
+        let base = graph("1");
+        let commits = vec![
+            // Changes `lib`, impacting `lib` and `bin`
+            (Changes::default(), graph("2")),
+            // Changes `bin` again, and `other` for the first time
+            (
+                Changes::testing(&[
+                    Status::Modified(CellPath::new("foo//bar/bin.cpp")),
+                    Status::Modified(CellPath::new("foo//bar/other.cpp")),
+                ]),
+                graph("2"),
+            ),
+        ];
+        let res = attribute_commits(&base, &commits, None)
+            .into_iter()
+            .map(|x| (x.target.to_string(), x.commit, x.depth))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            res,
+            vec![
+                ("foo//bar:lib".to_owned(), 0, 0),
+                ("foo//bar:bin".to_owned(), 0, 1),
+                ("foo//bar:other".to_owned(), 1, 0),
+            ]
+        );
+    }
+}
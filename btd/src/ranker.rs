@@ -0,0 +1,174 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Split impacted tests into shards which take about as long as each other to run,
+//! given how long each test took historically, rather than round-robin.
+//!
+//! We use the longest processing time first heuristic: take the tests longest first,
+//! adding each to the shard with the least work so far. The longest shard is then
+//! within 4/3 of the best possible.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use serde::Serialize;
+use td_util::json;
+
+use crate::buck::types::TargetLabel;
+use crate::diff_outputs;
+use crate::score::Budget;
+
+/// Split the impacted tests from a BTD run into `--shards` balanced by their historical
+/// durations, printing each shard as a line of JSON.
+#[derive(clap::Args, Debug)]
+pub struct ShardArgs {
+    /// Output of a BTD run listing the tests to shard, in any format it writes, e.g.
+    /// `--json` or `--output-format v2`.
+    #[arg(long, value_name = "FILE")]
+    impacted: PathBuf,
+
+    /// How many shards to split the tests into.
+    #[arg(long, value_name = "N")]
+    shards: NonZeroUsize,
+
+    /// The duration of tests in seconds, in the same formats as `btd --durations`.
+    /// Tests not listed are assumed to take the mean duration.
+    #[arg(long, value_name = "FILE")]
+    durations: PathBuf,
+
+    /// Also write the targets of each shard to `DIR/shard-N.txt`, one per line, e.g.
+    /// to pass to `buck2 test @DIR/shard-0.txt`.
+    #[arg(long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+}
+
+/// The tests assigned to one shard.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Shard {
+    pub shard: usize,
+    /// The total expected duration of the tests, in seconds.
+    pub duration: f64,
+    /// Longest first.
+    pub targets: Vec<TargetLabel>,
+}
+
+/// Split `tests` into `shards` shards, given the duration of each test. Some shards are
+/// empty if there are fewer tests than shards.
+pub fn shard(
+    tests: &[TargetLabel],
+    durations: &HashMap<TargetLabel, f64>,
+    shards: NonZeroUsize,
+) -> Vec<Shard> {
+    let mean = if durations.is_empty() {
+        // Without any history, balance by the number of tests
+        1.0
+    } else {
+        durations.values().sum::<f64>() / durations.len() as f64
+    };
+    let mut tests = tests
+        .iter()
+        .map(|x| (x, durations.get(x).copied().unwrap_or(mean)))
+        .collect::<Vec<_>>();
+    tests.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut res = (0..shards.get())
+        .map(|shard| Shard {
+            shard,
+            duration: 0.0,
+            targets: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    for (target, duration) in tests {
+        // The first shard with the least work, so ties fill the shards in order
+        let next = res
+            .iter_mut()
+            .min_by(|a, b| a.duration.total_cmp(&b.duration))
+            .unwrap();
+        next.duration += duration;
+        next.targets.push(target.clone());
+    }
+    res
+}
+
+pub fn main(args: ShardArgs) -> anyhow::Result<()> {
+    let impacted = fs::read_to_string(&args.impacted)
+        .with_context(|| format!("When reading `{}`", args.impacted.display()))?;
+    let tests = diff_outputs::parse_output(&impacted)
+        .into_keys()
+        .collect::<Vec<_>>();
+    let durations = Budget::read_durations(&args.durations)?;
+    let res = shard(&tests, &durations, args.shards);
+
+    if let Some(dir) = &args.out_dir {
+        fs::create_dir_all(dir).with_context(|| format!("When creating `{}`", dir.display()))?;
+        for x in &res {
+            let file = dir.join(format!("shard-{}.txt", x.shard));
+            let mut out = BufWriter::new(
+                fs::File::create(&file)
+                    .with_context(|| format!("When creating `{}`", file.display()))?,
+            );
+            for target in &x.targets {
+                writeln!(out, "{target}")?;
+            }
+            out.flush()?;
+        }
+    }
+    json::write_json_lines(BufWriter::new(stdout().lock()), &res)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard() {
+        let label = |name: &str| TargetLabel::new(&format!("foo//bar:{name}"));
+        let tests = ["a", "b", "c", "d", "e", "unknown"].map(label);
+        let durations = HashMap::from([
+            (label("a"), 7.0),
+            (label("b"), 5.0),
+            (label("c"), 4.0),
+            (label("d"), 3.0),
+            (label("e"), 1.0),
+        ]);
+        // `unknown` takes the mean, 4 seconds, so sorts after `c`
+        let res = shard(&tests, &durations, NonZeroUsize::new(2).unwrap());
+        assert_eq!(
+            res,
+            vec![
+                Shard {
+                    shard: 0,
+                    duration: 12.0,
+                    targets: vec![label("a"), label("unknown"), label("e")],
+                },
+                Shard {
+                    shard: 1,
+                    duration: 12.0,
+                    targets: vec![label("b"), label("c"), label("d")],
+                },
+            ]
+        );
+
+        // More shards than tests leaves some empty
+        let res = shard(
+            &[label("a")],
+            &HashMap::new(),
+            NonZeroUsize::new(3).unwrap(),
+        );
+        assert_eq!(res[0].targets, vec![label("a")]);
+        assert!(res[1].targets.is_empty() && res[2].targets.is_empty());
+    }
+}
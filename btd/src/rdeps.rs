@@ -0,0 +1,680 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! The reverse dependencies of a graph, i.e. which targets depend on each target.
+//!
+//! Building them takes a while on big graphs, so they can be saved as an index file
+//! and loaded by later runs against the same targets, e.g. a service answering many
+//! queries about one snapshot. The index is little-endian binary:
+//!
+//! * The magic bytes `BTDRDEP1`, then a fingerprint of the targets and their count.
+//! * Labels depended on which aren't targets, e.g. those removed, each as a
+//!   `u32` length and its bytes, prefixed by their count as a `u64`.
+//! * The `ci_deps` patterns, each as a string and the `u32` position of the target
+//!   with it, prefixed by their count.
+//! * A `u64` offset for every target, then every other label, then one more for the end,
+//!   into the `u32` positions of the targets depending on them, which come last.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::Hasher;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use serde::Serialize;
+use td_util::json;
+use td_util::prelude::*;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::buck::target_map::TargetMap;
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::Package;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetName;
+use crate::buck::types::TargetPattern;
+use crate::diff;
+use crate::diff::GraphImpact;
+use crate::diff::ImpactReason;
+use crate::diff::RootImpactKind;
+use crate::impact_rule::ImpactRules;
+use crate::snapshot::Fnv;
+
+const MAGIC: &[u8; 8] = b"BTDRDEP1";
+
+#[derive(Debug, Error)]
+enum RdepsError {
+    #[error("Not a BTD rdeps index")]
+    NotIndex,
+    #[error("The rdeps index is truncated or corrupt")]
+    Corrupt,
+    #[error("The rdeps index was saved for different targets than those being diffed")]
+    Mismatch,
+    #[error("No targets match `{0}`")]
+    NoMatch(String),
+}
+
+/// Print the targets which transitively depend on those matching the patterns,
+/// nearest first, one per line.
+#[derive(clap::Args, Debug)]
+pub struct RdepsArgs {
+    /// Targets file to query, either the JSON output of `buck2 targets` or a snapshot.
+    #[arg(long, value_name = "FILE")]
+    targets: PathBuf,
+
+    /// Patterns for the targets whose reverse dependencies to find, e.g. `foo//bar:baz`.
+    #[arg(value_name = "TARGET_PATTERN", required = true)]
+    patterns: Vec<String>,
+
+    /// Only go this many levels, e.g. `1` for the direct reverse dependencies.
+    #[arg(long)]
+    depth: Option<usize>,
+
+    /// Only traverse and report targets matching these patterns, as with `btd --universe`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    universe: Vec<String>,
+
+    /// Don't report targets matching these patterns, as with `btd --exclude`.
+    #[arg(long, value_name = "TARGET_PATTERN")]
+    exclude: Vec<String>,
+
+    /// The `tests` of each target also count as depending on it.
+    #[arg(long)]
+    follow_tests: bool,
+
+    /// Targets also count as depending on their `toolchain_deps` and `exec_deps`.
+    #[arg(long)]
+    follow_toolchain_deps: bool,
+
+    /// Print each target as a JSON object with its `depth`.
+    #[arg(long)]
+    json_lines: bool,
+}
+
+/// A target depending on those queried.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Rdep {
+    pub target: TargetLabel,
+    /// `1` for targets depending on them directly.
+    pub depth: usize,
+}
+
+/// The edges which count as depending on a target, besides `deps` and `ci_deps`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Edges {
+    /// The `tests` of each target depend on it.
+    pub tests: bool,
+    /// Targets depend on their `toolchain_deps` and `exec_deps`, so changing a toolchain
+    /// impacts everything built with it.
+    pub toolchain_deps: bool,
+}
+
+/// The targets depending on each target.
+pub enum Rdeps<'a> {
+    Map(TargetMap<&'a BuckTarget>),
+    Index(Index<'a>),
+}
+
+/// An index loaded from a file, resolved against the targets it was saved for.
+pub struct Index<'a> {
+    targets: Vec<&'a BuckTarget>,
+    /// The position of each label in `offsets`.
+    slots: HashMap<TargetLabel, usize>,
+    offsets: Vec<u64>,
+    rdeps: Vec<u32>,
+    /// For labels which aren't in `slots`, only the `ci_deps` patterns can match.
+    patterns: TargetMap<u32>,
+}
+
+impl<'a> Rdeps<'a> {
+    pub fn new(diff: &'a Targets) -> Self {
+        Self::with_edges(diff, Edges::default())
+    }
+
+    /// Like [`Rdeps::new`], but the `tests` of each target also count as depending on it.
+    pub fn with_tests(diff: &'a Targets) -> Self {
+        Self::with_edges(
+            diff,
+            Edges {
+                tests: true,
+                ..Edges::default()
+            },
+        )
+    }
+
+    /// Like [`Rdeps::new`], but following the other `edges` too.
+    pub fn with_edges(diff: &'a Targets, edges: Edges) -> Self {
+        Self::with_rules(diff, edges, &ImpactRules::default())
+    }
+
+    /// Like [`Rdeps::with_edges`], but also following the extra deps of the `rules`.
+    pub fn with_rules(diff: &'a Targets, edges: Edges, rules: &ImpactRules) -> Self {
+        Self::Map(build_map(diff, edges, rules, |_, x| x))
+    }
+
+    pub fn get<'b>(
+        &'b self,
+        label: &'b TargetLabel,
+    ) -> Box<dyn Iterator<Item = &'a BuckTarget> + 'b> {
+        match self {
+            Self::Map(map) => Box::new(map.get(label).copied()),
+            Self::Index(index) => match index.slots.get(label) {
+                Some(slot) => {
+                    let range = index.offsets[*slot] as usize..index.offsets[*slot + 1] as usize;
+                    Box::new(
+                        index.rdeps[range]
+                            .iter()
+                            .map(|x| index.targets[*x as usize]),
+                    )
+                }
+                None => Box::new(
+                    index
+                        .patterns
+                        .get(label)
+                        .map(|x| index.targets[*x as usize]),
+                ),
+            },
+        }
+    }
+
+    /// Load an index written by [`Rdeps::save_index`] for the targets `diff`.
+    pub fn load_index(file: &Path, diff: &'a Targets) -> anyhow::Result<Self> {
+        let data = fs::read(file).with_context(|| format!("When reading `{}`", file.display()))?;
+        Self::parse_index(&data, diff)
+            .with_context(|| format!("When loading rdeps index `{}`", file.display()))
+    }
+
+    fn parse_index(data: &[u8], diff: &'a Targets) -> anyhow::Result<Self> {
+        let mut data = Reader(data);
+        if data.bytes(MAGIC.len())? != MAGIC {
+            return Err(RdepsError::NotIndex.into());
+        }
+        let targets = diff.targets().collect::<Vec<_>>();
+        if data.u64()? != fingerprint(diff) || data.u64()? != targets.len() as u64 {
+            return Err(RdepsError::Mismatch.into());
+        }
+        let mut slots = targets
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x.label(), i))
+            .collect::<HashMap<_, _>>();
+        let others = data.u64()? as usize;
+        for i in 0..others {
+            slots.insert(TargetLabel::new(data.str()?), targets.len() + i);
+        }
+        let mut patterns = TargetMap::new();
+        for _ in 0..data.u64()? {
+            let pattern = TargetPattern::new(data.str()?);
+            patterns.insert_pattern(&pattern, data.u32()?);
+        }
+        let offsets = (0..targets.len() + others + 1)
+            .map(|_| data.u64())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let rdeps = (0..offsets.last().copied().unwrap_or_default())
+            .map(|_| data.u32())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !data.0.is_empty()
+            || offsets.windows(2).any(|x| x[0] > x[1])
+            || rdeps.iter().any(|x| *x as usize >= targets.len())
+        {
+            return Err(RdepsError::Corrupt.into());
+        }
+        Ok(Self::Index(Index {
+            targets,
+            slots,
+            offsets,
+            rdeps,
+            patterns,
+        }))
+    }
+
+    /// Save an index of the rdeps of `diff`, to be loaded by [`Rdeps::load_index`].
+    pub fn save_index(file: &Path, diff: &Targets) -> anyhow::Result<()> {
+        let mut out = BufWriter::new(
+            fs::File::create(file)
+                .with_context(|| format!("When creating `{}`", file.display()))?,
+        );
+        write_index(&mut out, diff)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn write_index(out: &mut impl Write, diff: &Targets) -> anyhow::Result<()> {
+    let map = build_map(diff, Edges::default(), &ImpactRules::default(), |i, _| i);
+    let targets = diff.targets().map(|x| x.label()).collect::<Vec<_>>();
+    let known = targets.iter().collect::<HashSet<_>>();
+    let mut others = map
+        .literals()
+        .filter(|x| !known.contains(x))
+        .cloned()
+        .collect::<Vec<_>>();
+    others.sort();
+
+    out.write_all(MAGIC)?;
+    out.write_all(&fingerprint(diff).to_le_bytes())?;
+    out.write_all(&(targets.len() as u64).to_le_bytes())?;
+    out.write_all(&(others.len() as u64).to_le_bytes())?;
+    for x in &others {
+        write_str(out, x.as_str())?;
+    }
+    let patterns = diff
+        .targets()
+        .enumerate()
+        .flat_map(|(i, x)| {
+            x.ci_deps
+                .iter()
+                .filter(|x| x.as_target_label().is_none())
+                .map(move |x| (x, i as u32))
+        })
+        .collect::<Vec<_>>();
+    out.write_all(&(patterns.len() as u64).to_le_bytes())?;
+    for (pattern, i) in patterns {
+        write_str(out, pattern.as_str())?;
+        out.write_all(&i.to_le_bytes())?;
+    }
+    let rdeps = targets
+        .iter()
+        .chain(&others)
+        .map(|x| map.get(x).copied().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let mut offset = 0u64;
+    out.write_all(&offset.to_le_bytes())?;
+    for x in &rdeps {
+        offset += x.len() as u64;
+        out.write_all(&offset.to_le_bytes())?;
+    }
+    for x in rdeps.iter().flatten() {
+        out.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_str(out: &mut impl Write, x: &str) -> anyhow::Result<()> {
+    out.write_all(&(x.len() as u32).to_le_bytes())?;
+    out.write_all(x.as_bytes())?;
+    Ok(())
+}
+
+/// Identify the targets an index was saved for, from the edges [`build_map`] can
+/// follow, so this changes whenever the rdeps might, e.g. when deps are pruned.
+pub fn fingerprint(diff: &Targets) -> u64 {
+    // Each string is marked and length prefixed, and each list ended, so different
+    // targets can't hash the same bytes.
+    fn write<'a>(hasher: &mut Fnv, xs: impl IntoIterator<Item = &'a str>) {
+        for x in xs {
+            hasher.write_u8(1);
+            hasher.write(&(x.len() as u64).to_le_bytes());
+            hasher.write(x.as_bytes());
+        }
+        hasher.write_u8(0);
+    }
+
+    let mut hasher = Fnv::default();
+    for x in diff.targets() {
+        write(&mut hasher, [x.label().as_str(), x.hash.as_str()]);
+        write(&mut hasher, x.deps.iter().map(|x| x.as_str()));
+        write(&mut hasher, x.tests.iter().map(|x| x.as_str()));
+        write(&mut hasher, x.toolchain_deps.iter().map(|x| x.as_str()));
+        write(&mut hasher, x.exec_deps.iter().map(|x| x.as_str()));
+        write(&mut hasher, x.ci_deps.iter().map(|x| x.as_str()));
+    }
+    hasher.finish()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(RdepsError::Corrupt.into());
+        }
+        let (res, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(res)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn str(&mut self) -> anyhow::Result<&'a str> {
+        let n = self.u32()? as usize;
+        Ok(std::str::from_utf8(self.bytes(n)?)?)
+    }
+}
+
+/// The targets transitively depending on `roots`, nearest first, going up to `depth`
+/// levels. The `roots` themselves are not included.
+pub fn transitive_rdeps<'a>(
+    targets: &'a Targets,
+    rdeps: &Rdeps<'a>,
+    roots: &[&'a BuckTarget],
+    depth: Option<usize>,
+) -> Vec<Rdep> {
+    let seed = GraphImpact::from_recursive(
+        roots.map(|x| (*x, ImpactReason::new(x, RootImpactKind::ManualForRerun))),
+    );
+    let mut res = Vec::new();
+    let mut level_depth = 0;
+    diff::recursive_target_changes_with_rdeps(
+        targets,
+        Some(rdeps),
+        &seed,
+        depth,
+        true,
+        |_| true,
+        |level| {
+            // The first level is the roots themselves
+            if level_depth > 0 {
+                res.extend(level.iter().map(|(x, _)| Rdep {
+                    target: x.label(),
+                    depth: level_depth,
+                }));
+            }
+            level_depth += 1;
+        },
+    );
+    res
+}
+
+pub fn main(args: RdepsArgs) -> anyhow::Result<()> {
+    let parse = |xs: &[String]| {
+        xs.iter()
+            .map(|x| TargetPattern::new(x).parse())
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let patterns = parse(&args.patterns)?;
+    let universe = parse(&args.universe)?;
+    let exclude = parse(&args.exclude)?;
+
+    let targets = Targets::from_file(&args.targets)?.restrict(&universe);
+    let roots = targets
+        .targets()
+        .filter(|x| patterns.iter().any(|p| p.matches(&x.label())))
+        .collect::<Vec<_>>();
+    if roots.is_empty() {
+        return Err(RdepsError::NoMatch(args.patterns.join(" ")).into());
+    }
+    let rdeps = Rdeps::with_edges(
+        &targets,
+        Edges {
+            tests: args.follow_tests,
+            toolchain_deps: args.follow_toolchain_deps,
+        },
+    );
+    let mut res = transitive_rdeps(&targets, &rdeps, &roots, args.depth);
+    res.retain(|x| !exclude.iter().any(|p| p.matches(&x.target)));
+
+    let mut out = BufWriter::new(stdout().lock());
+    if args.json_lines {
+        json::write_json_lines(out, &res)?;
+    } else {
+        for x in &res {
+            writeln!(out, "{}", x.target)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Map each label to the targets depending on it, storing `value` of the target
+/// and its position in `diff`, following the other `edges` and the extra deps of the
+/// `rules` too.
+fn build_map<'a, T: Copy>(
+    diff: &'a Targets,
+    edges: Edges,
+    rules: &ImpactRules,
+    value: impl Fn(u32, &'a BuckTarget) -> T,
+) -> TargetMap<T> {
+    // We expect most things will have at least one dependency, so a reasonable approximate size
+    let mut rdeps: TargetMap<T> = TargetMap::with_capacity(diff.len_targets_upperbound());
+    let mut hints: HashMap<(&Package, TargetName), TargetLabel> = HashMap::new();
+    let mut tested: HashMap<&TargetLabel, Vec<TargetLabel>> = HashMap::new();
+    for (i, target) in diff.targets().enumerate() {
+        let v = value(i as u32, target);
+        if edges.tests {
+            for x in target.tests.iter() {
+                tested.entry(x).or_default().push(target.label());
+            }
+        }
+        for d in target.deps.iter() {
+            rdeps.insert(d, v)
+        }
+        if edges.toolchain_deps {
+            for d in target.toolchain_deps.iter().chain(target.exec_deps.iter()) {
+                rdeps.insert(d, v)
+            }
+        }
+        for d in rules.extra_deps(target) {
+            rdeps.insert(&d, v)
+        }
+        for d in target.ci_deps.iter() {
+            if let Some(label) = d.as_target_label() {
+                if label.is_package_relative() {
+                    rdeps.insert(&target.package.join(&label.target_name()), v);
+                } else {
+                    rdeps.insert(&label, v);
+                }
+            } else {
+                rdeps.insert_pattern(d, v);
+            }
+        }
+        if target.rule_type.short() == "ci_hint" {
+            match hint_applies_to(target) {
+                Some(dest) => {
+                    hints.insert(dest, target.label());
+                }
+                None => warn!("`ci_hint` target has invalid name: `{}`", target.label()),
+            }
+        }
+    }
+    // We record the hints going through (while we don't have the targets to hand),
+    // then fill them in later with this loop
+    if !hints.is_empty() {
+        for (i, target) in diff.targets().enumerate() {
+            if let Some(hint) = hints.remove(&(&target.package, target.name.clone())) {
+                rdeps.insert(&hint, value(i as u32, target));
+                if hints.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+    // Likewise the tests, which we only know the labels of until now
+    if !tested.is_empty() {
+        for (i, target) in diff.targets().enumerate() {
+            if let Some(labels) = tested.remove(&target.label()) {
+                for x in &labels {
+                    rdeps.insert(x, value(i as u32, target));
+                }
+            }
+        }
+    }
+    rdeps
+}
+
+fn hint_applies_to(target: &BuckTarget) -> Option<(&Package, TargetName)> {
+    // for hints, the name will be `foo//bar:ci_hint@baz` which means
+    // we need to test `foo//bar:baz`.
+    Some((
+        &target.package,
+        TargetName::new(target.name.as_str().strip_prefix("ci_hint@")?),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::TargetHash;
+
+    #[test]
+    fn test_rdeps_index() {
+        let target = |name: &str, deps: &[&str], ci_deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ci_deps: ci_deps.iter().map(|x| TargetPattern::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &["foo//bar:removed"], &[]),
+            target("bin", &["foo//bar:lib"], &[]),
+            target("test", &["foo//bar:bin"], &["foo//bar:lib", "foo//baz/..."]),
+        ]);
+        let mut data = Vec::new();
+        write_index(&mut data, &targets).unwrap();
+        let map = Rdeps::new(&targets);
+        let index = Rdeps::parse_index(&data, &targets).unwrap();
+        assert!(matches!(index, Rdeps::Index(_)));
+        for label in [
+            "foo//bar:lib",
+            "foo//bar:bin",
+            "foo//bar:test",
+            "foo//bar:removed",
+            "foo//baz/qux:removed",
+            "foo//other:removed",
+        ] {
+            let label = TargetLabel::new(label);
+            let get = |rdeps: &Rdeps| {
+                let mut res = rdeps.get(&label).map(|x| x.label()).collect::<Vec<_>>();
+                res.sort();
+                res
+            };
+            assert_eq!(get(&map), get(&index), "{label}");
+        }
+        assert_eq!(
+            index
+                .get(&TargetLabel::new("foo//bar:lib"))
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bin", "test"]
+        );
+
+        // A different snapshot, or a truncated file, are rejected
+        let changed = Targets::new(vec![TargetsEntry::Target(BuckTarget {
+            hash: TargetHash::new("changed"),
+            ..BuckTarget::testing("lib", "foo//bar", "prelude//rules.bzl:cxx_library")
+        })]);
+        assert!(Rdeps::parse_index(&data, &changed).is_err());
+        // Deps rewritten without changing the target hash, as `--check-visibility` prunes them
+        let pruned = Targets::new(vec![
+            target("lib", &[], &[]),
+            target("bin", &["foo//bar:lib"], &[]),
+            target("test", &["foo//bar:bin"], &["foo//bar:lib", "foo//baz/..."]),
+        ]);
+        assert!(Rdeps::parse_index(&data, &pruned).is_err());
+        assert!(Rdeps::parse_index(&data[..data.len() - 1], &targets).is_err());
+        assert!(Rdeps::parse_index(b"not an index", &targets).is_err());
+    }
+
+    #[test]
+    fn test_rdeps_with_tests() {
+        let target = |name: &str, deps: &[&str], tests: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                tests: tests.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &[], &["foo//bar:lib_test", "foo//bar:missing_test"]),
+            target("bin", &["foo//bar:lib"], &[]),
+            target("lib_test", &[], &[]),
+        ]);
+        let get = |rdeps: &Rdeps| {
+            let mut res = rdeps
+                .get(&TargetLabel::new("foo//bar:lib"))
+                .map(|x| x.name.as_str().to_owned())
+                .collect::<Vec<_>>();
+            res.sort();
+            res
+        };
+        assert_eq!(get(&Rdeps::new(&targets)), vec!["bin"]);
+        assert_eq!(get(&Rdeps::with_tests(&targets)), vec!["bin", "lib_test"]);
+    }
+
+    #[test]
+    fn test_rdeps_with_toolchain_deps() {
+        let target = |name: &str, deps: &[&str], toolchain_deps: &[&str], exec_deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                toolchain_deps: toolchain_deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                exec_deps: exec_deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &[], &["toolchains//:cxx"], &[]),
+            target("gen", &[], &[], &["toolchains//:cxx"]),
+            target("bin", &["foo//bar:lib"], &[], &[]),
+        ]);
+        let get = |rdeps: &Rdeps| {
+            let mut res = rdeps
+                .get(&TargetLabel::new("toolchains//:cxx"))
+                .map(|x| x.name.as_str().to_owned())
+                .collect::<Vec<_>>();
+            res.sort();
+            res
+        };
+        let edges = Edges {
+            toolchain_deps: true,
+            ..Edges::default()
+        };
+        assert_eq!(get(&Rdeps::new(&targets)), Vec::<String>::new());
+        assert_eq!(get(&Rdeps::with_edges(&targets, edges)), vec!["gen", "lib"]);
+    }
+
+    #[test]
+    fn test_transitive_rdeps() {
+        let target = |name: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &[]),
+            target("util", &[]),
+            target("bin", &["foo//bar:lib"]),
+            target("test", &["foo//bar:bin", "foo//bar:util"]),
+            target("other", &["foo//bar:util"]),
+        ]);
+        let rdeps = Rdeps::new(&targets);
+        let by_label = targets.targets_by_label();
+        let root = |x: &str| by_label[&TargetLabel::new(x)];
+        let query = |roots: &[&str], depth| {
+            transitive_rdeps(&targets, &rdeps, &roots.map(|x| root(x)), depth)
+                .map(|x| (x.target.to_string(), x.depth))
+        };
+        let rdep = |x: &str, depth| (format!("foo//bar:{x}"), depth);
+
+        assert_eq!(
+            query(&["foo//bar:lib"], None),
+            vec![rdep("bin", 1), rdep("test", 2)]
+        );
+        assert_eq!(query(&["foo//bar:lib"], Some(1)), vec![rdep("bin", 1)]);
+        assert_eq!(
+            query(&["foo//bar:lib", "foo//bar:util"], None),
+            vec![rdep("bin", 1), rdep("other", 1), rdep("test", 1)]
+        );
+        assert_eq!(query(&["foo//bar:test"], None), vec![]);
+    }
+}
@@ -246,6 +246,7 @@ fn find_closest_enclosing_package(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::buck::arena::Deps;
     use crate::buck::targets::BuckError;
     use crate::buck::targets::BuckImport;
     use crate::buck::targets::BuckTarget;
@@ -289,7 +290,7 @@ mod tests {
                 package: Some(Package::new("fbcode//pkg/hello")),
             }),
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([
+                deps: Deps::from([
                     TargetLabel::new("toolchains//:python"),
                     TargetLabel::new("fbcode//python:library"),
                 ]),
@@ -335,7 +336,7 @@ mod tests {
                 package: Some(Package::new("fbcode//pkg/hello")),
             }),
             TargetsEntry::Target(BuckTarget {
-                deps: Box::new([
+                deps: Deps::from([
                     TargetLabel::new("toolchains//:python"),
                     TargetLabel::new("fbcode//python:library"),
                 ]),
@@ -31,7 +31,7 @@ enum StatusParseError {
 }
 
 impl Status<ProjectRelativePath> {
-    fn from_str(value: &str) -> anyhow::Result<Self> {
+    pub(crate) fn from_str(value: &str) -> anyhow::Result<Self> {
         let mut it = value.chars();
         let typ = it.next();
         if it.next() != Some(' ') {
@@ -101,6 +101,22 @@ fn parse_status(data: &str) -> anyhow::Result<Vec<Status<ProjectRelativePath>>>
         .collect::<anyhow::Result<Vec<_>>>()
 }
 
+/// Read a file listing paths relative to the root of the repo, one per line,
+/// treating each as modified.
+pub fn read_paths(path: &Path) -> anyhow::Result<Vec<Status<ProjectRelativePath>>> {
+    Ok(parse_paths(&fs::read_to_string(path).with_context(
+        || format!("When reading `{}`", path.display()),
+    )?))
+}
+
+fn parse_paths(data: &str) -> Vec<Status<ProjectRelativePath>> {
+    data.lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| Status::Modified(ProjectRelativePath::new(x)))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,4 +146,15 @@ R quux.js
         assert!(parse_status("notaline").is_err());
         assert!(parse_status("not a line").is_err());
     }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(
+            parse_paths("proj/foo.h\n\n  bar.rs \n"),
+            vec![
+                Status::Modified(ProjectRelativePath::new("proj/foo.h")),
+                Status::Modified(ProjectRelativePath::new("bar.rs")),
+            ]
+        );
+    }
 }
@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Recognise impacted targets which CI shouldn't schedule, by the labels conventionally
+//! marking them, e.g. `manual` for targets only ever built or run by hand, and leave
+//! them out or report them separately.
+//!
+//! A target's labels include those applied to its package, so a whole package can be
+//! marked at once.
+
+use std::collections::HashSet;
+
+use clap::ValueEnum;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::TargetLabel;
+use crate::diff::ImpactReason;
+
+/// The labels which conventionally mean a target isn't for CI to schedule.
+pub const CONVENTIONAL_LABELS: &[&str] = &["ci_skip", "disabled", "do_not_schedule", "manual"];
+
+/// What to do with impacted targets which CI shouldn't schedule.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnschedulablePolicy {
+    /// Report them like any other impacted target.
+    #[default]
+    Keep,
+    /// Leave them out.
+    Remove,
+    /// Report them as `quarantined`, so CI can run them without blocking on them.
+    Demote,
+}
+
+/// Which impacted targets CI shouldn't schedule, and what to do with them.
+#[derive(Debug, Default)]
+pub struct Unschedulable {
+    policy: UnschedulablePolicy,
+    labels: Vec<String>,
+}
+
+impl Unschedulable {
+    /// The [`CONVENTIONAL_LABELS`], plus those in `respect`, less those in `ignore`.
+    pub fn new(policy: UnschedulablePolicy, respect: &[String], ignore: &[String]) -> Self {
+        let mut labels = CONVENTIONAL_LABELS
+            .iter()
+            .map(|x| (*x).to_owned())
+            .chain(respect.iter().cloned())
+            .filter(|x| !ignore.contains(x))
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels.dedup();
+        Self { policy, labels }
+    }
+
+    pub fn is_keep(&self) -> bool {
+        self.policy == UnschedulablePolicy::Keep
+    }
+
+    pub fn matches(&self, target: &BuckTarget) -> bool {
+        self.labels
+            .iter()
+            .any(|x| target.labels.contains(x) || target.package_values.labels.contains(x))
+    }
+
+    /// Leave the targets CI shouldn't schedule out of `levels`, with `remove`.
+    pub fn remove<'a>(
+        &self,
+        mut levels: Vec<Vec<(&'a BuckTarget, ImpactReason)>>,
+    ) -> Vec<Vec<(&'a BuckTarget, ImpactReason)>> {
+        if self.policy == UnschedulablePolicy::Remove {
+            for level in &mut levels {
+                level.retain(|(x, _)| !self.matches(x));
+            }
+        }
+        levels
+    }
+
+    /// The targets in `levels` which CI shouldn't schedule, to report as quarantined,
+    /// with `demote`.
+    pub fn demoted(
+        &self,
+        levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    ) -> Option<HashSet<TargetLabel>> {
+        (self.policy == UnschedulablePolicy::Demote).then(|| {
+            levels
+                .iter()
+                .flatten()
+                .filter(|(x, _)| self.matches(x))
+                .map(|(x, _)| x.label())
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use td_util::prelude::*;
+
+    use super::*;
+    use crate::buck::labels::Labels;
+    use crate::buck::types::PackageValues;
+    use crate::diff::RootImpactKind;
+
+    #[test]
+    fn test_unschedulable() {
+        let target = |name: &str, labels: &[&str]| BuckTarget {
+            labels: Labels::new(labels),
+            ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_test")
+        };
+        let lib = target("lib", &[]);
+        let manual = target("manual", &["manual"]);
+        let skipped = target("skipped", &["ci_skip", "unit"]);
+        let slow = target("slow", &["slow"]);
+        let package = BuckTarget {
+            package_values: PackageValues::new(&["disabled"], serde_json::Value::Null),
+            ..target("package", &[])
+        };
+        let reason = ImpactReason::new(&lib, RootImpactKind::Inputs);
+        let levels = vec![
+            vec![(&lib, reason.clone()), (&manual, reason.clone())],
+            vec![
+                (&skipped, reason.clone()),
+                (&slow, reason.clone()),
+                (&package, reason),
+            ],
+        ];
+        let names = |levels: &[Vec<(&BuckTarget, ImpactReason)>]| {
+            levels.map(|level| level.map(|(x, _)| x.name.as_str().to_owned()))
+        };
+
+        let respect = ["slow".to_owned()];
+        let ignore = ["manual".to_owned()];
+        let remove = Unschedulable::new(UnschedulablePolicy::Remove, &respect, &ignore);
+        assert_eq!(
+            names(&remove.remove(levels.clone())),
+            vec![vec!["lib", "manual"], vec![]]
+        );
+        assert_eq!(remove.demoted(&levels), None);
+
+        let demote = Unschedulable::new(UnschedulablePolicy::Demote, &[], &[]);
+        assert_eq!(names(&demote.remove(levels.clone())), names(&levels));
+        assert_eq!(
+            demote.demoted(&levels),
+            Some(HashSet::from([
+                manual.label(),
+                skipped.label(),
+                package.label()
+            ]))
+        );
+    }
+}
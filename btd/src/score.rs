@@ -0,0 +1,350 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Score impacted tests by how valuable they are to run, so schedulers with a time
+//! budget can run the highest scoring first.
+//!
+//! The score of a test is the weighted sum of three signals, each between `0` and `1`:
+//! `1 / (1 + depth)`, so tests closer to the change score higher, its historical
+//! failure rate, and `1 / (1 + ln(1 + size))`, where `size` is its number of inputs
+//! and dependencies, so smaller, cheaper tests score higher.
+//!
+//! A [`Budget`] then keeps only the highest scoring tests, by count or total duration.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::TargetLabel;
+
+/// The weight of each signal in the score, read from JSON. Missing fields are left
+/// at their defaults.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoreWeights {
+    pub depth: f64,
+    pub failure_rate: f64,
+    pub size: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            depth: 1.0,
+            failure_rate: 2.0,
+            size: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum ScoreError {
+    #[error("Expected `TARGET,RATE` in the failure rates, got `{0}`")]
+    MalformedLine(String),
+    #[error("The failure rate of `{target}` is {rate}, but must be between 0 and 1")]
+    OutOfRange { target: String, rate: f64 },
+    #[error("The duration of `{target}` is {duration}, but can't be negative")]
+    NegativeDuration { target: String, duration: f64 },
+}
+
+/// Scores impacted tests, or does nothing if it has no weights.
+#[derive(Debug, Default)]
+pub struct Scorer {
+    weights: Option<ScoreWeights>,
+    failure_rates: HashMap<TargetLabel, f64>,
+}
+
+impl Scorer {
+    pub fn new(weights: ScoreWeights, failure_rates: HashMap<TargetLabel, f64>) -> Self {
+        Self {
+            weights: Some(weights),
+            failure_rates,
+        }
+    }
+
+    pub fn read_weights(file: &Path) -> anyhow::Result<ScoreWeights> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading score config `{}`", file.display()))?;
+        serde_json::from_str(&data)
+            .with_context(|| format!("When parsing score config `{}`", file.display()))
+    }
+
+    /// Read the failure rate of each test, either a JSON object from target to rate, or
+    /// CSV lines of `target,rate`, optionally with a header.
+    pub fn read_failure_rates(file: &Path) -> anyhow::Result<HashMap<TargetLabel, f64>> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading failure rates `{}`", file.display()))?;
+        parse_failure_rates(&data)
+            .with_context(|| format!("When parsing failure rates `{}`", file.display()))
+    }
+
+    /// The score of `x`, impacted at `depth`, or `None` if there are no weights.
+    pub fn score(&self, x: &BuckTarget, depth: u64) -> Option<f64> {
+        let weights = self.weights.as_ref()?;
+        let failure_rate = self.failure_rates.get(&x.label()).copied().unwrap_or(0.0);
+        let size = (x.inputs.len() + x.deps.len()) as f64;
+        let score = weights.depth / (1.0 + depth as f64)
+            + weights.failure_rate * failure_rate
+            + weights.size / (1.0 + size.ln_1p());
+        // Enough precision to rank by, without noise in the output
+        Some((score * 1000.0).round() / 1000.0)
+    }
+}
+
+/// Parse a JSON object from target to number, or CSV lines of `target,number`,
+/// optionally with a header.
+fn parse_table(data: &str) -> anyhow::Result<Vec<(String, f64)>> {
+    Ok(if data.trim_start().starts_with('{') {
+        serde_json::from_str::<HashMap<String, f64>>(data)?
+            .into_iter()
+            .collect()
+    } else {
+        let mut res = Vec::new();
+        for (i, line) in data.lines().map(str::trim).enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let (target, rate) = line
+                .split_once(',')
+                .ok_or_else(|| ScoreError::MalformedLine(line.to_owned()))?;
+            match rate.trim().parse::<f64>() {
+                Ok(rate) => res.push((target.trim().to_owned(), rate)),
+                // The header
+                Err(_) if i == 0 => {}
+                Err(_) => return Err(ScoreError::MalformedLine(line.to_owned()).into()),
+            }
+        }
+        res
+    })
+}
+
+fn parse_failure_rates(data: &str) -> anyhow::Result<HashMap<TargetLabel, f64>> {
+    parse_table(data)?
+        .into_iter()
+        .map(|(target, rate)| {
+            if (0.0..=1.0).contains(&rate) {
+                Ok((TargetLabel::new(&target), rate))
+            } else {
+                Err(ScoreError::OutOfRange { target, rate }.into())
+            }
+        })
+        .collect()
+}
+
+fn parse_durations(data: &str) -> anyhow::Result<HashMap<TargetLabel, f64>> {
+    parse_table(data)?
+        .into_iter()
+        .map(|(target, duration)| {
+            if duration >= 0.0 {
+                Ok((TargetLabel::new(&target), duration))
+            } else {
+                Err(ScoreError::NegativeDuration { target, duration }.into())
+            }
+        })
+        .collect()
+}
+
+/// Why a [`Budget`] left a test out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// `--target-budget` tests with higher scores were already selected.
+    TargetBudget,
+    /// Running it would take the selected tests over `--time-budget`.
+    TimeBudget,
+}
+
+/// A test left out by a [`Budget`].
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Skipped {
+    pub target: TargetLabel,
+    pub score: f64,
+    /// The duration counted against `--time-budget`, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    pub reason: SkipReason,
+}
+
+/// Limits on the scored tests to run, for CI with limited capacity.
+#[derive(Debug, Default)]
+pub struct Budget {
+    max_targets: Option<usize>,
+    /// The maximum total duration, with the duration of each test.
+    max_duration: Option<(f64, HashMap<TargetLabel, f64>)>,
+}
+
+impl Budget {
+    pub fn new(max_targets: Option<usize>) -> Self {
+        Self {
+            max_targets,
+            max_duration: None,
+        }
+    }
+
+    pub fn with_max_duration(self, max: f64, durations: HashMap<TargetLabel, f64>) -> Self {
+        Self {
+            max_duration: Some((max, durations)),
+            ..self
+        }
+    }
+
+    /// Read the duration of each test in seconds, in the same formats as
+    /// [`read_failure_rates`](Scorer::read_failure_rates).
+    pub fn read_durations(file: &Path) -> anyhow::Result<HashMap<TargetLabel, f64>> {
+        let data = fs::read_to_string(file)
+            .with_context(|| format!("When reading durations `{}`", file.display()))?;
+        parse_durations(&data)
+            .with_context(|| format!("When parsing durations `{}`", file.display()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_targets.is_none() && self.max_duration.is_none()
+    }
+
+    /// Select the scored `tests` to run, highest score first, skipping any which would go
+    /// over the budget. A test without a known duration is assumed to take the mean of the
+    /// known durations. Returns the skipped tests, highest score first.
+    pub fn select(&self, tests: &[(TargetLabel, f64)]) -> Vec<Skipped> {
+        let mut tests = tests.iter().collect::<Vec<_>>();
+        tests.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.key().cmp(&b.0.key())));
+        let mean = self.max_duration.as_ref().map(|(_, durations)| {
+            if durations.is_empty() {
+                0.0
+            } else {
+                durations.values().sum::<f64>() / durations.len() as f64
+            }
+        });
+
+        let mut selected = 0;
+        let mut total = 0.0;
+        let mut res = Vec::new();
+        for (target, score) in tests {
+            let duration = self
+                .max_duration
+                .as_ref()
+                .map(|(_, durations)| durations.get(target).copied().or(mean).unwrap_or(0.0));
+            let reason = if self.max_targets.is_some_and(|max| selected >= max) {
+                Some(SkipReason::TargetBudget)
+            } else if let (Some((max, _)), Some(duration)) = (&self.max_duration, duration) {
+                (total + duration > *max).then_some(SkipReason::TimeBudget)
+            } else {
+                None
+            };
+            match reason {
+                Some(reason) => res.push(Skipped {
+                    target: target.clone(),
+                    score: *score,
+                    duration,
+                    reason,
+                }),
+                None => {
+                    selected += 1;
+                    total += duration.unwrap_or(0.0);
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        let test = BuckTarget::testing("test", "foo//bar", "prelude//rules.bzl:cxx_test");
+        let big = BuckTarget {
+            deps: (0..100)
+                .map(|i| TargetLabel::new(&format!("foo//bar:dep{i}")))
+                .collect(),
+            ..BuckTarget::testing("big", "foo//bar", "prelude//rules.bzl:cxx_test")
+        };
+        assert_eq!(Scorer::default().score(&test, 0), None);
+
+        let scorer = Scorer::new(
+            ScoreWeights::default(),
+            HashMap::from([(TargetLabel::new("foo//bar:big"), 0.5)]),
+        );
+        assert_eq!(scorer.score(&test, 0), Some(1.5));
+        assert_eq!(scorer.score(&test, 1), Some(1.0));
+        // Flaky and close, so worth running despite being big
+        assert_eq!(scorer.score(&big, 0), Some(2.089));
+    }
+
+    #[test]
+    fn test_parse_failure_rates() {
+        let expected = HashMap::from([
+            (TargetLabel::new("foo//bar:a"), 0.25),
+            (TargetLabel::new("foo//bar:b"), 0.0),
+        ]);
+        let csv = "target,failure_rate\nfoo//bar:a,0.25\n\nfoo//bar:b, 0\n";
+        assert_eq!(parse_failure_rates(csv).unwrap(), expected);
+        assert_eq!(
+            parse_failure_rates(r#"{"foo//bar:a": 0.25, "foo//bar:b": 0}"#).unwrap(),
+            expected
+        );
+        assert!(parse_failure_rates("foo//bar:a,0.25\nfoo//bar:b\n").is_err());
+        assert!(parse_failure_rates("foo//bar:a,0.25\nfoo//bar:b,high\n").is_err());
+        assert!(parse_failure_rates("foo//bar:a,1.5\n").is_err());
+        assert!(parse_durations("foo//bar:a,-1\n").is_err());
+    }
+
+    #[test]
+    fn test_budget() {
+        let tests = [("a", 1.0), ("b", 3.0), ("c", 2.0), ("d", 0.5)]
+            .map(|(name, score)| (TargetLabel::new(&format!("foo//bar:{name}")), score));
+        let skipped = |budget: &Budget| {
+            budget
+                .select(&tests)
+                .into_iter()
+                .map(|x| (x.target.to_string(), x.reason))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(skipped(&Budget::default()), Vec::new());
+        assert_eq!(
+            skipped(&Budget::new(Some(2))),
+            vec![
+                ("foo//bar:a".to_owned(), SkipReason::TargetBudget),
+                ("foo//bar:d".to_owned(), SkipReason::TargetBudget),
+            ]
+        );
+
+        // `c` is too long, but the shorter `a` still fits, and `d` takes the mean of 10
+        let durations = HashMap::from([
+            (TargetLabel::new("foo//bar:a"), 5.0),
+            (TargetLabel::new("foo//bar:b"), 10.0),
+            (TargetLabel::new("foo//bar:c"), 15.0),
+        ]);
+        let budget = Budget::new(None).with_max_duration(20.0, durations);
+        assert_eq!(
+            budget.select(&tests),
+            vec![
+                Skipped {
+                    target: TargetLabel::new("foo//bar:c"),
+                    score: 2.0,
+                    duration: Some(15.0),
+                    reason: SkipReason::TimeBudget,
+                },
+                Skipped {
+                    target: TargetLabel::new("foo//bar:d"),
+                    score: 0.5,
+                    duration: Some(10.0),
+                    reason: SkipReason::TimeBudget,
+                },
+            ]
+        );
+    }
+}
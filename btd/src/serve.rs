@@ -0,0 +1,406 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Load a targets graph once, then answer impact queries over a unix socket, so
+//! interactive tools don't pay for parsing a large graph on every query.
+//!
+//! Each line sent on a connection is a JSON-RPC 2.0 request, and is answered by one
+//! line with the response. The methods are:
+//!
+//! * `impact`, with `files` (relative to the root of the repo, treated as modified)
+//!   and an optional `depth`, returns the impacted targets as `btd --json` would.
+//! * `rdeps`, with a `target` and an optional `depth`, returns the targets which
+//!   transitively depend on it, with their distance from it.
+//! * `why`, with `files` and a `target`, explains why the target is impacted by
+//!   changing the files, as `btd --why` would, or returns `null` if it isn't.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use anyhow::Context as _;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+use tracing::info;
+use tracing::warn;
+
+use crate::buck::cells::CellInfo;
+use crate::buck::labels::Labels;
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::ProjectRelativePath;
+use crate::buck::types::TargetLabel;
+use crate::changes::Changes;
+use crate::diff;
+use crate::diff::GraphImpact;
+use crate::diff::ImpactReason;
+use crate::output::Output;
+use crate::rdeps;
+use crate::rdeps::Rdeps;
+use crate::sapling::status::Status;
+use crate::why;
+
+/// Serve impact queries against a targets graph over a unix socket.
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// File containing the output of `buck2 audit cell` in the root of the repo.
+    #[arg(long, value_name = "FILE")]
+    cells: PathBuf,
+
+    /// File containing the output of `buck2 audit config --cells --json` in the root of the repo.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Targets file to answer queries against.
+    #[arg(long, value_name = "FILE")]
+    targets: PathBuf,
+
+    /// Path of the unix socket to listen on.
+    #[arg(long, value_name = "PATH")]
+    socket: PathBuf,
+}
+
+#[derive(Debug, Error)]
+enum ServeError {
+    #[error("Another server is already listening on `{0}`")]
+    InUse(String),
+}
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The request was well formed, but couldn't be answered, e.g. an unknown target.
+const QUERY_FAILED: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImpactParams {
+    files: Vec<String>,
+    #[serde(default)]
+    depth: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RdepsParams {
+    target: String,
+    #[serde(default)]
+    depth: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WhyParams {
+    files: Vec<String>,
+    target: String,
+}
+
+/// Answers queries against a graph, built once.
+pub struct Server<'a> {
+    cells: CellInfo,
+    targets: &'a Targets,
+    by_label: HashMap<TargetLabel, &'a BuckTarget>,
+    rdeps: Rdeps<'a>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(cells: CellInfo, targets: &'a Targets) -> Self {
+        Self {
+            cells,
+            targets,
+            by_label: targets.targets_by_label(),
+            rdeps: Rdeps::new(targets),
+        }
+    }
+
+    /// Answer a single line of JSON-RPC.
+    pub fn handle(&self, line: &str) -> Response {
+        let request: Request = match serde_json::from_str(line) {
+            Ok(x) => x,
+            Err(e) => return Response::new(Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+        };
+        let result = match request.method.as_str() {
+            "impact" => params(request.params).and_then(|x| self.impact(x)),
+            "rdeps" => params(request.params).and_then(|x| self.rdeps(x)),
+            "why" => params(request.params).and_then(|x| self.why(x)),
+            method => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method `{method}`"),
+            )),
+        };
+        Response::new(request.id, result)
+    }
+
+    fn changes(&self, files: &[String]) -> Result<Changes, RpcError> {
+        let status = files
+            .iter()
+            .map(|x| Status::Modified(ProjectRelativePath::new(x)))
+            .collect();
+        Changes::new(&self.cells, status).map_err(|e| RpcError::new(QUERY_FAILED, e))
+    }
+
+    fn levels(
+        &self,
+        changes: &GraphImpact<'a>,
+        depth: Option<usize>,
+    ) -> Vec<Vec<(&'a BuckTarget, ImpactReason)>> {
+        let mut res = Vec::new();
+        diff::recursive_target_changes_with_rdeps(
+            self.targets,
+            Some(&self.rdeps),
+            changes,
+            depth,
+            true,
+            |_| true,
+            |x| res.push(x),
+        );
+        res
+    }
+
+    /// The graph is the same before and after, so only the files changing matters.
+    fn impacted(
+        &self,
+        changes: &Changes,
+        depth: Option<usize>,
+    ) -> Vec<Vec<(&'a BuckTarget, ImpactReason)>> {
+        let immediate = diff::immediate_target_changes(self.targets, self.targets, changes, false);
+        self.levels(&immediate, depth)
+    }
+
+    fn impact(&self, params: ImpactParams) -> Result<Value, RpcError> {
+        let changes = self.changes(&params.files)?;
+        let levels = self.impacted(&changes, params.depth);
+        let res: Vec<Output> = levels
+            .iter()
+            .enumerate()
+            .flat_map(|(depth, level)| {
+                level.iter().map(move |(x, reason)| {
+                    Output::from_target(x, depth as u64, &Labels::default(), reason.clone())
+                })
+            })
+            .collect();
+        to_value(res)
+    }
+
+    fn rdeps(&self, params: RdepsParams) -> Result<Value, RpcError> {
+        let target = self.target(&params.target)?;
+        let res = rdeps::transitive_rdeps(self.targets, &self.rdeps, &[target], params.depth);
+        to_value(res)
+    }
+
+    fn why(&self, params: WhyParams) -> Result<Value, RpcError> {
+        let target = self.target(&params.target)?.label();
+        let changes = self.changes(&params.files)?;
+        let levels = self.impacted(&changes, None);
+        to_value(why::explain(&levels, &changes, &target))
+    }
+
+    fn target(&self, label: &str) -> Result<&'a BuckTarget, RpcError> {
+        self.by_label
+            .get(&TargetLabel::new(label))
+            .copied()
+            .ok_or_else(|| RpcError::new(QUERY_FAILED, format!("Unknown target `{label}`")))
+    }
+
+    /// Answer requests from `stream` until it is closed.
+    fn serve(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let mut out = BufWriter::new(stream.try_clone()?);
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            serde_json::to_writer(&mut out, &self.handle(&line))?;
+            out.write_all(b"\n")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Response {
+    fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(x) => (Some(x), None),
+            Err(e) => (None, Some(e)),
+        };
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn to_value(x: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(x).map_err(|e| RpcError::new(QUERY_FAILED, e))
+}
+
+/// Listen on `socket`, replacing it if it was left behind by a server no longer running.
+fn bind(socket: &Path) -> anyhow::Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(ServeError::InUse(socket.display().to_string()).into());
+        }
+        fs::remove_file(socket)
+            .with_context(|| format!("When removing stale socket `{}`", socket.display()))?;
+    }
+    UnixListener::bind(socket).with_context(|| format!("When listening on `{}`", socket.display()))
+}
+
+pub fn main(args: ServeArgs) -> anyhow::Result<()> {
+    let mut cells = CellInfo::new(&args.cells)?;
+    if let Some(config) = &args.config {
+        cells.load_config_data(config)?;
+    }
+    let targets = Targets::from_file(&args.targets)?;
+    let server = Server::new(cells, &targets);
+    let listener = bind(&args.socket)?;
+    info!("Serving queries on `{}`", args.socket.display());
+    let server = &server;
+    thread::scope(|s| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    s.spawn(move || {
+                        if let Err(e) = server.serve(stream) {
+                            warn!("Connection failed: {e:#}");
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept a connection: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::CellPath;
+
+    #[test]
+    fn test_handle() {
+        let target = |name: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                inputs: Box::new([CellPath::new(&format!("root//baz/{name}.c"))]),
+                ..BuckTarget::testing(name, "root//baz", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &[]),
+            target("bin", &["root//baz:lib"]),
+            target("test", &["root//baz:bin"]),
+        ]);
+        let server = Server::new(CellInfo::testing(), &targets);
+        let call =
+            |request: Value| serde_json::to_value(server.handle(&request.to_string())).unwrap();
+
+        let res = call(json!({"jsonrpc": "2.0", "id": 1, "method": "impact",
+            "params": {"files": ["baz/lib.c"], "depth": 1}}));
+        assert_eq!(res["id"], 1);
+        let impacted = res["result"].as_array().unwrap();
+        assert_eq!(impacted.len(), 2);
+        assert_eq!(impacted[0]["target"], "root//baz:lib");
+        assert_eq!(impacted[1]["target"], "root//baz:bin");
+        assert_eq!(impacted[1]["depth"], 1);
+
+        let res = call(json!({"id": 2, "method": "rdeps",
+            "params": {"target": "root//baz:lib", "depth": 1}}));
+        assert_eq!(
+            res["result"],
+            json!([{"target": "root//baz:bin", "depth": 1}])
+        );
+        let res = call(json!({"id": 3, "method": "rdeps", "params": {"target": "root//baz:lib"}}));
+        assert_eq!(
+            res["result"],
+            json!([
+                {"target": "root//baz:bin", "depth": 1},
+                {"target": "root//baz:test", "depth": 2},
+            ])
+        );
+
+        let res = call(json!({"id": 3, "method": "why",
+            "params": {"files": ["baz/lib.c"], "target": "root//baz:test"}}));
+        assert_eq!(
+            res["result"]["path"],
+            json!(["root//baz:lib", "root//baz:bin", "root//baz:test"])
+        );
+        let res = call(json!({"id": 4, "method": "why",
+            "params": {"files": ["baz/bin.c"], "target": "root//baz:lib"}}));
+        assert_eq!(res["result"], Value::Null);
+
+        let code = |request: Value| call(request)["error"]["code"].as_i64().unwrap();
+        assert_eq!(code(json!({"id": 5, "method": "build"})), METHOD_NOT_FOUND);
+        assert_eq!(
+            code(json!({"id": 6, "method": "rdeps", "params": {"targets": []}})),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            code(json!({"id": 7, "method": "rdeps", "params": {"target": "root//baz:nope"}})),
+            QUERY_FAILED
+        );
+        assert_eq!(server.handle("{").error.unwrap().code, PARSE_ERROR);
+    }
+}
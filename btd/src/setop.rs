@@ -0,0 +1,260 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Combine the impacted targets of several BTD runs, e.g. one per cell, as sets.
+//!
+//! Each output may be in any of the formats BTD writes, as for `btd diff-outputs`.
+//! A target in more than one output keeps every field of its records, taking the
+//! first output's value where they differ, except for `depth`, which is the smallest.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use clap::ValueEnum;
+use serde_json::Map;
+use serde_json::Value;
+use td_util::json;
+
+use crate::buck::types::TargetLabel;
+
+/// Combine the impacted targets of BTD runs, printing each target in the result as a
+/// line of JSON, with everything the outputs said about it.
+#[derive(clap::Args, Debug)]
+pub struct SetopArgs {
+    /// How to combine the outputs.
+    #[arg(value_enum)]
+    op: SetOp,
+
+    /// Outputs of BTD runs, in any format it writes.
+    #[arg(value_name = "FILE", num_args = 2.., required = true)]
+    files: Vec<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// The targets in any output.
+    Union,
+    /// The targets in every output.
+    Intersect,
+    /// The targets in the first output, but none of the others.
+    Subtract,
+}
+
+/// The impacted targets of a BTD output, each with its record, and whether the output
+/// was marked `truncated` or `partial`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImpactSet {
+    pub targets: BTreeMap<TargetLabel, Map<String, Value>>,
+    pub truncated: bool,
+    pub partial: bool,
+}
+
+/// Add the fields of `from` missing from `to`, keeping the smallest `depth`.
+fn merge(to: &mut Map<String, Value>, from: Map<String, Value>) {
+    for (k, v) in from {
+        match to.get_mut(&k) {
+            None => {
+                to.insert(k, v);
+            }
+            Some(x) if x.is_null() => *x = v,
+            Some(x) if k == "depth" => {
+                if let (Some(a), Some(b)) = (x.as_u64(), v.as_u64()) {
+                    *x = a.min(b).into();
+                }
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+impl ImpactSet {
+    fn add(&mut self, record: Map<String, Value>) {
+        if let Some(target) = record.get("target").and_then(Value::as_str) {
+            let target = TargetLabel::new(target);
+            match self.targets.get_mut(&target) {
+                None => {
+                    self.targets.insert(target, record);
+                }
+                Some(x) => merge(x, record),
+            }
+        }
+    }
+
+    fn add_value(&mut self, x: Value) {
+        match x {
+            Value::Array(xs) => xs.into_iter().for_each(|x| self.add_value(x)),
+            Value::String(x) => self.add(Map::from_iter([("target".to_owned(), x.into())])),
+            Value::Object(mut x) => {
+                self.truncated |= x.get("truncated") == Some(&Value::Bool(true));
+                self.partial |= x.get("partial") == Some(&Value::Bool(true));
+                // With `--split-immediate`, the targets are split by whether they changed
+                let lists = ["targets", "immediate_changes", "transitive_changes"]
+                    .into_iter()
+                    .filter_map(|k| x.remove(k))
+                    .collect::<Vec<_>>();
+                if lists.is_empty() {
+                    self.add(x);
+                } else {
+                    lists.into_iter().for_each(|xs| self.add_value(xs));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Parse a BTD output. Text output only names the targets under the heading of
+    /// their level, so their records only have a `target` and `depth`.
+    pub fn parse(src: &str) -> Self {
+        let mut res = Self::default();
+        let values = serde_json::Deserializer::from_str(src)
+            .into_iter::<Value>()
+            .collect::<Result<Vec<_>, _>>();
+        match values {
+            Ok(values) => values.into_iter().for_each(|x| res.add_value(x)),
+            // The text format, one target per line, perhaps under a heading for each level,
+            // followed by the targets which weren't impacted, e.g. `Removed`
+            Err(_) => {
+                let mut impacted = true;
+                let mut depth = None;
+                for x in src.lines().map(str::trim) {
+                    if let Some(level) = x.strip_prefix("Level ") {
+                        depth = level.parse::<u64>().ok();
+                        continue;
+                    }
+                    match x {
+                        "Truncated" => res.truncated = true,
+                        "Partial" => res.partial = true,
+                        "Quarantined" | "Removed" => impacted = false,
+                        _ if impacted && x.contains("//") => {
+                            let mut record = Map::from_iter([("target".to_owned(), x.into())]);
+                            if let Some(depth) = depth {
+                                record.insert("depth".to_owned(), depth.into());
+                            }
+                            res.add(record);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Combine `sets` with `op`. The result is incomplete if any of them was.
+    pub fn combine(op: SetOp, sets: Vec<ImpactSet>) -> Self {
+        let mut sets = sets.into_iter();
+        let mut res = sets.next().unwrap_or_default();
+        for set in sets {
+            res.truncated |= set.truncated;
+            res.partial |= set.partial;
+            match op {
+                SetOp::Union => {
+                    for (_, record) in set.targets {
+                        res.add(record);
+                    }
+                }
+                SetOp::Intersect => {
+                    let mut other = set.targets;
+                    res.targets
+                        .retain(|target, record| match other.remove(target) {
+                            Some(x) => {
+                                merge(record, x);
+                                true
+                            }
+                            None => false,
+                        });
+                }
+                SetOp::Subtract => res.targets.retain(|x, _| !set.targets.contains_key(x)),
+            }
+        }
+        res
+    }
+
+    /// The records, by depth then target, with the records of targets without a depth
+    /// last, followed by any `truncated` or `partial` markers, as BTD writes them.
+    pub fn into_values(self) -> Vec<Value> {
+        let mut res = self.targets.into_iter().collect::<Vec<_>>();
+        res.sort_by_key(|(target, x)| {
+            let depth = x.get("depth").and_then(Value::as_u64).unwrap_or(u64::MAX);
+            (depth, target.key())
+        });
+        let mut res = res
+            .into_iter()
+            .map(|(_, x)| Value::Object(x))
+            .collect::<Vec<_>>();
+        if self.truncated {
+            res.push(serde_json::json!({"truncated": true}));
+        }
+        if self.partial {
+            res.push(serde_json::json!({"partial": true}));
+        }
+        res
+    }
+}
+
+pub fn main(args: SetopArgs) -> anyhow::Result<()> {
+    let mut sets = Vec::with_capacity(args.files.len());
+    for file in &args.files {
+        let src = fs::read_to_string(file)
+            .with_context(|| format!("When reading `{}`", file.display()))?;
+        sets.push(ImpactSet::parse(&src));
+    }
+    let res = ImpactSet::combine(args.op, sets);
+    json::write_json_lines(BufWriter::new(stdout().lock()), res.into_values())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setop() {
+        let a = ImpactSet::parse(
+            "{\"target\":\"foo//bar:a\",\"depth\":0,\"type\":\"rust_library\"}\n\
+            {\"target\":\"foo//bar:b\",\"depth\":2,\"oncall\":null}\n\
+            {\"target\":\"foo//bar:c\",\"depth\":1}\n",
+        );
+        let b = ImpactSet::parse(
+            "{\"version\":2,\"targets\":[{\"target\":\"foo//bar:b\",\"depth\":1,\
+            \"oncall\":\"my_team\",\"owners\":[\"@me\"]}],\"truncated\":true}",
+        );
+        let c = ImpactSet::parse(
+            "Level 0\n  foo//bar:a\nLevel 1\n  foo//bar:d\nRemoved\n  foo//bar:e\n",
+        );
+        let combine = |op, sets: &[&ImpactSet]| {
+            ImpactSet::combine(op, sets.iter().map(|x| (*x).clone()).collect()).into_values()
+        };
+        let a0 = serde_json::json!({"target": "foo//bar:a", "depth": 0, "type": "rust_library"});
+        let c1 = serde_json::json!({"target": "foo//bar:c", "depth": 1});
+        let truncated = serde_json::json!({"truncated": true});
+
+        assert_eq!(
+            combine(SetOp::Union, &[&a, &b, &c]),
+            vec![
+                a0.clone(),
+                serde_json::json!({
+                    "target": "foo//bar:b",
+                    "depth": 1,
+                    "oncall": "my_team",
+                    "owners": ["@me"],
+                }),
+                c1.clone(),
+                serde_json::json!({"target": "foo//bar:d", "depth": 1}),
+                truncated.clone(),
+            ]
+        );
+        assert_eq!(combine(SetOp::Intersect, &[&a, &c]), vec![a0]);
+        assert_eq!(combine(SetOp::Subtract, &[&a, &b, &c]), vec![c1, truncated]);
+    }
+}
@@ -0,0 +1,809 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! A compact binary encoding of [`Targets`], which is much faster to load than
+//! re-parsing the `buck2 targets` JSON.
+//!
+//! The file is the magic bytes, a format version, a header, a table of every distinct
+//! string, then the entries, which refer to strings by their index in the table.
+//! The header is JSON recording where the targets came from, e.g. their revision, so
+//! results are traceable to their inputs and a stale snapshot can be caught before use.
+//! It is followed by a hash of the rest of the file, to catch a corrupt one.
+//! All integers are LEB128 varints.
+
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hasher;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context as _;
+use clap::ArgGroup;
+use serde::Deserialize;
+use serde::Serialize;
+use td_util::json;
+use td_util::string::InternString;
+use thiserror::Error;
+
+use crate::buck::labels::Labels;
+use crate::buck::targets::Attributes;
+use crate::buck::targets::BuckError;
+use crate::buck::targets::BuckImport;
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::ReadOptions;
+use crate::buck::targets::Targets;
+use crate::buck::targets::TargetsEntry;
+use crate::buck::types::CellPath;
+use crate::buck::types::Glob;
+use crate::buck::types::Oncall;
+use crate::buck::types::Package;
+use crate::buck::types::PackageValues;
+use crate::buck::types::RuleType;
+use crate::buck::types::TargetHash;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetName;
+use crate::buck::types::TargetPattern;
+use crate::changes;
+
+const MAGIC: &[u8; 8] = b"BTDSNAP\0";
+/// How many bytes to peek at to tell whether a file is a snapshot.
+pub const MAGIC_LEN: usize = MAGIC.len();
+const VERSION: u64 = 7;
+
+const TAG_TARGET: u8 = 0;
+const TAG_IMPORT: u8 = 1;
+const TAG_ERROR: u8 = 2;
+
+#[derive(Error, Debug)]
+enum SnapshotError {
+    #[error("Not a BTD snapshot (bad magic bytes)")]
+    BadMagic,
+    #[error("Snapshot has version {0}, but only version {VERSION} is supported")]
+    UnsupportedVersion(u64),
+    #[error("Snapshot is truncated")]
+    Truncated,
+    #[error("Snapshot contains an integer that is too large")]
+    VarintOverflow,
+    #[error("Snapshot refers to string {0}, but only has {1}")]
+    UnknownString(u64, usize),
+    #[error("Snapshot contains an entry with unknown tag {0}")]
+    UnknownTag(u8),
+    #[error(
+        "Snapshot is of revision `{recorded}`, but the base revision is `{expected}`, so is probably stale. Regenerate it, or pass `--skip-verification`"
+    )]
+    RevisionMismatch { recorded: String, expected: String },
+    #[error(
+        "Snapshot doesn't record its revision, so can't be checked against the base revision `{0}`. Write it with `btd snapshot --revision`, or pass `--skip-verification`"
+    )]
+    NoRevision(String),
+    #[error(
+        "Snapshot records revision `{0}`, which isn't a full commit hash, so can't be checked against the base revision. Write it with `btd snapshot --revision`, or pass `--skip-verification`"
+    )]
+    ShortRevision(String),
+    #[error("Snapshot content hash is {actual:016x}, but its header records {recorded:016x}")]
+    HashMismatch { recorded: u64, actual: u64 },
+}
+
+/// Convert between `buck2 targets` output and the binary snapshot format.
+/// Anywhere BTD reads targets (e.g. `--base`) it also accepts a snapshot.
+#[derive(clap::Args, Debug)]
+#[command(group(ArgGroup::new("mode").required(true).args(["write", "read"])))]
+pub struct SnapshotArgs {
+    /// Targets file to snapshot, either the JSON output of `buck2 targets` or a snapshot.
+    #[arg(long, value_name = "FILE", requires = "write")]
+    targets: Option<PathBuf>,
+
+    /// Write a snapshot of `--targets` to this file.
+    #[arg(long, value_name = "FILE", requires = "targets")]
+    write: Option<PathBuf>,
+
+    /// With `--write`, record that the targets are of revision `REV`, so BTD can check
+    /// the snapshot matches the base revision it is given. Unless `REV` is a full commit
+    /// hash, source control is asked for it, e.g. for `.` or a short hash.
+    #[arg(long, value_name = "REV", requires = "write")]
+    revision: Option<String>,
+
+    /// With `--write`, record the patterns `buck2 targets` was run on. May be repeated.
+    #[arg(long, value_name = "TARGET_PATTERN", requires = "write")]
+    universe: Vec<String>,
+
+    /// With `--write`, record the version of Buck which produced the targets, e.g. the
+    /// output of `buck2 --version`.
+    #[arg(long, value_name = "VERSION", requires = "write")]
+    buck_version: Option<String>,
+
+    /// Read a snapshot, printing it out as JSON lines.
+    #[arg(long, value_name = "FILE")]
+    read: Option<PathBuf>,
+}
+
+pub fn main(args: SnapshotArgs) -> anyhow::Result<()> {
+    if let (Some(targets), Some(write)) = (&args.targets, &args.write) {
+        let header = Header {
+            revision: args.revision.as_deref().map(full_revision).transpose()?,
+            created: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+            universe: args.universe.clone(),
+            buck_version: args.buck_version.clone(),
+        };
+        // Keep everything, for whichever run reads the snapshot
+        let options = ReadOptions {
+            attributes: true,
+            ..ReadOptions::default()
+        };
+        let (targets, _) = Targets::from_file_with(targets, options)?;
+        write_file_with(&targets, &header, write)
+    } else if let Some(read) = &args.read {
+        let targets = read_file(read)?;
+        json::write_json_lines(BufWriter::new(stdout().lock()), targets.entries())
+    } else {
+        unreachable!("clap requires `--write` or `--read`")
+    }
+}
+
+/// Does `data` start with the snapshot magic bytes.
+pub fn is_snapshot_data(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn write_file(targets: &Targets, file: &Path) -> anyhow::Result<()> {
+    write_file_with(targets, &Header::default(), file)
+}
+
+pub fn write_file_with(targets: &Targets, header: &Header, file: &Path) -> anyhow::Result<()> {
+    fs::write(file, encode(targets, header))
+        .with_context(|| format!("When writing snapshot `{}`", file.display()))
+}
+
+pub fn read_file(file: &Path) -> anyhow::Result<Targets> {
+    let data = fs::read(file).with_context(|| format!("When reading `{}`", file.display()))?;
+    decode(&data).with_context(|| format!("When reading snapshot `{}`", file.display()))
+}
+
+/// Read a snapshot from `reader`, e.g. one whose start was peeked at to tell it is one.
+/// Given a `revision`, first checks the snapshot was written for it, see [`verify`].
+/// The bytes checked are those decoded, so the file can't change in between.
+pub fn read_from(
+    mut reader: impl Read,
+    revision: Option<&str>,
+) -> anyhow::Result<(Targets, Header)> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    if let Some(revision) = revision {
+        verify(&data, &full_revision(revision)?).context("When verifying snapshot")?;
+    }
+    decode_with_header(&data)
+}
+
+/// Whether `x` is a full Sapling or git commit hash, rather than a prefix or a name.
+fn is_full_hash(x: &str) -> bool {
+    matches!(x.len(), 40 | 64) && x.bytes().all(|x| x.is_ascii_hexdigit())
+}
+
+/// The full hash of `revision`, asking source control unless it already is one, e.g.
+/// for `.` or a short hash.
+fn full_revision(revision: &str) -> anyhow::Result<String> {
+    if is_full_hash(revision) {
+        Ok(revision.to_owned())
+    } else {
+        changes::resolve_revision(revision)
+    }
+}
+
+/// Check the snapshot `data` was written for `revision`, a full commit hash, and that its
+/// contents match the hash in its header.
+fn verify(data: &[u8], revision: &str) -> anyhow::Result<()> {
+    let (header, content) = decode_header(data)?;
+    match &header.header.revision {
+        None => return Err(SnapshotError::NoRevision(revision.to_owned()).into()),
+        Some(recorded) if !is_full_hash(recorded) => {
+            return Err(SnapshotError::ShortRevision(recorded.clone()).into());
+        }
+        Some(recorded) if recorded != revision => {
+            return Err(SnapshotError::RevisionMismatch {
+                recorded: recorded.clone(),
+                expected: revision.to_owned(),
+            }
+            .into());
+        }
+        Some(_) => {}
+    }
+    let actual = content_hash(content);
+    if actual != header.content_hash {
+        return Err(SnapshotError::HashMismatch {
+            recorded: header.content_hash,
+            actual,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// What a snapshot records about where its targets came from, written when it is created
+/// and reported in the `metadata` of the version 2 output.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    /// The revision the targets are of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// When the snapshot was written, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// The patterns `buck2 targets` was run on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub universe: Vec<String>,
+    /// The version of Buck which produced the targets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buck_version: Option<String>,
+}
+
+/// The header as read from a file, with the hash of the contents which follow it.
+struct RecordedHeader {
+    header: Header,
+    content_hash: u64,
+}
+
+fn content_hash(data: &[u8]) -> u64 {
+    let mut hasher = Fnv::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust versions.
+pub(crate) struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for x in bytes {
+            self.0 = (self.0 ^ u64::from(*x)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+#[derive(Default)]
+struct Encoder {
+    strings: HashMap<String, u64>,
+    body: Vec<u8>,
+}
+
+impl Encoder {
+    fn varint(&mut self, x: usize) {
+        write_varint(&mut self.body, x as u64)
+    }
+
+    fn string(&mut self, x: &str) {
+        let index = match self.strings.get(x) {
+            Some(index) => *index,
+            None => {
+                let index = self.strings.len() as u64;
+                self.strings.insert(x.to_owned(), index);
+                index
+            }
+        };
+        write_varint(&mut self.body, index)
+    }
+
+    fn strings<'a>(&mut self, xs: impl ExactSizeIterator<Item = &'a str>) {
+        self.varint(xs.len());
+        for x in xs {
+            self.string(x)
+        }
+    }
+
+    fn option(&mut self, x: Option<&str>) {
+        match x {
+            None => self.body.push(0),
+            Some(x) => {
+                self.body.push(1);
+                self.string(x)
+            }
+        }
+    }
+
+    fn labels(&mut self, x: &Labels) {
+        self.strings(x.iter().map(|x| x.as_str()))
+    }
+
+    fn target(&mut self, x: &BuckTarget) {
+        self.string(x.name.as_str());
+        self.string(x.package.as_str());
+        self.labels(&x.package_values.labels);
+        self.string(&x.package_values.cfg_modifiers.to_string());
+        self.string(x.rule_type.as_str());
+        self.option(x.oncall.as_ref().map(|x| x.as_str()));
+        self.strings(x.deps.iter().map(|x| x.as_str()));
+        self.strings(x.inputs.iter().map(|x| x.as_str()));
+        self.string(x.hash.as_str());
+        self.labels(&x.labels);
+        self.strings(x.ci_srcs.iter().map(|x| x.as_str()));
+        self.strings(x.ci_deps.iter().map(|x| x.as_str()));
+        self.strings(x.tests.iter().map(|x| x.as_str()));
+        self.strings(x.toolchain_deps.iter().map(|x| x.as_str()));
+        self.strings(x.exec_deps.iter().map(|x| x.as_str()));
+        self.strings(x.visibility.iter().map(|x| x.as_str()));
+        self.varint(x.attributes.len());
+        for (name, value) in x.attributes.iter() {
+            self.string(name.as_str());
+            self.string(&value.to_string());
+        }
+    }
+
+    fn entry(&mut self, x: &TargetsEntry) {
+        match x {
+            TargetsEntry::Target(x) => {
+                self.body.push(TAG_TARGET);
+                self.target(x);
+            }
+            TargetsEntry::Import(x) => {
+                self.body.push(TAG_IMPORT);
+                self.string(x.file.as_str());
+                self.strings(x.imports.iter().map(|x| x.as_str()));
+                self.option(x.package.as_ref().map(|x| x.as_str()));
+            }
+            TargetsEntry::Error(x) => {
+                self.body.push(TAG_ERROR);
+                self.string(x.package.as_str());
+                self.string(&x.error);
+            }
+        }
+    }
+}
+
+fn encode(targets: &Targets, header: &Header) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    let entries = targets.entries().collect::<Vec<_>>();
+    encoder.varint(entries.len());
+    for x in entries {
+        encoder.entry(x);
+    }
+
+    let mut table = vec![""; encoder.strings.len()];
+    for (x, index) in &encoder.strings {
+        table[*index as usize] = x;
+    }
+    let mut content = Vec::with_capacity(encoder.body.len() + encoder.strings.len() * 32);
+    write_varint(&mut content, table.len() as u64);
+    for x in table {
+        write_varint(&mut content, x.len() as u64);
+        content.extend_from_slice(x.as_bytes());
+    }
+    content.extend_from_slice(&encoder.body);
+
+    let mut res = Vec::with_capacity(content.len() + 64);
+    res.extend_from_slice(MAGIC);
+    write_varint(&mut res, VERSION);
+    let header = serde_json::to_string(header).unwrap();
+    write_varint(&mut res, header.len() as u64);
+    res.extend_from_slice(header.as_bytes());
+    res.extend_from_slice(&content_hash(&content).to_le_bytes());
+    res.extend_from_slice(&content);
+    res
+}
+
+/// Read a varint a `byte` at a time.
+fn read_varint(mut byte: impl FnMut() -> anyhow::Result<u8>) -> anyhow::Result<u64> {
+    let mut res = 0;
+    let mut shift = 0;
+    loop {
+        let x = byte()?;
+        res |= u64::from(x & 0x7f) << shift;
+        if x & 0x80 == 0 {
+            return Ok(res);
+        }
+        shift += 7;
+        if shift >= u64::BITS {
+            return Err(SnapshotError::VarintOverflow.into());
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    strings: Vec<&'a str>,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        let (x, rest) = self.data.split_first().ok_or(SnapshotError::Truncated)?;
+        self.data = rest;
+        Ok(*x)
+    }
+
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        read_varint(|| self.byte())
+    }
+
+    fn len(&mut self) -> anyhow::Result<usize> {
+        let len = self.varint()? as usize;
+        // Every item takes at least one byte, so this guards against allocating huge vectors
+        // from a corrupt length.
+        if len > self.data.len() {
+            return Err(SnapshotError::Truncated.into());
+        }
+        Ok(len)
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(SnapshotError::Truncated.into());
+        }
+        let (x, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(x)
+    }
+
+    fn string(&mut self) -> anyhow::Result<&'a str> {
+        let index = self.varint()?;
+        match self.strings.get(index as usize) {
+            Some(x) => Ok(*x),
+            None => Err(SnapshotError::UnknownString(index, self.strings.len()).into()),
+        }
+    }
+
+    fn list<T>(&mut self, f: impl Fn(&str) -> T) -> anyhow::Result<Box<[T]>> {
+        let len = self.len()?;
+        let mut res = Vec::with_capacity(len);
+        for _ in 0..len {
+            res.push(f(self.string()?));
+        }
+        Ok(res.into_boxed_slice())
+    }
+
+    fn inline_string(&mut self) -> anyhow::Result<&'a str> {
+        let len = self.varint()? as usize;
+        Ok(std::str::from_utf8(self.bytes(len)?)?)
+    }
+
+    fn option<T>(&mut self, f: impl Fn(&str) -> T) -> anyhow::Result<Option<T>> {
+        match self.byte()? {
+            0 => Ok(None),
+            _ => Ok(Some(f(self.string()?))),
+        }
+    }
+
+    fn labels(&mut self) -> anyhow::Result<Labels> {
+        let len = self.len()?;
+        let mut res = Vec::with_capacity(len);
+        for _ in 0..len {
+            res.push(self.string()?);
+        }
+        Ok(Labels::new(&res))
+    }
+
+    fn target(&mut self) -> anyhow::Result<BuckTarget> {
+        Ok(BuckTarget {
+            name: TargetName::new(self.string()?),
+            package: Package::new(self.string()?),
+            package_values: PackageValues {
+                labels: self.labels()?,
+                cfg_modifiers: serde_json::from_str(self.string()?)?,
+            },
+            rule_type: RuleType::new(self.string()?),
+            oncall: self.option(Oncall::new)?,
+            deps: self.list(TargetLabel::new)?.into(),
+            inputs: self.list(CellPath::new)?,
+            hash: TargetHash::new(self.string()?),
+            labels: self.labels()?,
+            ci_srcs: self.list(Glob::new)?,
+            ci_deps: self.list(TargetPattern::new)?,
+            tests: self.list(TargetLabel::new)?,
+            toolchain_deps: self.list(TargetLabel::new)?,
+            exec_deps: self.list(TargetLabel::new)?,
+            visibility: self.list(TargetPattern::new)?,
+            attributes: self.attributes()?,
+        })
+    }
+
+    fn attributes(&mut self) -> anyhow::Result<Attributes> {
+        let len = self.len()?;
+        let mut res = Vec::with_capacity(len);
+        for _ in 0..len {
+            res.push((
+                InternString::new(self.string()?),
+                serde_json::from_str(self.string()?)?,
+            ));
+        }
+        Ok(Attributes::new(res))
+    }
+
+    fn entry(&mut self) -> anyhow::Result<TargetsEntry> {
+        Ok(match self.byte()? {
+            TAG_TARGET => TargetsEntry::Target(self.target()?),
+            TAG_IMPORT => TargetsEntry::Import(BuckImport {
+                file: CellPath::new(self.string()?),
+                imports: self.list(CellPath::new)?,
+                package: self.option(Package::new)?,
+            }),
+            TAG_ERROR => TargetsEntry::Error(BuckError {
+                package: Package::new(self.string()?),
+                error: self.string()?.to_owned(),
+            }),
+            tag => return Err(SnapshotError::UnknownTag(tag).into()),
+        })
+    }
+}
+
+/// The header of a snapshot, and the contents after it.
+fn decode_header(data: &[u8]) -> anyhow::Result<(RecordedHeader, &[u8])> {
+    let data = data
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or(SnapshotError::BadMagic)?;
+    let mut decoder = Decoder {
+        data,
+        strings: Vec::new(),
+    };
+    let version = decoder.varint()?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version).into());
+    }
+    let header = serde_json::from_str(decoder.inline_string()?)?;
+    let hash = decoder.bytes(8)?;
+    let header = RecordedHeader {
+        header,
+        content_hash: u64::from_le_bytes(hash.try_into().unwrap()),
+    };
+    Ok((header, decoder.data))
+}
+
+/// Decode a snapshot already in memory, e.g. where there is no file system to read it
+/// from.
+pub fn decode(data: &[u8]) -> anyhow::Result<Targets> {
+    Ok(decode_with_header(data)?.0)
+}
+
+fn decode_with_header(data: &[u8]) -> anyhow::Result<(Targets, Header)> {
+    let (header, data) = decode_header(data)?;
+    let mut decoder = Decoder {
+        data,
+        strings: Vec::new(),
+    };
+    let strings = decoder.len()?;
+    decoder.strings.reserve(strings);
+    for _ in 0..strings {
+        let x = decoder.inline_string()?;
+        decoder.strings.push(x);
+    }
+    let entries = decoder.len()?;
+    let mut res = Vec::with_capacity(entries);
+    for _ in 0..entries {
+        res.push(decoder.entry()?);
+    }
+    Ok((Targets::new(res), header.header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::arena::Deps;
+
+    fn sample() -> Targets {
+        Targets::new(vec![
+            TargetsEntry::Import(BuckImport {
+                file: CellPath::new("fbcode//pkg/TARGETS"),
+                imports: Box::new([CellPath::new("prelude//prelude.bzl")]),
+                package: Some(Package::new("fbcode//pkg")),
+            }),
+            TargetsEntry::Import(BuckImport {
+                file: CellPath::new("fbcode//infra/defs.bzl"),
+                imports: Box::new([]),
+                package: None,
+            }),
+            TargetsEntry::Target(BuckTarget {
+                deps: Deps::from([
+                    TargetLabel::new("toolchains//:python"),
+                    TargetLabel::new("fbcode//python:library"),
+                ]),
+                inputs: Box::new([CellPath::new("fbcode//pkg/file.py")]),
+                hash: TargetHash::new("43ce1a7a56f10225413a2991febb853a"),
+                package_values: PackageValues::new(
+                    &["ci:@fbcode//mode/opt"],
+                    serde_json::json!({"modifier": ["a"]}),
+                ),
+                labels: Labels::new(&["my_label", "another_label"]),
+                oncall: Some(Oncall::new("my_team")),
+                ci_srcs: Box::new([Glob::new("fbcode/pkg/**"), Glob::new("!**/*.md")]),
+                ci_deps: Box::new([TargetPattern::new("fbcode//other/...")]),
+                tests: Box::new([TargetLabel::new("fbcode//pkg:test_test")]),
+                toolchain_deps: Box::new([TargetLabel::new("toolchains//:cxx")]),
+                exec_deps: Box::new([TargetLabel::new("fbcode//tools:codegen")]),
+                visibility: Box::new([TargetPattern::new("fbcode//pkg/...")]),
+                attributes: Attributes::new(vec![(
+                    InternString::new("metadata"),
+                    serde_json::json!({"owner": ["me"]}),
+                )]),
+                ..BuckTarget::testing("test", "fbcode//pkg", "prelude//rules.bzl:python_library")
+            }),
+            TargetsEntry::Target(BuckTarget::testing(
+                "test2",
+                "fbcode//pkg",
+                "prelude//rules.bzl:cxx_library",
+            )),
+            TargetsEntry::Error(BuckError {
+                package: Package::new("fbcode//broken"),
+                error: "Broken :(".to_owned(),
+            }),
+        ])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let targets = sample();
+        let res = decode(&encode(&targets, &Header::default())).unwrap();
+        assert_eq!(
+            res.entries().collect::<Vec<_>>(),
+            targets.entries().collect::<Vec<_>>()
+        );
+        let empty = decode(&encode(&Targets::new(Vec::new()), &Header::default())).unwrap();
+        assert_eq!(empty.entries().count(), 0);
+    }
+
+    #[test]
+    fn test_round_trip_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_file(&sample(), file.path()).unwrap();
+        let res = Targets::from_file(file.path()).unwrap();
+        assert_eq!(res.entries().count(), sample().entries().count());
+
+        let data = fs::read(file.path()).unwrap();
+        assert!(is_snapshot_data(&data));
+        let res = Targets::from_bytes(&data).unwrap();
+        assert_eq!(res.entries().count(), sample().entries().count());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_fifo() {
+        // A pipe can only be read once, so its start must not be lost telling its format
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("targets");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap()
+            .success());
+        let mut json = Vec::new();
+        json::write_json_lines(&mut json, sample().entries()).unwrap();
+        for data in [encode(&sample(), &Header::default()), json] {
+            let writer = {
+                let fifo = fifo.clone();
+                std::thread::spawn(move || fs::write(fifo, data).unwrap())
+            };
+            let res = Targets::from_file(&fifo).unwrap();
+            writer.join().unwrap();
+            assert_eq!(res.entries().count(), sample().entries().count());
+        }
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let data = encode(&sample(), &Header::default());
+        assert!(decode(b"not a snapshot").is_err());
+        for i in [0, MAGIC.len(), data.len() / 2, data.len() - 1] {
+            assert!(decode(&data[..i]).is_err());
+        }
+        let mut version = data.clone();
+        version[MAGIC.len()] = 99;
+        assert!(decode(&version).is_err());
+    }
+
+    #[test]
+    fn test_header() {
+        let header = Header {
+            revision: Some("0123456789abcdef".to_owned()),
+            created: Some(1700000000),
+            universe: vec!["fbcode//...".to_owned()],
+            buck_version: Some("buck2 2023-11-10".to_owned()),
+        };
+        let data = encode(&sample(), &header);
+        assert_eq!(decode_header(&data).unwrap().0.header, header);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut json = Vec::new();
+        json::write_json_lines(&mut json, sample().entries()).unwrap();
+        fs::write(file.path(), json).unwrap();
+        let options = ReadOptions::default();
+        let (_, read) = Targets::from_file_with(file.path(), options).unwrap();
+        assert_eq!(read, None);
+        write_file_with(&sample(), &header, file.path()).unwrap();
+        let (res, read) = Targets::from_file_with(file.path(), options).unwrap();
+        assert_eq!(read, Some(header));
+        assert_eq!(res.entries().count(), 5);
+    }
+
+    #[test]
+    fn test_verify() {
+        let revision = "0123456789abcdef0123456789abcdef01234567";
+        let header = Header {
+            revision: Some(revision.to_owned()),
+            ..Header::default()
+        };
+        let data = encode(&sample(), &header);
+        assert!(verify(&data, revision).is_ok());
+        let err = verify(&data, "fedcba9876543210fedcba9876543210fedcba98").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::RevisionMismatch { .. })
+        ));
+
+        // Flipping a byte of the contents fails the hash check
+        let mut corrupt = data.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let err = verify(&corrupt, revision).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::HashMismatch { .. })
+        ));
+
+        let unrecorded = encode(&sample(), &Header::default());
+        let err = verify(&unrecorded, revision).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::NoRevision(_))
+        ));
+
+        // A short hash could be ambiguous, or match the wrong commit
+        let short = Header {
+            revision: Some(revision[..12].to_owned()),
+            ..Header::default()
+        };
+        let err = verify(&encode(&sample(), &short), revision).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::ShortRevision(_))
+        ));
+
+        // Only the full hash matches, not a prefix of it
+        let err = verify(&data, &revision[..39]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::RevisionMismatch { .. })
+        ));
+
+        let (res, read) = read_from(data.as_slice(), Some(revision)).unwrap();
+        assert_eq!(res.entries().count(), sample().entries().count());
+        assert_eq!(read, header);
+        assert!(read_from(data.as_slice(), Some(&"f".repeat(40))).is_err());
+    }
+
+    #[test]
+    fn test_varint() {
+        for x in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut data = Vec::new();
+            write_varint(&mut data, x);
+            let mut decoder = Decoder {
+                data: &data,
+                strings: Vec::new(),
+            };
+            assert_eq!(decoder.varint().unwrap(), x);
+            assert!(decoder.data.is_empty());
+        }
+    }
+}
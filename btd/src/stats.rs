@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Statistics about the impacted targets and how long each phase took,
+//! so dashboards can track how the impact set grows over time.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use serde::Serialize;
+use td_util::intern::Interned;
+use td_util::intern::Oncalls;
+use td_util::intern::Packages;
+use td_util::intern::RuleTypes;
+use td_util::string::InternLabel;
+use td_util::string::InternStats;
+use td_util::string::InternString;
+
+use crate::buck::arena::ArenaStats;
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::diff::ImpactReason;
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// Number of targets in the graph before the change.
+    pub base_targets: usize,
+    /// Number of targets in the graph after the change.
+    pub diff_targets: usize,
+    /// How the deps of the graph before the change are stored.
+    pub base_deps: ArenaStats,
+    /// How the deps of the graph after the change are stored.
+    pub diff_deps: ArenaStats,
+    /// Number of targets impacted, at any depth.
+    pub total_changes: u64,
+    /// Impacted targets by short rule type, e.g. `cxx_library`.
+    pub by_rule_type: BTreeMap<String, u64>,
+    /// Impacted targets by cell.
+    pub by_cell: BTreeMap<String, u64>,
+    /// Impacted targets at each depth, starting at 0.
+    pub by_depth: Vec<u64>,
+    /// How long each phase took, in the order they ran.
+    pub phases: Vec<Phase>,
+    /// The strings interned while reading, shared by both graphs.
+    pub strings: InternStats,
+    /// The labels interned while reading, whose names are in `strings`.
+    pub labels: InternStats,
+    /// The packages interned while reading, each kept apart from other strings.
+    pub packages: InternStats,
+    pub rule_types: InternStats,
+    pub oncalls: InternStats,
+    /// The strings interned most often, with `--stats-top-strings`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_strings: Vec<StringUses>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Phase {
+    pub name: String,
+    pub seconds: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StringUses {
+    pub string: String,
+    pub uses: usize,
+}
+
+impl Stats {
+    pub fn new(base: &Targets, diff: &Targets) -> Self {
+        Self {
+            base_targets: base.targets().count(),
+            diff_targets: diff.targets().count(),
+            base_deps: ArenaStats::new(base.targets()),
+            diff_deps: ArenaStats::new(diff.targets()),
+            ..Self::default()
+        }
+    }
+
+    /// Count the impacted targets at `depth`. Levels must be added in order of depth.
+    pub fn add_level(&mut self, depth: usize, level: &[(&BuckTarget, ImpactReason)]) {
+        if level.is_empty() {
+            // The traversal ends with an empty level, which doesn't make a new depth
+            return;
+        }
+        if self.by_depth.len() <= depth {
+            self.by_depth.resize(depth + 1, 0);
+        }
+        self.by_depth[depth] += level.len() as u64;
+        self.total_changes += level.len() as u64;
+        for (x, _) in level {
+            *self
+                .by_rule_type
+                .entry(x.rule_type.short().to_owned())
+                .or_default() += 1;
+            *self
+                .by_cell
+                .entry(x.package.cell().as_str().to_owned())
+                .or_default() += 1;
+        }
+    }
+
+    /// Record the phases from the time each started, as offsets from the start of the run,
+    /// with the last phase running until `end`.
+    pub fn set_phases(&mut self, starts: &[(String, Duration)], end: Duration) {
+        self.phases = starts
+            .iter()
+            .enumerate()
+            .map(|(i, (name, start))| {
+                let finish = starts.get(i + 1).map_or(end, |x| x.1);
+                Phase {
+                    name: name.clone(),
+                    seconds: finish.saturating_sub(*start).as_secs_f64(),
+                }
+            })
+            .collect();
+    }
+
+    /// Record how much has been interned, along with the `top` most interned strings
+    /// if they were tracked.
+    pub fn set_interned(&mut self, top: usize) {
+        self.strings = InternString::stats();
+        self.labels = InternLabel::stats();
+        self.packages = Interned::<Packages>::stats();
+        self.rule_types = Interned::<RuleTypes>::stats();
+        self.oncalls = Interned::<Oncalls>::stats();
+        self.top_strings = InternString::most_used(top)
+            .into_iter()
+            .map(|(x, uses)| StringUses {
+                string: x.as_str().to_owned(),
+                uses,
+            })
+            .collect();
+    }
+
+    pub fn write_file(&self, file: &Path) -> anyhow::Result<()> {
+        let out = BufWriter::new(File::create(file)?);
+        serde_json::to_writer_pretty(out, self)
+            .with_context(|| format!("When writing stats to `{}`", file.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+    use crate::diff::RootImpactKind;
+
+    #[test]
+    fn test_stats() {
+        let lib = BuckTarget::testing("lib", "foo//bar", "prelude//rules.bzl:cxx_library");
+        let bin = BuckTarget::testing("bin", "foo//bar", "prelude//rules.bzl:cxx_binary");
+        let test = BuckTarget::testing("test", "baz//qux", "prelude//rules.bzl:cxx_test");
+        let targets = Targets::new(vec![
+            TargetsEntry::Target(lib.clone()),
+            TargetsEntry::Target(BuckTarget {
+                deps: [lib.label()].into(),
+                ..bin.clone()
+            }),
+        ]);
+        let reason = |x: &BuckTarget| ImpactReason::new(x, RootImpactKind::Inputs);
+
+        let mut stats = Stats::new(&targets, &Targets::new(Vec::new()));
+        stats.add_level(0, &[(&lib, reason(&lib))]);
+        stats.add_level(1, &[(&bin, reason(&lib)), (&test, reason(&lib))]);
+        stats.add_level(2, &[]);
+        stats.set_phases(
+            &[
+                ("reading".to_owned(), Duration::from_secs(0)),
+                ("diffing".to_owned(), Duration::from_secs(2)),
+            ],
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(stats.base_targets, 2);
+        assert_eq!(stats.base_deps.deps, 1);
+        assert_eq!(stats.base_deps.arenas, 1);
+        assert_eq!(stats.diff_deps, ArenaStats::default());
+        assert_eq!(stats.diff_targets, 0);
+        assert_eq!(stats.total_changes, 3);
+        assert_eq!(stats.by_depth, vec![1, 2]);
+        assert_eq!(
+            stats.by_rule_type,
+            BTreeMap::from([
+                ("cxx_binary".to_owned(), 1),
+                ("cxx_library".to_owned(), 1),
+                ("cxx_test".to_owned(), 1),
+            ])
+        );
+        assert_eq!(
+            stats.by_cell,
+            BTreeMap::from([("baz".to_owned(), 1), ("foo".to_owned(), 2)])
+        );
+        assert_eq!(
+            stats.phases,
+            vec![
+                Phase {
+                    name: "reading".to_owned(),
+                    seconds: 2.0
+                },
+                Phase {
+                    name: "diffing".to_owned(),
+                    seconds: 3.0
+                },
+            ]
+        );
+    }
+}
@@ -7,49 +7,26 @@
  * of this source tree.
  */
 
-use std::collections::HashMap;
 use std::collections::HashSet;
 
-use crate::buck::targets::BuckTarget;
 use crate::buck::targets::Targets;
-use crate::buck::types::TargetLabel;
-use crate::buck::types::TargetLabelKeyRef;
+use crate::buck::types::TargetLabelKey;
+use crate::propagate::propagate_labels;
 
-// Currently, this function doesn't support progagating 'uses_sudo' label for target patterns.
-// We can possibly live with this version until a use case found.
-pub fn requires_sudo_recursively(targets: &Targets) -> HashSet<TargetLabelKeyRef> {
-    let mut rdeps: HashMap<&TargetLabel, Vec<&BuckTarget>> =
-        HashMap::with_capacity(targets.len_targets_upperbound());
-    let mut todo: Vec<&BuckTarget> = Vec::new();
-    let mut sudos: HashSet<TargetLabelKeyRef> = HashSet::new();
-
-    for target in targets.targets() {
-        for d in target.deps.iter() {
-            rdeps.entry(d).or_default().push(target);
-        }
-        if target.labels.contains("uses_sudo") {
-            todo.push(target);
-            sudos.insert(target.label_key());
-        }
-    }
-
-    while let Some(lbl) = todo.pop() {
-        if let Some(parents) = rdeps.get(&lbl.label()) {
-            for parent in parents {
-                if sudos.insert(parent.label_key()) {
-                    todo.push(*parent)
-                }
-            }
-        }
-    }
-
-    sudos
+/// The targets with the label `uses_sudo`, or which depend on one that does.
+/// A special case of [`propagate_labels`].
+pub fn requires_sudo_recursively(targets: &Targets) -> HashSet<TargetLabelKey> {
+    propagate_labels(targets, &["uses_sudo"])
+        .into_values()
+        .next()
+        .unwrap_or_default()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::buck::labels::Labels;
+    use crate::buck::targets::BuckTarget;
     use crate::buck::targets::TargetsEntry;
     use crate::buck::types::Package;
     use crate::buck::types::TargetName;
@@ -99,7 +76,7 @@ mod tests {
         let targets_by_key = targets.targets_by_label_key();
         let mut res = requires_sudo_recursively(&targets)
             .iter()
-            .map(|x| targets_by_key.get(x).unwrap().name.as_str())
+            .map(|x| targets_by_key.get(&x.to_ref()).unwrap().name.as_str())
             .collect::<Vec<_>>();
         res.sort();
 
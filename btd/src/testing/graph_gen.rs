@@ -0,0 +1,264 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Generate synthetic target graphs, e.g. to benchmark parsing and traversal
+//! (see `btd/benches`). The same [`GraphSpec`] always generates the same graph,
+//! on any platform, so measurements are reproducible.
+//!
+//! Targets only depend on targets generated before them, so the graph is acyclic.
+
+use std::io::stdout;
+use std::io::BufWriter;
+
+use clap::ValueEnum;
+use td_util::json;
+
+use crate::buck::labels::Labels;
+use crate::buck::targets::Attributes;
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::targets::TargetsEntry;
+use crate::buck::types::CellPath;
+use crate::buck::types::Package;
+use crate::buck::types::PackageValues;
+use crate::buck::types::ProjectRelativePath;
+use crate::buck::types::RuleType;
+use crate::buck::types::TargetHash;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetName;
+
+const RULE_TYPES: [&str; 4] = [
+    "prelude//rules.bzl:cxx_library",
+    "prelude//rules.bzl:cxx_binary",
+    "prelude//rules.bzl:cxx_test",
+    "prelude//rules.bzl:python_library",
+];
+
+/// How labels are picked for each target.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelDistribution {
+    /// Every label is equally likely.
+    Uniform,
+    /// Earlier labels are much more common, as in real repos.
+    #[default]
+    Skewed,
+}
+
+const DEFAULT: GraphSpec = GraphSpec {
+    seed: 0,
+    targets: 1000,
+    packages: 100,
+    fan_out: 5,
+    labels: 10,
+    labels_per_target: 2,
+    label_distribution: LabelDistribution::Skewed,
+};
+
+/// The shape of a generated graph. Print one as JSON lines, like `buck2 targets`,
+/// with the hidden `btd generate` subcommand.
+#[derive(clap::Args, Debug, Clone)]
+pub struct GraphSpec {
+    /// Seed for the pseudo-random choices.
+    #[arg(long, default_value_t = DEFAULT.seed)]
+    pub seed: u64,
+
+    /// Number of targets.
+    #[arg(long, default_value_t = DEFAULT.targets)]
+    pub targets: usize,
+
+    /// Number of packages the targets are spread across.
+    #[arg(long, default_value_t = DEFAULT.packages)]
+    pub packages: usize,
+
+    /// Largest number of dependencies of each target.
+    #[arg(long, default_value_t = DEFAULT.fan_out)]
+    pub fan_out: usize,
+
+    /// Number of distinct labels.
+    #[arg(long, default_value_t = DEFAULT.labels)]
+    pub labels: usize,
+
+    /// Largest number of labels on each target.
+    #[arg(long, default_value_t = DEFAULT.labels_per_target)]
+    pub labels_per_target: usize,
+
+    #[arg(long, value_enum, default_value_t = DEFAULT.label_distribution)]
+    pub label_distribution: LabelDistribution,
+}
+
+impl Default for GraphSpec {
+    fn default() -> Self {
+        DEFAULT
+    }
+}
+
+/// SplitMix64, which is tiny and good enough for generating test data.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, or `0` if `n` is `0`.
+    fn below(&mut self, n: usize) -> usize {
+        if n == 0 {
+            0
+        } else {
+            (self.next() % n as u64) as usize
+        }
+    }
+}
+
+/// Packages are nested two deep, so directories contain several packages.
+fn package(index: usize) -> Package {
+    Package::new(&format!("root//gen/d{}/p{index}", index % 10))
+}
+
+fn label(spec: &GraphSpec, index: usize) -> TargetLabel {
+    package(index % spec.packages.max(1)).join(&TargetName::new(&format!("t{index}")))
+}
+
+/// Generate the graph described by `spec`.
+pub fn generate(spec: &GraphSpec) -> Targets {
+    let mut rng = Rng(spec.seed);
+    let label_names = (0..spec.labels)
+        .map(|i| format!("label{i}"))
+        .collect::<Vec<_>>();
+    let mut entries = Vec::with_capacity(spec.targets);
+    for i in 0..spec.targets {
+        let package = package(i % spec.packages.max(1));
+        let mut deps = (0..rng.below(spec.fan_out + 1))
+            .filter(|_| i > 0)
+            .map(|_| label(spec, rng.below(i)))
+            .collect::<Vec<_>>();
+        deps.sort();
+        deps.dedup();
+        let mut labels = (0..rng.below(spec.labels_per_target + 1))
+            .filter(|_| spec.labels > 0)
+            .map(|_| {
+                let index = match spec.label_distribution {
+                    LabelDistribution::Uniform => rng.below(spec.labels),
+                    LabelDistribution::Skewed => {
+                        let limit = rng.below(spec.labels) + 1;
+                        rng.below(limit)
+                    }
+                };
+                label_names[index].as_str()
+            })
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels.dedup();
+        let rule_type = RULE_TYPES[rng.below(RULE_TYPES.len())];
+        entries.push(TargetsEntry::Target(BuckTarget {
+            inputs: Box::new([CellPath::new(&format!("{package}/t{i}.c"))]),
+            name: TargetName::new(&format!("t{i}")),
+            package,
+            package_values: PackageValues::default(),
+            rule_type: RuleType::new(rule_type),
+            oncall: None,
+            deps: deps.into(),
+            hash: TargetHash::new(&format!("{:016x}", rng.next())),
+            labels: Labels::new(&labels),
+            ci_srcs: Box::new([]),
+            ci_deps: Box::new([]),
+            tests: Box::new([]),
+            toolchain_deps: Box::new([]),
+            exec_deps: Box::new([]),
+            visibility: Box::new([]),
+            attributes: Attributes::default(),
+        }));
+    }
+    Targets::new(entries)
+}
+
+/// The sources of `count` targets picked from a graph generated by `spec`, relative to
+/// the root of a repo whose `root` cell is at the top, e.g. for `Changes::new`.
+pub fn changed_files(spec: &GraphSpec, count: usize, seed: u64) -> Vec<ProjectRelativePath> {
+    let mut rng = Rng(seed);
+    (0..count)
+        .filter(|_| spec.targets > 0)
+        .map(|_| {
+            let i = rng.below(spec.targets);
+            let package = package(i % spec.packages.max(1));
+            let path = CellPath::new(&format!("{package}/t{i}.c"));
+            ProjectRelativePath::new(path.path().as_str())
+        })
+        .collect()
+}
+
+pub fn main(spec: GraphSpec) -> anyhow::Result<()> {
+    let targets = generate(&spec);
+    json::write_json_lines(BufWriter::new(stdout().lock()), targets.entries())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::buck::cells::CellInfo;
+    use crate::changes::Changes;
+    use crate::cycles;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_generate() {
+        let spec = GraphSpec {
+            targets: 500,
+            packages: 30,
+            ..GraphSpec::default()
+        };
+        let serialize = |targets: &Targets| {
+            let mut out = Vec::new();
+            json::write_json_lines(&mut out, targets.entries()).unwrap();
+            out
+        };
+        let targets = generate(&spec);
+        let data = serialize(&targets);
+        assert_eq!(data, serialize(&generate(&spec)));
+        assert_ne!(
+            data,
+            serialize(&generate(&GraphSpec {
+                seed: 1,
+                ..spec.clone()
+            }))
+        );
+
+        // Round trips through JSON, with every dependency in the graph and no cycles
+        let parsed = Targets::from_reader(&data[..]).unwrap();
+        assert_eq!(parsed.targets().count(), 500);
+        let labels = parsed.targets().map(|x| x.label()).collect::<HashSet<_>>();
+        assert!(parsed
+            .targets()
+            .flat_map(|x| x.deps.iter())
+            .all(|x| labels.contains(x)));
+        assert!(parsed.targets().any(|x| x.deps.len() > 1));
+        assert!(parsed.targets().any(|x| !x.labels.is_empty()));
+        cycles::check_cycles(&parsed).unwrap();
+
+        let packages = parsed.targets().map(|x| &x.package).collect::<HashSet<_>>();
+        assert_eq!(packages.len(), 30);
+
+        let files = changed_files(&spec, 3, 0)
+            .into_iter()
+            .map(Status::Modified)
+            .collect();
+        let changes = Changes::new(&CellInfo::testing(), files).unwrap();
+        let inputs = parsed
+            .targets()
+            .flat_map(|x| x.inputs.iter())
+            .collect::<HashSet<_>>();
+        assert!(changes.cell_paths().all(|x| inputs.contains(x)));
+    }
+}
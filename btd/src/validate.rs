@@ -0,0 +1,203 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Check a targets file for structural problems before using it for change detection,
+//! so a corrupt graph is caught up front rather than producing confusing results.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::stdout;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use td_util::json;
+use thiserror::Error;
+
+use crate::buck::targets::Targets;
+use crate::buck::types::Package;
+use crate::buck::types::TargetLabel;
+
+/// Check a targets file for dangling dependencies, duplicate targets, malformed labels
+/// and packages with errors, printing each problem found as a line of JSON.
+/// Fails if there were any problems.
+#[derive(clap::Args, Debug)]
+pub struct ValidateArgs {
+    /// Targets file to check, either the JSON output of `buck2 targets` or a snapshot.
+    #[arg(long, value_name = "FILE")]
+    targets: PathBuf,
+}
+
+/// A problem found in a targets file.
+#[derive(Debug, Error, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Diagnostic {
+    #[error("Target `{target}` depends on `{missing}`, which is not in the graph")]
+    DanglingDep {
+        target: TargetLabel,
+        missing: TargetLabel,
+    },
+    #[error("Target `{target}` is defined {count} times")]
+    DuplicateTarget { target: TargetLabel, count: usize },
+    #[error("Target `{target}` refers to malformed label `{label}`")]
+    MalformedLabel { target: String, label: String },
+    #[error("Package `{package}` failed with error produced by Buck2:\n{error}")]
+    PackageError { package: Package, error: String },
+}
+
+#[derive(Debug, Error)]
+enum ValidateError {
+    #[error("Found {0} problems in the targets file")]
+    Problems(usize),
+}
+
+/// Is `label` of the form `cell//package:name`.
+///
+/// ```
+/// use btd::validate::is_well_formed;
+/// assert!(is_well_formed("foo//bar:baz"));
+/// assert!(is_well_formed("foo//:baz"));
+/// assert!(!is_well_formed("//bar:baz"));
+/// assert!(!is_well_formed("foo//bar"));
+/// assert!(!is_well_formed("foo//bar:"));
+/// assert!(!is_well_formed("foo//bar:baz:qux"));
+/// assert!(!is_well_formed("foo//bar/:baz"));
+/// ```
+pub fn is_well_formed(label: &str) -> bool {
+    let Some((cell, rest)) = label.split_once("//") else {
+        return false;
+    };
+    let Some((package, name)) = rest.split_once(':') else {
+        return false;
+    };
+    !cell.is_empty()
+        && !name.is_empty()
+        && !name.contains(':')
+        && !package.ends_with('/')
+        && !label.contains(char::is_whitespace)
+}
+
+/// Find every structural problem in `targets`, in a deterministic order.
+pub fn validate(targets: &Targets) -> Vec<Diagnostic> {
+    let mut res = Vec::new();
+
+    let mut errors = targets.errors().collect::<Vec<_>>();
+    errors.sort_by(|a, b| a.package.cmp(&b.package));
+    for x in errors {
+        res.push(Diagnostic::PackageError {
+            package: x.package.clone(),
+            error: x.error.clone(),
+        });
+    }
+
+    let mut counts: HashMap<TargetLabel, usize> = HashMap::new();
+    for x in targets.targets() {
+        *counts.entry(x.label()).or_default() += 1;
+    }
+    let mut duplicates = counts
+        .iter()
+        .filter(|(_, count)| **count > 1)
+        .collect::<Vec<_>>();
+    duplicates.sort();
+    for (target, count) in duplicates {
+        res.push(Diagnostic::DuplicateTarget {
+            target: target.clone(),
+            count: *count,
+        });
+    }
+
+    let mut reported = HashSet::new();
+    for x in targets.targets() {
+        let label = x.label();
+        if !is_well_formed(label.as_str()) {
+            if reported.insert(label.clone()) {
+                res.push(Diagnostic::MalformedLabel {
+                    target: label.to_string(),
+                    label: label.to_string(),
+                });
+            }
+            continue;
+        }
+        for dep in x.deps.iter() {
+            if !is_well_formed(dep.as_str()) {
+                res.push(Diagnostic::MalformedLabel {
+                    target: label.to_string(),
+                    label: dep.to_string(),
+                });
+            } else if !counts.contains_key(dep) {
+                res.push(Diagnostic::DanglingDep {
+                    target: label.clone(),
+                    missing: dep.clone(),
+                });
+            }
+        }
+    }
+    res
+}
+
+pub fn main(args: ValidateArgs) -> anyhow::Result<()> {
+    let diagnostics = validate(&Targets::from_file(&args.targets)?);
+    json::write_json_lines(BufWriter::new(stdout().lock()), &diagnostics)?;
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidateError::Problems(diagnostics.len()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::BuckError;
+    use crate::buck::targets::BuckTarget;
+    use crate::buck::targets::TargetsEntry;
+
+    #[test]
+    fn test_validate() {
+        let target = |name: &str, deps: &[&str]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &[]),
+            target("bin", &["foo//bar:lib", "foo//bar:missing", "foo//bar"]),
+            target("lib", &[]),
+            TargetsEntry::Error(BuckError {
+                package: Package::new("foo//baz"),
+                error: "bad".to_owned(),
+            }),
+        ]);
+        assert_eq!(
+            validate(&targets),
+            vec![
+                Diagnostic::PackageError {
+                    package: Package::new("foo//baz"),
+                    error: "bad".to_owned(),
+                },
+                Diagnostic::DuplicateTarget {
+                    target: TargetLabel::new("foo//bar:lib"),
+                    count: 2,
+                },
+                Diagnostic::DanglingDep {
+                    target: TargetLabel::new("foo//bar:bin"),
+                    missing: TargetLabel::new("foo//bar:missing"),
+                },
+                Diagnostic::MalformedLabel {
+                    target: "foo//bar:bin".to_owned(),
+                    label: "foo//bar".to_owned(),
+                },
+            ]
+        );
+
+        let valid = Targets::new(vec![target("lib", &[]), target("bin", &["foo//bar:lib"])]);
+        assert_eq!(validate(&valid), Vec::new());
+    }
+}
@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Buck only lets a target depend on targets visible to it, so a dependency on a
+//! target which isn't can't exist in a build that succeeds. Finding one usually means
+//! the targets are inconsistent, e.g. some were queried at a different revision.
+//!
+//! Targets in the same package can always see each other. A target whose
+//! `visibility` wasn't output, or has a pattern we can't parse, is treated as public.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use clap::ValueEnum;
+use tracing::debug;
+use tracing::warn;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::targets::Targets;
+use crate::buck::types::ParsedTargetPattern;
+use crate::buck::types::TargetLabel;
+
+/// What to do about dependencies on targets which aren't visible to the dependent.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisibilityCheck {
+    /// Trust every dependency.
+    #[default]
+    Off,
+    /// Warn about them.
+    Warn,
+    /// Warn about them, then drop them, so no impact flows along them.
+    Prune,
+}
+
+/// The patterns of targets which can see `x`, or `None` if every target can.
+fn visible_to(x: &BuckTarget) -> Option<Vec<ParsedTargetPattern>> {
+    if x.visibility.is_empty() {
+        return None;
+    }
+    x.visibility
+        .iter()
+        .map(|x| match x.as_str() {
+            "PUBLIC" => None,
+            _ => x.parse().ok(),
+        })
+        .collect()
+}
+
+/// The dependencies in `targets` on targets which aren't visible to the dependent, as
+/// pairs of the dependent and the dependency.
+pub fn invisible_deps(targets: &Targets) -> Vec<(TargetLabel, TargetLabel)> {
+    let by_label = targets.targets_by_label();
+    let mut visibility = HashMap::new();
+    let mut res = Vec::new();
+    for x in targets.targets() {
+        let label = x.label();
+        for d in x.deps.iter() {
+            let Some(dep) = by_label.get(d) else {
+                continue;
+            };
+            if dep.package == x.package {
+                continue;
+            }
+            let patterns = visibility
+                .entry(d)
+                .or_insert_with(|| visible_to(dep))
+                .as_deref();
+            if patterns.is_some_and(|xs| !xs.iter().any(|p| p.matches(&label))) {
+                res.push((label.clone(), d.clone()));
+            }
+        }
+    }
+    res
+}
+
+/// Check the dependencies in `targets`, which are the `graph` (`base` or `diff`) targets,
+/// against the visibility of their targets.
+pub fn check_visibility(mut targets: Targets, check: VisibilityCheck, graph: &str) -> Targets {
+    if check == VisibilityCheck::Off {
+        return targets;
+    }
+    let invisible = invisible_deps(&targets);
+    let Some((target, dep)) = invisible.first() else {
+        return targets;
+    };
+    warn!(
+        "Found {} dependencies in the {graph} targets on targets not visible to them, \
+        e.g. `{target}` on `{dep}`",
+        invisible.len()
+    );
+    for (target, dep) in &invisible {
+        debug!("`{target}` depends on `{dep}`, which isn't visible to it");
+    }
+    if check == VisibilityCheck::Prune {
+        let mut prune: HashMap<TargetLabel, HashSet<TargetLabel>> = HashMap::new();
+        for (target, dep) in invisible {
+            prune.entry(target).or_default().insert(dep);
+        }
+        for x in targets.targets_mut() {
+            if let Some(prune) = prune.get(&x.label()) {
+                x.deps = x
+                    .deps
+                    .iter()
+                    .filter(|d| !prune.contains(*d))
+                    .cloned()
+                    .collect();
+            }
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::TargetsEntry;
+    use crate::buck::types::TargetPattern;
+
+    fn target(name: &str, deps: &[&str], visibility: &[&str]) -> TargetsEntry {
+        let (package, name) = name.split_once(':').unwrap();
+        TargetsEntry::Target(BuckTarget {
+            deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+            visibility: visibility.iter().map(|x| TargetPattern::new(x)).collect(),
+            ..BuckTarget::testing(name, package, "prelude//rules.bzl:cxx_library")
+        })
+    }
+
+    #[test]
+    fn test_check_visibility() {
+        let targets = Targets::new(vec![
+            target("foo//lib:private", &[], &["foo//app/..."]),
+            target("foo//lib:public", &[], &["PUBLIC"]),
+            target("foo//lib:unknown", &[], &[]),
+            target("foo//lib:same", &["foo//lib:private"], &[]),
+            target("foo//app/sub:ok", &["foo//lib:private"], &[]),
+            target(
+                "foo//other:bad",
+                &[
+                    "foo//lib:private",
+                    "foo//lib:public",
+                    "foo//lib:unknown",
+                    "foo//missing:x",
+                ],
+                &[],
+            ),
+        ]);
+        assert_eq!(
+            invisible_deps(&targets),
+            vec![(
+                TargetLabel::new("foo//other:bad"),
+                TargetLabel::new("foo//lib:private")
+            )]
+        );
+
+        let deps = |targets: &Targets| {
+            targets
+                .targets()
+                .find(|x| x.name.as_str() == "bad")
+                .unwrap()
+                .deps
+                .len()
+        };
+        let targets = check_visibility(targets, VisibilityCheck::Warn, "diff");
+        assert_eq!(deps(&targets), 4);
+        let targets = check_visibility(targets, VisibilityCheck::Prune, "diff");
+        assert_eq!(deps(&targets), 3);
+        assert_eq!(invisible_deps(&targets), Vec::new());
+    }
+}
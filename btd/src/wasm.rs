@@ -0,0 +1,116 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! WebAssembly bindings for exploring impact in a browser, enabled with the `wasm`
+//! feature, so a web UI can load a snapshot and answer `rdeps` and `why` queries
+//! client-side.
+//!
+//! There is no file system, so the targets are passed in as bytes, and without threads
+//! Rayon runs the traversal on the calling thread. Build the module with
+//! `cargo rustc -p btd --lib --release --target wasm32-unknown-unknown --features wasm --crate-type cdylib`,
+//! then generate its JavaScript bindings with `wasm-bindgen --target web`.
+
+use wasm_bindgen::prelude::*;
+
+use crate::buck::targets::Targets;
+use crate::buck::types::TargetLabel;
+use crate::buck::types::TargetPattern;
+use crate::changes::Changes;
+use crate::diff;
+use crate::diff::GraphImpact;
+use crate::diff::ImpactReason;
+use crate::diff::RootImpactKind;
+use crate::expand;
+use crate::rdeps;
+use crate::rdeps::Edges;
+use crate::rdeps::Rdeps;
+use crate::why;
+
+fn js_error(e: anyhow::Error) -> JsError {
+    JsError::new(&format!("{e:#}"))
+}
+
+/// The targets of a snapshot, loaded once to answer many queries.
+#[wasm_bindgen]
+pub struct Snapshot {
+    targets: Targets,
+}
+
+impl Snapshot {
+    fn query_rdeps(
+        &self,
+        pattern: &str,
+        depth: Option<usize>,
+        follow_tests: bool,
+    ) -> anyhow::Result<String> {
+        let roots = expand::expand(&self.targets, &[TargetPattern::new(pattern)])?;
+        let edges = Edges {
+            tests: follow_tests,
+            ..Edges::default()
+        };
+        let rdeps = Rdeps::with_edges(&self.targets, edges);
+        let res = rdeps::transitive_rdeps(&self.targets, &rdeps, &roots, depth);
+        Ok(serde_json::to_string(&res)?)
+    }
+
+    fn query_why(&self, changed: &str, target: &str) -> anyhow::Result<String> {
+        let roots = expand::expand(&self.targets, &[TargetPattern::new(changed)])?;
+        let seed = GraphImpact::from_recursive(
+            roots
+                .iter()
+                .map(|x| (*x, ImpactReason::new(x, RootImpactKind::Query)))
+                .collect(),
+        );
+        let mut levels = Vec::new();
+        diff::recursive_target_changes_with(
+            &self.targets,
+            &seed,
+            None,
+            |_| true,
+            |level| levels.push(level),
+        );
+        let res = why::explain(&levels, &Changes::default(), &TargetLabel::new(target));
+        Ok(serde_json::to_string(&res)?)
+    }
+}
+
+#[wasm_bindgen]
+impl Snapshot {
+    /// Load a snapshot written by `btd snapshot`, or the JSON lines output of
+    /// `buck2 targets`, from its bytes.
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8]) -> Result<Snapshot, JsError> {
+        let targets = Targets::from_bytes(data).map_err(js_error)?;
+        Ok(Self { targets })
+    }
+
+    /// How many targets the snapshot has.
+    #[wasm_bindgen(js_name = targetCount)]
+    pub fn target_count(&self) -> usize {
+        self.targets.targets().count()
+    }
+
+    /// The targets transitively depending on those matching `pattern`, as a JSON array
+    /// of objects with their `target` and `depth`, nearest first, as `btd rdeps`.
+    pub fn rdeps(
+        &self,
+        pattern: &str,
+        depth: Option<usize>,
+        follow_tests: bool,
+    ) -> Result<String, JsError> {
+        self.query_rdeps(pattern, depth, follow_tests)
+            .map_err(js_error)
+    }
+
+    /// Why `target` would be impacted if the targets matching `changed` changed, as the
+    /// JSON of `btd --why`, which is `null` if it wouldn't be.
+    pub fn why(&self, changed: &str, target: &str) -> Result<String, JsError> {
+        self.query_why(changed, target).map_err(js_error)
+    }
+}
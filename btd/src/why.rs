@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Explain why a target was impacted, by following the `affected_dep` of each
+//! impacted target back to a target that changed itself.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::buck::targets::BuckTarget;
+use crate::buck::types::CellPath;
+use crate::buck::types::TargetLabel;
+use crate::changes::Changes;
+use crate::diff::ImpactReason;
+use crate::diff::RootImpactKind;
+use crate::output::OutputFormat;
+
+/// The chain through which a change reached a target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Explanation {
+    pub target: TargetLabel,
+    /// Why the first target in `path` was impacted.
+    pub root_cause: RootImpactKind,
+    /// The changed files among the inputs of the first target in `path`.
+    pub changed_files: Vec<CellPath>,
+    /// From the target that changed to `target`, each depending on the one before.
+    pub path: Vec<TargetLabel>,
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} is impacted because:", self.target)?;
+        for file in &self.changed_files {
+            writeln!(f, "  {file} changed")?;
+        }
+        let (root, rest) = self.path.split_first().expect("path is never empty");
+        writeln!(f, "  {root} changed ({})", self.root_cause)?;
+        let mut dep = root;
+        for x in rest {
+            writeln!(f, "  -> {x} depends on {dep}")?;
+            dep = x;
+        }
+        Ok(())
+    }
+}
+
+/// Explain why `target` is among the impacted targets in `levels`, or return
+/// `None` if it was not impacted. Since levels are found breadth first, the
+/// chain is a shortest one.
+pub fn explain(
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    changes: &Changes,
+    target: &TargetLabel,
+) -> Option<Explanation> {
+    let impacted: HashMap<TargetLabel, (&BuckTarget, &ImpactReason)> = levels
+        .iter()
+        .flatten()
+        .map(|(x, reason)| (x.label(), (*x, reason)))
+        .collect();
+
+    let (_, reason) = impacted.get(target)?;
+    let mut path = vec![target.clone()];
+    let mut reason = *reason;
+    // Every step goes up a level, so a longer chain would be a cycle.
+    while !reason.affected_dep.is_empty() && path.len() <= levels.len() {
+        let dep = TargetLabel::new(&reason.affected_dep);
+        path.push(dep.clone());
+        match impacted.get(&dep) {
+            Some((_, dep_reason)) => reason = dep_reason,
+            // Removed targets are traversed but never reported.
+            None => break,
+        }
+    }
+    path.reverse();
+
+    let changed_files = match impacted.get(&path[0]) {
+        Some((root, _)) => root
+            .inputs
+            .iter()
+            .filter(|x| changes.contains_cell_path(x))
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+    Some(Explanation {
+        target: target.clone(),
+        root_cause: reason.root_cause.1,
+        changed_files,
+        path,
+    })
+}
+
+/// Print the explanation for `target`, or that it was not impacted.
+pub fn print_explanation(
+    levels: &[Vec<(&BuckTarget, ImpactReason)>],
+    changes: &Changes,
+    target: &TargetLabel,
+    format: OutputFormat,
+) {
+    let explanation = explain(levels, changes, target);
+    match format {
+        OutputFormat::Text => match explanation {
+            Some(x) => print!("{x}"),
+            None => println!("{target} is not impacted"),
+        },
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            println!("{}", serde_json::to_string(&explanation).unwrap())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::targets::Targets;
+    use crate::buck::targets::TargetsEntry;
+    use crate::diff;
+    use crate::sapling::status::Status;
+
+    #[test]
+    fn test_explain() {
+        let file = CellPath::new("foo//bar/lib.cpp");
+        let target = |name: &str, deps: &[&str], inputs: &[&CellPath]| {
+            TargetsEntry::Target(BuckTarget {
+                deps: deps.iter().map(|x| TargetLabel::new(x)).collect(),
+                inputs: inputs.iter().map(|x| (*x).clone()).collect(),
+                ..BuckTarget::testing(name, "foo//bar", "prelude//rules.bzl:cxx_library")
+            })
+        };
+        let targets = Targets::new(vec![
+            target("lib", &[], &[&file]),
+            target("mid", &["foo//bar:lib"], &[]),
+            target("bin", &["foo//bar:mid", "foo//bar:lib"], &[]),
+            target("top", &["foo//bar:bin"], &[]),
+            target("other", &[], &[]),
+        ]);
+        let changes = Changes::testing(&[Status::Modified(file.clone())]);
+        let immediate = diff::immediate_target_changes(&targets, &targets, &changes, false);
+        let levels = diff::recursive_target_changes(&targets, &immediate, None, |_| true);
+
+        let res = explain(&levels, &changes, &TargetLabel::new("foo//bar:top")).unwrap();
+        assert_eq!(res.root_cause, RootImpactKind::Inputs);
+        assert_eq!(res.changed_files, vec![file]);
+        // The direct edge to `lib` is shorter than going through `mid`.
+        assert_eq!(
+            res.path,
+            vec![
+                TargetLabel::new("foo//bar:lib"),
+                TargetLabel::new("foo//bar:bin"),
+                TargetLabel::new("foo//bar:top"),
+            ]
+        );
+        assert_eq!(
+            res.to_string(),
+            "foo//bar:top is impacted because:\n  foo//bar/lib.cpp changed\n  foo//bar:lib changed (inputs)\n  -> foo//bar:bin depends on foo//bar:lib\n  -> foo//bar:top depends on foo//bar:bin\n"
+        );
+
+        assert_eq!(
+            explain(&levels, &changes, &TargetLabel::new("foo//bar:other")),
+            None
+        );
+    }
+}
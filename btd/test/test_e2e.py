@@ -113,6 +113,9 @@ def test_run(patch_name):
         out_btd2 = Path(output_dir).joinpath("btd2.json")
         out_targets = Path(output_dir).joinpath("targets.txt")
         out_rerun = Path(output_dir).joinpath("rerun.txt")
+        out_bxl = Path(output_dir).joinpath("bxl.txt")
+        out_bxl_metadata = Path(output_dir).joinpath("bxl.json")
+        out_bxl_result = Path(output_dir).joinpath("bxl_result.json")
         btd_args = [
             "--check-dangling",
             "--cells",
@@ -193,10 +196,48 @@ def test_run(patch_name):
             run(buck, "build", "@" + str(out_targets))
             # Check custom properties
             check_properties(patch_name, output)
+            # And that BXL can consume them
+            check_bxl(
+                btd,
+                buck,
+                btd_args + ["--diff", out_diff],
+                output,
+                out_bxl,
+                out_bxl_metadata,
+                out_bxl_result,
+            )
         rerun = read_file(out_rerun)
         check_properties_rerun(patch_name, rerun)
 
 
+def check_bxl(btd, buck, btd_args, output, out_bxl, out_bxl_metadata, out_bxl_result):
+    run(
+        btd,
+        *btd_args,
+        "--bxl-out",
+        out_bxl,
+        "--bxl-metadata",
+        out_bxl_metadata,
+    )
+    targets = [x["target"] for x in output]
+    assert read_file(out_bxl) == "".join(x + "\n" for x in targets)
+    assert json.loads(read_file(out_bxl_metadata)) == {x["target"]: x for x in output}
+    if targets:
+        script = Path(__file__).parent.parent.joinpath("bxl", "impacted.bxl")
+        shutil.copy(script, "impacted.bxl")
+        run(
+            buck,
+            "bxl",
+            "root//impacted.bxl:impacted",
+            "--",
+            "--build",
+            "--targets",
+            "@" + str(out_bxl),
+            output=out_bxl_result,
+        )
+        assert json.loads(read_file(out_bxl_result)) == targets
+
+
 def check_properties(patch, rdeps):
     if patch == "nothing":
         assert rdeps == []
@@ -14,8 +14,10 @@ use clap::FromArgMatches;
 use clap::Parser;
 use fbinit::FacebookInit;
 use td_util::cli::get_args;
+use td_util::cli::multicall_args;
 
 /// Generic binary for the pieces of the new target-determinator framework.
+/// Each can also be run through a link to this binary named after it, e.g. `btd`.
 #[allow(clippy::large_enum_variant)] // Only one instance, so not a big deal
 #[derive(Parser)]
 #[command(name = "supertd", version = get_version())]
@@ -37,7 +39,7 @@ enum Args {
 
 #[fbinit::main]
 pub fn main(fb: FacebookInit) -> anyhow::Result<()> {
-    let _guard = td_util::init(fb);
+    let _guard = td_util::init(fb)?;
 
     let mut command = Args::command();
     if std::env::var_os("SUPERTD_IGNORE_EXTRA_ARGUMENTS") == Some("1".into()) {
@@ -47,7 +49,8 @@ pub fn main(fb: FacebookInit) -> anyhow::Result<()> {
         // But we might want to have it briefly on for a rollout.
         command = command.ignore_errors(true);
     }
-    let matches = command.get_matches_from(get_args()?);
+    let args = multicall_args(&command, get_args()?);
+    let matches = command.get_matches_from(args);
     match Args::from_arg_matches(&matches) {
         Err(err) => err.format(&mut Args::command()).exit(),
         Ok(args) => match args {
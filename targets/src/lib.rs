@@ -12,6 +12,8 @@
 
 #![forbid(unsafe_code)]
 
+pub mod shard;
+
 use std::path::PathBuf;
 use std::process;
 use std::process::Command;
@@ -19,6 +21,8 @@ use std::process::Command;
 use clap::Parser;
 use td_util::command::display_command;
 
+use crate::shard::ShardBy;
+
 /// Run `buck2 targets` with all the arguments required for BTD/Citadel.
 #[derive(Parser)]
 pub struct Args {
@@ -34,10 +38,24 @@ pub struct Args {
     #[arg(long)]
     dry_run: bool,
 
+    /// Output every attribute of each target, not just those BTD needs, so BTD can
+    /// compare targets attribute by attribute (see `btd --ignore-attribute`).
+    #[arg(long)]
+    all_attributes: bool,
+
     // Isolation directory to use for buck invocations.
     #[arg(long)]
     isolation_dir: Option<String>,
 
+    /// Split the patterns into shards, running a `buck2 targets` for each and
+    /// concatenating their outputs. If any shard fails, nothing is output.
+    #[arg(long, value_enum, default_value_t)]
+    shard_by: ShardBy,
+
+    /// The most shards to run at once with `--shard-by`.
+    #[arg(long, default_value_t = 4)]
+    jobs: usize,
+
     /// Arguments passed onwards - typically patterns.
     #[arg(value_name = "ARGS")]
     arguments: Vec<String>,
@@ -51,7 +69,7 @@ pub fn targets_arguments() -> &'static [&'static str] {
         "--no-cache",
         "--show-unconfigured-target-hash",
         "--json-lines",
-        "--output-attribute=^buck\\.|^name$|^labels$|^ci_srcs$|^ci_deps$",
+        "--output-attribute=^buck\\.|^name$|^labels$|^ci_srcs$|^ci_deps$|^tests$|^visibility$",
         "--imports",
         // `buck.cfg_modifiers` is PACKAGE value key for modifiers which may change configurations of all targets
         // covered by the PACKAGE. We need BTD to specifically query for these PACKAGE values because buck currently
@@ -62,10 +80,32 @@ pub fn targets_arguments() -> &'static [&'static str] {
 }
 
 pub fn main(args: Args) -> anyhow::Result<()> {
+    if args.shard_by != ShardBy::None {
+        let t = std::time::Instant::now();
+        let isolation_dir = args.isolation_dir.as_deref();
+        let shards = shard::run(
+            || targets_command(&args.buck, isolation_dir, args.all_attributes),
+            || buck_command(&args.buck, isolation_dir),
+            args.output.as_deref(),
+            args.dry_run,
+            args.shard_by,
+            args.jobs,
+            &args.arguments,
+        )?;
+        if !args.dry_run {
+            td_util::scuba!(
+                event: TARGETS_SUCCESS,
+                duration: t.elapsed(),
+                data: json!({"shards": shards}),
+            );
+        }
+        return Ok(());
+    }
     run(
         &args.buck,
         args.output,
         args.dry_run,
+        args.all_attributes,
         args.isolation_dir,
         &args.arguments,
     )
@@ -79,24 +119,19 @@ pub fn main(args: Args) -> anyhow::Result<()> {
 /// * `buck` - The command to run Buck, typically "buck2".
 /// * `output_file` - Optional path to the file where the output will be written. If not provided, the output is written to stdout.
 /// * `dry_run` - If set to `true`, the command will print the command that would have been executed instead of executing it, without executing it.
+/// * `all_attributes` - If set to `true`, every attribute of each target is output, not just those BTD needs.
 /// * `isolation_dir` - If set, the buck invocation will use this isolation prefix.
 /// * `arguments` - Additional arguments typically provided as patterns to be passed to the `buck2 targets` command.
 pub fn run(
     buck: &str,
     output_file: Option<PathBuf>,
     dry_run: bool,
+    all_attributes: bool,
     isolation_dir: Option<String>,
     arguments: &[String],
 ) -> anyhow::Result<()> {
     let t = std::time::Instant::now();
-    let mut command = Command::new(buck);
-
-    // This is an argument for buck.
-    if let Some(prefix) = isolation_dir {
-        command.args(["--isolation-dir", &prefix]);
-    }
-
-    command.args(targets_arguments());
+    let mut command = targets_command(buck, isolation_dir.as_deref(), all_attributes);
     if let Some(x) = &output_file {
         command.arg("--output");
         command.arg(x);
@@ -119,3 +154,22 @@ pub fn run(
         process::exit(status.code().unwrap_or(1));
     }
 }
+
+fn buck_command(buck: &str, isolation_dir: Option<&str>) -> Command {
+    let mut command = Command::new(buck);
+
+    // This is an argument for buck.
+    if let Some(prefix) = isolation_dir {
+        command.args(["--isolation-dir", prefix]);
+    }
+    command
+}
+
+fn targets_command(buck: &str, isolation_dir: Option<&str>, all_attributes: bool) -> Command {
+    let mut command = buck_command(buck, isolation_dir);
+    command.args(targets_arguments());
+    if all_attributes {
+        command.arg("--output-attribute=.*");
+    }
+    command
+}
@@ -0,0 +1,279 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Split the patterns given to `buck2 targets` into shards, run one `buck2 targets`
+//! per shard concurrently, and concatenate their outputs in order.
+//!
+//! Only arguments containing `//` are treated as patterns. Every other argument is
+//! passed to each shard, so flags taking a value must not look like a pattern.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+
+use anyhow::Context as _;
+use clap::ValueEnum;
+use tempfile::NamedTempFile;
+use thiserror::Error;
+
+/// Files which make a directory a package.
+const BUILD_FILES: &[&str] = &["BUCK", "BUCK.v2", "TARGETS", "TARGETS.v2"];
+
+/// How to split the patterns into shards.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardBy {
+    /// Run a single `buck2 targets`.
+    #[default]
+    None,
+    /// One shard for the patterns in each cell.
+    Cell,
+    /// Like `cell`, but each recursive pattern, e.g. `cell//foo/...`, is split
+    /// into one shard per directory beneath it.
+    Directory,
+}
+
+#[derive(Error, Debug)]
+enum ShardError {
+    #[error("`buck2 targets` failed with {status} for the shard `{patterns}`")]
+    Failed {
+        status: ExitStatus,
+        patterns: String,
+    },
+    #[error("Expected `buck2 audit cell --json` to print an object of cell paths")]
+    CellPaths,
+}
+
+/// Group `patterns` into the patterns of each shard. With [`ShardBy::Directory`],
+/// `subdirs` gives the patterns to split a recursive pattern into, if it can be.
+pub fn split(
+    patterns: &[String],
+    shard_by: ShardBy,
+    subdirs: impl Fn(&str) -> Option<Vec<String>>,
+) -> Vec<Vec<String>> {
+    let mut cells: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    let mut dirs = Vec::new();
+    for x in patterns {
+        if shard_by == ShardBy::Directory {
+            if let Some(xs) = subdirs(x) {
+                dirs.extend(xs.into_iter().map(|x| vec![x]));
+                continue;
+            }
+        }
+        let cell = x.split_once("//").map_or("", |(cell, _)| cell);
+        cells.entry(cell).or_default().push(x.clone());
+    }
+    let mut res = cells.into_values().collect::<Vec<_>>();
+    res.extend(dirs);
+    res
+}
+
+/// The patterns for the packages beneath the recursive `pattern`, e.g. `foo//bar/...`
+/// becomes `foo//bar:` if it is a package, and `foo//bar/baz/...` for each directory.
+/// `None` if the pattern isn't recursive or its cell or directory can't be found.
+pub fn subdirs(cells: &HashMap<String, PathBuf>, pattern: &str) -> Option<Vec<String>> {
+    let (cell, path) = pattern.split_once("//")?;
+    let path = path.strip_suffix("...")?;
+    let path = path.strip_suffix('/').unwrap_or(path);
+    let dir = cells.get(cell)?.join(path);
+    let prefix = if path.is_empty() {
+        format!("{cell}//")
+    } else {
+        format!("{cell}//{path}/")
+    };
+    let mut res = Vec::new();
+    if BUILD_FILES.iter().any(|x| dir.join(x).is_file()) {
+        res.push(format!("{cell}//{path}:"));
+    }
+    let mut names = fs::read_dir(&dir)
+        .ok()?
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_ok_and(|x| x.is_dir()))
+        .filter_map(|x| x.file_name().into_string().ok())
+        .filter(|x| !x.starts_with('.') && x != "buck-out")
+        .collect::<Vec<_>>();
+    names.sort();
+    res.extend(names.into_iter().map(|x| format!("{prefix}{x}/...")));
+    Some(res)
+}
+
+/// The root of each cell, from `buck2 audit cell --json`.
+fn cell_paths(mut command: Command) -> anyhow::Result<HashMap<String, PathBuf>> {
+    command.args(["audit", "cell", "--json"]);
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(ShardError::Failed {
+            status: output.status,
+            patterns: "audit cell".to_owned(),
+        }
+        .into());
+    }
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    value
+        .as_object()
+        .ok_or(ShardError::CellPaths)?
+        .iter()
+        .map(|(name, path)| {
+            Ok((
+                name.clone(),
+                PathBuf::from(path.as_str().ok_or(ShardError::CellPaths)?),
+            ))
+        })
+        .collect()
+}
+
+/// Run `buck2 targets` for each shard of the patterns in `arguments`, with up to `jobs`
+/// at once, and write their concatenated output to `output_file`, or stdout.
+/// `command` gives a `buck2 targets` command without any arguments from `arguments`,
+/// and `base` a bare `buck2` command, used to find the cells with [`ShardBy::Directory`].
+pub fn run(
+    command: impl Fn() -> Command + Sync,
+    base: impl Fn() -> Command,
+    output_file: Option<&Path>,
+    dry_run: bool,
+    shard_by: ShardBy,
+    jobs: usize,
+    arguments: &[String],
+) -> anyhow::Result<usize> {
+    let (patterns, flags): (Vec<_>, Vec<_>) =
+        arguments.iter().cloned().partition(|x| x.contains("//"));
+    let shards = if shard_by == ShardBy::Directory {
+        let cells = cell_paths(base()).context("When finding the cells to shard by")?;
+        split(&patterns, shard_by, |x| subdirs(&cells, x))
+    } else {
+        split(&patterns, shard_by, |_| None)
+    };
+    let shard_command = |shard: &[String]| {
+        let mut command = command();
+        command.args(&flags).args(shard);
+        command
+    };
+
+    if dry_run {
+        for shard in &shards {
+            println!(
+                "{}",
+                td_util::command::display_command(&shard_command(shard))
+            );
+        }
+        return Ok(shards.len());
+    }
+
+    let outputs = shards
+        .iter()
+        .map(|_| NamedTempFile::new())
+        .collect::<io::Result<Vec<_>>>()?;
+    let next = AtomicUsize::new(0);
+    let statuses = Mutex::new(vec![None; shards.len()]);
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, shards.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(shard) = shards.get(i) else {
+                    break;
+                };
+                let mut command = shard_command(shard);
+                command.arg("--output").arg(outputs[i].path());
+                let status = command.status().map_err(|e| e.to_string());
+                statuses.lock().unwrap()[i] = Some(status);
+            });
+        }
+    });
+
+    // Only write the output once every shard has succeeded, so it is never partial
+    for (shard, status) in shards.iter().zip(statuses.into_inner().unwrap()) {
+        let status = status
+            .expect("every shard is run")
+            .map_err(anyhow::Error::msg)
+            .with_context(|| format!("When running `buck2 targets` for `{}`", shard.join(" ")))?;
+        if !status.success() {
+            return Err(ShardError::Failed {
+                status,
+                patterns: shard.join(" "),
+            }
+            .into());
+        }
+    }
+    let mut out: Box<dyn Write> = match output_file {
+        Some(file) => Box::new(
+            File::create(file).with_context(|| format!("When creating `{}`", file.display()))?,
+        ),
+        None => Box::new(io::stdout().lock()),
+    };
+    for x in &outputs {
+        io::copy(&mut File::open(x.path())?, &mut out)?;
+    }
+    out.flush()?;
+    Ok(shards.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let patterns = ["foo//a/...", "bar//:", "foo//b:c", "//local/..."].map(String::from);
+        assert_eq!(
+            split(&patterns, ShardBy::Cell, |_| None),
+            vec![
+                vec!["//local/..."],
+                vec!["bar//:"],
+                vec!["foo//a/...", "foo//b:c"],
+            ]
+        );
+        let subdirs = |x: &str| (x == "foo//a/...").then(|| vec!["foo//a/x/...".to_owned()]);
+        assert_eq!(
+            split(&patterns, ShardBy::Directory, subdirs),
+            vec![
+                vec!["//local/..."],
+                vec!["bar//:"],
+                vec!["foo//b:c"],
+                vec!["foo//a/x/..."],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subdirs() {
+        let dir = tempfile::tempdir().unwrap();
+        for x in ["a/b", "a/c", "a/.hidden", "buck-out"] {
+            fs::create_dir_all(dir.path().join(x)).unwrap();
+        }
+        fs::write(dir.path().join("a/BUCK"), "").unwrap();
+        fs::write(dir.path().join("a/file.txt"), "").unwrap();
+        let cells = HashMap::from([("foo".to_owned(), dir.path().to_owned())]);
+
+        assert_eq!(
+            subdirs(&cells, "foo//a/..."),
+            Some(vec![
+                "foo//a:".to_owned(),
+                "foo//a/b/...".to_owned(),
+                "foo//a/c/...".to_owned(),
+            ])
+        );
+        assert_eq!(
+            subdirs(&cells, "foo//..."),
+            Some(vec!["foo//a/...".to_owned()])
+        );
+        assert_eq!(subdirs(&cells, "foo//a:"), None);
+        assert_eq!(subdirs(&cells, "foo//missing/..."), None);
+        assert_eq!(subdirs(&cells, "bar//..."), None);
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Benchmark interning the same strings from many threads at once, as parallel
+//! parsing does, with `cargo bench -p td_util`. Compares `InternString`, which is
+//! sharded, against a single unsharded interner.
+
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::thread;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use equivalent::Equivalent;
+use static_interner::Interner;
+use td_util::string::InternString;
+
+const STRINGS: usize = 10_000;
+const THREADS: [usize; 3] = [1, 4, 16];
+
+#[derive(PartialEq, Eq)]
+struct Data(Box<str>);
+
+impl Hash for Data {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.0.as_bytes())
+    }
+}
+
+struct Key<'a>(&'a str);
+
+impl Hash for Key<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.0.as_bytes())
+    }
+}
+
+impl Equivalent<Data> for Key<'_> {
+    fn equivalent(&self, key: &Data) -> bool {
+        self.0 == &*key.0
+    }
+}
+
+impl From<Key<'_>> for Data {
+    fn from(value: Key<'_>) -> Self {
+        Data(value.0.into())
+    }
+}
+
+static UNSHARDED: Interner<Data> = Interner::new();
+
+/// Intern every string on each of `threads` threads.
+fn run(threads: usize, strings: &[String], intern: impl Fn(&str) + Sync) {
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for x in strings {
+                    intern(x)
+                }
+            });
+        }
+    });
+}
+
+fn bench_intern(c: &mut Criterion) {
+    let strings = (0..STRINGS)
+        .map(|i| format!("fbcode//package{}:target{i}", i % 100))
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("intern");
+    for threads in THREADS {
+        group.throughput(Throughput::Elements((threads * STRINGS) as u64));
+        group.bench_with_input(BenchmarkId::new("sharded", threads), &threads, |b, &t| {
+            b.iter(|| {
+                run(t, &strings, |x| {
+                    InternString::new(x);
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("unsharded", threads), &threads, |b, &t| {
+            b.iter(|| {
+                run(t, &strings, |x| {
+                    UNSHARDED.intern(Key(x));
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_intern);
+criterion_main!(benches);
@@ -10,14 +10,106 @@
 //! Helper functions for the supertd CLIs, so they are all consistent.
 //! Supports things like args files.
 
+use std::cell::RefCell;
 use std::env::args_os;
 use std::ffi::OsString;
+use std::path::Path;
 
 use anyhow::Context as _;
 use argfile::Argument;
 use clap::Parser;
+use clap::ValueEnum;
 
+use crate::tracing::LogFormat;
+use crate::tracing::LogOptions;
+
+/// Passing this flag replaces `${VAR}` in arg files with the environment variable
+/// `VAR`, and `$${` with `${`. The flag itself is removed before the args are parsed.
+pub const EXPAND_ENV_FLAG: &str = "--expand-env";
+
+thread_local! {
+    /// The first error expanding an arg file, as `argfile` doesn't let parsing fail.
+    static EXPAND_ERROR: RefCell<Option<anyhow::Error>> = const { RefCell::new(None) };
+}
+
+/// Replace `${VAR}` in `content` with `lookup("VAR")`, and `$${` with `${`.
+fn expand_env(content: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut res = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(i) = rest.find('$') {
+        res.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(x) = rest.strip_prefix("$${") {
+            res.push_str("${");
+            rest = x;
+        } else if let Some(x) = rest.strip_prefix("${") {
+            let Some((name, after)) = x.split_once('}').filter(|x| !x.0.contains('\n')) else {
+                anyhow::bail!("Unterminated `${{` in `{}`", rest.lines().next().unwrap());
+            };
+            match lookup(name) {
+                Some(value) => res.push_str(&value),
+                None => anyhow::bail!("Environment variable `{name}` isn't set"),
+            }
+            rest = after;
+        } else {
+            res.push('$');
+            rest = &rest[1..];
+        }
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+/// Take `--log-format` and `--log-level` (each as `--flag VALUE` or `--flag=VALUE`) out
+/// of `args`, stopping at `--`, so every binary accepts them without declaring them.
+fn take_log_options(args: &mut Vec<OsString>) -> anyhow::Result<LogOptions> {
+    let mut res = LogOptions::default();
+    let mut rest = std::mem::take(args).into_iter();
+    while let Some(arg) = rest.next() {
+        let text = arg.to_string_lossy().into_owned();
+        if text == "--" {
+            args.push(arg);
+            args.extend(rest.by_ref());
+            break;
+        }
+        let (flag, value) = match text.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_owned())),
+            None => (text.as_str(), None),
+        };
+        if flag != "--log-format" && flag != "--log-level" {
+            args.push(arg);
+            continue;
+        }
+        let Some(value) = value.or_else(|| Some(rest.next()?.to_string_lossy().into_owned()))
+        else {
+            anyhow::bail!("Missing value for `{flag}`");
+        };
+        if flag == "--log-format" {
+            res.format = LogFormat::from_str(&value, true).map_err(|_| {
+                anyhow::anyhow!("Invalid `--log-format` of `{value}`, expected `text` or `json`")
+            })?;
+        } else {
+            res.level = Some(value.parse().map_err(|_| {
+                anyhow::anyhow!("Invalid `--log-level` of `{value}`, expected e.g. `debug`")
+            })?);
+        }
+    }
+    Ok(res)
+}
+
+/// The logging options given on the command line, see [`take_log_options`].
+pub fn log_options() -> anyhow::Result<LogOptions> {
+    take_log_options(&mut expand_args()?)
+}
+
+/// The command line arguments, with arg files expanded and the logging options removed.
 pub fn get_args() -> anyhow::Result<Vec<OsString>> {
+    let mut res = expand_args()?;
+    take_log_options(&mut res)?;
+    Ok(res)
+}
+
+fn expand_args() -> anyhow::Result<Vec<OsString>> {
     // Buck2 drops empty lines in arg files, so we should do the same.
     fn parse_file_skipping_blanks(content: &str, prefix: char) -> Vec<Argument> {
         let mut res = argfile::parse_fromfile(content, prefix);
@@ -28,8 +120,43 @@ pub fn get_args() -> anyhow::Result<Vec<OsString>> {
         res
     }
 
-    argfile::expand_args_from(args_os(), parse_file_skipping_blanks, argfile::PREFIX)
-        .context("When parsing arg files")
+    fn parse_file_expanding_env(content: &str, prefix: char) -> Vec<Argument> {
+        match expand_env(content, |x| std::env::var(x).ok()) {
+            Ok(content) => parse_file_skipping_blanks(&content, prefix),
+            Err(e) => {
+                EXPAND_ERROR.with(|x| x.borrow_mut().get_or_insert(e));
+                Vec::new()
+            }
+        }
+    }
+
+    let (expand, args): (Vec<_>, Vec<_>) = args_os().partition(|x| x == EXPAND_ENV_FLAG);
+    let parse = if expand.is_empty() {
+        parse_file_skipping_blanks
+    } else {
+        parse_file_expanding_env
+    };
+    let res = argfile::expand_args_from(args.into_iter(), parse, argfile::PREFIX)
+        .context("When parsing arg files")?;
+    match EXPAND_ERROR.with(|x| x.borrow_mut().take()) {
+        Some(e) => Err(e).context("When expanding environment variables in arg files"),
+        None => Ok(res),
+    }
+}
+
+/// For a binary with subcommands, e.g. `supertd`, run through a link named after one of
+/// them, insert that subcommand, so a `btd` link runs `supertd btd`. A binary `btd.exe`
+/// counts as `btd`. Other `args` are returned unchanged.
+pub fn multicall_args(command: &clap::Command, mut args: Vec<OsString>) -> Vec<OsString> {
+    let name = args
+        .first()
+        .and_then(|x| Path::new(x).file_stem()?.to_str())
+        .filter(|x| command.find_subcommand(x).is_some())
+        .map(OsString::from);
+    if let Some(name) = name {
+        args.insert(1, name);
+    }
+    args
 }
 
 /// Set up tracing so it prints to stderr, and can be used for output.
@@ -37,3 +164,84 @@ pub fn get_args() -> anyhow::Result<Vec<OsString>> {
 pub fn parse_args<T: Parser>() -> anyhow::Result<T> {
     Ok(T::parse_from(get_args()?))
 }
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::filter::LevelFilter;
+
+    use super::*;
+
+    #[test]
+    fn test_expand_env() {
+        let lookup = |x: &str| match x {
+            "REV" => Some("abc123".to_owned()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_env("--rev\n${REV}\n--x=${EMPTY}y", lookup).unwrap(),
+            "--rev\nabc123\n--x=y"
+        );
+        assert_eq!(
+            expand_env("$HOME $ $${REV} $$ ${REV}$", lookup).unwrap(),
+            "$HOME $ ${REV} $$ abc123$"
+        );
+        assert_eq!(
+            expand_env("--rev\n${MISSING}", lookup)
+                .unwrap_err()
+                .to_string(),
+            "Environment variable `MISSING` isn't set"
+        );
+        assert_eq!(
+            expand_env("--rev\n${REV\n--x}", lookup)
+                .unwrap_err()
+                .to_string(),
+            "Unterminated `${` in `${REV`"
+        );
+    }
+
+    #[test]
+    fn test_multicall_args() {
+        let command = clap::Command::new("supertd")
+            .subcommand(clap::Command::new("btd"))
+            .subcommand(clap::Command::new("targets"));
+        let multicall = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect();
+            multicall_args(&command, args)
+        };
+        assert_eq!(
+            multicall(&["/bin/btd", "--json"]),
+            ["/bin/btd", "btd", "--json"]
+        );
+        assert_eq!(multicall(&["targets.exe"]), ["targets.exe", "targets"]);
+        assert_eq!(multicall(&["supertd", "btd"]), ["supertd", "btd"]);
+        assert_eq!(multicall(&["audit", "cell"]), ["audit", "cell"]);
+    }
+
+    #[test]
+    fn test_take_log_options() {
+        let take = |args: &[&str]| {
+            let mut args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            take_log_options(&mut args).map(|res| (res, args))
+        };
+        let args = ["btd", "--log-format", "json", "--json", "--log-level=warn"];
+        let (res, args) = take(&args).unwrap();
+        assert_eq!(res.format, LogFormat::Json);
+        assert_eq!(res.level, Some(LevelFilter::WARN));
+        assert_eq!(args, ["btd", "--json"]);
+
+        let args = ["targets", "--log-format=text", "--", "--log-level", "x"];
+        let (res, args) = take(&args).unwrap();
+        assert_eq!(res, LogOptions::default());
+        assert_eq!(args, ["targets", "--", "--log-level", "x"]);
+
+        assert_eq!(
+            take(&["btd", "--log-format=xml"]).unwrap_err().to_string(),
+            "Invalid `--log-format` of `xml`, expected `text` or `json`"
+        );
+        assert_eq!(
+            take(&["btd", "--log-level"]).unwrap_err().to_string(),
+            "Missing value for `--log-level`"
+        );
+    }
+}
@@ -8,11 +8,19 @@
  */
 
 use std::process::Command;
+use std::process::Output;
+use std::thread;
+use std::time::Duration;
 use std::time::Instant;
 
 use tracing::debug;
+use tracing::warn;
 
 /// Run a command printing out debugging information.
+///
+/// Every command is run through here, so this is the one place to stop
+/// processes being spawned where there are none, see below.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn with_command<T>(
     command: Command,
     run: impl Fn(Command) -> anyhow::Result<T>,
@@ -24,6 +32,18 @@ pub fn with_command<T>(
     Ok(res)
 }
 
+/// WebAssembly has no processes, so fail rather than run `command`.
+#[cfg(target_arch = "wasm32")]
+pub fn with_command<T>(
+    command: Command,
+    _run: impl Fn(Command) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    Err(anyhow::anyhow!(
+        "Can't run `{}` on WebAssembly",
+        display_command(&command)
+    ))
+}
+
 /// Works only for command lines we produce, without environment variables
 /// or any argument escaping.
 pub fn display_command(command: &Command) -> String {
@@ -34,3 +54,111 @@ pub fn display_command(command: &Command) -> String {
     }
     res.to_string_lossy().into_owned()
 }
+
+/// When to run a failed command again, e.g. `buck2` after its daemon fell over.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The most times to run the command, so `1` never retries.
+    pub attempts: usize,
+    /// How long to wait before the first retry, doubling before each one after.
+    pub backoff: Duration,
+    /// Only retry these exit codes, or any failure if this and `stderr` are both empty.
+    pub exit_codes: Vec<i32>,
+    /// Only retry if stderr contains one of these, or any failure if this and
+    /// `exit_codes` are both empty. A failure matching either is retried.
+    pub stderr: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::from_secs(1),
+            exit_codes: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the failed `output` is worth retrying.
+    pub fn is_retryable(&self, output: &Output) -> bool {
+        if self.exit_codes.is_empty() && self.stderr.is_empty() {
+            return true;
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        output
+            .status
+            .code()
+            .is_some_and(|x| self.exit_codes.contains(&x))
+            || self.stderr.iter().any(|x| stderr.contains(x.as_str()))
+    }
+}
+
+/// Run `command` to completion, capturing its output, and run it again while it fails in a
+/// way `policy` allows retrying. Returns the last output, which may still be a failure,
+/// along with how many retries there were.
+pub fn output_with_retries(
+    mut command: Command,
+    policy: &RetryPolicy,
+) -> anyhow::Result<(Output, usize)> {
+    let mut backoff = policy.backoff;
+    let mut retries = 0;
+    loop {
+        let res = command.output()?;
+        if res.status.success() || retries + 1 >= policy.attempts || !policy.is_retryable(&res) {
+            return Ok((res, retries));
+        }
+        retries += 1;
+        warn!(
+            "`{}` failed with {}, retrying in {:.1}s (retry {retries} of {})",
+            display_command(&command),
+            res.status,
+            backoff.as_secs_f64(),
+            policy.attempts - 1
+        );
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_with_retries() {
+        // Fails with 3 the first two times it is run, then succeeds
+        let dir = tempfile::tempdir().unwrap();
+        let script = format!(
+            "echo >> {0}/runs; [ $(wc -l < {0}/runs) -gt 2 ] || {{ echo died >&2; exit 3; }}",
+            dir.path().display()
+        );
+        let run = |policy: &RetryPolicy| {
+            let _ = std::fs::remove_file(dir.path().join("runs"));
+            let mut command = Command::new("sh");
+            command.args(["-c", &script]);
+            let (res, retries) = output_with_retries(command, policy).unwrap();
+            (res.status.success(), retries)
+        };
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(run(&policy), (true, 2));
+        assert_eq!(run(&RetryPolicy::default()), (false, 0));
+        let codes = |exit_codes| RetryPolicy {
+            exit_codes,
+            ..policy.clone()
+        };
+        assert_eq!(run(&codes(vec![3])), (true, 2));
+        assert_eq!(run(&codes(vec![1])), (false, 0));
+        let stderr = RetryPolicy {
+            stderr: vec!["died".to_owned()],
+            ..codes(vec![1])
+        };
+        assert_eq!(run(&stderr), (true, 2));
+    }
+}
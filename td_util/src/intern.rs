@@ -0,0 +1,181 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Interned strings of a particular kind, e.g. packages, each kind with its own
+//! interner. An `Interned<Packages>` can't be mixed up with an `Interned<RuleTypes>`
+//! or a plain [`InternString`] without the compiler noticing, and the stats of each
+//! interner show how much memory each kind takes.
+
+use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+
+use serde::de::Visitor;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+use crate::string::InternStats;
+use crate::string::InternString;
+use crate::string::StringInterner;
+
+/// A kind of interned string, with its own interner.
+pub trait Domain: 'static {
+    fn interner() -> &'static StringInterner;
+}
+
+/// Packages, e.g. `fbcode//buck2`, including those of interned labels.
+pub enum Packages {}
+
+/// Rule types, e.g. `prelude//rules.bzl:genrule`.
+pub enum RuleTypes {}
+
+/// Oncalls, e.g. `ci_efficiency`.
+pub enum Oncalls {}
+
+impl Domain for Packages {
+    fn interner() -> &'static StringInterner {
+        static INTERNER: StringInterner = StringInterner::new();
+        &INTERNER
+    }
+}
+
+impl Domain for RuleTypes {
+    fn interner() -> &'static StringInterner {
+        static INTERNER: StringInterner = StringInterner::new();
+        &INTERNER
+    }
+}
+
+impl Domain for Oncalls {
+    fn interner() -> &'static StringInterner {
+        static INTERNER: StringInterner = StringInterner::new();
+        &INTERNER
+    }
+}
+
+/// A string interned by the interner of `D`, so only comparable with strings of the
+/// same domain. Short strings are stored inline, like [`InternString`].
+pub struct Interned<D: Domain>(InternString, PhantomData<fn() -> D>);
+
+impl<D: Domain> Interned<D> {
+    pub fn new(x: &str) -> Self {
+        Self(InternString::new_in(D::interner(), x), PhantomData)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// How many strings of this domain have been interned, and how big they are.
+    pub fn stats() -> InternStats {
+        D::interner().stats()
+    }
+}
+
+// Implemented by hand, as deriving them would require `D` to implement them too
+
+impl<D: Domain> Clone for Interned<D> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+impl<D: Domain> PartialEq for Interned<D> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<D: Domain> Eq for Interned<D> {}
+
+impl<D: Domain> Hash for Interned<D> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<D: Domain> PartialOrd for Interned<D> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<D: Domain> Ord for Interned<D> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<D: Domain> fmt::Debug for Interned<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Interned").field(&self.as_str()).finish()
+    }
+}
+
+impl<D: Domain> fmt::Display for Interned<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<D: Domain> Serialize for Interned<D> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de, D: Domain> Deserialize<'de> for Interned<D> {
+    fn deserialize<De: Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        deserializer.deserialize_str(InternedVisitor(PhantomData))
+    }
+}
+
+struct InternedVisitor<D>(PhantomData<fn() -> D>);
+
+impl<'de, D: Domain> Visitor<'de> for InternedVisitor<D> {
+    type Value = Interned<D>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Interned::new(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interned() {
+        let long = "fbcode//some/long/package";
+        let a = Interned::<Packages>::new(long);
+        assert_eq!(a, Interned::new(long));
+        assert_eq!(a.as_str(), long);
+        assert_ne!(a, Interned::new("fbcode//other/long/package"));
+        assert!(a < Interned::new("fbcode//zzz"));
+        assert!(Interned::<Packages>::stats().count >= 2);
+
+        let before = Interned::<Oncalls>::stats().count;
+        Interned::<RuleTypes>::new("prelude//rules.bzl:rust_library");
+        assert_eq!(Interned::<Oncalls>::stats().count, before);
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(json, format!("\"{long}\""));
+        assert_eq!(
+            serde_json::from_str::<Interned<Packages>>(&json).unwrap(),
+            a
+        );
+    }
+}
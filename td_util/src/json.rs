@@ -13,65 +13,217 @@ use std::fs::File;
 use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Chain;
+use std::io::Cursor;
+use std::io::Read;
 use std::io::Write;
+use std::mem;
 use std::path::Path;
 use std::sync::Mutex;
 
 use anyhow::Context as _;
+use flate2::read::MultiGzDecoder;
+use rayon::prelude::*;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::mmap;
+
 // Function definition mostly to get the error types to line up
 fn parse_line<T: for<'a> Deserialize<'a>>(x: Result<String, io::Error>) -> anyhow::Result<T> {
     let x = x?;
     serde_json::from_str(&x).with_context(|| format!("When parsing: {x}"))
 }
 
-fn is_zstd(filename: &Path) -> bool {
-    match filename.extension() {
-        Some(x) => x == "zst",
-        None => false,
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Zstd,
+    Gzip,
 }
 
-fn open_file(filename: &Path) -> anyhow::Result<impl BufRead + Send> {
-    let file = File::open(filename)?;
-    if is_zstd(filename) {
-        Ok(Box::new(BufReader::new(zstd::Decoder::new(file)?)) as Box<dyn BufRead + Send>)
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+/// A reader which yields the bytes already [`peek`]ed, then the rest.
+pub type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// The first `len` bytes of `reader` (fewer if it ends sooner), with a reader yielding
+/// all of its bytes, those included. Unlike seeking back, this works on pipes, e.g.
+/// `--base <(zstdcat targets.json.zst)`.
+pub fn peek<R: Read>(mut reader: R, len: usize) -> io::Result<(Vec<u8>, Peeked<R>)> {
+    let mut start = Vec::with_capacity(len);
+    (&mut reader).take(len as u64).read_to_end(&mut start)?;
+    Ok((start.clone(), Cursor::new(start).chain(reader)))
+}
+
+/// Detect the compression from the magic bytes at the start of the file,
+/// so compressed files are read correctly whatever they are called.
+fn compression(magic: &[u8]) -> Compression {
+    if magic.starts_with(ZSTD_MAGIC) {
+        Compression::Zstd
+    } else if magic.starts_with(GZIP_MAGIC) {
+        Compression::Gzip
     } else {
-        Ok(Box::new(BufReader::new(file)))
+        Compression::None
     }
 }
 
+/// Decompress `reader` as it is read if it is zstd or gzip compressed.
+pub fn decompress(reader: impl Read + Send + 'static) -> io::Result<Box<dyn BufRead + Send>> {
+    let (magic, reader) = peek(reader, ZSTD_MAGIC.len())?;
+    Ok(match compression(&magic) {
+        Compression::None => Box::new(BufReader::new(reader)) as Box<dyn BufRead + Send>,
+        Compression::Zstd => zstd_decoder(reader)?,
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn zstd_decoder(reader: impl Read + Send + 'static) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(Box::new(BufReader::new(zstd::Decoder::new(reader)?)))
+}
+
+/// The zstd library is written in C, so isn't built for WebAssembly.
+#[cfg(target_arch = "wasm32")]
+fn zstd_decoder(_reader: impl Read + Send + 'static) -> io::Result<Box<dyn BufRead + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd compression isn't supported on WebAssembly, decompress the file first",
+    ))
+}
+
+/// Open a file, decompressing it as it is read if it is zstd or gzip compressed.
+fn open_file(filename: &Path) -> anyhow::Result<impl BufRead + Send> {
+    Ok(decompress(File::open(filename)?)?)
+}
+
+/// Number of lines parsed by each parallel task in `read_file_lines_unordered`.
+/// Large enough to amortise the task overhead, small enough to balance the work.
+const LINES_PER_CHUNK: usize = 1024;
+
 /// Read a file that consists of many JSON blobs, one per line.
 /// The order of the entries does not matter.
+///
+/// Lines are split into chunks which are parsed in parallel, each into its own
+/// vector, which are concatenated at the end.
 pub fn read_file_lines_unordered<T: for<'a> Deserialize<'a> + Send>(
     filename: &Path,
 ) -> anyhow::Result<Vec<T>> {
     fn f<T: for<'a> Deserialize<'a> + Send>(filename: &Path) -> anyhow::Result<Vec<T>> {
-        let result = Mutex::new(Vec::new());
-        let error = Mutex::new(None);
-        let file = open_file(filename)?;
+        read_lines_unordered(open_file(filename)?)
+    }
+    f(filename).with_context(|| format!("When reading JSON-lines file `{}`", filename.display()))
+}
 
-        rayon::scope(|s| {
-            for x in file.lines() {
-                s.spawn(|_| match parse_line(x) {
-                    Err(e) => {
-                        error.lock().unwrap().get_or_insert(e);
-                    }
-                    Ok(v) => result.lock().unwrap().push(v),
-                });
-            }
-        });
+/// Like `read_file_lines_unordered`, but reading from `reader`, e.g. stdin. Each chunk is
+/// parsed as soon as its lines arrive, so parsing overlaps with a producer which is still
+/// writing. Blank lines are skipped.
+pub fn read_lines_unordered<T: for<'a> Deserialize<'a> + Send>(
+    reader: impl BufRead + Send,
+) -> anyhow::Result<Vec<T>> {
+    fn parse_chunk<T: for<'a> Deserialize<'a>>(chunk: Vec<String>) -> anyhow::Result<Vec<T>> {
+        chunk.into_iter().map(|x| parse_line(Ok(x))).collect()
+    }
+
+    let results = Mutex::new(Vec::new());
+    let error = Mutex::new(None);
 
-        if let Some(err) = error.into_inner().unwrap() {
-            return Err(err);
+    rayon::scope(|s| {
+        let spawn = |chunk: Vec<String>| {
+            let results = &results;
+            let error = &error;
+            s.spawn(move |_| match parse_chunk(chunk) {
+                Err(e) => {
+                    error.lock().unwrap().get_or_insert(e);
+                }
+                Ok(v) => results.lock().unwrap().push(v),
+            })
+        };
+        let mut chunk = Vec::with_capacity(LINES_PER_CHUNK);
+        for line in reader.lines() {
+            match line {
+                Err(e) => {
+                    error.lock().unwrap().get_or_insert(e.into());
+                    return;
+                }
+                Ok(line) if line.is_empty() => continue,
+                Ok(line) => chunk.push(line),
+            }
+            if chunk.len() == LINES_PER_CHUNK {
+                spawn(mem::replace(
+                    &mut chunk,
+                    Vec::with_capacity(LINES_PER_CHUNK),
+                ));
+            }
+        }
+        if !chunk.is_empty() {
+            spawn(chunk);
         }
-        Ok(result.into_inner().unwrap())
+    });
+
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+    let results = results.into_inner().unwrap();
+    let mut res = Vec::with_capacity(results.iter().map(|x| x.len()).sum());
+    for x in results {
+        res.extend(x);
+    }
+    Ok(res)
+}
+
+/// Like `read_file_lines_unordered`, but memory maps the file and deserializes each line
+/// straight out of the mapping. Strings are only borrowed from the mapping until they
+/// are interned (or skipped), so the file is never buffered in memory, reducing the peak
+/// memory while parsing. Compressed files can't be mapped, so are decompressed as they are read.
+pub fn read_file_lines_mmap<T: for<'a> Deserialize<'a> + Send>(
+    filename: &Path,
+) -> anyhow::Result<Vec<T>> {
+    fn f<T: for<'a> Deserialize<'a> + Send>(filename: &Path) -> anyhow::Result<Vec<T>> {
+        read_lines_mmap(peek(File::open(filename)?, 0)?.1)
     }
     f(filename).with_context(|| format!("When reading JSON-lines file `{}`", filename.display()))
 }
 
+/// Like `read_file_lines_mmap`, but given the file, opened at its start, after peeking at
+/// it. Only a regular file can be mapped, so anything else, e.g. a pipe from
+/// `--base <(zstdcat targets.json.zst)`, is parsed as it is read, like
+/// `read_lines_unordered`.
+pub fn read_lines_mmap<T: for<'a> Deserialize<'a> + Send>(
+    reader: Peeked<File>,
+) -> anyhow::Result<Vec<T>> {
+    fn parse_slice<T: for<'a> Deserialize<'a>>(x: &[u8]) -> anyhow::Result<T> {
+        serde_json::from_slice(x)
+            .with_context(|| format!("When parsing: {}", String::from_utf8_lossy(x)))
+    }
+
+    let metadata = reader.get_ref().1.metadata()?;
+    let (magic, reader) = peek(reader, ZSTD_MAGIC.len())?;
+    if !metadata.is_file() || compression(&magic) != Compression::None {
+        return read_lines_unordered(decompress(reader)?);
+    }
+    if metadata.len() == 0 {
+        return Ok(Vec::new());
+    }
+    let (_, reader) = reader.into_inner();
+    let (_, file) = reader.into_inner();
+    let map = mmap::map(&file)?;
+    let lines = map
+        .split(|x| *x == b'\n')
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    let chunks = lines
+        .par_chunks(LINES_PER_CHUNK)
+        .map(|chunk| chunk.iter().map(|x| parse_slice(x)).collect())
+        .collect::<anyhow::Result<Vec<Vec<T>>>>()?;
+    let mut res = Vec::with_capacity(chunks.iter().map(|x| x.len()).sum());
+    for x in chunks {
+        res.extend(x);
+    }
+    Ok(res)
+}
+
 /// Read a file that consists of many JSON blobs, one per line.
 pub fn read_file_lines<T: for<'a> Deserialize<'a>>(filename: &Path) -> anyhow::Result<Vec<T>> {
     fn f<T: for<'a> Deserialize<'a>>(filename: &Path) -> anyhow::Result<Vec<T>> {
@@ -134,12 +286,18 @@ pub fn parse_key_val(s: &str) -> anyhow::Result<(String, String)> {
 mod tests {
     use std::io::Write;
 
+    use flate2::write::GzEncoder;
     use tempfile::NamedTempFile;
 
+    use crate::json::decompress;
+    use crate::json::peek;
     use crate::json::read_file_lines;
+    use crate::json::read_file_lines_mmap;
     use crate::json::read_file_lines_unordered;
+    use crate::json::read_lines_unordered;
     use crate::json::write_json_lines;
     use crate::json::write_json_per_line;
+    use crate::json::LINES_PER_CHUNK;
 
     #[test]
     fn test_json_lines() {
@@ -186,4 +344,115 @@ mod tests {
         assert!(read_file_lines_unordered::<i32>(file.path()).is_err());
         assert!(read_file_lines::<i32>(file.path()).is_err());
     }
+
+    #[test]
+    fn test_json_lines_many_chunks() {
+        let mut file = NamedTempFile::new().unwrap();
+        let data: Vec<i32> = (0..(LINES_PER_CHUNK as i32 * 3 + 7)).collect();
+        write_json_lines(file.as_file_mut(), &data).unwrap();
+        let mut unordered = read_file_lines_unordered::<i32>(file.path()).unwrap();
+        unordered.sort();
+        assert_eq!(unordered, data);
+
+        // An error in the final, partial, chunk is still reported
+        file.write_all(b"Not an i32\n").unwrap();
+        assert!(read_file_lines_unordered::<i32>(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_json_lines_compressed() {
+        let data: Vec<i32> = (0..100).collect();
+        let mut plain = Vec::new();
+        write_json_lines(&mut plain, &data).unwrap();
+
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&plain).unwrap();
+        let compressed = [
+            zstd::encode_all(plain.as_slice(), 0).unwrap(),
+            gzip.finish().unwrap(),
+        ];
+        for bytes in compressed {
+            // The name doesn't matter, only the magic bytes
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(&bytes).unwrap();
+            assert_eq!(read_file_lines::<i32>(file.path()).unwrap(), data);
+            let mut unordered = read_file_lines_mmap::<i32>(file.path()).unwrap();
+            unordered.sort();
+            assert_eq!(unordered, data);
+        }
+    }
+
+    /// Yields one byte per read, and can't seek, like a slow pipe.
+    struct Dribble(std::vec::IntoIter<u8>);
+
+    impl std::io::Read for Dribble {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match (buf.first_mut(), self.0.next()) {
+                (Some(x), Some(y)) => {
+                    *x = y;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_peek() {
+        let (start, mut rest) = peek(Dribble(b"hello".to_vec().into_iter()), 4).unwrap();
+        assert_eq!(start, b"hell");
+        let mut all = String::new();
+        std::io::Read::read_to_string(&mut rest, &mut all).unwrap();
+        assert_eq!(all, "hello");
+
+        let (start, _) = peek(Dribble(b"hi".to_vec().into_iter()), 4).unwrap();
+        assert_eq!(start, b"hi");
+
+        let data: Vec<i32> = (0..100).collect();
+        let mut plain = Vec::new();
+        write_json_lines(&mut plain, &data).unwrap();
+        let compressed = zstd::encode_all(plain.as_slice(), 0).unwrap();
+        let mut unordered =
+            read_lines_unordered::<i32>(decompress(Dribble(compressed.into_iter())).unwrap())
+                .unwrap();
+        unordered.sort();
+        assert_eq!(unordered, data);
+    }
+
+    #[test]
+    fn test_json_lines_mmap() {
+        let mut file = NamedTempFile::new().unwrap();
+        assert!(read_file_lines_mmap::<i32>(file.path()).unwrap().is_empty());
+
+        let data: Vec<i32> = (0..(LINES_PER_CHUNK as i32 * 2 + 3)).collect();
+        write_json_lines(file.as_file_mut(), &data).unwrap();
+        assert_eq!(read_file_lines_mmap::<i32>(file.path()).unwrap(), data);
+
+        file.write_all(b"Not an i32\n").unwrap();
+        assert!(read_file_lines_mmap::<i32>(file.path()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_json_lines_fifo() {
+        // Can't be mapped, and has no length, but still has lines
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("targets.json");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap()
+            .success());
+        let data: Vec<i32> = (0..100).collect();
+        let writer = {
+            let (fifo, data) = (fifo.clone(), data.clone());
+            std::thread::spawn(move || {
+                write_json_lines(std::fs::File::create(fifo).unwrap(), &data).unwrap()
+            })
+        };
+        let mut res = read_file_lines_mmap::<i32>(&fifo).unwrap();
+        writer.join().unwrap();
+        res.sort();
+        assert_eq!(res, data);
+    }
 }
@@ -7,13 +7,17 @@
  * of this source tree.
  */
 
-#![forbid(unsafe_code)]
+// Only allowed for memory mapping files, see `mmap`, and reading strings stored
+// inline, see `string::InternString::as_str`
+#![deny(unsafe_code)]
 
 pub mod cli;
 pub mod command;
 pub mod directives;
+pub mod intern;
 pub mod json;
 pub mod knobs;
+mod mmap;
 pub mod no_hash;
 pub mod prelude;
 pub mod project;
@@ -24,6 +28,8 @@ pub mod tracing;
 pub mod xplat;
 
 /// Initialize `tracing` and `supertd_events` Scuba client.
+/// Logging is configured by the `--log-format` and `--log-level` arguments,
+/// see `cli::log_options`, which fails if they are invalid.
 ///
 /// Returns a guard that flushes the Scuba client when dropped.
 ///
@@ -31,7 +37,7 @@ pub mod xplat;
 ///
 /// Panics if environment variable `SUPERTD_SCUBA_LOGFILE` is set and the log
 /// file cannot be opened for writing.
-pub fn init(fb: fbinit::FacebookInit) -> supertd_events::ScubaClientGuard {
-    tracing::init_tracing();
-    supertd_events::init(fb)
+pub fn init(fb: fbinit::FacebookInit) -> anyhow::Result<supertd_events::ScubaClientGuard> {
+    tracing::init_tracing(&cli::log_options()?);
+    Ok(supertd_events::init(fb))
 }
@@ -0,0 +1,37 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Memory mapping files, kept apart as it needs unsafe code.
+
+#![allow(unsafe_code)]
+
+use std::fs::File;
+use std::io;
+#[cfg(target_arch = "wasm32")]
+use std::io::Read;
+
+#[cfg(not(target_arch = "wasm32"))]
+use memmap2::Mmap;
+
+/// Map all of `file` into memory. Modifying the file while it is mapped is undefined
+/// behaviour, so only map files which are never written to once produced, like the
+/// output of `buck2 targets`.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn map(file: &File) -> io::Result<Mmap> {
+    // SAFETY: Callers only map files which aren't modified, see above.
+    unsafe { Mmap::map(file) }
+}
+
+/// There is no memory mapping on WebAssembly, so read all of `file` instead.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn map(mut file: &File) -> io::Result<Vec<u8>> {
+    let mut res = Vec::new();
+    file.read_to_end(&mut res)?;
+    Ok(res)
+}
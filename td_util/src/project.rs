@@ -18,6 +18,8 @@ use std::process::Command;
 use clap::ValueEnum;
 use parse_display::Display;
 
+use crate::command::with_command;
+
 #[derive(ValueEnum, Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
 #[derive(serde::Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -39,7 +41,9 @@ impl TdProject {
 }
 
 pub fn get_repo_root() -> anyhow::Result<PathBuf> {
-    let output = Command::new("hg").arg("root").output()?;
+    let mut command = Command::new("hg");
+    command.arg("root");
+    let output = with_command(command, |mut command| Ok(command.output()?))?;
     let stdout = String::from_utf8_lossy(&output.stdout).replace('\n', "");
     Ok(PathBuf::from(stdout))
 }
@@ -9,12 +9,27 @@
 
 //! A simple interning utility for strings.
 //! Significantly reduce the memory of repeated strings.
+//!
+//! Each interner is split into shards, chosen by a hash of the string, so threads
+//! parsing in parallel rarely contend on the same shard. A string always goes to the
+//! same shard, so it is still only stored once.
+//!
+//! Strings of up to [`INLINE`] bytes, e.g. most rule types and target names, are
+//! instead stored inside the [`InternString`] itself, which avoids hashing them and
+//! following a pointer to read them. A string is always stored the same way, so
+//! equality still only needs to compare the representations.
 
+use std::collections::HashMap;
 use std::fmt;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
 
 use equivalent::Equivalent;
+use lazy_static::lazy_static;
 use parse_display::Display;
 use serde::de::Visitor;
 use serde::Deserialize;
@@ -23,14 +38,211 @@ use serde::Serialize;
 use static_interner::Intern;
 use static_interner::Interner;
 
+use crate::intern::Interned;
+use crate::intern::Packages;
+
 type StrData = Key<Box<str>>;
 
-static INTERNER: Interner<StrData> = Interner::new();
+static INTERNER: StringInterner = StringInterner::new();
+
+const SHARDS: usize = 16;
+
+/// An [`Interner`] split into [`SHARDS`] shards.
+struct ShardedInterner<T: 'static>([Shard<T>; SHARDS]);
+
+struct Shard<T: 'static> {
+    interner: Interner<T>,
+    stats: ShardStats,
+}
+
+/// Counts of the values inserted into a shard. Interning the same value on several
+/// threads at once may count it more than once.
+struct ShardStats {
+    count: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl ShardStats {
+    fn add(&self, bytes: usize) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl<T: Eq + Hash + Send + Sync> ShardedInterner<T> {
+    // Only used to initialise the shards, each of which is its own copy
+    #[allow(clippy::declare_interior_mutable_const)]
+    const SHARD: Shard<T> = Shard {
+        interner: Interner::new(),
+        stats: ShardStats {
+            count: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        },
+    };
+
+    const fn new() -> Self {
+        Self([Self::SHARD; SHARDS])
+    }
+
+    fn intern<Q: Hash + Equivalent<T>>(&'static self, value: Q) -> Intern<T>
+    where
+        Counted<'static, Q>: Into<T>,
+    {
+        let mut hasher = ShardHasher::default();
+        value.hash(&mut hasher);
+        let shard = &self.0[hasher.finish() as usize % SHARDS];
+        shard.interner.intern(Counted {
+            value,
+            stats: &shard.stats,
+        })
+    }
+
+    fn stats(&self) -> InternStats {
+        let shards = self
+            .0
+            .iter()
+            .map(|x| x.stats.count.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        InternStats {
+            count: shards.iter().sum(),
+            bytes: self
+                .0
+                .iter()
+                .map(|x| x.stats.bytes.load(Ordering::Relaxed))
+                .sum(),
+            shards,
+        }
+    }
+}
+
+/// A value being interned, which counts itself in the stats of its shard when the
+/// interner converts it, i.e. only when it wasn't already interned.
+struct Counted<'a, Q> {
+    value: Q,
+    stats: &'a ShardStats,
+}
+
+impl<Q: Hash> Hash for Counted<'_, Q> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value.hash(state)
+    }
+}
+
+impl<Q: Equivalent<T>, T> Equivalent<T> for Counted<'_, Q> {
+    fn equivalent(&self, key: &T) -> bool {
+        self.value.equivalent(key)
+    }
+}
+
+impl<Q: Into<StrData>> From<Counted<'_, Q>> for StrData {
+    fn from(value: Counted<'_, Q>) -> Self {
+        let res: Self = value.value.into();
+        value.stats.add(res.0.len());
+        res
+    }
+}
+
+impl<Q: Into<LabelData>> From<Counted<'_, Q>> for LabelData {
+    fn from(value: Counted<'_, Q>) -> Self {
+        let res: Self = value.value.into();
+        value.stats.add(res.label.len());
+        res
+    }
+}
+
+/// An interner for strings, separate from the one [`InternString::new`] uses, so
+/// strings of different kinds can be kept apart, see [`crate::intern`].
+pub struct StringInterner(ShardedInterner<StrData>);
+
+impl StringInterner {
+    // Only useful as a `static`, so no `Default`
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self(ShardedInterner::new())
+    }
+
+    pub fn stats(&'static self) -> InternStats {
+        self.0.stats()
+    }
+}
+
+/// How much an interner holds, from [`InternString::stats`] or [`InternLabel::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InternStats {
+    /// Number of distinct values interned.
+    pub count: usize,
+    /// Total length of the values, not counting the overhead of storing them.
+    pub bytes: usize,
+    /// Number of values in each shard, which should be roughly even.
+    pub shards: Vec<usize>,
+}
+
+/// Whether to count how often each string is interned, see [`InternString::track_uses`].
+static TRACK_USES: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref USES: Mutex<HashMap<InternString, usize>> = Mutex::new(HashMap::new());
+}
+
+/// FNV-1a, which is cheap for short strings and, like the hashes of the keys
+/// need, gives the same result however the bytes are split between calls to `write`.
+struct ShardHasher(u64);
+
+impl Default for ShardHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for ShardHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for x in bytes {
+            self.0 = (self.0 ^ u64::from(*x)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The longest string stored inline, which keeps [`InternString`] at 16 bytes: the
+/// enum tag, the length and the bytes.
+const INLINE: usize = 14;
 
 /// An interned string whose contents are stored only once.
-// Eq/PartialEq are OK, because they short-circuit on the hash
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Display)]
-pub struct InternString(Intern<StrData>);
+// Eq/PartialEq are OK, because a string is always stored the same way, and
+// interned strings short-circuit on the hash
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct InternString(Repr);
+
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum Repr {
+    /// The first `len` bytes are the string.
+    Inline { len: u8, bytes: [u8; INLINE] },
+    /// Longer than `INLINE` bytes.
+    Interned(Intern<StrData>),
+}
+
+impl Repr {
+    /// The inline representation of the concatenation of `xs`, if it's short enough.
+    fn inline(xs: &[&str]) -> Option<Self> {
+        let len = xs.iter().map(|x| x.len()).sum::<usize>();
+        if len > INLINE {
+            return None;
+        }
+        let mut bytes = [0; INLINE];
+        let mut i = 0;
+        for x in xs {
+            bytes[i..i + x.len()].copy_from_slice(x.as_bytes());
+            i += x.len();
+        }
+        Some(Self::Inline {
+            len: len as u8,
+            bytes,
+        })
+    }
+}
 
 struct InternStringVisitor;
 
@@ -80,6 +292,30 @@ impl Default for InternString {
     }
 }
 
+impl PartialOrd for InternString {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InternString {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl fmt::Debug for InternString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("InternString").field(&self.as_str()).finish()
+    }
+}
+
+impl fmt::Display for InternString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Display)]
 struct Key<T>(T);
 
@@ -139,6 +375,12 @@ impl<'a> Equivalent<StrData> for Key<(&'a str, &'a str, &'a str)> {
     }
 }
 
+impl<'a> Equivalent<LabelData> for Key<&'a str> {
+    fn equivalent(&self, key: &LabelData) -> bool {
+        self.0 == &*key.label
+    }
+}
+
 impl<'a> From<Key<&'a str>> for StrData {
     fn from(value: Key<&str>) -> Self {
         Key(value.0.into())
@@ -161,22 +403,235 @@ impl<'a> From<Key<(&'a str, &'a str, &'a str)>> for StrData {
     }
 }
 
+/// Split a configured label, e.g. `foo//bar:baz (cfg//:linux)`, into the label and
+/// its configuration, which may itself contain a `:`.
+fn split_configuration(label: &str) -> (&str, Option<&str>) {
+    match label.strip_suffix(')').and_then(|x| x.rsplit_once(" (")) {
+        Some((label, cfg)) => (label, Some(cfg)),
+        None => (label, None),
+    }
+}
+
+impl<'a> From<Key<&'a str>> for LabelData {
+    fn from(value: Key<&str>) -> Self {
+        // The name keeps the configuration, so labels in different configurations differ
+        let (unconfigured, _) = split_configuration(value.0);
+        let (package, name) = match unconfigured.rfind(':') {
+            Some(i) => (&value.0[..i], &value.0[i + 1..]),
+            None => (value.0, ""),
+        };
+        LabelData {
+            label: value.0.into(),
+            package: Interned::new(package),
+            name: InternString::new(name),
+        }
+    }
+}
+
 impl InternString {
     pub fn new(x: &str) -> Self {
-        InternString(INTERNER.intern(Key(x)))
+        Self::new_in(&INTERNER, x)
+    }
+
+    /// Like `new`, but interned by `interner`. Only equal to strings from the same one.
+    pub(crate) fn new_in(interner: &'static StringInterner, x: &str) -> Self {
+        let repr = Repr::inline(&[x]).unwrap_or_else(|| Repr::Interned(interner.0.intern(Key(x))));
+        InternString(repr).used()
     }
 
     /// Equivalent to `new` with the three arguments concatenated.
     pub fn new3(x: &str, y: &str, z: &str) -> Self {
-        InternString(INTERNER.intern(Key((x, y, z))))
+        let repr = Repr::inline(&[x, y, z])
+            .unwrap_or_else(|| Repr::Interned(INTERNER.0.intern(Key((x, y, z)))));
+        InternString(repr).used()
     }
 
     pub fn from_string(x: String) -> Self {
-        InternString(INTERNER.intern(Key(x)))
+        let repr = Repr::inline(&[&x]).unwrap_or_else(|| Repr::Interned(INTERNER.0.intern(Key(x))));
+        InternString(repr).used()
     }
 
     pub fn as_str(&self) -> &str {
-        &self.0.0
+        match &self.0 {
+            Repr::Inline { len, bytes } => {
+                // SAFETY: Only ever copied from a `str`, and never split inside a character.
+                #[allow(unsafe_code)]
+                unsafe {
+                    std::str::from_utf8_unchecked(&bytes[..*len as usize])
+                }
+            }
+            Repr::Interned(x) => &x.0,
+        }
+    }
+
+    /// How many strings have been interned, and how big they are. Strings stored
+    /// inline aren't counted, as they take no space in the interner.
+    pub fn stats() -> InternStats {
+        INTERNER.stats()
+    }
+
+    /// Start or stop counting how often each string is interned, for
+    /// [`most_used`](Self::most_used). Meant for debugging, as every string interned
+    /// while counting takes a global lock.
+    pub fn track_uses(enabled: bool) {
+        TRACK_USES.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The `k` strings interned most often while [`track_uses`](Self::track_uses) was
+    /// enabled, with how often, most used first.
+    pub fn most_used(k: usize) -> Vec<(InternString, usize)> {
+        let mut res = USES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(x, n)| (x.clone(), *n))
+            .collect::<Vec<_>>();
+        res.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        res.truncate(k);
+        res
+    }
+
+    fn used(self) -> Self {
+        if TRACK_USES.load(Ordering::Relaxed) {
+            *USES.lock().unwrap().entry(self.clone()).or_default() += 1;
+        }
+        self
+    }
+}
+
+static LABELS: ShardedInterner<LabelData> = ShardedInterner::new();
+
+/// An interned label of the form `package:name`, e.g. `fbcode//buck2:buck2`, which
+/// also keeps its package and name interned. Splitting a label into its
+/// parts is free, and joining the parts only allocates the first time the label is seen.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InternLabel(Intern<LabelData>);
+
+// Ordered by `label`, as the parts follow from it.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct LabelData {
+    label: Box<str>,
+    package: Interned<Packages>,
+    name: InternString,
+}
+
+// Must hash the same as `Key<&str>` of the label
+impl Hash for LabelData {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.label.as_bytes())
+    }
+}
+
+/// The package and name of a label, to be joined with `:`.
+struct LabelParts<'a>(&'a Interned<Packages>, &'a InternString);
+
+impl<'a> Hash for LabelParts<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.0.as_str().as_bytes());
+        state.write(b":");
+        state.write(self.1.as_str().as_bytes());
+    }
+}
+
+impl<'a> Equivalent<LabelData> for LabelParts<'a> {
+    fn equivalent(&self, key: &LabelData) -> bool {
+        // The parts are interned, so compare cheaply. Checking the length rules out
+        // labels without a `:`, whose package is the whole label.
+        key.package == *self.0
+            && key.name == *self.1
+            && key.label.len() == self.0.as_str().len() + 1 + self.1.as_str().len()
+    }
+}
+
+impl<'a> From<LabelParts<'a>> for LabelData {
+    fn from(value: LabelParts) -> Self {
+        LabelData {
+            label: format!("{}:{}", value.0, value.1).into_boxed_str(),
+            package: value.0.clone(),
+            name: value.1.clone(),
+        }
+    }
+}
+
+impl InternLabel {
+    /// Split at the last `:` before any configuration, which stays part of the name.
+    /// A label without a `:` has an empty name.
+    pub fn new(label: &str) -> Self {
+        InternLabel(LABELS.intern(Key(label)))
+    }
+
+    /// Equivalent to `new` of `package:name`, but reuses the interned parts.
+    pub fn join(package: &Interned<Packages>, name: &InternString) -> Self {
+        InternLabel(LABELS.intern(LabelParts(package, name)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0.label
+    }
+
+    /// The label without any configuration.
+    pub fn unconfigured(&self) -> &str {
+        split_configuration(&self.0.label).0
+    }
+
+    /// The configuration, e.g. `cfg//:linux` for `foo//bar:baz (cfg//:linux)`.
+    pub fn configuration(&self) -> Option<&str> {
+        split_configuration(&self.0.label).1
+    }
+
+    pub fn package(&self) -> &Interned<Packages> {
+        &self.0.package
+    }
+
+    pub fn name(&self) -> &InternString {
+        &self.0.name
+    }
+
+    /// How many labels have been interned, and how big they are. Their packages are
+    /// counted by [`Interned::stats`], and their names by [`InternString::stats`].
+    pub fn stats() -> InternStats {
+        LABELS.stats()
+    }
+}
+
+impl fmt::Display for InternLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for InternLabel {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for InternLabel {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(InternLabelVisitor)
+    }
+}
+
+struct InternLabelVisitor;
+
+impl<'de> Visitor<'de> for InternLabelVisitor {
+    type Value = InternLabel;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a label")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(InternLabel::new(v))
     }
 }
 
@@ -195,4 +650,151 @@ mod tests {
             InternString::new3("ab", "", "defg!")
         );
     }
+
+    #[test]
+    fn test_intern_string_inline() {
+        assert_eq!(std::mem::size_of::<InternString>(), 16);
+        let long = "é".repeat(8);
+        for x in [
+            "",
+            "a",
+            "cxx_library",
+            "fourteen_bytes",
+            "fifteen_bytes!!",
+            &long,
+        ] {
+            let (a, b) = x.split_at(x.len() / 2);
+            assert_eq!(InternString::new(x).as_str(), x);
+            assert_eq!(InternString::new(x), InternString::new3(a, "", b));
+            assert_eq!(
+                InternString::new(x),
+                InternString::from_string(x.to_owned())
+            );
+        }
+        assert!(matches!(
+            InternString::new("fourteen_bytes").0,
+            Repr::Inline { .. }
+        ));
+        assert!(matches!(
+            InternString::new("fifteen_bytes!!").0,
+            Repr::Interned(_)
+        ));
+        assert_ne!(
+            InternString::new("fourteen_bytes"),
+            InternString::new("fourteen_byte")
+        );
+
+        let mut xs =
+            ["b", "a_long_interned_string", "a", "c_long_interned_string"].map(InternString::new);
+        xs.sort();
+        assert_eq!(
+            xs.map(|x| x.to_string()),
+            ["a", "a_long_interned_string", "b", "c_long_interned_string"]
+        );
+    }
+
+    #[test]
+    fn test_intern_string_threads() {
+        let strings = (0..1000)
+            .map(|i| format!("foo//bar:baz{i}"))
+            .collect::<Vec<_>>();
+        let intern = || {
+            strings
+                .iter()
+                .map(|x| InternString::new(x))
+                .collect::<Vec<_>>()
+        };
+        let interned = std::thread::scope(|s| {
+            let threads = (0..4).map(|_| s.spawn(intern)).collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|x| x.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        for xs in &interned {
+            assert_eq!(xs, &interned[0]);
+        }
+        assert_eq!(interned[0][7].as_str(), "foo//bar:baz7");
+    }
+
+    #[test]
+    fn test_intern_stats() {
+        let before = InternString::stats();
+        InternString::new("stats_test_long_a");
+        InternString::new3("stats_", "test_long", "_bc");
+        InternString::new("stats_test_long_a");
+        InternString::new("inline");
+        let after = InternString::stats();
+        // Other tests may intern strings at the same time
+        assert!(after.count >= before.count + 2);
+        assert!(after.bytes >= before.bytes + 35);
+        assert_eq!(after.shards.len(), SHARDS);
+        assert_eq!(after.shards.iter().sum::<usize>(), after.count);
+
+        let labels = InternLabel::stats().count;
+        InternLabel::new("foo//stats:test");
+        assert!(InternLabel::stats().count > labels);
+    }
+
+    #[test]
+    fn test_most_used() {
+        InternString::track_uses(true);
+        for _ in 0..3 {
+            InternString::new("most_used_test_a");
+        }
+        InternString::from_string("most_used_test_b".to_owned());
+        InternString::track_uses(false);
+        InternString::new("most_used_test_b");
+        let used = InternString::most_used(usize::MAX)
+            .into_iter()
+            .filter(|x| x.0.as_str().starts_with("most_used_test"))
+            .map(|(x, n)| (x.as_str().to_owned(), n))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            used,
+            vec![
+                ("most_used_test_a".to_owned(), 3),
+                ("most_used_test_b".to_owned(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_intern_label() {
+        let label = InternLabel::new("foo//bar:baz");
+        assert_eq!(label.as_str(), "foo//bar:baz");
+        assert_eq!(label.package(), &Interned::new("foo//bar"));
+        assert_eq!(label.name(), &InternString::new("baz"));
+        assert_eq!(
+            label,
+            InternLabel::join(&Interned::new("foo//bar"), &InternString::new("baz"))
+        );
+        assert_eq!(
+            InternLabel::join(&Interned::new("foo//qux"), &InternString::new("baz")).as_str(),
+            "foo//qux:baz"
+        );
+
+        let configured = InternLabel::new("foo//bar:baz (cfg//:linux)");
+        assert_eq!(configured.package(), &Interned::new("foo//bar"));
+        assert_eq!(configured.name(), &InternString::new("baz (cfg//:linux)"));
+        assert_eq!(configured.unconfigured(), "foo//bar:baz");
+        assert_eq!(configured.configuration(), Some("cfg//:linux"));
+        assert_eq!(label.unconfigured(), "foo//bar:baz");
+        assert_eq!(label.configuration(), None);
+        assert_eq!(
+            configured,
+            InternLabel::join(
+                &Interned::new("foo//bar"),
+                &InternString::new("baz (cfg//:linux)")
+            )
+        );
+
+        let nameless = InternLabel::new("foo//bar");
+        assert_eq!(nameless.package(), &Interned::new("foo//bar"));
+        assert_eq!(nameless.name(), &InternString::new(""));
+        assert_ne!(
+            nameless,
+            InternLabel::join(&Interned::new("foo//bar"), &InternString::new(""))
+        );
+    }
 }
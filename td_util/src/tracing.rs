@@ -14,17 +14,41 @@ use std::io::stderr;
 use std::io::stdout;
 use std::io::IsTerminal;
 
+use clap::ValueEnum;
 use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::Layer;
 
+/// How log events are written to stderr.
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event, with its fields and enclosing span, for log aggregation.
+    Json,
+}
+
+/// The logging options shared by every binary, see `cli::log_options`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    /// The most verbose level logged, overriding `RUST_LOG` and the defaults.
+    pub level: Option<LevelFilter>,
+}
+
 /// Set up tracing so it prints to stderr, and can be used for output.
 /// Most things should use `info` and `debug` level for showing messages.
-pub fn init_tracing() {
+///
+/// With the `otlp` feature, if `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are
+/// also exported there, so big deployments can see where each run spends its time.
+pub fn init_tracing(options: &LogOptions) {
     let mut env_filter = EnvFilter::from_default_env();
-    if std::env::var_os("RUST_LOG").is_none() {
+    if let Some(level) = options.level {
+        env_filter = EnvFilter::default().add_directive(level.into());
+    } else if std::env::var_os("RUST_LOG").is_none() {
         // Enable info log by default
         env_filter = env_filter.add_directive(LevelFilter::INFO.into());
         // Debug log for target determinator packages
@@ -49,9 +73,44 @@ pub fn init_tracing() {
         .with_line_number(false)
         .with_file(false)
         .with_writer(stderr)
-        .with_ansi(stdout().is_terminal())
-        .with_target(false)
-        .with_filter(env_filter);
+        .with_target(false);
+    let layer = match options.format {
+        LogFormat::Text => layer
+            .with_ansi(stdout().is_terminal())
+            .with_filter(env_filter)
+            .boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_ansi(false)
+            .with_span_list(false)
+            .with_filter(env_filter)
+            .boxed(),
+    };
 
-    tracing_subscriber::registry().with(layer).init();
+    let registry = tracing_subscriber::registry().with(layer);
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(otlp_layer());
+    registry.init();
+}
+
+/// Export spans over OTLP/HTTP, configured by the standard `OTEL_*` environment variables.
+/// Spans are exported as they end, so there is nothing to flush on exit.
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>() -> Option<impl Layer<S>>
+where
+    S: ::tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http())
+        .install_simple();
+    match tracer {
+        Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(e) => {
+            // Tracing isn't set up yet, so print directly
+            eprintln!("Failed to set up OpenTelemetry export: {e}");
+            None
+        }
+    }
 }